@@ -0,0 +1,76 @@
+//! Benchmarks of the handshake, session cipher, signature recovery and relay.
+//! Run with `cargo bench -p chamomile --features bench`.
+
+use chamomile::bench::{handshake, Cipher, Relay};
+use chamomile_types::key::Key;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+const SIZES: [usize; 3] = [64, 1024, 64 * 1024];
+
+fn keys() -> (Key, Key) {
+    let mut rng = ChaChaRng::from_seed([1u8; 32]);
+    (Key::generate(&mut rng), Key::generate(&mut rng))
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let (a, b) = keys();
+    c.bench_function("handshake", |bench| {
+        bench.iter(|| assert!(handshake(&a, &b)))
+    });
+}
+
+fn bench_cipher(c: &mut Criterion) {
+    let (a, b) = keys();
+    let (a_cipher, b_cipher) = Cipher::pair(&a, &b);
+    let mut group = c.benchmark_group("cipher");
+    for size in SIZES {
+        let data = vec![7u8; size];
+        let e_data = a_cipher.encrypt(data.clone());
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("encrypt", size), &data, |bench, data| {
+            bench.iter(|| a_cipher.encrypt(data.clone()))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("decrypt", size),
+            &e_data,
+            |bench, e_data| bench.iter(|| b_cipher.decrypt(e_data.clone()).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+fn bench_signature(c: &mut Criterion) {
+    let (a, _) = keys();
+    let msg = b"chamomile signature recovery";
+    let sign = a.sign(msg);
+    c.bench_function("signature peer_id", |bench| {
+        bench.iter(|| sign.peer_id(msg).unwrap())
+    });
+}
+
+fn bench_relay(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut relay = rt.block_on(Relay::new());
+    let mut group = c.benchmark_group("relay");
+    for size in SIZES {
+        let data = vec![7u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("one hop", size), &data, |bench, data| {
+            bench.iter(|| assert!(rt.block_on(relay.forward(data.clone()))))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_handshake,
+    bench_cipher,
+    bench_signature,
+    bench_relay
+);
+criterion_main!(benches);
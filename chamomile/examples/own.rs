@@ -28,12 +28,15 @@ async fn main() {
     config.db_dir = std::path::PathBuf::from(format!(".data/own/{}", rng.gen::<u8>()));
 
     // default key to test own.
-    let key = Key::from_db_bytes(&[
-        0, 72, 137, 44, 19, 236, 242, 211, 157, 163, 190, 217, 116, 14, 149, 254, 211, 242, 248,
-        101, 191, 114, 185, 88, 249, 177, 115, 181, 251, 9, 10, 71, 13, 236, 8, 166, 64, 201, 101,
-        183, 186, 156, 138, 166, 75, 253, 158, 211, 124, 155, 152, 89, 33, 8, 72, 160, 108, 248,
-        205, 76, 100, 75, 133, 247, 202,
-    ])
+    let key = Key::from_db_bytes_with(
+        &[
+            0, 72, 137, 44, 19, 236, 242, 211, 157, 163, 190, 217, 116, 14, 149, 254, 211, 242,
+            248, 101, 191, 114, 185, 88, 249, 177, 115, 181, 251, 9, 10, 71, 13, 236, 8, 166, 64,
+            201, 101, 183, 186, 156, 138, 166, 75, 253, 158, 211, 124, 155, 152, 89, 33, 8, 72,
+            160, 108, 248, 205, 76, 100, 75, 133, 247, 202,
+        ],
+        true,
+    )
     .unwrap();
 
     let (peer_id, send, mut recv) = start_with_key(config, key).await.unwrap();
@@ -32,7 +32,7 @@ async fn main() {
 
     while let Some(message) = recv.recv().await {
         match message {
-            ReceiveMessage::Data(peer_id, bytes) => {
+            ReceiveMessage::Data(peer_id, bytes, _) => {
                 println!("Recv data from: {}, {:?}", peer_id.short_show(), bytes);
             }
             ReceiveMessage::StableConnect(peer, join_data) => {
@@ -43,7 +43,7 @@ async fn main() {
         fn mod_reduce(mut i: u32) -> u8 {
             loop {
                 if i > 255 {
-                    i = i - 255
+                    i -= 255
                 } else {
                     break;
                 }
@@ -67,7 +67,7 @@ async fn main() {
 
     while let Some(message) = recv.recv().await {
         match message {
-            ReceiveMessage::Data(remote_id, bytes) => {
+            ReceiveMessage::Data(remote_id, bytes, _) => {
                 println!(
                     "Recv permissionless data from: {}, {}-{:?}",
                     remote_id.short_show(),
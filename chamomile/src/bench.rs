@@ -0,0 +1,96 @@
+//! The hot paths entry, only for benchmarks (feature `bench`).
+//! All run in memory with channels, no real network sockets.
+
+use chamomile_types::{key::Key, Peer, PeerId};
+use std::sync::Arc;
+use tokio::sync::mpsc::Receiver;
+
+use crate::global::memory_global;
+use crate::kad::KadValue;
+use crate::session::{new_session_channel, ConnectType, Session, SessionMessage};
+use crate::session_key::SessionKey;
+use crate::transports::{new_endpoint_channel, EndpointMessage};
+
+/// the full handshake of both sides, return if the session keys are ok.
+pub fn handshake(a: &Key, b: &Key) -> bool {
+    let (mut a_session, a_dh) = SessionKey::generate(a);
+    let (mut b_session, b_dh) = SessionKey::generate(b);
+    a_session.complete(&b.peer_id(), b_dh) && b_session.complete(&a.peer_id(), a_dh)
+}
+
+/// the session cipher after handshake.
+pub struct Cipher(SessionKey);
+
+impl Cipher {
+    /// the ciphers of both sides.
+    pub fn pair(a: &Key, b: &Key) -> (Cipher, Cipher) {
+        let (mut a_session, a_dh) = SessionKey::generate(a);
+        let (mut b_session, b_dh) = SessionKey::generate(b);
+        assert!(a_session.complete(&b.peer_id(), b_dh));
+        assert!(b_session.complete(&a.peer_id(), a_dh));
+        (Cipher(a_session), Cipher(b_session))
+    }
+
+    pub fn encrypt(&self, msg: Vec<u8>) -> Vec<u8> {
+        self.0.encrypt(msg)
+    }
+
+    pub fn decrypt(&self, msg: Vec<u8>) -> Option<Vec<u8>> {
+        self.0.decrypt(msg).ok()
+    }
+}
+
+/// the relay of one hop, from the previous session to the next peer session.
+pub struct Relay {
+    session: Session,
+    next: Receiver<SessionMessage>,
+    from: PeerId,
+    to: PeerId,
+    _endpoint: Receiver<EndpointMessage>,
+}
+
+impl Relay {
+    pub async fn new() -> Relay {
+        let (global, _) = memory_global();
+        let (from, to) = (PeerId([2u8; 20]), PeerId([3u8; 20]));
+
+        let (next_sender, next) = new_session_channel();
+        let (next_stream, _) = new_endpoint_channel();
+        let mut next_peer = Peer::peer(to);
+        next_peer.assist = to;
+        let kv = KadValue(next_sender, next_stream, next_peer);
+        global.peer_list.write().await.add_dht(kv).await;
+
+        let (session_key, _) = SessionKey::generate(global.key.as_ref());
+        let (session_sender, _) = new_session_channel();
+        let (_, stream_receiver) = new_endpoint_channel();
+        let (endpoint_sender, endpoint) = new_endpoint_channel();
+        let session = Session::new(
+            Peer::peer(PeerId([1u8; 20])),
+            session_sender,
+            stream_receiver,
+            ConnectType::Direct(endpoint_sender),
+            session_key,
+            Arc::new(global),
+            false,
+            false,
+        );
+
+        Relay {
+            session,
+            next,
+            from,
+            to,
+            _endpoint: endpoint,
+        }
+    }
+
+    /// forward the relay data to the next peer, return if it is forwarded.
+    pub async fn forward(&mut self, data: Vec<u8>) -> bool {
+        let msg = EndpointMessage::RelayData(self.from, self.to, data);
+        if self.session.handle_endpoint(msg).await.is_err() {
+            return false;
+        }
+        matches!(self.next.recv().await, Some(SessionMessage::RelayData(..)))
+    }
+}
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
 use tokio::sync::mpsc::Sender;
 
-use chamomile_types::{Peer, PeerId};
+use chamomile_types::{types::PeerTier, Peer, PeerId};
 
 use crate::kad::KadValue;
 use crate::session::SessionMessage;
@@ -14,6 +16,7 @@ pub enum BufferKey {
     Addr(SocketAddr),
 }
 
+#[allow(clippy::type_complexity)]
 pub(crate) struct Buffer {
     /// queue for connect to ip addr. if has one, not send aggin.
     dhts: HashMap<SocketAddr, bool>,
@@ -23,19 +26,27 @@ pub(crate) struct Buffer {
     results: HashMap<PeerId, (bool, Vec<(u64, Vec<u8>)>)>,
     /// tmp stable waiting outside to stable result. 60s if no-ok, close it.
     tmps: HashMap<PeerId, (bool, KadValue, bool)>,
+    /// relayed bytes buffered for tmp sessions still in handshake,
+    /// and the time of first buffered.
+    tmp_buffered: HashMap<PeerId, (Instant, usize)>,
+    /// max bytes of all tmp buffered, 0 is unlimited.
+    tmp_limit: usize,
 }
 
 impl Buffer {
-    pub fn init() -> Self {
+    pub fn init(tmp_limit: usize) -> Self {
         Buffer {
             dhts: HashMap::new(),
             connects: HashMap::new(),
             results: HashMap::new(),
             tmps: HashMap::new(),
+            tmp_buffered: HashMap::new(),
+            tmp_limit,
         }
     }
 
-    pub fn _add_dht(&mut self, ip: &SocketAddr) -> bool {
+    /// Result is new one or not. if not new, it is pending or recently failed.
+    pub fn add_dht(&mut self, ip: &SocketAddr) -> bool {
         if self.dhts.contains_key(ip) {
             false
         } else {
@@ -44,7 +55,7 @@ impl Buffer {
         }
     }
 
-    pub fn _remove_dht(&mut self, ip: &SocketAddr) {
+    pub fn remove_dht(&mut self, ip: &SocketAddr) {
         self.dhts.remove(ip);
     }
 
@@ -59,6 +70,7 @@ impl Buffer {
         }
     }
 
+    /// the buffered in the order of added, drain it in send order.
     pub fn remove_connect(&mut self, key: BufferKey) -> Vec<(u64, Vec<u8>)> {
         self.connects.remove(&key).map(|v| v.1).unwrap_or(vec![])
     }
@@ -86,22 +98,82 @@ impl Buffer {
         self.tmps.get(peer_id).map(|(_, v, _)| &v.0)
     }
 
-    pub fn get_tmp_stream(&self, peer_id: &PeerId) -> Option<&Sender<EndpointMessage>> {
-        self.tmps.get(peer_id).map(|(_, v, _)| &v.1)
+    pub fn has_tmp(&self, peer_id: &PeerId) -> bool {
+        self.tmps.contains_key(peer_id)
     }
 
     pub fn add_tmp(&mut self, peer_id: PeerId, value: KadValue, is_d: bool) {
         self.tmps.insert(peer_id, (false, value, is_d));
     }
 
+    /// the tmp session handshake is ok, the stream is reading, not buffered.
     pub fn update_peer(&mut self, peer_id: &PeerId, peer: Peer) {
-        self.tmps.get_mut(peer_id).map(|(_, v, _)| v.2 = peer);
+        if let Some((_, v, _)) = self.tmps.get_mut(peer_id) {
+            v.2 = peer;
+        }
+        self.tmp_buffered.remove(peer_id);
     }
 
     pub fn remove_tmp(&mut self, peer_id: &PeerId) -> Option<(KadValue, bool)> {
+        self.tmp_buffered.remove(peer_id);
         self.tmps.remove(peer_id).map(|(_, v, is_d)| (v, is_d))
     }
 
+    /// total relayed bytes buffered for tmp sessions in handshake.
+    pub fn tmp_buffered(&self) -> usize {
+        self.tmp_buffered.values().map(|(_, n)| n).sum()
+    }
+
+    /// get the tmp stream to buffer the relayed frame. if the tmp session is in
+    /// handshake (stream not reading), account the bytes, when over the limit,
+    /// close the stalled sessions, lower priority and older first (never the high
+    /// priority ones), if still over, refuse it.
+    pub fn buffer_tmp(
+        &mut self,
+        peer_id: &PeerId,
+        len: usize,
+        tiers: &Mutex<HashMap<PeerId, PeerTier>>,
+    ) -> Option<Sender<EndpointMessage>> {
+        let (_, v, _) = self.tmps.get(peer_id)?;
+        if self.tmp_limit == 0 || v.2.id != PeerId::default() {
+            return Some(v.1.clone());
+        }
+
+        let mut total = self.tmp_buffered();
+        while total + len > self.tmp_limit {
+            let tiers = tiers.lock().unwrap();
+            let tier = |id: &PeerId| match tiers.get(id) {
+                Some(PeerTier::Low) => 0,
+                Some(PeerTier::High) => 2,
+                _ => 1,
+            };
+            let oldest = self
+                .tmp_buffered
+                .iter()
+                .filter(|(id, _)| *id != peer_id && tier(id) < 2)
+                .min_by_key(|(id, (start, _))| (tier(id), *start))
+                .map(|(id, _)| *id);
+            drop(tiers);
+            if let Some(id) = oldest {
+                debug!("Tmp buffer is full, close stalled session.");
+                if let Some((KadValue(ss, _, _), _)) = self.remove_tmp(&id) {
+                    let _ = ss.try_send(SessionMessage::Close);
+                }
+                total = self.tmp_buffered();
+            } else {
+                debug!("Tmp buffer is full, drop it.");
+                return None;
+            }
+        }
+
+        let entry = self
+            .tmp_buffered
+            .entry(*peer_id)
+            .or_insert((Instant::now(), 0));
+        entry.1 += len;
+        self.tmps.get(peer_id).map(|(_, v, _)| v.1.clone())
+    }
+
     pub async fn timer_clear(&mut self) {
         let mut dht_deletes = vec![];
         for (ip, t) in self.dhts.iter_mut() {
@@ -149,7 +221,56 @@ impl Buffer {
             }
         }
         for id in tmp_deletes {
-            self.tmps.remove(&id);
+            self.remove_tmp(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::new_session_channel;
+    use crate::transports::new_endpoint_channel;
+
+    #[test]
+    fn test_tmp_priority() {
+        let mut buffer = Buffer::init(300);
+        let tiers = Mutex::new(HashMap::new());
+        let (high, normal, low, new) = (
+            PeerId([1u8; 20]),
+            PeerId([2u8; 20]),
+            PeerId([3u8; 20]),
+            PeerId([4u8; 20]),
+        );
+        tiers.lock().unwrap().insert(high, PeerTier::High);
+        tiers.lock().unwrap().insert(low, PeerTier::Low);
+
+        let mut sessions = vec![];
+        // the high is the oldest stalled.
+        for id in [high, normal, low, new] {
+            let (session_sender, session_receiver) = new_session_channel();
+            let (stream_sender, _) = new_endpoint_channel();
+            let value = KadValue(session_sender, stream_sender, Peer::default());
+            buffer.add_tmp(id, value, false);
+            sessions.push((id, session_receiver));
+            if id != new {
+                assert!(buffer.buffer_tmp(&id, 100, &tiers).is_some());
+            }
+        }
+
+        // under pressure, the low is evicted first, then the normal.
+        assert!(buffer.buffer_tmp(&new, 100, &tiers).is_some());
+        assert!(!buffer.has_tmp(&low));
+        assert!(buffer.has_tmp(&normal));
+        assert!(buffer.buffer_tmp(&new, 100, &tiers).is_some());
+        assert!(!buffer.has_tmp(&normal));
+
+        // the high is never evicted, refuse the new one.
+        assert!(buffer.buffer_tmp(&new, 100, &tiers).is_none());
+        assert!(buffer.has_tmp(&high));
+        for (id, mut receiver) in sessions {
+            let closed = matches!(receiver.try_recv(), Ok(SessionMessage::Close));
+            assert_eq!(closed, id == low || id == normal);
         }
     }
 }
@@ -1,7 +1,18 @@
-use std::net::IpAddr;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use chamomile_types::{Peer, PeerId};
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaChaRng,
+};
+
+use chamomile_types::{types::new_io_error, Peer, PeerId};
+
+use crate::storage::Storage;
 
 /// Chammomile Configs.
 #[derive(Debug, Clone)]
@@ -37,13 +48,416 @@ pub struct Config {
     /// if send failure, will return:
     /// `Delivery(DeliveryType::Data, 1u64, false, vec![1u8, 2u8, ..., 8u8])`
     pub delivery_length: usize,
+    /// If `session_resume` is true, will save the session tickets after handshake,
+    /// and when reconnect to same peer, will try to resume the session,
+    /// skip the signature verify and DH computing.
+    /// The tickets is encrypted saved in the storage, and invalid when key changed.
+    pub session_resume: bool,
+    /// Rewrite or veto the addresses before advertised to remotes (handshake & DHT).
+    /// Default is pass everything.
+    pub address_filter: AddressFilter,
+    /// Keepalive interval (seconds). Only send ping when no frame received
+    /// from remote in this interval, and close it when lost 4 times. Default is 2s.
+    pub keepalive: u64,
+    /// Max running sessions of DHT connections and incoming relay connections.
+    /// When full, new connections will be closed. Default is 0 (unlimited).
+    pub max_sessions: usize,
+    /// What to do when the session continuous decrypt failure.
+    /// Default is rekey after 5 failures, and close after 10 failures.
+    pub decrypt_policy: DecryptPolicy,
+    /// If `relay_sign` is true, the data send to no-connected peer by relay,
+    /// will be signed by self key, so the relays and receiver can verify the origin.
+    /// Remote need support it. Default is false.
+    pub relay_sign: bool,
+    /// If `relay_verify` is true, will drop the relay data which cannot verify
+    /// the origin (no signature, and not encrypted by session), and only forward
+    /// the unsigned relay data received from its origin directly. Default is false.
+    pub relay_verify: bool,
+    /// Socket options when binding the transport listeners.
+    /// Default is reuse address, not reuse port, and backlog 1024.
+    pub socket_options: SocketOptions,
+    /// Proof-of-work difficulty (leading zero bits) of stable connect and result, the
+    /// join data need include a nonce that `keccak256(peer_id || nonce)` match it.
+    /// It is fixed per network, all nodes need same. Capped to 32, the start fails
+    /// when the nonce is not found in 60s. Default is 0 (disabled).
+    pub pow_difficulty: u8,
+    /// Max relay flows (pair of origin and target) served for others at the same time.
+    /// When full, new relay requests will be declined, and the source will choose
+    /// other relays. Idle flows expire after 60s. Default is 0 (unlimited).
+    pub max_relay_flows: usize,
+    /// Static network mode, for small fixed networks. DHT messages will not be sent
+    /// or handled, only connect to the configured peers (allowlist and outside).
+    /// Default is false.
+    pub static_network: bool,
+    /// Leader election, when stable connected peers changed, recompute the leader
+    /// (lowest peer id) of self and them, and send `ReceiveMessage::Leader` if changed.
+    /// Default is false.
+    pub leader_election: bool,
+    /// Max bytes of relayed frames buffered for the sessions still in handshake
+    /// (all sessions), when full, close the oldest stalled sessions first.
+    /// Default is 16 MiB, 0 is unlimited.
+    pub max_tmp_buffer: usize,
+    /// Read the old raw key file (no checksum), and rewrite it with checksum.
+    /// If false, the old key file is rejected as corrupted. Default is true.
+    pub legacy_key: bool,
+    /// Custom persistence backend of the key and peers,
+    /// if none, use the files in the `db_dir`. Default is none.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Max peers in the DHT help to new connected remote, the public and
+    /// closest (XOR distance to remote) peers first. Default is 20, 0 is unlimited.
+    pub max_dht_help: usize,
+    /// Identity pinning (trust-on-first-use), pin the peer id first seen at the
+    /// dialed address, and check it when dial the address again. Default is `Off`.
+    pub pin_policy: PinPolicy,
+    /// Relay/bootstrap server mode, the data to self (`Data`, `Metadata`, `LargeData`
+    /// and `Stream`) is dropped, not sent to outside, relay and DHT help keep working.
+    /// Use `Config::server` for the tuned config. Default is false.
+    pub server_mode: bool,
+    /// Verify before advertise, only the peers reachable at their address (self
+    /// dialed it, or dial back to it) are in the DHT help. Default is false.
+    pub verify_advertise: bool,
+    /// Strict encryption, never send or deliver the data not encrypted end to end, the
+    /// permissionless relay data (no session with the origin) is dropped. Default is false.
+    pub require_encryption: bool,
+    /// Priority of the bootstraps (allowlist) by address, the higher is dialed first,
+    /// not listed is 0. In the same priority, the more success history first. Default is empty.
+    pub bootstrap_priority: Vec<(SocketAddr, u32)>,
+    /// Max bootstraps dialed at the same time, the next (lower priority) ones are
+    /// dialed only when all the dialed failed. Default is 0 (dial all at once).
+    pub bootstrap_dials: usize,
+    /// Gateway capability, allow `SendMessage::DataFrom` to send data on behalf of
+    /// other peer id, the data must carry the origin proof signed by that peer for this
+    /// gateway id, the receiver accept a proof once in a short window.
+    /// Default is false.
+    pub relay_on_behalf: bool,
+    /// The interval (seconds) of background maintenance, run the `maintenance` tasks.
+    /// Default is 30s, 0 is disabled.
+    pub maintenance_interval: u64,
+    /// The tasks of background maintenance. Default is prune and metrics.
+    pub maintenance: Maintenance,
+    /// Capacity of recently seen relay message ids, the duplicates in the window are
+    /// dropped (e.g. looped, multi-path or replayed relay data). The id is the hash of
+    /// origin, target and payload, so the same payload to the same peer in the window
+    /// is duplicate too, make it unique (e.g. with a sequence) when enabled.
+    /// Default is 0 (disabled).
+    pub seen_capacity: usize,
+    /// The window (seconds) of the seen message id. Default is 60s.
+    pub seen_ttl: u64,
+    /// Send the data frames with the session sequence, remote tells outside
+    /// `ReceiveMessage::SequenceGap` when some are missing. Only sent to the remote of
+    /// protocol version 2 or newer, the older gets the plain frames. Default is false.
+    pub sequence_frames: bool,
+    /// Max lifetime (seconds) of the session, when over, either side closes it
+    /// (remote get `PeerLeave` with `CloseReason::Lifetime`), and the dialer redials
+    /// with a full handshake and new session key after the old one is closed, no
+    /// matter it is active. Different to rekey,
+    /// it forces re-auth. Stable session is told `StableLeave` as other closes, and
+    /// need stable connect again. Default is 0 (unlimited).
+    pub session_lifetime: u64,
+    /// Pad the encrypted data frames to the size buckets, the padding is stripped
+    /// by remote. Only padded to the remote of protocol version 2 or newer.
+    /// Default is off.
+    pub frame_padding: FramePadding,
+    /// The allowed clock difference (seconds) to remote when compare the timestamp
+    /// from remote, e.g. the origin proof of `DataFrom`, see `timestamp_valid`, and
+    /// the seq (unix millis) of the signed DHT records, the far future is dropped.
+    /// The local timers use monotonic clock, no skew. Default is 30s.
+    pub max_clock_skew: u64,
+    /// The sliding window (seconds) of the throughput rates in `NetworkStats`,
+    /// aggregate and per peer of the live sessions. Default is 10s, 0 is disabled.
+    pub rate_window: u64,
+    /// Not advertise the local addresses (loopback, link-local, RFC1918 and ULA) of
+    /// self and peers in handshake and DHT, when the peer has a public address, before
+    /// the `address_filter`. Default is true.
+    pub strip_local_addrs: bool,
+    /// Seed the rng of the key, assist id and handshakes, so the same seed reproduces
+    /// the exact PeerId and handshake bytes. Only for tests, never set it in
+    /// production, the keys are predictable. Default is None (from entropy).
+    pub rng_seed: Option<[u8; 32]>,
+    /// Only gossip and dial the DHT entries self signed by their peers (id, seq and
+    /// addresses), every peer sends its signed record to the connected, and they
+    /// gossip the newest one as is, never the one changed by the address filter.
+    /// the unsigned or invalid entries are dropped, so all peers in the network
+    /// need it. Default is false.
+    pub signed_dht: bool,
+}
+
+/// The tasks of background maintenance, run on the `maintenance_interval` timer.
+#[derive(Debug, Clone, Copy)]
+pub struct Maintenance {
+    /// prune the stale peers (session closed) and expired blocks. Default is true.
+    pub prune: bool,
+    /// recompute the derived peer metrics (e.g. `NetworkStats.peers`). Default is true.
+    pub metrics: bool,
+    /// emit `ReceiveMessage::Stats` to outside. Default is false.
+    pub stats: bool,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            prune: true,
+            metrics: true,
+            stats: false,
+        }
+    }
+}
+
+/// User-supplied filter for advertised addresses.
+/// It receive the candidate addresses, and return the addresses can be advertised,
+/// can remove or reorder them, the first one will be used in the `Peer`.
+#[derive(Clone)]
+pub struct AddressFilter(Arc<dyn Fn(Vec<SocketAddr>) -> Vec<SocketAddr> + Send + Sync>);
+
+impl AddressFilter {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(Vec<SocketAddr>) -> Vec<SocketAddr> + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// apply filter to the peer, if all addresses removed, return None.
+    pub(crate) fn apply(&self, mut peer: Peer) -> Option<Peer> {
+        let candidates = peer.addrs();
+        let main_transport = peer.transport;
+        let addrs = (self.0)(candidates.iter().map(|(socket, _)| *socket).collect());
+        let transport_of = |socket: &SocketAddr| {
+            candidates
+                .iter()
+                .find(|(s, _)| s == socket)
+                .map(|(_, t)| *t)
+                .unwrap_or(main_transport)
+        };
+
+        let (first, others) = addrs.split_first()?;
+        peer.transport = transport_of(first);
+        peer.socket = *first;
+        peer.others = Default::default();
+        for socket in others {
+            peer.add_addr(*socket, transport_of(socket));
+        }
+        Some(peer)
+    }
+
+    /// strip the local addresses (loopback, link-local, private and ULA) before
+    /// this filter, when there is a public one, only local are kept (e.g. LAN).
+    pub fn strip_local(self) -> Self {
+        Self::new(move |addrs: Vec<SocketAddr>| {
+            if addrs.iter().all(|a| is_local_ip(&a.ip())) {
+                (self.0)(addrs)
+            } else {
+                (self.0)(
+                    addrs
+                        .into_iter()
+                        .filter(|a| !is_local_ip(&a.ip()))
+                        .collect(),
+                )
+            }
+        })
+    }
+
+    /// apply filter to self peer, if all addresses removed,
+    /// only keep the port for remote to build NAT address.
+    pub(crate) fn apply_own(&self, peer: Peer) -> Peer {
+        self.apply(peer).unwrap_or_else(|| {
+            let mut peer = peer;
+            peer.socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), peer.socket.port());
+            peer.others = Default::default();
+            peer
+        })
+    }
+}
+
+/// the address is not useful out of the host or local network.
+pub(crate) fn is_local_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_ip(&IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_unspecified()
+            }
+        },
+    }
+}
+
+impl Default for AddressFilter {
+    fn default() -> Self {
+        Self::new(|addrs| addrs)
+    }
+}
+
+impl Debug for AddressFilter {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "AddressFilter")
+    }
+}
+
+/// Session decrypt failure policy, count the consecutive decrypt failures,
+/// and reset when decrypt success. `0` means never.
+#[derive(Debug, Clone, Copy)]
+pub struct DecryptPolicy {
+    /// when failures reach it, try to rekey with remote (new handshake).
+    pub rekey_after: u32,
+    /// when failures reach it, close the session.
+    pub close_after: u32,
+    /// when the session key is not ready (remote not completed it, or the answer
+    /// of rekey lost) in these heartbeats, resend our handshake.
+    pub resend_key_after: u32,
+    /// when resent these times and still not ready, close the session.
+    pub resend_key_times: u32,
+}
+
+impl Default for DecryptPolicy {
+    fn default() -> Self {
+        Self {
+            rekey_after: 5,
+            close_after: 10,
+            resend_key_after: 3,
+            resend_key_times: 3,
+        }
+    }
+}
+
+/// Identity pinning policy, when the pinned address presents a different peer id.
+/// it will tell outside `ReceiveMessage::PinMismatch` when `Warn` or `Reject`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PinPolicy {
+    /// no pinning.
+    Off,
+    /// accept the connection, only warning.
+    Warn,
+    /// reject the connection.
+    Reject,
+}
+
+/// Padding of the encrypted data frames, the frame size is snapped to buckets,
+/// so the payload size is hidden (traffic analysis defense).
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum FramePadding {
+    /// no padding.
+    #[default]
+    Off,
+    /// the next power of two.
+    PowerOfTwo,
+    /// the smallest bucket not less than the frame, larger frames are not padded.
+    Buckets(Vec<usize>),
+}
+
+impl FramePadding {
+    /// the padded size of the frame.
+    pub fn target(&self, len: usize) -> usize {
+        match self {
+            FramePadding::Off => len,
+            FramePadding::PowerOfTwo => len.next_power_of_two(),
+            FramePadding::Buckets(buckets) => buckets
+                .iter()
+                .filter(|b| **b >= len)
+                .min()
+                .copied()
+                .unwrap_or(len),
+        }
+    }
+}
+
+/// Socket options applied to the transports (TCP & QUIC).
+/// `reuse_port` is needed when share the port, e.g. TCP simultaneous-open hole punching.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// set SO_REUSEADDR.
+    pub reuse_address: bool,
+    /// set SO_REUSEPORT, only unix.
+    pub reuse_port: bool,
+    /// TCP listen backlog.
+    pub backlog: u32,
+    /// Retry times of outbound handshake when it failure is transient (timeout,
+    /// connection reset), rejected by remote will not retry. Default is 2.
+    pub handshake_retries: u8,
+    /// Transport keepalive interval (milliseconds), send an empty frame (TCP) or
+    /// QUIC ping when idle, only keep the NAT mapping alive, the session never
+    /// see it. Set it shorter than the session `keepalive`. Default is 0 (disable).
+    pub keepalive: u64,
+    /// Dial outbound TCP connections through this SOCKS5 proxy (no auth), e.g. Tor.
+    /// Inbound rely on the hidden service configured outside. `.onion` is not
+    /// supported, the peer address is socket address. Default is none.
+    pub proxy: Option<SocketAddr>,
+    /// The delays of retries (handshake retry, find node), one place to tune it.
+    pub backoff: BackoffPolicy,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 1024,
+            handshake_retries: 2,
+            keepalive: 0,
+            proxy: None,
+            backoff: BackoffPolicy::default(),
+        }
+    }
+}
+
+/// Retry delay policy, the n-th retry (from 0) wait `initial * multiplier^n`,
+/// not more than `max`, and random +/- `jitter` (fraction of the delay),
+/// so many peers will not retry at the same time.
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffPolicy {
+    /// the delay of first retry. Default is 200ms.
+    pub initial: Duration,
+    /// the max delay (before jitter). Default is 10s.
+    pub max: Duration,
+    /// the delay growth of every retry. Default is 2.
+    pub multiplier: f64,
+    /// random fraction of the delay, in 0..=1. Default is 0.1.
+    pub jitter: f64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(200),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// the delay of the retry without jitter.
+    pub fn base(&self, retry: u32) -> Duration {
+        let delay = self.initial.as_secs_f64() * self.multiplier.powf(retry as f64);
+        Duration::try_from_secs_f64(delay)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+
+    /// the delay of the retry with random jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let rand = ChaChaRng::from_entropy().next_u32() as f64 / u32::MAX as f64;
+        self.jittered(retry, rand)
+    }
+
+    /// rand in 0..=1, 0 is the lowest and 1 is the highest delay.
+    fn jittered(&self, retry: u32, rand: f64) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        self.base(retry).mul_f64(1.0 - jitter + 2.0 * jitter * rand)
+    }
 }
 
 impl Config {
     pub fn default(peer: Peer) -> Self {
         Self {
             db_dir: PathBuf::from("./"),
-            peer: peer,
+            peer,
             allowlist: vec![],
             blocklist: vec![],
             allow_peer_list: vec![],
@@ -51,9 +465,58 @@ impl Config {
             permission: false,
             only_stable_data: false,
             delivery_length: 0,
+            session_resume: false,
+            address_filter: AddressFilter::default(),
+            keepalive: 2,
+            max_sessions: 0,
+            decrypt_policy: DecryptPolicy::default(),
+            relay_sign: false,
+            relay_verify: false,
+            socket_options: SocketOptions::default(),
+            pow_difficulty: 0,
+            max_relay_flows: 0,
+            static_network: false,
+            leader_election: false,
+            max_tmp_buffer: 16 * 1024 * 1024,
+            legacy_key: true,
+            storage: None,
+            max_dht_help: 20,
+            pin_policy: PinPolicy::Off,
+            server_mode: false,
+            verify_advertise: false,
+            require_encryption: false,
+            bootstrap_priority: vec![],
+            bootstrap_dials: 0,
+            relay_on_behalf: false,
+            maintenance_interval: 30,
+            maintenance: Maintenance::default(),
+            seen_capacity: 0,
+            seen_ttl: 60,
+            sequence_frames: false,
+            session_lifetime: 0,
+            frame_padding: FramePadding::Off,
+            max_clock_skew: 30,
+            rate_window: 10,
+            strip_local_addrs: true,
+            rng_seed: None,
+            signed_dht: false,
         }
     }
 
+    /// the config of relay/bootstrap server, it never originates and receives data,
+    /// only relay and help DHT for others, tuned for many connections.
+    pub fn server(peer: Peer) -> Self {
+        let mut config = Self::default(peer);
+        config.server_mode = true;
+        config.permission = false;
+        config.static_network = false;
+        config.keepalive = 10;
+        config.max_tmp_buffer = 64 * 1024 * 1024;
+        config.socket_options.backlog = 4096;
+        config
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         db_dir: PathBuf,
         peer: Peer,
@@ -75,6 +538,276 @@ impl Config {
             permission,
             only_stable_data,
             delivery_length,
+            ..Config::default(peer)
+        }
+    }
+}
+
+/// fluent setters of the config fields, same name as the field.
+macro_rules! setters {
+    ($($name:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("set `Config::", stringify!($name), "`.")]
+            pub fn $name(mut self, $name: $ty) -> Self {
+                self.0.$name = $name;
+                self
+            }
+        )*
+    };
+}
+
+/// Builder of the node config, start from the defaults (`Config::default`),
+/// change the knobs by fluent setters, and validate them when `build`.
+/// Example:
+/// ```ignore
+/// let config = NodeConfig::new(Peer::socket(addr))
+///     .keepalive(5)
+///     .max_sessions(100)
+///     .build()?;
+/// let (peer_id, send, recv) = start(config).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct NodeConfig(Config);
+
+impl NodeConfig {
+    pub fn new(peer: Peer) -> Self {
+        Self(Config::default(peer))
+    }
+
+    setters! {
+        db_dir: PathBuf,
+        peer: Peer,
+        allowlist: Vec<Peer>,
+        blocklist: Vec<IpAddr>,
+        allow_peer_list: Vec<PeerId>,
+        block_peer_list: Vec<PeerId>,
+        permission: bool,
+        only_stable_data: bool,
+        delivery_length: usize,
+        session_resume: bool,
+        address_filter: AddressFilter,
+        keepalive: u64,
+        max_sessions: usize,
+        decrypt_policy: DecryptPolicy,
+        relay_sign: bool,
+        relay_verify: bool,
+        socket_options: SocketOptions,
+        pow_difficulty: u8,
+        max_relay_flows: usize,
+        static_network: bool,
+        leader_election: bool,
+        max_tmp_buffer: usize,
+        legacy_key: bool,
+        max_dht_help: usize,
+        pin_policy: PinPolicy,
+        server_mode: bool,
+        verify_advertise: bool,
+        require_encryption: bool,
+        bootstrap_priority: Vec<(SocketAddr, u32)>,
+        bootstrap_dials: usize,
+        relay_on_behalf: bool,
+        maintenance_interval: u64,
+        maintenance: Maintenance,
+        seen_capacity: usize,
+        seen_ttl: u64,
+        sequence_frames: bool,
+        session_lifetime: u64,
+        frame_padding: FramePadding,
+        max_clock_skew: u64,
+        rate_window: u64,
+        strip_local_addrs: bool,
+        rng_seed: Option<[u8; 32]>,
+        signed_dht: bool,
+    }
+
+    /// set `Config::storage`.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.0.storage = Some(storage);
+        self
+    }
+
+    /// check the knobs work together, and return the config.
+    pub fn build(self) -> Result<Config> {
+        let config = self.0;
+        let policy = config.decrypt_policy;
+        if policy.rekey_after != 0
+            && policy.close_after != 0
+            && policy.rekey_after >= policy.close_after
+        {
+            return Err(new_io_error("decrypt policy rekey after close"));
         }
+        if config.seen_capacity != 0 && config.seen_ttl == 0 {
+            return Err(new_io_error("seen ids need the ttl"));
+        }
+        if let FramePadding::Buckets(buckets) = &config.frame_padding {
+            if buckets.is_empty() || buckets.contains(&0) {
+                return Err(new_io_error("frame padding buckets invalid"));
+            }
+        }
+        let backoff = config.socket_options.backoff;
+        if backoff.multiplier < 1.0 || !(0.0..=1.0).contains(&backoff.jitter) {
+            return Err(new_io_error("backoff policy invalid"));
+        }
+        let transport_keepalive = config.socket_options.keepalive;
+        if transport_keepalive != 0
+            && config.keepalive != 0
+            && transport_keepalive >= config.keepalive * 1000
+        {
+            return Err(new_io_error(
+                "transport keepalive not shorter than keepalive",
+            ));
+        }
+        Ok(config)
+    }
+}
+
+impl From<Config> for NodeConfig {
+    fn from(config: Config) -> Self {
+        Self(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_filter() {
+        // strip RFC1918 addresses.
+        let filter = AddressFilter::new(|addrs| {
+            addrs
+                .into_iter()
+                .filter(|a| !matches!(a.ip(), IpAddr::V4(ip) if ip.is_private()))
+                .collect()
+        });
+
+        let public: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+        let private: SocketAddr = "192.168.1.2:7364".parse().unwrap();
+
+        let peer = filter.apply(Peer::socket(public)).unwrap();
+        assert_eq!(peer.socket, public);
+        assert!(filter.apply(Peer::socket(private)).is_none());
+
+        let own = filter.apply_own(Peer::socket(private));
+        assert_eq!(own.socket.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(own.socket.port(), 7364);
+
+        // default pass everything.
+        let peer = AddressFilter::default()
+            .apply(Peer::socket(private))
+            .unwrap();
+        assert_eq!(peer.socket, private);
+    }
+
+    #[test]
+    fn test_node_config() {
+        let addr: SocketAddr = "127.0.0.1:7364".parse().unwrap();
+        let config = NodeConfig::new(Peer::socket(addr))
+            .keepalive(5)
+            .max_sessions(100)
+            .frame_padding(FramePadding::PowerOfTwo)
+            .seen_capacity(1024)
+            .build()
+            .unwrap();
+        assert_eq!(config.keepalive, 5);
+        assert_eq!(config.max_sessions, 100);
+        assert_eq!(config.frame_padding, FramePadding::PowerOfTwo);
+        assert_eq!(config.seen_capacity, 1024);
+        // others are default.
+        assert_eq!(config.seen_ttl, 60);
+        assert_eq!(config.peer.socket, addr);
+
+        // from the preset.
+        let config = NodeConfig::from(Config::server(Peer::socket(addr)))
+            .max_relay_flows(8)
+            .build()
+            .unwrap();
+        assert!(config.server_mode);
+        // the data to self is dropped by the server mode, not the stable filter.
+        assert!(!config.only_stable_data);
+        assert_eq!(config.max_relay_flows, 8);
+
+        let invalid = [
+            NodeConfig::new(Peer::socket(addr)).decrypt_policy(DecryptPolicy {
+                rekey_after: 10,
+                close_after: 5,
+                ..Default::default()
+            }),
+            NodeConfig::new(Peer::socket(addr))
+                .seen_capacity(8)
+                .seen_ttl(0),
+            NodeConfig::new(Peer::socket(addr)).frame_padding(FramePadding::Buckets(vec![])),
+            NodeConfig::new(Peer::socket(addr)).socket_options(SocketOptions {
+                keepalive: 5000,
+                ..Default::default()
+            }),
+        ];
+        for config in invalid {
+            assert!(config.build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_strip_local() {
+        let filter = AddressFilter::default().strip_local();
+        let public: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+        let mut peer = Peer::socket("192.168.1.2:7364".parse().unwrap());
+        peer.add_addr("127.0.0.1:7364".parse().unwrap(), peer.transport);
+        peer.add_addr(public, peer.transport);
+        peer.add_addr("[fd00::1]:7364".parse().unwrap(), peer.transport);
+        let peer = filter.apply(peer).unwrap();
+        assert_eq!(peer.addrs(), vec![(public, peer.transport)]);
+
+        // only local (e.g. LAN), keep them.
+        let lan: SocketAddr = "10.0.0.2:7364".parse().unwrap();
+        let mut peer = Peer::socket(lan);
+        peer.add_addr("[fe80::1]:7364".parse().unwrap(), peer.transport);
+        assert_eq!(filter.apply(peer).unwrap().addrs().len(), 2);
+
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "169.254.1.1",
+            "::1",
+            "fc00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_local_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1"] {
+            assert!(!is_local_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_backoff_policy() {
+        let policy = BackoffPolicy {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            multiplier: 3.0,
+            jitter: 0.2,
+        };
+        let bases: Vec<u64> = (0..5).map(|i| policy.base(i).as_millis() as u64).collect();
+        assert_eq!(bases, vec![100, 300, 900, 1000, 1000]);
+        assert_eq!(policy.base(u32::MAX), policy.max);
+
+        // jitter bounds.
+        assert_eq!(policy.jittered(1, 0.0), Duration::from_millis(240));
+        assert_eq!(policy.jittered(1, 0.5), Duration::from_millis(300));
+        assert_eq!(policy.jittered(1, 1.0), Duration::from_millis(360));
+        for i in 0..5 {
+            for _ in 0..20 {
+                let delay = policy.delay(i);
+                assert!(delay >= policy.base(i).mul_f64(0.8));
+                assert!(delay <= policy.base(i).mul_f64(1.2));
+            }
+        }
+
+        // no jitter.
+        let policy = BackoffPolicy {
+            jitter: 0.0,
+            ..BackoffPolicy::default()
+        };
+        assert_eq!(policy.delay(2), Duration::from_millis(800));
     }
 }
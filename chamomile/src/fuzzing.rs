@@ -0,0 +1,57 @@
+//! The wire parsers entry, only for fuzz targets (feature `fuzzing`).
+//! All parsers receive the remote bytes, must not panic.
+
+use chamomile_types::{
+    key::{Key, Signature},
+    Peer, PeerId,
+};
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+use std::sync::OnceLock;
+
+use crate::hole_punching::{Hole, DHT};
+use crate::session::CoreData;
+use crate::session_key::SessionKey;
+use crate::transports::{EndpointMessage, RemotePublic};
+
+pub fn endpoint_message(bytes: &[u8]) {
+    let _ = EndpointMessage::from_bytes(bytes.to_vec());
+}
+
+pub fn core_data(bytes: &[u8]) {
+    let _ = CoreData::from_bytes(bytes.to_vec());
+}
+
+pub fn remote_public(bytes: &[u8]) {
+    let _ = RemotePublic::from_bytes(bytes.to_vec());
+}
+
+pub fn dht(bytes: &[u8]) {
+    let _ = DHT::from_bytes(bytes);
+}
+
+pub fn peer(bytes: &[u8]) {
+    let _ = Peer::from_bytes(bytes);
+    let _ = Peer::decode(bytes);
+}
+
+pub fn signature(bytes: &[u8]) {
+    let _ = Signature::from_bytes(bytes);
+}
+
+pub fn hole(bytes: &[u8]) {
+    if let Some(byte) = bytes.first() {
+        let _ = Hole::from_byte(*byte);
+    }
+}
+
+/// the handshake bytes, after the remote public parsed.
+pub fn session_key(bytes: &[u8]) {
+    static KEY: OnceLock<Key> = OnceLock::new();
+    let key = KEY.get_or_init(|| Key::generate(&mut ChaChaRng::from_seed([1u8; 32])));
+
+    let (mut session, _) = SessionKey::generate(key);
+    let _ = session.complete(&PeerId::default(), bytes.to_vec());
+    let ticket = Some([1u8; 32]);
+    let rng = &mut ChaChaRng::from_seed([2u8; 32]);
+    let _ = SessionKey::generate_complete(key, &PeerId::default(), bytes.to_vec(), ticket, rng);
+}
@@ -1,105 +1,1610 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::{
     io::Result,
-    sync::{mpsc::Sender, RwLock},
+    sync::{
+        mpsc::{self, error::TrySendError, Sender},
+        watch, OwnedSemaphorePermit, RwLock, Semaphore,
+    },
+    task::JoinSet,
+    time::{sleep, Duration, Instant},
 };
 
 use chamomile_types::{
-    key::Key,
-    message::ReceiveMessage,
-    types::{new_io_error, TransportType},
+    key::{Signature, Signer},
+    message::{NetworkStats, ReceiveMessage, Throughput},
+    types::{
+        elect_leader, new_io_error, CloseReason, ConnectError, ConnectResult, PeerTier, Priority,
+        SessionState, TransportType, PEER_ID_LENGTH,
+    },
     Peer, PeerId,
 };
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaChaRng,
+};
+use sha3::{Digest, Keccak256};
+
+use crate::buffer::{Buffer, BufferKey};
+use crate::config::{
+    AddressFilter, DecryptPolicy, FramePadding, Maintenance, PinPolicy, SocketOptions,
+};
+use crate::hole_punching::{External, NatType, StunResult, DHT};
+use crate::kad::KadValue;
+use crate::peer_list::PeerList;
+use crate::primitives::STORAGE_PINS_KEY;
+use crate::session::{wait_handshake, CoreData, SessionMessage};
+use crate::session_key::SessionKey;
+use crate::storage::Storage;
+use crate::ticket::Tickets;
+use crate::transports::{
+    compatible_version, new_endpoint_channel, start, ConnectTimer, EndpointMessage, RemotePublic,
+    TransportRecvMessage, TransportSendMessage, PROTOCOL_MIN_VERSION,
+};
+
+/// max redials when the session lifetime is over or the high priority peer is lost.
+const REDIAL_RETRIES: u32 = 3;
+
+/// waiting time (s) of a redial result.
+const REDIAL_TIMEOUT: u64 = 10;
+
+/// max proof-of-work difficulty (leading zero bits), larger config is capped.
+pub(crate) const POW_MAX_DIFFICULTY: u8 = 32;
+
+/// max time (s) of solving the proof-of-work when start.
+pub(crate) const POW_SOLVE_TIMEOUT: u64 = 60;
+
+/// waiting time (s) of dial back to verify the remote address.
+pub(crate) const VERIFY_ADVERTISE_TIMEOUT: u64 = 5;
+
+/// domain of the relay data origin signature.
+const RELAY_DOMAIN: &[u8] = b"chamomile-relay";
+
+/// sign the relay data by origin, relays and receiver can verify it.
+pub(crate) fn relay_sign(key: &dyn Signer, from: &PeerId, to: &PeerId, data: &[u8]) -> Vec<u8> {
+    let msg = [RELAY_DOMAIN, &from.0, &to.0, data].concat();
+    key.sign(&msg).to_bytes()
+}
+
+/// domain of the origin proof, data sent by a gateway on behalf of the origin.
+const ORIGIN_DOMAIN: &[u8] = b"chamomile-origin";
+
+/// the origin proof is valid in the window (s) after signed.
+pub(crate) const ORIGIN_PROOF_WINDOW: u64 = 60;
+
+/// max accepted origin proofs kept in the window.
+pub(crate) const ORIGIN_SEEN_CAPACITY: usize = 4096;
+
+/// the head of origin data: gateway id, timestamp and nonce.
+pub(crate) const ORIGIN_HEAD_LENGTH: usize = PEER_ID_LENGTH + 16;
+
+/// the origin proof of the data to `to`, signed by the origin key, only the `gateway`
+/// can send the data on behalf of the origin with it (`SendMessage::DataFrom`).
+/// the proof has the time and a random nonce, receiver accept it once in a short window.
+pub fn origin_proof(key: &dyn Signer, gateway: &PeerId, to: &PeerId, data: &[u8]) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    origin_proof_at(key, gateway, to, data, timestamp)
+}
+
+/// the origin proof signed at the timestamp (unix seconds).
+pub(crate) fn origin_proof_at(
+    key: &dyn Signer,
+    gateway: &PeerId,
+    to: &PeerId,
+    data: &[u8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let nonce = ChaChaRng::from_entropy().next_u64();
+    let head = [
+        &gateway.0[..],
+        &timestamp.to_be_bytes(),
+        &nonce.to_be_bytes(),
+    ]
+    .concat();
+    let msg = [ORIGIN_DOMAIN, &key.peer_id().0, &to.0, &head, data].concat();
+    [key.sign(&msg).to_bytes(), head].concat()
+}
+
+/// verify the origin data (head + data) is signed by the origin `from`,
+/// return the gateway and the timestamp.
+pub(crate) fn origin_verify(
+    from: &PeerId,
+    to: &PeerId,
+    sign: &[u8],
+    data: &[u8],
+) -> Option<(PeerId, u64)> {
+    if data.len() < ORIGIN_HEAD_LENGTH {
+        return None;
+    }
+    let msg = [ORIGIN_DOMAIN, &from.0, &to.0, data].concat();
+    let id = Signature::from_bytes(sign)
+        .and_then(|sign| sign.peer_id(&msg))
+        .ok()?;
+    if &id != from {
+        return None;
+    }
+    let gateway = PeerId::from_bytes(&data[..PEER_ID_LENGTH]).ok()?;
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&data[PEER_ID_LENGTH..PEER_ID_LENGTH + 8]);
+    Some((gateway, u64::from_be_bytes(timestamp)))
+}
+
+/// check the remote timestamp (unix seconds) is in the window (seconds) before `now`,
+/// tolerate the clock difference `skew` of two peers, in both directions.
+pub fn timestamp_valid(remote: u64, now: u64, window: u64, skew: u64) -> bool {
+    remote <= now.saturating_add(skew) && remote.saturating_add(window.saturating_add(skew)) >= now
+}
+
+/// verify the relay data is signed by the origin `from`.
+pub(crate) fn relay_verify(from: &PeerId, to: &PeerId, sign: &[u8], data: &[u8]) -> bool {
+    let msg = [RELAY_DOMAIN, &from.0, &to.0, data].concat();
+    Signature::from_bytes(sign)
+        .and_then(|sign| sign.peer_id(&msg))
+        .is_ok_and(|id| &id == from)
+}
+
+/// domain of the peer record self signature.
+const RECORD_DOMAIN: &[u8] = b"chamomile-peer-record";
+
+/// the signed part of the peer record: the id, the seq and all addresses.
+fn peer_record(peer: &Peer, seq: u64) -> Vec<u8> {
+    let mut msg = [RECORD_DOMAIN, &peer.id.0, &seq.to_be_bytes()].concat();
+    for (addr, transport) in peer.addrs() {
+        match addr.ip() {
+            IpAddr::V4(ip) => msg.extend(ip.octets()),
+            IpAddr::V6(ip) => msg.extend(ip.octets()),
+        }
+        msg.extend(addr.port().to_be_bytes());
+        msg.push(transport.to_byte());
+    }
+    msg
+}
+
+/// the self signed peer record: peer, seq and signature.
+pub(crate) type PeerRecord = (Peer, u64, Vec<u8>);
+
+/// sign self peer record (id, seq and addresses), it is gossiped in DHT as is,
+/// so everyone can verify the addresses are claimed by the peer itself, and the
+/// newer seq replaces the old record.
+pub(crate) fn peer_record_sign(key: &dyn Signer, peer: &Peer, seq: u64) -> Vec<u8> {
+    key.sign(&peer_record(peer, seq)).to_bytes()
+}
+
+/// verify the peer record is signed by the peer of the claimed id.
+pub(crate) fn peer_record_verify(peer: &Peer, seq: u64, sign: &[u8]) -> bool {
+    Signature::from_bytes(sign)
+        .and_then(|sign| sign.peer_id(&peer_record(peer, seq)))
+        .is_ok_and(|id| id == peer.id)
+}
+
+/// leading zero bits of keccak256(peer_id || nonce).
+fn pow_bits(peer_id: &PeerId, nonce: u64) -> u32 {
+    let mut hasher = Keccak256::new();
+    hasher.update(peer_id.0);
+    hasher.update(nonce.to_le_bytes());
+    let mut bits = 0;
+    for b in hasher.finalize() {
+        bits += b.leading_zeros();
+        if b != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// find the proof-of-work nonce of the peer, it only need once when start.
+/// none when not found before the deadline. it is blocking, run it out of runtime.
+pub(crate) fn pow_solve(
+    peer_id: &PeerId,
+    difficulty: u8,
+    deadline: std::time::Instant,
+) -> Option<u64> {
+    let difficulty = difficulty.min(POW_MAX_DIFFICULTY);
+    for nonce in 0..u64::MAX {
+        if pow_verify(peer_id, nonce, difficulty) {
+            return Some(nonce);
+        }
+        if nonce % 4096 == 0 && std::time::Instant::now() >= deadline {
+            return None;
+        }
+    }
+    None
+}
+
+/// verify the proof-of-work nonce of the peer.
+pub(crate) fn pow_verify(peer_id: &PeerId, nonce: u64, difficulty: u8) -> bool {
+    difficulty == 0 || pow_bits(peer_id, nonce) >= difficulty as u32
+}
+
+/// copy the outside message for observer, the chunks receiver cannot be shared.
+fn observed(msg: &ReceiveMessage) -> Option<ReceiveMessage> {
+    Some(match msg {
+        ReceiveMessage::StableConnect(p, d) => ReceiveMessage::StableConnect(*p, d.clone()),
+        ReceiveMessage::StableResult(p, ok, d) => ReceiveMessage::StableResult(*p, *ok, d.clone()),
+        ReceiveMessage::ResultConnect(p, d) => ReceiveMessage::ResultConnect(*p, d.clone()),
+        ReceiveMessage::StableLeave(p) => ReceiveMessage::StableLeave(*p),
+        ReceiveMessage::Data(p, d, is_direct) => ReceiveMessage::Data(*p, d.clone(), *is_direct),
+        ReceiveMessage::Metadata(p, m, d) => ReceiveMessage::Metadata(*p, m.clone(), d.clone()),
+        ReceiveMessage::Protocol(p, id, d, is_direct) => {
+            ReceiveMessage::Protocol(*p, *id, d.clone(), *is_direct)
+        }
+        ReceiveMessage::Delivery(t, id, ok, d) => {
+            ReceiveMessage::Delivery(t.clone(), *id, *ok, d.clone())
+        }
+        ReceiveMessage::PeerLeave(p, r) => ReceiveMessage::PeerLeave(*p, *r),
+        ReceiveMessage::NetworkLost => ReceiveMessage::NetworkLost,
+        ReceiveMessage::OwnConnect(p) => ReceiveMessage::OwnConnect(*p),
+        ReceiveMessage::OwnLeave(p) => ReceiveMessage::OwnLeave(*p),
+        ReceiveMessage::OwnEvent(p, d) => ReceiveMessage::OwnEvent(*p, d.clone()),
+        ReceiveMessage::Leader(p) => ReceiveMessage::Leader(*p),
+        ReceiveMessage::ExternalAddrChanged(a) => ReceiveMessage::ExternalAddrChanged(*a),
+        ReceiveMessage::PinMismatch(a, p, n) => ReceiveMessage::PinMismatch(*a, *p, *n),
+        ReceiveMessage::Stats(s) => ReceiveMessage::Stats(s.clone()),
+        ReceiveMessage::SequenceGap(p, n) => ReceiveMessage::SequenceGap(*p, *n),
+        ReceiveMessage::LargeData(..) | ReceiveMessage::Stream(..) => return None,
+    })
+}
+
+pub(crate) struct Global {
+    pub peer: Peer,
+    pub key: Arc<dyn Signer>,
+    pub trans: Sender<TransportRecvMessage>,
+    pub transports: Arc<RwLock<HashMap<TransportType, Sender<TransportSendMessage>>>>,
+    pub out_sender: Sender<ReceiveMessage>,
+    pub peer_list: Arc<RwLock<PeerList>>,
+    pub buffer: Arc<RwLock<Buffer>>,
+    pub tickets: Arc<RwLock<Tickets>>,
+    pub is_relay_data: bool,
+    pub delivery_length: usize,
+    pub relay_load: RelayLoad,
+    pub address_filter: AddressFilter,
+    pub keepalive: u64,
+    pub session_limit: Option<Arc<Semaphore>>,
+    pub decrypt_policy: DecryptPolicy,
+    pub relay_sign: bool,
+    pub relay_verify: bool,
+    /// connection establishment timers of connected peers.
+    pub connects: Arc<RwLock<HashMap<PeerId, ConnectTimer>>>,
+    /// read-only observer of outside messages, drop-on-full.
+    pub observer: Arc<RwLock<Option<Sender<ReceiveMessage>>>>,
+    pub socket_options: SocketOptions,
+    pub health: Health,
+    /// draining for shutdown, not accept new inbound sessions.
+    pub draining: AtomicBool,
+    pub pow_difficulty: u8,
+    /// self proof-of-work nonce.
+    pub pow_nonce: u64,
+    /// the waiters of connect result by the dialed address, all of the same
+    /// address wait the one pending dial.
+    #[allow(clippy::type_complexity)]
+    pub connect_waits: Arc<RwLock<HashMap<SocketAddr, Vec<Sender<ConnectResult>>>>>,
+    pub relay_flows: RelayFlows,
+    /// static network, no DHT.
+    pub static_network: bool,
+    pub leader_election: bool,
+    /// current leader of self and stable peers.
+    pub leader: Mutex<Option<PeerId>>,
+    /// the believed external address of self.
+    pub external: Mutex<External>,
+    /// the reflexive addresses reported by remotes, to classify NAT type.
+    pub stun: Mutex<StunResult>,
+    /// max peers in DHT help, 0 is unlimited.
+    pub max_dht_help: usize,
+    /// pinned peer id of the dialed addresses.
+    pub pins: Pins,
+    /// relay/bootstrap server, not send data to outside.
+    pub server_mode: bool,
+    /// only advertise the verified peers in DHT help.
+    pub verify_advertise: bool,
+    /// never send or deliver the relay data not encrypted end to end.
+    pub require_encryption: bool,
+    /// max bootstraps dialed at the same time, 0 is all.
+    pub bootstrap_dials: usize,
+    /// allow send data on behalf of other peer id (gateway).
+    pub relay_on_behalf: bool,
+    /// recently seen relay message ids.
+    pub seen: SeenIds,
+    /// accepted origin proofs in the proof window, reject the replayed.
+    pub origin_seen: SeenIds,
+    /// send the lane frames with sequence.
+    pub sequence_frames: bool,
+    /// max lifetime (seconds) of the dialed sessions, 0 is unlimited.
+    pub session_lifetime: u64,
+    /// padding of the encrypted data frames.
+    pub frame_padding: FramePadding,
+    /// delivery of remote data to outside is paused.
+    pub delivery_paused: watch::Sender<bool>,
+    /// registered sub-protocol receivers of the data, protocol id => receiver.
+    pub protocols: Arc<RwLock<HashMap<u16, Sender<ReceiveMessage>>>>,
+    /// allowed clock difference (seconds) to remote timestamps.
+    pub max_clock_skew: u64,
+    /// the window (seconds) of the sessions throughput, 0 is disabled.
+    pub rate_window: u64,
+    /// the tiers of peers set by outside, not set is `Normal`.
+    pub tiers: Mutex<HashMap<PeerId, PeerTier>>,
+    /// the rng of the handshakes.
+    pub rng: NodeRng,
+    /// the session states of peers.
+    pub session_states: SessionStates,
+    /// the live stats of the running sessions.
+    pub session_stats: SessionStats,
+    /// only gossip and dial the self signed DHT entries.
+    pub signed_dht: bool,
+    /// the newest self signed records (peer, seq, signature) of the connected peers,
+    /// gossip them when signed DHT.
+    pub records: Mutex<HashMap<PeerId, PeerRecord>>,
+    /// the last seq of self record.
+    pub record_seq: AtomicU64,
+}
+
+/// Relay utilization, relayed bytes for other peers.
+/// the load is KiB in last check period, and advertise to remotes.
+#[derive(Default)]
+pub(crate) struct RelayLoad {
+    current: AtomicU64,
+    last: AtomicU64,
+}
+
+impl RelayLoad {
+    /// record relayed bytes.
+    pub fn add(&self, len: usize) {
+        self.current.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// start a new period.
+    pub fn tick(&self) {
+        let current = self.current.swap(0, Ordering::Relaxed);
+        self.last.store(current, Ordering::Relaxed);
+    }
+
+    /// current load indicator (KiB).
+    pub fn load(&self) -> u32 {
+        let bytes = self
+            .last
+            .load(Ordering::Relaxed)
+            .max(self.current.load(Ordering::Relaxed));
+        (bytes / 1024).min(u32::MAX as u64) as u32
+    }
+}
+
+/// Identity pinning (trust-on-first-use), dialed address => first seen peer id.
+pub(crate) struct Pins {
+    pub policy: PinPolicy,
+    pins: Mutex<HashMap<SocketAddr, PeerId>>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Pins {
+    /// `saved` is the pins bytes read from the storage, line is `addr peer_id`.
+    pub fn load(policy: PinPolicy, storage: Arc<dyn Storage>, saved: &[u8]) -> Pins {
+        let mut pins = HashMap::new();
+        for line in String::from_utf8_lossy(saved).lines() {
+            if let Some((addr, id)) = line.split_once(' ') {
+                if let (Ok(addr), Ok(id)) = (addr.parse(), PeerId::from_hex(id)) {
+                    pins.insert(addr, id);
+                }
+            }
+        }
+        Pins {
+            policy,
+            pins: Mutex::new(pins),
+            storage,
+        }
+    }
+
+    /// check the peer id of the address, pin it when first seen.
+    /// return the pinned peer id when mismatch.
+    pub async fn check(&self, addr: SocketAddr, id: PeerId) -> Option<PeerId> {
+        if self.policy == PinPolicy::Off {
+            return None;
+        }
+        let bytes = {
+            let mut pins = self.pins.lock().unwrap();
+            match pins.get(&addr) {
+                Some(pinned) if pinned != &id => return Some(*pinned),
+                Some(_) => return None,
+                None => {}
+            }
+            pins.insert(addr, id);
+            pins.iter()
+                .map(|(a, p)| format!("{} {}\n", a, p.to_hex()))
+                .collect::<String>()
+                .into_bytes()
+        };
+        let _ = self.storage.put(STORAGE_PINS_KEY, bytes).await;
+        None
+    }
+}
+
+/// the idle time (seconds) of a relay flow, it will be expired.
+const RELAY_FLOW_IDLE: u64 = 60;
+
+/// Relay flows served for others, pair of peers => last relayed time.
+/// the limit is 0 means unlimited.
+pub(crate) struct RelayFlows {
+    limit: usize,
+    flows: Mutex<HashMap<(PeerId, PeerId), Instant>>,
+    /// decline all new flows (draining).
+    closed: AtomicBool,
+}
+
+impl RelayFlows {
+    pub fn new(limit: usize) -> RelayFlows {
+        RelayFlows {
+            limit,
+            flows: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// record the relay between two peers, false when it is a new flow and full.
+    pub fn allow(&self, a: &PeerId, b: &PeerId) -> bool {
+        let key = if a < b { (*a, *b) } else { (*b, *a) };
+        let mut flows = self.flows.lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) && !flows.contains_key(&key) {
+            return false;
+        }
+        if self.limit > 0 && !flows.contains_key(&key) && flows.len() >= self.limit {
+            Self::expire(&mut flows);
+            if flows.len() >= self.limit {
+                return false;
+            }
+        }
+        flows.insert(key, Instant::now());
+        true
+    }
+
+    /// decline new flows, the existing flows keep going.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    /// clear the idle flows.
+    pub fn tick(&self) {
+        Self::expire(&mut self.flows.lock().unwrap());
+    }
+
+    fn expire(flows: &mut HashMap<(PeerId, PeerId), Instant>) {
+        let idle = Duration::from_secs(RELAY_FLOW_IDLE);
+        flows.retain(|_, t| t.elapsed() < idle);
+    }
+
+    /// current active relay flows.
+    pub fn count(&self) -> usize {
+        let idle = Duration::from_secs(RELAY_FLOW_IDLE);
+        let flows = self.flows.lock().unwrap();
+        flows.values().filter(|t| t.elapsed() < idle).count()
+    }
+}
+
+/// the id of the relayed message, keccak256(from || to || data).
+pub(crate) fn seen_id(from: &PeerId, to: &PeerId, data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(from.0);
+    hasher.update(to.0);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Recently seen message ids in the ttl window, drop the duplicates of the relay path.
+/// when full, the oldest is evicted first, so an id is kept at least the time of
+/// `capacity` new ids arrived (or ttl), set capacity larger than the rate * ttl.
+/// the capacity is 0 means disabled.
+#[allow(clippy::type_complexity)]
+pub(crate) struct SeenIds {
+    capacity: usize,
+    ttl: Duration,
+    /// id => first seen, and the seen order.
+    seen: Mutex<(HashMap<[u8; 32], Instant>, VecDeque<[u8; 32]>)>,
+}
+
+impl SeenIds {
+    pub fn new(capacity: usize, ttl: Duration) -> SeenIds {
+        SeenIds {
+            capacity,
+            ttl,
+            seen: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// record the id, true when it is first seen (in the window), false is duplicate.
+    pub fn check(&self, id: [u8; 32]) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        let mut seen = self.seen.lock().unwrap();
+        let (ids, order) = &mut *seen;
+
+        // expire the old ones.
+        while let Some(old) = order.front() {
+            if ids.get(old).is_some_and(|t| t.elapsed() < self.ttl) {
+                break;
+            }
+            ids.remove(old);
+            order.pop_front();
+        }
+
+        if ids.contains_key(&id) {
+            return false;
+        }
+        if ids.len() >= self.capacity {
+            if let Some(old) = order.pop_front() {
+                ids.remove(&old);
+            }
+        }
+        ids.insert(id, Instant::now());
+        order.push_back(id);
+        true
+    }
+}
+
+/// the frames count of one second, only the session task writes it.
+#[derive(Default)]
+struct RateBucket {
+    second: AtomicU64,
+    send_bytes: AtomicU64,
+    send_msgs: AtomicU64,
+    recv_bytes: AtomicU64,
+    recv_msgs: AtomicU64,
+}
+
+/// the sent frame sizes buckets, by the power of two.
+const FRAME_SIZE_BUCKETS: usize = 32;
+
+/// The live stats of a session, updated by the session task, read by peer infos and
+/// network stats. all are atomics, the frames not take any lock.
+pub(crate) struct PeerStats {
+    /// last received frame (unix secs), 0 is never.
+    last_seen: AtomicU64,
+    /// last keepalive round trip (micros), 0 is not measured.
+    rtt: AtomicU64,
+    /// the sent encrypted frame sizes histogram.
+    sizes: [AtomicU64; FRAME_SIZE_BUCKETS],
+    /// sliding window throughput, a ring of per-second buckets, so the memory is
+    /// bounded by the window. the window is 0 means disabled.
+    start: Instant,
+    rates: Vec<RateBucket>,
+}
+
+impl PeerStats {
+    pub fn new(window: u64) -> PeerStats {
+        PeerStats {
+            last_seen: AtomicU64::new(0),
+            rtt: AtomicU64::new(0),
+            sizes: Default::default(),
+            start: Instant::now(),
+            rates: (0..window).map(|_| RateBucket::default()).collect(),
+        }
+    }
+
+    /// received a frame from remote.
+    pub fn seen(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.last_seen.store(now, Ordering::Relaxed);
+    }
+
+    pub fn set_rtt(&self, rtt: Duration) {
+        let micros = (rtt.as_micros() as u64).max(1);
+        self.rtt.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn last_seen(&self) -> Option<u64> {
+        let secs = self.last_seen.load(Ordering::Relaxed);
+        (secs > 0).then_some(secs)
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        let micros = self.rtt.load(Ordering::Relaxed);
+        (micros > 0).then(|| Duration::from_micros(micros))
+    }
+
+    /// record a sent frame.
+    pub fn send(&self, bytes: usize) {
+        let bucket =
+            (bytes.next_power_of_two().trailing_zeros() as usize).min(FRAME_SIZE_BUCKETS - 1);
+        self.sizes[bucket].fetch_add(1, Ordering::Relaxed);
+        self.add(self.start.elapsed().as_secs(), bytes, true);
+    }
+
+    /// record a received frame.
+    pub fn recv(&self, bytes: usize) {
+        self.add(self.start.elapsed().as_secs(), bytes, false);
+    }
+
+    fn add(&self, second: u64, bytes: usize, is_send: bool) {
+        if self.rates.is_empty() {
+            return;
+        }
+        let bucket = &self.rates[(second % self.rates.len() as u64) as usize];
+        if bucket.second.load(Ordering::Relaxed) != second {
+            bucket.send_bytes.store(0, Ordering::Relaxed);
+            bucket.send_msgs.store(0, Ordering::Relaxed);
+            bucket.recv_bytes.store(0, Ordering::Relaxed);
+            bucket.recv_msgs.store(0, Ordering::Relaxed);
+            bucket.second.store(second, Ordering::Relaxed);
+        }
+        if is_send {
+            bucket.send_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            bucket.send_msgs.fetch_add(1, Ordering::Relaxed);
+        } else {
+            bucket.recv_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            bucket.recv_msgs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// the sent frame sizes histogram, the size is rounded up to power of two.
+    pub fn frame_sizes(&self) -> Vec<(usize, u64)> {
+        self.sizes
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (1usize << i, count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// the throughput in the window.
+    pub fn throughput(&self) -> Throughput {
+        self.rate(self.start.elapsed().as_secs())
+    }
+
+    /// the average of the buckets in the window (end at `second`).
+    fn rate(&self, second: u64) -> Throughput {
+        if self.rates.is_empty() {
+            return Throughput::default();
+        }
+        let window = self.rates.len() as u64;
+        let mut sum = [0u64; 4];
+        for bucket in &self.rates {
+            let s = bucket.second.load(Ordering::Relaxed);
+            if s <= second && s + window > second {
+                sum[0] += bucket.send_bytes.load(Ordering::Relaxed);
+                sum[1] += bucket.send_msgs.load(Ordering::Relaxed);
+                sum[2] += bucket.recv_bytes.load(Ordering::Relaxed);
+                sum[3] += bucket.recv_msgs.load(Ordering::Relaxed);
+            }
+        }
+        let window = window as f64;
+        Throughput {
+            send_bytes: sum[0] as f64 / window,
+            send_msgs: sum[1] as f64 / window,
+            recv_bytes: sum[2] as f64 / window,
+            recv_msgs: sum[3] as f64 / window,
+        }
+    }
+}
+
+/// The stats of the running sessions, registered when session ready.
+pub(crate) struct SessionStats(Mutex<HashMap<PeerId, Arc<PeerStats>>>);
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats(Mutex::new(HashMap::new()))
+    }
+
+    pub fn insert(&self, peer_id: PeerId, stats: Arc<PeerStats>) {
+        self.0.lock().unwrap().insert(peer_id, stats);
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<Arc<PeerStats>> {
+        self.0.lock().unwrap().get(peer_id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<(PeerId, Arc<PeerStats>)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, stats)| (*id, stats.clone()))
+            .collect()
+    }
+
+    /// remove the stats, only if it is still the same session.
+    pub fn remove(&self, peer_id: &PeerId, stats: &Arc<PeerStats>) {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions.get(peer_id).is_some_and(|s| Arc::ptr_eq(s, stats)) {
+            sessions.remove(peer_id);
+        }
+    }
+}
+
+/// the not ready (or closing) session state is stale after it (seconds), pruned.
+pub(crate) const SESSION_STATE_STALE: u64 = 60;
+
+/// The session state transitions of the latest connection to every peer. only move
+/// forward, so a duplicate connection not changes the running session, and a new
+/// connection starts again after closed. only the dialed or authenticated peers are
+/// recorded, the inbound side has no `Connecting` and `Handshaking`, it starts at
+/// `KeyExchange` when the session key of the remote is completed.
+pub(crate) struct SessionStates(Mutex<HashMap<PeerId, (Instant, Vec<SessionState>)>>);
+
+impl SessionStates {
+    pub fn new() -> SessionStates {
+        SessionStates(Mutex::new(HashMap::new()))
+    }
+
+    /// transition to the state, false if it is not forward.
+    pub fn set(&self, peer_id: PeerId, state: SessionState) -> bool {
+        let mut states = self.0.lock().unwrap();
+        let (changed, history) = states
+            .entry(peer_id)
+            .or_insert_with(|| (Instant::now(), vec![]));
+        match history.last() {
+            Some(SessionState::Closed) if state < SessionState::Closing => history.clear(),
+            Some(last) if *last >= state => return false,
+            _ => {}
+        }
+        history.push(state);
+        *changed = Instant::now();
+        true
+    }
+
+    /// the connection failed before the session ready.
+    pub fn fail(&self, peer_id: &PeerId) {
+        if let Some((changed, history)) = self.0.lock().unwrap().get_mut(peer_id) {
+            if history.last().is_some_and(|s| *s < SessionState::Ready) {
+                history.push(SessionState::Closed);
+                *changed = Instant::now();
+            }
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Vec<SessionState> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .map(|(_, history)| history.clone())
+            .unwrap_or_default()
+    }
+
+    /// drop the closed, and the not ready (or closing) not changed in `stale`,
+    /// return the count.
+    pub fn prune(&self, stale: Duration) -> usize {
+        let mut states = self.0.lock().unwrap();
+        let len = states.len();
+        states.retain(|_, (changed, history)| match history.last() {
+            Some(SessionState::Closed) => false,
+            Some(SessionState::Ready) => true,
+            _ => changed.elapsed() < stale,
+        });
+        len - states.len()
+    }
+}
+
+/// the rng streams of the seeded `Config::rng_seed`, one per purpose, so adding
+/// draws to one not changes the others.
+pub(crate) const RNG_STREAM_KEY: u64 = 1;
+pub(crate) const RNG_STREAM_ASSIST: u64 = 2;
+pub(crate) const RNG_STREAM_SESSION: u64 = 3;
+
+/// The rng of keys and handshakes. default is from entropy, only tests seed it
+/// (`Config::rng_seed`) to reproduce the exact keys and handshake bytes.
+pub(crate) struct NodeRng(Option<Mutex<ChaChaRng>>);
+
+impl NodeRng {
+    pub fn new(seed: Option<[u8; 32]>, stream: u64) -> NodeRng {
+        NodeRng(seed.map(|seed| {
+            let mut rng = ChaChaRng::from_seed(seed);
+            rng.set_stream(stream);
+            Mutex::new(rng)
+        }))
+    }
+
+    /// a new rng, from entropy, or the next one of the seeded.
+    pub fn rng(&self) -> ChaChaRng {
+        match &self.0 {
+            Some(seeded) => {
+                let mut seed = [0u8; 32];
+                seeded.lock().unwrap().fill_bytes(&mut seed);
+                ChaChaRng::from_seed(seed)
+            }
+            None => ChaChaRng::from_entropy(),
+        }
+    }
+}
+
+/// Node liveness, had connected to network, last activity (unix ms) and live sessions.
+#[derive(Default)]
+pub(crate) struct Health {
+    bootstrapped: AtomicBool,
+    last_active: AtomicU64,
+    /// live session tasks.
+    sessions: AtomicUsize,
+    /// known peers, recomputed by maintenance.
+    peers: AtomicUsize,
+}
+
+impl Health {
+    /// a session is running.
+    pub fn connected(&self) {
+        self.bootstrapped.store(true, Ordering::Relaxed);
+        self.active();
+    }
+
+    /// received message from remote.
+    pub fn active(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.last_active.store(now, Ordering::Relaxed);
+    }
+
+    pub fn bootstrapped(&self) -> bool {
+        self.bootstrapped.load(Ordering::Relaxed)
+    }
+
+    /// a session task is started.
+    pub fn session_start(&self) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a session task is exited.
+    pub fn session_end(&self) {
+        self.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn set_peers(&self, peers: usize) {
+        self.peers.store(peers, Ordering::Relaxed);
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers.load(Ordering::Relaxed)
+    }
+
+    pub fn last_active(&self) -> Option<SystemTime> {
+        match self.last_active.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+}
+
+impl Global {
+    #[inline]
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer.id
+    }
+
+    #[inline]
+    pub fn assist_id(&self) -> &PeerId {
+        &self.peer.assist
+    }
+
+    /// self peer info advertised to remotes.
+    /// when NAT type known, `is_pub` follows it, and the reflexive address agreed by
+    /// the quorum is advertised only when others can use it (not symmetric).
+    pub fn advertised_peer(&self) -> Peer {
+        let mut peer = self.peer;
+        let nat_type = self.nat_type();
+        if nat_type != NatType::Unknown {
+            peer.is_pub = nat_type.is_pub();
+        }
+        if nat_type.is_reflexive_usable() {
+            if let Some(addr) = self.stun.lock().unwrap().reflexive() {
+                peer.socket = addr;
+            }
+        }
+        self.address_filter.apply_own(peer)
+    }
+
+    /// the NAT type of self, classified by the reflexive addresses.
+    pub fn nat_type(&self) -> NatType {
+        self.stun.lock().unwrap().classify(self.peer.socket)
+    }
+
+    #[inline]
+    pub fn generate_remote(&self) -> (SessionKey, RemotePublic) {
+        let (session_key, dh_bytes) =
+            SessionKey::generate_with(self.key.as_ref(), &mut self.rng.rng());
+        let remote_pk = RemotePublic::new(self.advertised_peer(), dh_bytes);
+        (session_key, remote_pk)
+    }
+
+    /// generate remote info, if had the remote's ticket, offer session resumption.
+    pub async fn generate_remote_for(&self, remote_id: &PeerId) -> (SessionKey, RemotePublic) {
+        let ticket = self.tickets.read().await.get(remote_id);
+        if let Some(ticket) = ticket {
+            let (session_key, dh_bytes) =
+                SessionKey::generate_resume(self.key.as_ref(), ticket, &mut self.rng.rng());
+            (
+                session_key,
+                RemotePublic::new(self.advertised_peer(), dh_bytes),
+            )
+        } else {
+            self.generate_remote()
+        }
+    }
+
+    /// generate remote info with the first data. if had the remote's ticket,
+    /// send it as early data in the resumption offer, or keep it to send
+    /// after connected.
+    pub async fn generate_remote_early(
+        &self,
+        remote_id: &PeerId,
+        data: Vec<u8>,
+    ) -> (SessionKey, RemotePublic) {
+        let ticket = self.tickets.read().await.get(remote_id);
+        if let Some(ticket) = ticket {
+            let (session_key, dh_bytes) = SessionKey::generate_resume_early(
+                self.key.as_ref(),
+                ticket,
+                data,
+                &mut self.rng.rng(),
+            );
+            (
+                session_key,
+                RemotePublic::new(self.advertised_peer(), dh_bytes),
+            )
+        } else {
+            let (mut session_key, remote_pk) = self.generate_remote();
+            session_key.set_early(data);
+            (session_key, remote_pk)
+        }
+    }
+
+    /// the ticket is used and rotated in one lock, so it is only used once,
+    /// a replayed offer cannot resume again.
+    pub async fn complete_remote(
+        &self,
+        remote_id: &PeerId,
+        dh_bytes: Vec<u8>,
+    ) -> Option<(SessionKey, RemotePublic)> {
+        let mut tickets = self.tickets.write().await;
+        let ticket = tickets.get(remote_id);
+        if let Some((session_key, dh_bytes)) = SessionKey::generate_complete(
+            self.key.as_ref(),
+            remote_id,
+            dh_bytes,
+            ticket,
+            &mut self.rng.rng(),
+        ) {
+            if let Some(ticket) = session_key.ticket() {
+                tickets.add(*remote_id, ticket).await;
+            }
+            drop(tickets);
+            let remote_pk = RemotePublic::new(self.advertised_peer(), dh_bytes);
+            Some((session_key, remote_pk))
+        } else {
+            None
+        }
+    }
+
+    /// save the session ticket for next resumption.
+    pub async fn save_ticket(&self, remote_id: &PeerId, session_key: &SessionKey) {
+        if let Some(ticket) = session_key.ticket() {
+            self.tickets.write().await.add(*remote_id, ticket).await;
+        }
+    }
+
+    #[inline]
+    pub async fn trans_send(
+        &self,
+        trans_type: &TransportType,
+        msg: TransportSendMessage,
+    ) -> Result<()> {
+        let trans_lock = self.transports.read().await;
+        if let Some(sender) = trans_lock.get(trans_type) {
+            sender
+                .send(msg)
+                .await
+                .map_err(|_e| new_io_error("Transport missing"))
+        } else {
+            drop(trans_lock);
+            // start new transport to send it.
+            // Only TCP & QUIC
+            let main_send = self.trans.clone();
+            let mut new_peer = self.peer;
+            new_peer.transport = *trans_type;
+            new_peer.zero_port();
+
+            let (_, trans_send, _, _) =
+                start(&new_peer, Some(main_send), &self.socket_options).await?;
+            trans_send
+                .send(msg)
+                .await
+                .map_err(|_e| new_io_error("Transport missing"))?;
+            self.transports
+                .write()
+                .await
+                .insert(*trans_type, trans_send);
+            Ok(())
+        }
+    }
+
+    /// the tier of the peer.
+    pub fn peer_tier(&self, peer_id: &PeerId) -> PeerTier {
+        self.tiers
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// set the tier of the peer, `Normal` is same as not set.
+    pub fn set_peer_tier(&self, peer_id: PeerId, tier: PeerTier) {
+        let mut tiers = self.tiers.lock().unwrap();
+        if tier == PeerTier::Normal {
+            tiers.remove(&peer_id);
+        } else {
+            tiers.insert(peer_id, tier);
+        }
+    }
+
+    /// get a permit for new incoming session, it will release when session closed.
+    /// if no limit or the peer is high tier (exempt), permit is None.
+    pub fn session_permit(&self, peer_id: &PeerId) -> Result<Option<OwnedSemaphorePermit>> {
+        if self.peer_tier(peer_id) == PeerTier::High {
+            return Ok(None);
+        }
+        if let Some(limit) = &self.session_limit {
+            limit
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| new_io_error("too many sessions"))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// the DHT help to new connected remote, none when static network.
+    pub async fn dht_help(&self, remote_id: &PeerId) -> Option<DHT> {
+        if self.static_network {
+            return None;
+        }
+        let peers = self.peer_list.read().await.help_dht(
+            remote_id,
+            self.max_dht_help,
+            self.verify_advertise,
+        );
+        let mut dht = self.dht(peers);
+        if self.signed_dht {
+            // self record first, so remote can gossip us. not the unspecified one.
+            let peer = self.advertised_peer();
+            if !peer.socket.ip().is_unspecified() && self.record_allowed(&peer) {
+                let seq = self.next_record_seq();
+                dht.0.insert(0, peer);
+                dht.2
+                    .insert(0, (seq, peer_record_sign(self.key.as_ref(), &peer, seq)));
+            }
+        }
+        Some(dht)
+    }
+
+    /// the DHT of the peers, filtered by the address filter. when signed DHT,
+    /// they are the self signed records as is, the peers without record, or the
+    /// record changed by the filter are skipped.
+    pub fn dht(&self, peers: Vec<Peer>) -> DHT {
+        let load = self.relay_load.load();
+        if !self.signed_dht {
+            let peers = peers
+                .into_iter()
+                .filter_map(|p| self.address_filter.apply(p))
+                .collect();
+            return DHT(peers, load, vec![]);
+        }
+
+        let records = self.records.lock().unwrap();
+        let (peers, signs) = peers
+            .iter()
+            .filter_map(|p| records.get(&p.id))
+            .filter(|(peer, _, _)| self.record_allowed(peer))
+            .map(|(peer, seq, sign)| (*peer, (*seq, sign.clone())))
+            .unzip();
+        DHT(peers, load, signs)
+    }
+
+    /// the signed record can be gossiped as is, the address filter not changes it.
+    fn record_allowed(&self, peer: &Peer) -> bool {
+        self.address_filter.apply(*peer).as_ref() == Some(peer)
+    }
+
+    /// the next seq of self record, unix millis, monotonic when the clock goes back.
+    pub fn next_record_seq(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let last = self
+            .record_seq
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap_or(now);
+        now.max(last + 1)
+    }
+
+    /// the record seq (unix millis) is not ahead of local clock more than the skew.
+    pub fn record_seq_valid(&self, seq: u64) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        seq <= now.saturating_add(self.max_clock_skew.saturating_mul(1000))
+    }
+
+    /// keep the record of the peer when it is newer, false if it is not.
+    pub fn record_update(&self, peer: Peer, seq: u64, sign: Vec<u8>) -> bool {
+        let mut records = self.records.lock().unwrap();
+        if records
+            .get(&peer.id)
+            .is_some_and(|(_, last, _)| *last >= seq)
+        {
+            return false;
+        }
+        records.insert(peer.id, (peer, seq, sign));
+        true
+    }
+
+    /// the record is older than the kept one of the peer.
+    pub fn record_stale(&self, peer_id: &PeerId, seq: u64) -> bool {
+        self.records
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .is_some_and(|(_, last, _)| *last > seq)
+    }
+
+    /// the session of the peer (stable or DHT), or the closest one, and if is it.
+    /// the sender is cloned out, so not hold the peer list lock when send.
+    pub async fn session_get(&self, peer_id: &PeerId) -> Option<(Sender<SessionMessage>, bool)> {
+        self.peer_list
+            .read()
+            .await
+            .get(peer_id)
+            .map(|(sender, _, is_it)| (sender.clone(), is_it))
+    }
+
+    /// the DHT session of the peer, or the closest one.
+    pub async fn dht_session(&self, peer_id: &PeerId) -> Option<Sender<SessionMessage>> {
+        self.peer_list
+            .read()
+            .await
+            .dht_get(peer_id)
+            .map(|(sender, _, _)| sender.clone())
+    }
+
+    /// the next closest session to relay, skip the prev peers.
+    pub async fn next_closest(
+        &self,
+        target: &PeerId,
+        prev: &[PeerId],
+    ) -> Option<Sender<SessionMessage>> {
+        self.peer_list
+            .read()
+            .await
+            .next_closest(target, prev)
+            .cloned()
+    }
+
+    /// DHT connect to the peer, try the candidate addresses in order (waiting by
+    /// the backoff policy), stop when the peer is connected.
+    pub async fn dht_connect(&self, peer: &Peer) -> Result<()> {
+        for (i, (socket, transport)) in peer.addrs().into_iter().enumerate() {
+            if i > 0 {
+                sleep(self.socket_options.backoff.delay(i as u32 - 1)).await;
+                if self.peer_list.read().await.contains(&peer.id) {
+                    break;
+                }
+            }
+            self.dht_dial(&peer.id, socket, transport, None, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// DHT connect to the peer main address, and wait the result.
+    /// if dial to same address is pending, wait the result of it.
+    pub async fn connect_wait(&self, peer: &Peer, timeout: Duration) -> ConnectResult {
+        if peer.effective_id() && self.peer_list.read().await.contains(&peer.id) {
+            return Ok(peer.id);
+        }
+        self.dial_wait(peer, timeout).await
+    }
+
+    /// dial the bootstraps in order, `bootstrap_dials` at a time, the next ones
+    /// only when all the dialed failed. return the connected count.
+    pub async fn bootstrap(self: Arc<Self>, timeout: Duration) -> usize {
+        let peers = self.peer_list.read().await.bootstrap_order();
+        let batch = if self.bootstrap_dials == 0 {
+            peers.len().max(1)
+        } else {
+            self.bootstrap_dials
+        };
+
+        for chunk in peers.chunks(batch) {
+            let mut dials = JoinSet::new();
+            for peer in chunk.iter().copied() {
+                let global = self.clone();
+                dials.spawn(async move {
+                    let is_ok = global.connect_wait(&peer, timeout).await.is_ok();
+                    (peer.socket, is_ok)
+                });
+            }
+
+            let mut connected = 0;
+            while let Some(Ok((socket, is_ok))) = dials.join_next().await {
+                self.peer_list.write().await.bootstrap_result(socket, is_ok);
+                if is_ok {
+                    connected += 1;
+                }
+            }
+            if connected > 0 {
+                return connected;
+            }
+            debug!("Bootstraps all failure, try the next ones.");
+        }
+        0
+    }
+
+    /// dial back to the address of the connected remote, if it is the same peer,
+    /// the address is verified, and can be advertised in DHT help.
+    pub async fn verify_advertised(&self, peer: &Peer, timeout: Duration) -> bool {
+        let verified = matches!(self.dial_wait(peer, timeout).await, Ok(id) if id == peer.id);
+        if verified {
+            self.peer_list.write().await.verify(peer);
+        } else {
+            debug!(
+                "Verify {} at {} failure.",
+                peer.id.short_show(),
+                peer.socket
+            );
+        }
+        verified
+    }
+
+    /// dial to the address and wait the handshake result.
+    async fn dial_wait(&self, peer: &Peer, timeout: Duration) -> ConnectResult {
+        let (sender, mut receiver) = mpsc::channel(1);
+        self.connect_waits
+            .write()
+            .await
+            .entry(peer.socket)
+            .or_default()
+            .push(sender.clone());
+        let dial = self
+            .dht_dial(
+                &peer.id,
+                peer.socket,
+                peer.transport,
+                Some(sender.clone()),
+                None,
+            )
+            .await;
+        let res = match dial {
+            Ok(()) => tokio::time::timeout(timeout, receiver.recv())
+                .await
+                .ok()
+                .flatten(),
+            Err(_) => Some(Err(ConnectError::Unreachable)),
+        };
+        {
+            let mut waits = self.connect_waits.write().await;
+            if let Some(senders) = waits.get_mut(&peer.socket) {
+                senders.retain(|s| !s.same_channel(&sender));
+                if senders.is_empty() {
+                    waits.remove(&peer.socket);
+                }
+            }
+        }
+        let res = match res {
+            // the transport only tell the dialer, share it with others.
+            Some(res) => {
+                self.connect_done(&peer.socket, res).await;
+                res
+            }
+            None => Err(ConnectError::Timeout),
+        };
+        if res.is_err() {
+            // failure, clear it, so can dial it again.
+            self.buffer.write().await.remove_dht(&peer.socket);
+            self.session_failed(&peer.id);
+        }
+        res
+    }
+
+    /// DHT connect to the peer main address with the first data, when had
+    /// the peer's ticket, the data is sent in the handshake (0-RTT).
+    pub async fn connect_data(&self, peer: &Peer, data: Vec<u8>) -> Result<()> {
+        if let Some((sender, true)) = self.session_get(&peer.id).await {
+            let _ = sender
+                .send(SessionMessage::Data(0, data, Priority::Normal))
+                .await;
+            return Ok(());
+        }
+        self.dht_dial(&peer.id, peer.socket, peer.transport, None, Some(data))
+            .await
+    }
+
+    /// dial the peer, send one data and close, no session is kept in peer list.
+    /// return the remote peer id when the data is enqueued to the transport.
+    pub async fn send_oneshot(
+        &self,
+        peer: &Peer,
+        data: Vec<u8>,
+        timeout: Duration,
+    ) -> ConnectResult {
+        let (endpoint_sender, endpoint_receiver) = new_endpoint_channel();
+        let (stream_sender, mut stream_receiver) = new_endpoint_channel();
+        let (mut session_key, remote_pk) = self.generate_remote_for(&peer.id).await;
+        self.trans_send(
+            &peer.transport,
+            TransportSendMessage::StableConnect(
+                stream_sender,
+                endpoint_receiver,
+                peer.socket,
+                remote_pk,
+            ),
+        )
+        .await
+        .map_err(|_| ConnectError::Unreachable)?;
+
+        let remote =
+            tokio::time::timeout(timeout, wait_handshake(&mut stream_receiver, &mut None)).await;
+        let RemotePublic(remote_peer, dh_key, version) = match remote {
+            Ok(Some(remote)) => remote,
+            Ok(None) => return Err(ConnectError::Unreachable),
+            Err(_) => {
+                let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+                return Err(ConnectError::Timeout);
+            }
+        };
+
+        let is_self = &remote_peer.id == self.peer_id() && &remote_peer.assist == self.assist_id();
+        let is_other = peer.effective_id() && remote_peer.id != peer.id;
+        if is_self || is_other || !compatible_version(version, PROTOCOL_MIN_VERSION) {
+            let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+            return Err(ConnectError::Rejected);
+        }
+        if !session_key.complete(&remote_peer.id, dh_key) {
+            let reason = Some(CloseReason::Crypto);
+            let _ = endpoint_sender.send(EndpointMessage::Close(reason)).await;
+            return Err(ConnectError::Handshake);
+        }
+
+        let data = CoreData::Data(0, data)
+            .to_padded_bytes(&self.frame_padding)
+            .map_err(|_| ConnectError::Rejected)?;
+        let bytes = session_key.encrypt(data);
+        endpoint_sender
+            .send(EndpointMessage::Data(bytes))
+            .await
+            .map_err(|_| ConnectError::Unreachable)?;
+        let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+        Ok(remote_peer.id)
+    }
+
+    /// transition the session state of the peer, skip the own sessions.
+    pub fn session_state(&self, peer_id: &PeerId, state: SessionState) {
+        let is_remote = peer_id != self.peer_id() && *peer_id != PeerId::default();
+        if is_remote && self.session_states.set(*peer_id, state) {
+            debug!("Session {} state: {:?}.", peer_id.short_show(), state);
+        }
+    }
+
+    /// the connection to the peer failed before the session ready.
+    pub fn session_failed(&self, peer_id: &PeerId) {
+        self.session_states.fail(peer_id);
+    }
+
+    /// dial the peer again when the session lifetime is over (`fresh`, full handshake,
+    /// the ticket is dropped), or the high tier peer is lost.
+    /// every retry waits by the backoff policy.
+    pub async fn redial(&self, peer: &Peer, fresh: bool) {
+        if fresh {
+            self.tickets.write().await.remove(&peer.id).await;
+        }
+        let timeout = Duration::from_secs(REDIAL_TIMEOUT);
+        for retry in 0..REDIAL_RETRIES {
+            sleep(self.socket_options.backoff.delay(retry)).await;
+            match self.connect_wait(peer, timeout).await {
+                Ok(_) => return,
+                Err(e) => debug!("Redial {} failure: {:?}", peer.id.short_show(), e),
+            }
+        }
+    }
+
+    /// tell all the connect waiters of the address the result.
+    pub async fn connect_done(&self, addr: &SocketAddr, res: ConnectResult) {
+        if let Some(senders) = self.connect_waits.write().await.remove(addr) {
+            for sender in senders {
+                let _ = sender.try_send(res);
+            }
+        }
+    }
+
+    /// find the peer only by the peer id, ask the closest peers in DHT,
+    /// and they will answer closer peers (or the target) to connect.
+    /// return the peer when connected, or error when timeout.
+    pub async fn find_node(&self, target: &PeerId, timeout: Duration) -> Result<Peer> {
+        if target == self.peer_id() {
+            return Err(new_io_error("find node to self"));
+        }
+        if self.static_network {
+            return Err(new_io_error("find node in static network"));
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut retry = 0;
+        loop {
+            if let Some(peer) = self.peer_list.read().await.peer(target) {
+                return Ok(peer);
+            }
+            if Instant::now() >= deadline {
+                return Err(new_io_error("find node timeout"));
+            }
 
-use crate::buffer::{Buffer, BufferKey};
-use crate::kad::KadValue;
-use crate::peer_list::PeerList;
-use crate::session_key::SessionKey;
-use crate::transports::{start, RemotePublic, TransportRecvMessage, TransportSendMessage};
+            let senders = self.peer_list.read().await.find_node_senders(target);
+            for sender in senders {
+                let _ = sender.send(SessionMessage::FindNode(*target)).await;
+            }
+            let delay = self.socket_options.backoff.delay(retry);
+            sleep(delay.min(deadline.saturating_duration_since(Instant::now()))).await;
+            retry += 1;
+        }
+    }
 
-pub(crate) struct Global {
-    pub peer: Peer,
-    pub key: Key,
-    pub trans: Sender<TransportRecvMessage>,
-    pub transports: Arc<RwLock<HashMap<TransportType, Sender<TransportSendMessage>>>>,
-    pub out_sender: Sender<ReceiveMessage>,
-    pub peer_list: Arc<RwLock<PeerList>>,
-    pub buffer: Arc<RwLock<Buffer>>,
-    pub is_relay_data: bool,
-    pub delivery_length: usize,
-}
+    /// DHT dial to the address. if dial to same address is pending
+    /// or recently failed, skip it.
+    async fn dht_dial(
+        &self,
+        id: &PeerId,
+        socket: SocketAddr,
+        transport: TransportType,
+        wait: Option<Sender<ConnectResult>>,
+        early: Option<Vec<u8>>,
+    ) -> Result<()> {
+        if !self.buffer.write().await.add_dht(&socket) {
+            debug!("DHT connect to {} is processing, skip it.", socket);
+            return Ok(());
+        }
 
-impl Global {
-    #[inline]
-    pub fn peer_id(&self) -> &PeerId {
-        &self.peer.id
+        self.session_state(id, SessionState::Connecting);
+        let (session_key, remote_pk) = if let Some(data) = early {
+            self.generate_remote_early(id, data).await
+        } else {
+            self.generate_remote_for(id).await
+        };
+        let res = self
+            .trans_send(
+                &transport,
+                TransportSendMessage::Connect(socket, remote_pk, session_key, wait),
+            )
+            .await;
+        if res.is_err() {
+            self.session_failed(id);
+            return res;
+        }
+        self.session_state(id, SessionState::Handshaking);
+        Ok(())
     }
 
-    #[inline]
-    pub fn assist_id(&self) -> &PeerId {
-        &self.peer.assist
+    /// record the first data received time of the connection.
+    pub async fn trace_first_data(&self, peer_id: &PeerId) {
+        if let Some(timer) = self.connects.write().await.get_mut(peer_id) {
+            if timer.trace.first_data.is_none() {
+                timer.trace.first_data = Some(timer.elapsed());
+            }
+        }
     }
 
-    #[inline]
-    pub fn generate_remote(&self) -> (SessionKey, RemotePublic) {
-        let (session_key, dh_bytes) = SessionKey::generate(&self.key);
-        let remote_pk = RemotePublic(self.peer.clone(), dh_bytes);
-        (session_key, remote_pk)
+    /// add the proof-of-work nonce before the stable connect/result join data.
+    pub fn pow_join(&self, data: Vec<u8>) -> Vec<u8> {
+        if self.pow_difficulty == 0 {
+            data
+        } else {
+            [&self.pow_nonce.to_le_bytes()[..], &data].concat()
+        }
     }
 
-    #[inline]
-    pub fn complete_remote(
-        &self,
-        remote_id: &PeerId,
-        dh_bytes: Vec<u8>,
-    ) -> Option<(SessionKey, RemotePublic)> {
-        if let Some((session_key, dh_bytes)) =
-            SessionKey::generate_complete(&self.key, remote_id, dh_bytes)
-        {
-            let remote_pk = RemotePublic(self.peer.clone(), dh_bytes);
-            Some((session_key, remote_pk))
+    /// verify and remove the proof-of-work nonce of remote join data.
+    pub fn pow_check(&self, peer_id: &PeerId, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.pow_difficulty == 0 {
+            return Some(data);
+        }
+        if data.len() < 8 {
+            return None;
+        }
+        let mut nonce = [0u8; 8];
+        nonce.copy_from_slice(&data[..8]);
+        if pow_verify(peer_id, u64::from_le_bytes(nonce), self.pow_difficulty) {
+            Some(data.split_off(8))
         } else {
             None
         }
     }
 
-    #[inline]
-    pub async fn trans_send(
-        &self,
-        trans_type: &TransportType,
-        msg: TransportSendMessage,
-    ) -> Result<()> {
-        let trans_lock = self.transports.read().await;
-        if let Some(sender) = trans_lock.get(trans_type) {
-            sender
-                .send(msg)
-                .await
-                .map_err(|_e| new_io_error("Transport missing"))
-        } else {
-            drop(trans_lock);
-            // start new transport to send it.
-            // Only TCP & QUIC
-            let main_send = self.trans.clone();
-            let mut new_peer = self.peer.clone();
-            new_peer.transport = *trans_type;
-            new_peer.zero_port();
+    /// pause delivering the remote data to outside.
+    pub fn pause_delivery(&self) {
+        self.delivery_paused.send_replace(true);
+    }
 
-            let (_, trans_send, _, _) = start(&new_peer, Some(main_send)).await?;
-            trans_send
-                .send(msg)
-                .await
-                .map_err(|_e| new_io_error("Transport missing"))?;
-            self.transports
-                .write()
-                .await
-                .insert(*trans_type, trans_send);
-            Ok(())
+    /// resume delivering, the waiting sessions continue.
+    pub fn resume_delivery(&self) {
+        self.delivery_paused.send_replace(false);
+    }
+
+    /// wait when the delivery is paused, the session delivery task waits, and the
+    /// session stops reading its remote when its delivery queue is full.
+    pub async fn delivery_wait(&self) {
+        if *self.delivery_paused.borrow() {
+            let _ = self.delivery_paused.subscribe().wait_for(|p| !*p).await;
         }
     }
 
+    /// check the remote timestamp (unix seconds) by the local wall clock,
+    /// with the configured clock skew.
+    pub fn timestamp_valid(&self, remote: u64, window: u64) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        timestamp_valid(remote, now, window, self.max_clock_skew)
+    }
+
     #[inline]
     pub async fn out_send(&self, msg: ReceiveMessage) -> Result<()> {
+        if self.server_mode
+            && matches!(
+                msg,
+                ReceiveMessage::Data(..)
+                    | ReceiveMessage::Metadata(..)
+                    | ReceiveMessage::Protocol(..)
+                    | ReceiveMessage::LargeData(..)
+                    | ReceiveMessage::Stream(..)
+            )
+        {
+            return Ok(());
+        }
+        if let Some(observer) = self.observer.read().await.as_ref() {
+            if let Some(copy) = observed(&msg) {
+                let _ = observer.try_send(copy);
+            }
+        }
+        let msg = match msg {
+            ReceiveMessage::Protocol(from, protocol, data, is_direct) => {
+                let receiver = self.protocols.read().await.get(&protocol).cloned();
+                if let Some(receiver) = receiver {
+                    // not wait a slow sub-protocol, it never stalls the others.
+                    match receiver.try_send(ReceiveMessage::Data(from, data, is_direct)) {
+                        Ok(()) => return Ok(()),
+                        Err(TrySendError::Full(ReceiveMessage::Data(from, data, is_direct))) => {
+                            warn!(
+                                "CHAMOMILE: protocol {} receiver is full, send to outside.",
+                                protocol
+                            );
+                            ReceiveMessage::Protocol(from, protocol, data, is_direct)
+                        }
+                        Err(TrySendError::Closed(ReceiveMessage::Data(from, data, is_direct))) => {
+                            // receiver dropped, unregister it.
+                            self.protocols.write().await.remove(&protocol);
+                            ReceiveMessage::Protocol(from, protocol, data, is_direct)
+                        }
+                        Err(_) => return Ok(()),
+                    }
+                } else {
+                    ReceiveMessage::Protocol(from, protocol, data, is_direct)
+                }
+            }
+            msg => msg,
+        };
         self.out_sender
             .send(msg)
             .await
@@ -137,7 +1642,7 @@ impl Global {
 
     pub async fn upgrade(&self, peer_id: &PeerId, assist_id: &PeerId) -> Result<()> {
         let v_some = self.buffer.write().await.remove_tmp(peer_id);
-        if let Some((v, is_d)) = v_some {
+        let res = if let Some((v, is_d)) = v_some {
             self.peer_list.write().await.add_stable(*peer_id, v, is_d);
             Ok(())
         } else {
@@ -145,7 +1650,9 @@ impl Global {
                 .write()
                 .await
                 .dht_to_stable(peer_id, assist_id)
-        }
+        };
+        self.check_leader().await;
+        res
     }
 
     pub async fn upgrade_own(&self, peer_id: &PeerId) {
@@ -158,10 +1665,8 @@ impl Global {
     pub async fn tmp_to_dht(&self, peer_id: &PeerId) -> Result<()> {
         let v_some = self.buffer.write().await.remove_tmp(peer_id);
         if let Some((v, is_d)) = v_some {
-            if is_d {
-                if self.peer_list.write().await.add_dht(v).await {
-                    return Ok(());
-                }
+            if is_d && self.peer_list.write().await.add_dht(v).await {
+                return Ok(());
             }
         }
         Err(new_io_error("missing buffer"))
@@ -174,6 +1679,934 @@ impl Global {
         buffer_lock.remove_stable(peer_id);
         drop(buffer_lock);
 
-        self.peer_list.write().await.stable_to_dht(peer_id)
+        let res = self.peer_list.write().await.stable_to_dht(peer_id);
+        self.check_leader().await;
+        res
+    }
+
+    /// remote (`from` ip) reported the address of self as seen by it.
+    pub fn observe_external(&self, addr: SocketAddr, from: IpAddr) {
+        self.external.lock().unwrap().observe(addr, Instant::now());
+        self.stun.lock().unwrap().observe(from, addr);
+    }
+
+    /// tell outside when the external address changed, and stable in debounce time.
+    pub async fn settle_external(&self) {
+        let changed = self.external.lock().unwrap().settle(Instant::now());
+        if let Some(addr) = changed {
+            let _ = self
+                .out_send(ReceiveMessage::ExternalAddrChanged(addr))
+                .await;
+        }
+    }
+
+    /// start draining, decline new inbound sessions and relay flows.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.relay_flows.close();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// the network stats snapshot, `peers` is from the last maintenance.
+    pub async fn stats(&self) -> NetworkStats {
+        let connects = self
+            .connects
+            .read()
+            .await
+            .iter()
+            .map(|(id, timer)| (*id, timer.trace))
+            .collect();
+        let peer_throughput: Vec<_> = self
+            .session_stats
+            .all()
+            .into_iter()
+            .map(|(id, stats)| (id, stats.throughput()))
+            .collect();
+        let mut throughput = Throughput::default();
+        for (_, rate) in &peer_throughput {
+            throughput.send_bytes += rate.send_bytes;
+            throughput.send_msgs += rate.send_msgs;
+            throughput.recv_bytes += rate.recv_bytes;
+            throughput.recv_msgs += rate.recv_msgs;
+        }
+        NetworkStats {
+            relay_load: self.relay_load.load(),
+            relay_flows: self.relay_flows.count(),
+            connects,
+            sessions: self.health.sessions(),
+            peers: self.health.peers(),
+            frame_sizes: self.frame_sizes(),
+            throughput,
+            peer_throughput,
+        }
+    }
+
+    /// the sent frame sizes histogram of the live sessions.
+    pub fn frame_sizes(&self) -> Vec<(PeerId, Vec<(usize, u64)>)> {
+        self.session_stats
+            .all()
+            .into_iter()
+            .map(|(id, stats)| (id, stats.frame_sizes()))
+            .filter(|(_, sizes)| !sizes.is_empty())
+            .collect()
+    }
+
+    /// one tick of background maintenance. it not waits the peer list when the hot
+    /// path holds it, just skip and do it in the next tick.
+    pub async fn maintain(&self, tasks: &Maintenance) {
+        if tasks.prune {
+            if let Ok(mut peer_list) = self.peer_list.try_write() {
+                let stales = peer_list.prune();
+                if !stales.is_empty() {
+                    debug!("Maintenance pruned {} stale peers.", stales.len());
+                }
+            } else {
+                debug!("Maintenance prune skipped, peer list is busy.");
+            }
+            let stale = Duration::from_secs(SESSION_STATE_STALE);
+            let closed = self.session_states.prune(stale);
+            if closed != 0 {
+                debug!(
+                    "Maintenance pruned {} closed or stale session states.",
+                    closed
+                );
+            }
+        }
+
+        if tasks.metrics {
+            if let Ok(peer_list) = self.peer_list.try_read() {
+                let peers = peer_list.dht_keys().len() + peer_list.stable_all().len();
+                self.health.set_peers(peers);
+            }
+        }
+
+        if tasks.stats {
+            let stats = self.stats().await;
+            let _ = self.out_send(ReceiveMessage::Stats(stats)).await;
+        }
+    }
+
+    /// recompute the leader of self and stable peers, tell outside when changed.
+    pub async fn check_leader(&self) {
+        if !self.leader_election {
+            return;
+        }
+        let mut peers: Vec<PeerId> = self
+            .peer_list
+            .read()
+            .await
+            .stable_all()
+            .into_keys()
+            .collect();
+        peers.push(*self.peer_id());
+        let leader = elect_leader(&peers);
+
+        let changed = {
+            let mut current = self.leader.lock().unwrap();
+            let changed = *current != leader;
+            *current = leader;
+            changed
+        };
+        if let (true, Some(leader)) = (changed, leader) {
+            let _ = self.out_send(ReceiveMessage::Leader(leader)).await;
+        }
+    }
+}
+
+/// the global in memory, no storage and transports, for tests and benchmarks.
+#[cfg(any(test, feature = "bench"))]
+pub(crate) fn memory_global() -> (Global, mpsc::Receiver<TransportSendMessage>) {
+    use crate::storage::FileStorage;
+    use chamomile_types::key::Key;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use std::path::PathBuf;
+
+    let key = Key::generate(&mut ChaChaRng::from_seed([1u8; 32]));
+    let mut peer = Peer::socket("127.0.0.1:0".parse().unwrap());
+    peer.id = key.peer_id();
+
+    let (trans, _) = mpsc::channel(8);
+    let (out_sender, _) = mpsc::channel(8);
+    let (trans_send, trans_recv) = mpsc::channel(8);
+    let mut transports = HashMap::new();
+    transports.insert(peer.transport, trans_send);
+
+    let peer_list = PeerList::load(
+        peer.id,
+        peer.assist,
+        Arc::new(FileStorage::new(PathBuf::from("/nonexistent"))),
+        &[],
+        vec![],
+        (vec![], vec![]),
+    );
+    let tickets = Tickets::load(
+        &key,
+        Arc::new(FileStorage::new(PathBuf::from("/nonexistent"))),
+        &[],
+        false,
+    );
+    let key = Arc::new(key);
+
+    let global = Global {
+        peer,
+        key,
+        trans,
+        out_sender,
+        transports: Arc::new(RwLock::new(transports)),
+        peer_list: Arc::new(RwLock::new(peer_list)),
+        buffer: Arc::new(RwLock::new(Buffer::init(0))),
+        tickets: Arc::new(RwLock::new(tickets)),
+        is_relay_data: true,
+        delivery_length: 0,
+        relay_load: RelayLoad::default(),
+        address_filter: AddressFilter::default(),
+        keepalive: 2,
+        session_limit: None,
+        decrypt_policy: DecryptPolicy::default(),
+        relay_sign: false,
+        relay_verify: false,
+        connects: Arc::new(RwLock::new(HashMap::new())),
+        observer: Arc::new(RwLock::new(None)),
+        socket_options: SocketOptions::default(),
+        health: Health::default(),
+        draining: AtomicBool::new(false),
+        pow_difficulty: 0,
+        pow_nonce: 0,
+        connect_waits: Arc::new(RwLock::new(HashMap::new())),
+        relay_flows: RelayFlows::new(0),
+        static_network: false,
+        leader_election: false,
+        leader: Mutex::new(None),
+        external: Mutex::new(External::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Duration::from_secs(crate::hole_punching::EXTERNAL_DEBOUNCE),
+        )),
+        stun: Mutex::new(StunResult::default()),
+        max_dht_help: 0,
+        pins: Pins::load(
+            PinPolicy::Off,
+            Arc::new(FileStorage::new(PathBuf::from("/nonexistent"))),
+            &[],
+        ),
+        server_mode: false,
+        verify_advertise: false,
+        require_encryption: false,
+        bootstrap_dials: 0,
+        relay_on_behalf: false,
+        seen: SeenIds::new(0, Duration::from_secs(60)),
+        origin_seen: SeenIds::new(
+            ORIGIN_SEEN_CAPACITY,
+            Duration::from_secs(ORIGIN_PROOF_WINDOW),
+        ),
+        sequence_frames: false,
+        session_lifetime: 0,
+        frame_padding: FramePadding::Off,
+        delivery_paused: watch::Sender::new(false),
+        protocols: Arc::new(RwLock::new(HashMap::new())),
+        max_clock_skew: 30,
+        rate_window: 10,
+        tiers: Mutex::new(HashMap::new()),
+        rng: NodeRng::new(None, RNG_STREAM_SESSION),
+        session_states: SessionStates::new(),
+        session_stats: SessionStats::new(),
+        signed_dht: false,
+        records: Mutex::new(HashMap::new()),
+        record_seq: AtomicU64::new(0),
+    };
+    (global, trans_recv)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+
+    pub(crate) use super::memory_global as test_global;
+
+    #[tokio::test]
+    async fn test_dht_connect_dedup() {
+        let (global, mut trans_recv) = test_global();
+        let addr: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+
+        // same peer gossiped by two DHT messages.
+        global.dht_connect(&Peer::socket(addr)).await.unwrap();
+        global.dht_connect(&Peer::socket(addr)).await.unwrap();
+
+        let mut dials = 0;
+        while let Ok(msg) = trans_recv.try_recv() {
+            if let TransportSendMessage::Connect(to, _, _, _) = msg {
+                assert_eq!(to, addr);
+                dials += 1;
+            }
+        }
+        assert_eq!(dials, 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_wait_timeout() {
+        let (global, mut trans_recv) = test_global();
+        let addr: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+
+        // dialed, but the transport never answer.
+        let mut peer = Peer::socket(addr);
+        peer.id = PeerId([7u8; 20]);
+        let res = global.connect_wait(&peer, Duration::from_millis(100)).await;
+        assert_eq!(res, Err(ConnectError::Timeout));
+        assert_eq!(
+            global.session_states.get(&peer.id),
+            vec![
+                SessionState::Connecting,
+                SessionState::Handshaking,
+                SessionState::Closed
+            ]
+        );
+        assert!(matches!(
+            trans_recv.try_recv(),
+            Ok(TransportSendMessage::Connect(to, _, _, Some(_))) if to == addr
+        ));
+        assert!(global.connect_waits.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_wait_concurrent() {
+        let (global, _trans_recv) = test_global();
+        let global = Arc::new(global);
+        let addr: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+        let remote = PeerId([7u8; 20]);
+
+        // the second dial to same address waits the pending one.
+        let mut waits = vec![];
+        for _ in 0..2 {
+            let g = global.clone();
+            waits.push(tokio::spawn(async move {
+                g.connect_wait(&Peer::socket(addr), Duration::from_secs(5))
+                    .await
+            }));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(global.connect_waits.read().await[&addr].len(), 2);
+
+        global.connect_done(&addr, Ok(remote)).await;
+        for wait in waits {
+            assert_eq!(wait.await.unwrap(), Ok(remote));
+        }
+        assert!(global.connect_waits.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_static_network() {
+        let (mut global, _) = test_global();
+        let peer_id = PeerId([3u8; 20]);
+        assert!(global.dht_help(&peer_id).await.is_some());
+
+        // no DHT push, no find node.
+        global.static_network = true;
+        assert!(global.dht_help(&peer_id).await.is_none());
+        let timeout = Duration::from_millis(100);
+        assert!(global.find_node(&peer_id, timeout).await.is_err());
+    }
+
+    /// relay throughput when peers join and leave at the same time.
+    /// run: cargo test --release -p chamomile bench_relay_churn -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn bench_relay_churn() {
+        let _ = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_test_writer()
+            .try_init();
+        use crate::kad::KadValue;
+        use crate::session::new_session_channel;
+        use crate::transports::new_endpoint_channel;
+
+        const RELAYS: usize = 4;
+        const MESSAGES: usize = 20000;
+
+        async fn run(hold_lock: bool) -> (f64, f64) {
+            let (global, _) = test_global();
+            let global = Arc::new(global);
+            let target = PeerId([9u8; 20]);
+            let (next_sender, mut next_receiver) = new_session_channel();
+            let (next_stream, _) = new_endpoint_channel();
+            let mut next = Peer::peer(target);
+            next.assist = target;
+            let kv = KadValue(next_sender, next_stream, next);
+            global.peer_list.write().await.add_dht(kv).await;
+
+            // the next hop is slower than relays, its channel is full sometimes.
+            tokio::spawn(async move {
+                while next_receiver.recv().await.is_some() {
+                    tokio::task::yield_now().await;
+                }
+            });
+
+            let done = Arc::new(AtomicBool::new(false));
+            let churn_global = global.clone();
+            let churn_done = done.clone();
+            let churn = tokio::spawn(async move {
+                let mut ops = 0u64;
+                while !churn_done.load(Ordering::Relaxed) {
+                    let mut id = [0u8; 20];
+                    id[..8].copy_from_slice(&ops.to_le_bytes());
+                    let mut peer = Peer::peer(PeerId(id));
+                    peer.assist = peer.id;
+                    let (sender, _) = new_session_channel();
+                    let (stream, _) = new_endpoint_channel();
+                    let kv = KadValue(sender, stream, peer);
+                    churn_global.peer_list.write().await.add_dht(kv).await;
+                    churn_global
+                        .peer_list
+                        .write()
+                        .await
+                        .remove_peer(&peer.id, &peer.assist);
+                    ops += 2;
+                }
+                ops
+            });
+
+            let start = Instant::now();
+            let mut relays = vec![];
+            for _ in 0..RELAYS {
+                let g = global.clone();
+                relays.push(tokio::spawn(async move {
+                    for i in 0..MESSAGES {
+                        let msg = SessionMessage::RelayData(target, target, vec![i as u8]);
+                        if hold_lock {
+                            if let Some(s) = g.peer_list.read().await.next_closest(&target, &[]) {
+                                let _ = s.send(msg).await;
+                            }
+                        } else if let Some(s) = g.next_closest(&target, &[]).await {
+                            let _ = s.send(msg).await;
+                        }
+                    }
+                }));
+            }
+            for relay in relays {
+                relay.await.unwrap();
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            done.store(true, Ordering::Relaxed);
+            let ops = churn.await.unwrap();
+
+            ((RELAYS * MESSAGES) as f64 / elapsed, ops as f64 / elapsed)
+        }
+
+        let (old_relay, old_churn) = run(true).await;
+        let (new_relay, new_churn) = run(false).await;
+        debug!("hold lock when send: {old_relay:.0} relay/s, {old_churn:.0} churn/s");
+        debug!("clone out sender:    {new_relay:.0} relay/s, {new_churn:.0} churn/s");
+    }
+
+    #[tokio::test]
+    async fn test_check_leader() {
+        let (mut global, _) = test_global();
+        let (out_sender, mut out_recv) = mpsc::channel(8);
+        global.out_sender = out_sender;
+
+        // disabled.
+        global.check_leader().await;
+        assert!(out_recv.try_recv().is_err());
+
+        // alone, self is leader, only tell once.
+        global.leader_election = true;
+        global.check_leader().await;
+        match out_recv.try_recv() {
+            Ok(ReceiveMessage::Leader(leader)) => assert_eq!(&leader, global.peer_id()),
+            _ => panic!("leader event missing"),
+        }
+        global.check_leader().await;
+        assert!(out_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_maintain() {
+        use crate::session::new_session_channel;
+
+        let (mut global, _) = test_global();
+        let (out_sender, mut out_recv) = mpsc::channel(8);
+        global.out_sender = out_sender;
+
+        let mut receivers = vec![];
+        for i in 1..=2u8 {
+            let mut peer = Peer::peer(PeerId([i; 20]));
+            peer.assist = peer.id;
+            let (sender, receiver) = new_session_channel();
+            let (stream, _) = new_endpoint_channel();
+            receivers.push(receiver);
+            let kv = KadValue(sender, stream, peer);
+            global.peer_list.write().await.add_dht(kv).await;
+        }
+        // the session of peer 2 is closed, but still in the list.
+        drop(receivers.pop());
+
+        let tasks = Maintenance {
+            stats: true,
+            ..Default::default()
+        };
+        global.maintain(&tasks).await;
+        assert_eq!(
+            global.peer_list.read().await.dht_keys(),
+            vec![PeerId([1; 20])]
+        );
+        match out_recv.try_recv() {
+            Ok(ReceiveMessage::Stats(stats)) => assert_eq!(stats.peers, 1),
+            _ => panic!("stats event missing"),
+        }
+
+        // no stats emitted by default.
+        global.maintain(&Maintenance::default()).await;
+        assert!(out_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pins() {
+        let dir = std::env::temp_dir().join(format!("chamomile-pins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(FileStorage::new(dir));
+        let addr: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+        let (a, b) = (PeerId([1u8; 20]), PeerId([2u8; 20]));
+
+        let pins = Pins::load(PinPolicy::Off, storage.clone(), &[]);
+        assert_eq!(pins.check(addr, a).await, None);
+        assert_eq!(pins.check(addr, b).await, None);
+
+        // first use is trusted, the different id later is mismatch.
+        let pins = Pins::load(PinPolicy::Reject, storage.clone(), &[]);
+        assert_eq!(pins.check(addr, a).await, None);
+        assert_eq!(pins.check(addr, a).await, None);
+        assert_eq!(pins.check(addr, b).await, Some(a));
+        assert_eq!(pins.check("1.2.3.4:7365".parse().unwrap(), b).await, None);
+
+        // pins are saved.
+        let saved = storage.get(STORAGE_PINS_KEY).await.unwrap().unwrap();
+        let pins = Pins::load(PinPolicy::Warn, storage, &saved);
+        assert_eq!(pins.check(addr, b).await, Some(a));
+    }
+
+    #[tokio::test]
+    async fn test_verify_advertise() {
+        use crate::kad::KadValue;
+        use crate::session::new_session_channel;
+        use crate::transports::new_endpoint_channel;
+
+        let (mut global, mut trans_recv) = test_global();
+        global.verify_advertise = true;
+        let remote = PeerId([1u8; 20]);
+        let mut peers = vec![];
+        for i in 2..4u8 {
+            let mut peer = Peer::socket(format!("127.0.0.1:{}", 7360 + i as u16).parse().unwrap());
+            peer.id = PeerId([i; 20]);
+            peer.assist = peer.id;
+            let (session_sender, _) = new_session_channel();
+            let (stream_sender, _) = new_endpoint_channel();
+            let kv = KadValue(session_sender, stream_sender, peer);
+            assert!(global.peer_list.write().await.add_dht(kv).await);
+            peers.push(peer);
+        }
+        let (good, bogus) = (peers[0], peers[1]);
+        global.peer_list.write().await.verify(&good);
+
+        // nobody answer the dial back, the bogus one is not gossiped.
+        let timeout = Duration::from_millis(100);
+        assert!(!global.verify_advertised(&bogus, timeout).await);
+        assert!(matches!(
+            trans_recv.try_recv(),
+            Ok(TransportSendMessage::Connect(addr, ..)) if addr == bogus.socket
+        ));
+        let DHT(helps, _, _) = global.dht_help(&remote).await.unwrap();
+        let ids: Vec<PeerId> = helps.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![good.id]);
+
+        // the address changed, need verify again.
+        global.peer_list.write().await.verify(&bogus);
+        assert_eq!(global.dht_help(&remote).await.unwrap().0.len(), 2);
+        let mut moved = good;
+        moved.socket = "127.0.0.1:7369".parse().unwrap();
+        assert!(!global.peer_list.read().await.is_verified(&moved));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_priority() {
+        let (mut global, mut trans_recv) = test_global();
+        global.bootstrap_dials = 1;
+        let (high, low): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:7401".parse().unwrap(),
+            "127.0.0.1:7402".parse().unwrap(),
+        );
+        let mut peer_list = global.peer_list.write().await;
+        peer_list.add_bootstrap(Peer::socket(low));
+        peer_list.add_bootstrap(Peer::socket(high));
+        peer_list.set_priority(high, 10);
+        peer_list.set_priority(low, 1);
+        drop(peer_list);
+        let global = Arc::new(global);
+        let task = tokio::spawn(global.clone().bootstrap(Duration::from_secs(5)));
+
+        // the high one first, and the low one only after it failed.
+        let wait = match trans_recv.recv().await {
+            Some(TransportSendMessage::Connect(addr, _, _, Some(wait))) if addr == high => wait,
+            _ => panic!("high priority bootstrap not dialed"),
+        };
+        sleep(Duration::from_millis(50)).await;
+        assert!(trans_recv.try_recv().is_err());
+        wait.send(Err(ConnectError::Unreachable)).await.unwrap();
+        let wait = match trans_recv.recv().await {
+            Some(TransportSendMessage::Connect(addr, _, _, Some(wait))) if addr == low => wait,
+            _ => panic!("low priority bootstrap not dialed"),
+        };
+        wait.send(Ok(PeerId([2u8; 20]))).await.unwrap();
+        assert_eq!(task.await.unwrap(), 1);
+
+        // same priority, the success history first.
+        global.peer_list.write().await.set_priority(high, 1);
+        let order: Vec<SocketAddr> = global
+            .peer_list
+            .read()
+            .await
+            .bootstrap_order()
+            .iter()
+            .map(|p| p.socket)
+            .collect();
+        assert_eq!(order, vec![low, high]);
+    }
+
+    #[test]
+    fn test_advertised_nat() {
+        let (global, _) = test_global();
+        let local = global.peer.socket;
+        let mapped: SocketAddr = "5.6.7.8:7364".parse().unwrap();
+        assert_eq!(global.nat_type(), NatType::Unknown);
+        assert_eq!(global.advertised_peer().socket, local);
+        assert!(global.advertised_peer().is_pub);
+
+        // not enough independent reporters, not adopt it.
+        global.observe_external(mapped, "1.1.1.1".parse().unwrap());
+        global.observe_external(mapped, "2.2.2.2".parse().unwrap());
+        assert_eq!(global.nat_type(), NatType::Unknown);
+        assert_eq!(global.advertised_peer().socket, local);
+
+        // port restricted, advertise the reflexive address.
+        global.observe_external(mapped, "4.4.4.4".parse().unwrap());
+        assert_eq!(global.nat_type(), NatType::PortRestricted);
+        assert_eq!(global.advertised_peer().socket, mapped);
+        assert!(!global.advertised_peer().is_pub);
+
+        // symmetric, the reflexive address is useless for others.
+        let other = "5.6.7.8:9000".parse().unwrap();
+        global.observe_external(other, "3.3.3.3".parse().unwrap());
+        assert_eq!(global.nat_type(), NatType::Symmetric);
+        assert_eq!(global.advertised_peer().socket, local);
+        assert!(!global.advertised_peer().is_pub);
+    }
+
+    #[test]
+    fn test_advertised_local() {
+        let (mut global, _) = test_global();
+        global.address_filter = AddressFilter::default().strip_local();
+        // bound on a private interface, with a public external address.
+        let public: SocketAddr = "1.2.3.4:7364".parse().unwrap();
+        global.peer.socket = "192.168.1.2:7364".parse().unwrap();
+        global.peer.add_addr(public, global.peer.transport);
+        let peer = global.advertised_peer();
+        assert_eq!(peer.socket, public);
+        assert_eq!(peer.addrs().len(), 1);
+
+        // the peers in DHT help too.
+        let mut remote = Peer::socket("10.0.0.2:7364".parse().unwrap());
+        remote.add_addr("5.6.7.8:7364".parse().unwrap(), remote.transport);
+        let remote = global.address_filter.apply(remote).unwrap();
+        assert_eq!(remote.socket, "5.6.7.8:7364".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_external_changed() {
+        let (mut global, _) = test_global();
+        let (out_sender, mut out_recv) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let current = "1.2.3.4:7364".parse().unwrap();
+        global.external = Mutex::new(External::new(current, Duration::from_millis(200)));
+        let from = "9.9.9.9".parse().unwrap();
+
+        // same as current.
+        global.observe_external(current, from);
+        sleep(Duration::from_millis(220)).await;
+        global.settle_external().await;
+        assert!(out_recv.try_recv().is_err());
+
+        // two different reflexive addresses, flapping is not told.
+        let new_addr: SocketAddr = "5.6.7.8:7364".parse().unwrap();
+        global.observe_external("5.6.7.9:7364".parse().unwrap(), from);
+        global.observe_external(new_addr, from);
+        sleep(Duration::from_millis(100)).await;
+        global.observe_external(new_addr, from);
+        global.settle_external().await;
+        assert!(out_recv.try_recv().is_err());
+
+        // stable after debounce, only tell once.
+        sleep(Duration::from_millis(120)).await;
+        global.settle_external().await;
+        global.settle_external().await;
+        match out_recv.try_recv() {
+            Ok(ReceiveMessage::ExternalAddrChanged(addr)) => assert_eq!(addr, new_addr),
+            _ => panic!("external changed event missing"),
+        }
+        assert!(out_recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_timestamp_skew() {
+        let now = 1_000_000;
+        // remote clock is ahead or behind a little, still in window.
+        assert!(timestamp_valid(now + 20, now, 60, 30));
+        assert!(timestamp_valid(now - 80, now, 60, 30));
+        assert!(!timestamp_valid(now + 40, now, 60, 30));
+        assert!(!timestamp_valid(now - 100, now, 60, 30));
+        // no skew is strict.
+        assert!(!timestamp_valid(now + 1, now, 60, 0));
+        // huge window and skew from the remote request not overflow.
+        assert!(timestamp_valid(now, now, u64::MAX, u64::MAX));
+        assert!(timestamp_valid(0, now, u64::MAX, 1));
+
+        let (mut global, _) = test_global();
+        global.max_clock_skew = 5;
+        let local = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(global.timestamp_valid(local + 3, 10));
+        assert!(!global.timestamp_valid(local + 60, 10));
+    }
+
+    #[tokio::test]
+    async fn test_seeded_handshake() {
+        let seeded = |seed| {
+            let (mut global, _) = test_global();
+            global.rng = NodeRng::new(Some(seed), RNG_STREAM_SESSION);
+            global
+        };
+        let (a, b) = (seeded([5u8; 32]), seeded([5u8; 32]));
+        for _ in 0..2 {
+            let (_, a_remote) = a.generate_remote();
+            let (_, b_remote) = b.generate_remote();
+            assert_eq!(a_remote.1, b_remote.1);
+        }
+
+        // the answer of the same offer is the same too.
+        let offerer = seeded([6u8; 32]);
+        let offer = offerer.generate_remote().1 .1;
+        let remote_id = offerer.key.peer_id();
+        let (a_session, a_answer) = a.complete_remote(&remote_id, offer.clone()).await.unwrap();
+        let (_, b_answer) = b.complete_remote(&remote_id, offer).await.unwrap();
+        assert!(a_session.is_ok());
+        assert_eq!(a_answer.1, b_answer.1);
+
+        // other seed, other bytes.
+        let c = seeded([7u8; 32]);
+        assert_ne!(a.generate_remote().1 .1, c.generate_remote().1 .1);
+    }
+
+    #[tokio::test]
+    async fn test_early_data_replay() {
+        use chamomile_types::key::Key;
+        use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+        let (mut global, _) = test_global();
+        let storage = Arc::new(FileStorage::new(std::path::PathBuf::from("/nonexistent")));
+        let tickets = Tickets::load(global.key.as_ref(), storage, &[], true);
+        global.tickets = Arc::new(RwLock::new(tickets));
+
+        let mut rng = ChaChaRng::from_seed([8u8; 32]);
+        let dialer = Key::generate(&mut rng);
+        let ticket = [3u8; 32];
+        global
+            .tickets
+            .write()
+            .await
+            .add(dialer.peer_id(), ticket)
+            .await;
+        let data = b"first payload".to_vec();
+        let (_, offer) = SessionKey::generate_resume_early(&dialer, ticket, data.clone(), &mut rng);
+
+        // first offer resumed with the early data.
+        let remote_id = dialer.peer_id();
+        let (mut session_key, _) = global
+            .complete_remote(&remote_id, offer.clone())
+            .await
+            .unwrap();
+        assert_eq!(session_key.take_early(), Some(data));
+
+        // replayed offer, the ticket is used, fallback to full handshake.
+        let (mut session_key, _) = global.complete_remote(&remote_id, offer).await.unwrap();
+        assert_eq!(session_key.take_early(), None);
+    }
+
+    #[test]
+    fn test_session_states() {
+        use SessionState::*;
+        let (a, b) = (PeerId([1u8; 20]), PeerId([2u8; 20]));
+        let states = SessionStates::new();
+        for state in [Connecting, Handshaking, KeyExchange, Ready] {
+            assert!(states.set(a, state));
+        }
+        // a duplicate connection not changes the ready session.
+        assert!(!states.set(a, KeyExchange));
+        states.fail(&a);
+        assert_eq!(states.get(&a).last(), Some(&Ready));
+        assert!(states.set(a, Closing) && states.set(a, Closed));
+        assert!(!states.set(a, Closing));
+
+        // a new connection starts again, and failed before ready.
+        assert!(states.set(a, Connecting));
+        states.fail(&a);
+        assert_eq!(states.get(&a), vec![Connecting, Closed]);
+
+        assert!(states.set(b, KeyExchange));
+        let stale = Duration::from_secs(SESSION_STATE_STALE);
+        assert_eq!(states.prune(stale), 1);
+        assert!(states.get(&a).is_empty());
+        assert_eq!(states.get(&b), vec![KeyExchange]);
+
+        // the stuck not ready is aged out, the ready is kept.
+        let c = PeerId([3u8; 20]);
+        assert!(states.set(c, KeyExchange) && states.set(c, Ready));
+        assert_eq!(states.prune(Duration::ZERO), 1);
+        assert!(states.get(&b).is_empty());
+        assert_eq!(states.get(&c), vec![KeyExchange, Ready]);
+    }
+
+    #[test]
+    fn test_rates() {
+        let stats = PeerStats::new(5);
+        // 10s, send 1000 bytes in 4 frames, and receive 500 bytes every second.
+        for second in 0..10 {
+            for _ in 0..4 {
+                stats.add(second, 250, true);
+            }
+            stats.add(second, 500, false);
+        }
+        let rate = stats.rate(9);
+        assert!((rate.send_bytes - 1000.0).abs() < 1.0);
+        assert!((rate.send_msgs - 4.0).abs() < 0.1);
+        assert!((rate.recv_bytes - 500.0).abs() < 1.0);
+
+        // idle for 3s, only the last 2 seconds in the window.
+        let rate = stats.rate(12);
+        assert!((rate.recv_bytes - 200.0).abs() < 1.0);
+        // all out of the window.
+        assert_eq!(stats.rate(20), Throughput::default());
+
+        // disabled.
+        let stats = PeerStats::new(0);
+        stats.send(100);
+        assert_eq!(stats.throughput(), Throughput::default());
+        assert_eq!(stats.frame_sizes(), vec![(128, 1)]);
+    }
+
+    #[test]
+    fn test_seen_ids() {
+        let (a, b) = (PeerId([1u8; 20]), PeerId([2u8; 20]));
+        let seen = SeenIds::new(2, Duration::from_millis(100));
+        let (m1, m2, m3) = (
+            seen_id(&a, &b, b"1"),
+            seen_id(&a, &b, b"2"),
+            seen_id(&a, &b, b"3"),
+        );
+        assert!(seen.check(m1));
+        assert!(!seen.check(m1));
+        assert_ne!(m1, seen_id(&b, &a, b"1"));
+
+        // full, the oldest is evicted, the newer is still suppressed.
+        assert!(seen.check(m2));
+        assert!(seen.check(m3));
+        assert!(!seen.check(m2));
+        assert!(!seen.check(m3));
+        assert!(seen.check(m1));
+
+        // expired after the window.
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(seen.check(m3));
+
+        // disabled.
+        let seen = SeenIds::new(0, Duration::from_millis(100));
+        assert!(seen.check(m1));
+        assert!(seen.check(m1));
+    }
+
+    #[test]
+    fn test_relay_flows_close() {
+        let flows = RelayFlows::new(0);
+        let (a, b, c) = (PeerId([1u8; 20]), PeerId([2u8; 20]), PeerId([3u8; 20]));
+        assert!(flows.allow(&a, &b));
+
+        // draining, the existing flow keep going.
+        flows.close();
+        assert!(flows.allow(&b, &a));
+        assert!(!flows.allow(&a, &c));
+    }
+
+    #[tokio::test]
+    async fn test_session_limit() {
+        let (mut global, _) = test_global();
+        global.session_limit = Some(Arc::new(Semaphore::new(3)));
+
+        // connection flood.
+        let mut sessions = vec![];
+        for _ in 0..10 {
+            if let Ok(permit) = global.session_permit(&PeerId::default()) {
+                sessions.push(permit);
+            }
+        }
+        assert_eq!(sessions.len(), 3);
+
+        // the high tier peer is exempt.
+        let high = PeerId([1u8; 20]);
+        global.set_peer_tier(high, PeerTier::High);
+        assert!(global.session_permit(&high).unwrap().is_none());
+        assert!(global.session_permit(&PeerId::default()).is_err());
+
+        // session closed, can accept new one.
+        sessions.pop();
+        assert!(global.session_permit(&PeerId::default()).is_ok());
+
+        // no limit.
+        global.session_limit = None;
+        assert!(global.session_permit(&PeerId::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pow() {
+        let (mut global, _) = test_global();
+        let peer_id = *global.peer_id();
+        let data = vec![1u8, 2, 3];
+
+        // disabled, join data not changed.
+        assert_eq!(global.pow_join(data.clone()), data);
+        assert_eq!(global.pow_check(&peer_id, data.clone()), Some(data.clone()));
+
+        global.pow_difficulty = 12;
+        let deadline = std::time::Instant::now() + Duration::from_secs(POW_SOLVE_TIMEOUT);
+        global.pow_nonce = pow_solve(&peer_id, 12, deadline).unwrap();
+        assert!(pow_bits(&peer_id, global.pow_nonce) >= 12);
+
+        // valid accepted.
+        let join = global.pow_join(data.clone());
+        assert_eq!(join.len(), data.len() + 8);
+        assert_eq!(global.pow_check(&peer_id, join.clone()), Some(data.clone()));
+
+        // other peer cannot use it.
+        assert!(global.pow_check(&PeerId([9u8; 20]), join).is_none());
+
+        // insufficient rejected.
+        let weak = (0..u64::MAX).find(|n| pow_bits(&peer_id, *n) < 12).unwrap();
+        let join = [&weak.to_le_bytes()[..], &data].concat();
+        assert!(global.pow_check(&peer_id, join).is_none());
+        assert!(global.pow_check(&peer_id, data).is_none());
+
+        // out of time, give up.
+        let passed = std::time::Instant::now();
+        assert!(pow_solve(&peer_id, 64, passed).is_none());
     }
 }
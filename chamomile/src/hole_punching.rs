@@ -1,7 +1,10 @@
+use std::collections::VecDeque;
 use std::io::Result;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::{Duration, Instant};
 
 use chamomile_types::{
+    key::SIGNATURE_LENGTH,
     peer::{Peer, PEER_LENGTH},
     types::{new_io_error, PeerId, TransportType},
 };
@@ -14,7 +17,13 @@ pub enum Hole {
     Help,
 }
 
-pub struct DHT(pub Vec<Peer>);
+/// DHT help peers, the sender's relay load (KiB in last period), and the record
+/// seq and self signature of the peers (`peer_record_sign`), empty when not signed.
+#[allow(clippy::upper_case_acronyms)]
+pub struct DHT(pub Vec<Peer>, pub u32, pub Vec<(u64, Vec<u8>)>);
+
+/// the bytes of a signed record: seq (u64) and signature.
+const RECORD_LENGTH: usize = 8 + SIGNATURE_LENGTH;
 
 impl Hole {
     pub fn from_byte(byte: u8) -> Result<Self> {
@@ -44,16 +53,54 @@ impl DHT {
         len_bytes.copy_from_slice(&bytes[0..4]);
         let len = u32::from_le_bytes(len_bytes) as usize;
         let raw_bytes = &bytes[4..];
-        if raw_bytes.len() < len * PEER_LENGTH {
+        if len
+            .checked_mul(PEER_LENGTH)
+            .is_none_or(|min_len| raw_bytes.len() < min_len)
+        {
             return Err(new_io_error("DHT bytes failure."));
         }
         let mut peers = vec![];
-        for i in 0..len {
-            peers.push(Peer::from_bytes(
-                &raw_bytes[i * PEER_LENGTH..(i + 1) * PEER_LENGTH],
-            )?);
+        let mut index = 0;
+        for _ in 0..len {
+            let (peer, peer_len) = Peer::decode(&raw_bytes[index..])?;
+            peers.push(peer);
+            index += peer_len;
         }
-        Ok(Self(peers))
+
+        // relay load is optional, old version donnot have it.
+        let load_bytes = &raw_bytes[index..];
+        let load = if load_bytes.len() >= 4 {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&load_bytes[0..4]);
+            u32::from_le_bytes(bytes)
+        } else {
+            0
+        };
+
+        // signed records are optional, after the relay load.
+        let mut signs = vec![];
+        if load_bytes.len() >= 8 {
+            let mut count_bytes = [0u8; 4];
+            count_bytes.copy_from_slice(&load_bytes[4..8]);
+            let count = u32::from_le_bytes(count_bytes) as usize;
+            let sign_bytes = &load_bytes[8..];
+            if count
+                .checked_mul(RECORD_LENGTH)
+                .is_none_or(|len| sign_bytes.len() < len)
+            {
+                return Err(new_io_error("DHT bytes failure."));
+            }
+            signs = sign_bytes
+                .chunks_exact(RECORD_LENGTH)
+                .take(count)
+                .map(|s| {
+                    let mut seq_bytes = [0u8; 8];
+                    seq_bytes.copy_from_slice(&s[..8]);
+                    (u64::from_le_bytes(seq_bytes), s[8..].to_vec())
+                })
+                .collect();
+        }
+        Ok(Self(peers, load, signs))
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -62,23 +109,166 @@ impl DHT {
         for peer in &self.0 {
             bytes.append(&mut peer.to_bytes());
         }
+        bytes.extend(&self.1.to_le_bytes());
+        if !self.2.is_empty() {
+            bytes.extend(&(self.2.len() as u32).to_le_bytes());
+            for (seq, sign) in &self.2 {
+                bytes.extend(&seq.to_le_bytes());
+                bytes.extend(sign);
+            }
+        }
         bytes
     }
 }
 
+/// the remote claimed `is_pub` (from its NAT type) is kept only when the port is same.
 pub fn nat(mut remote_addr: SocketAddr, mut local: Peer) -> Peer {
-    local.is_pub = remote_addr.port() == local.socket.port();
-    match local.transport {
-        TransportType::TCP => {
-            remote_addr.set_port(local.socket.port()); // TODO TCP hole punching
-        }
-        _ => {}
+    local.is_pub = local.is_pub && remote_addr.port() == local.socket.port();
+    if local.transport == TransportType::TCP {
+        remote_addr.set_port(local.socket.port()); // TODO TCP hole punching
     }
 
     local.socket = remote_addr;
     local
 }
 
+/// NAT type, classified by the reflexive addresses reported by remotes. there is no
+/// change request (remotes cannot reply from other ip or port), so the filtering is
+/// unknown, and a stable mapping is taken as port restricted.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum NatType {
+    /// not enough observations.
+    Unknown,
+    /// no NAT, the reflexive address is the local address.
+    Open,
+    /// same mapping for all remotes, others can use it with hole punching.
+    PortRestricted,
+    /// mapping changed with the destination, the reflexive address is useless for others.
+    Symmetric,
+}
+
+impl NatType {
+    /// others can connect to self directly.
+    pub fn is_pub(&self) -> bool {
+        matches!(self, NatType::Open)
+    }
+
+    /// the reflexive address can be used by others (maybe need hole punching).
+    pub fn is_reflexive_usable(&self) -> bool {
+        matches!(self, NatType::PortRestricted)
+    }
+}
+
+/// the independent remotes must agree on the reflexive address before use it.
+pub const REFLEXIVE_QUORUM: usize = 3;
+
+/// the max remembered reporters of the reflexive address.
+const REFLEXIVE_REPORTERS: usize = 8;
+
+/// The reflexive addresses reported by the authenticated remotes, the latest one of
+/// every network group (ipv4 /16, ipv6 /32), so one network cannot be the quorum.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StunResult {
+    /// (reporter group, reflexive address), oldest first.
+    reports: VecDeque<(IpAddr, SocketAddr)>,
+}
+
+impl StunResult {
+    /// the reflexive address reported by the server.
+    pub fn observe(&mut self, server: IpAddr, addr: SocketAddr) {
+        let group = net_group(server);
+        self.reports.retain(|(g, _)| *g != group);
+        if self.reports.len() >= REFLEXIVE_REPORTERS {
+            self.reports.pop_front();
+        }
+        self.reports.push_back((group, addr));
+    }
+
+    /// the reflexive address of self, when the quorum agree on it.
+    pub fn reflexive(&self) -> Option<SocketAddr> {
+        let (_, first) = self.reports.front()?;
+        if self.reports.len() >= REFLEXIVE_QUORUM && self.reports.iter().all(|(_, a)| a == first) {
+            Some(*first)
+        } else {
+            None
+        }
+    }
+
+    pub fn classify(&self, local: SocketAddr) -> NatType {
+        if let Some(first) = self.reflexive() {
+            // same as `nat()`, when listen all interfaces, only check the port.
+            if first == local || (local.ip().is_unspecified() && first.port() == local.port()) {
+                NatType::Open
+            } else {
+                NatType::PortRestricted
+            }
+        } else if self
+            .reports
+            .iter()
+            .any(|(_, a)| Some(a) != self.reports.front().map(|(_, f)| f))
+        {
+            NatType::Symmetric
+        } else {
+            NatType::Unknown
+        }
+    }
+}
+
+/// the network group of the ip, ipv4 /16, ipv6 /32.
+fn net_group(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            IpAddr::from([o[0], o[1], 0, 0])
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::from([s[0], s[1], 0, 0, 0, 0, 0, 0])
+        }
+    }
+}
+
+/// waiting the new external address stable.
+pub const EXTERNAL_DEBOUNCE: u64 = 20; // 20s
+
+/// the believed external address, changed only when the new one
+/// is reported stable in the debounce time (not flapping).
+pub(crate) struct External {
+    current: SocketAddr,
+    candidate: Option<(SocketAddr, Instant)>,
+    debounce: Duration,
+}
+
+impl External {
+    pub fn new(current: SocketAddr, debounce: Duration) -> Self {
+        Self {
+            current,
+            candidate: None,
+            debounce,
+        }
+    }
+
+    /// the reflexive address reported by remote.
+    pub fn observe(&mut self, addr: SocketAddr, now: Instant) {
+        if addr == self.current {
+            self.candidate = None;
+        } else if self.candidate.map(|(a, _)| a) != Some(addr) {
+            self.candidate = Some((addr, now));
+        }
+    }
+
+    /// the new address when the candidate is stable in debounce time.
+    pub fn settle(&mut self, now: Instant) -> Option<SocketAddr> {
+        let (addr, since) = self.candidate?;
+        if now.duration_since(since) < self.debounce {
+            return None;
+        }
+        self.candidate = None;
+        self.current = addr;
+        Some(addr)
+    }
+}
+
 pub(crate) async fn _handle(_remote_peer: &PeerId, hole: Hole, _peers: &PeerList) -> Result<()> {
     match hole {
         Hole::StunOne => {
@@ -94,3 +284,93 @@ pub(crate) async fn _handle(_remote_peer: &PeerId, hole: Hole, _peers: &PeerList
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(n: u8) -> IpAddr {
+        IpAddr::from([n, 0, 0, 1])
+    }
+
+    #[test]
+    fn test_nat_type() {
+        let local: SocketAddr = "192.168.1.2:7364".parse().unwrap();
+        let mapped: SocketAddr = "5.6.7.8:7364".parse().unwrap();
+
+        let mut stun = StunResult::default();
+        assert_eq!(stun.classify(local), NatType::Unknown);
+
+        // open, reflexive is local.
+        for n in 1..=3 {
+            stun.observe(server(n), local);
+        }
+        assert_eq!(stun.classify(local), NatType::Open);
+        assert!(NatType::Open.is_pub());
+        let any: SocketAddr = "0.0.0.0:7364".parse().unwrap();
+        let mut stun = StunResult::default();
+        for n in 1..=3 {
+            stun.observe(server(n), mapped);
+        }
+        assert_eq!(stun.classify(any), NatType::Open);
+
+        // less than the quorum is not enough.
+        let mut stun = StunResult::default();
+        stun.observe(server(1), mapped);
+        stun.observe(server(2), mapped);
+        assert_eq!(stun.classify(local), NatType::Unknown);
+        assert_eq!(stun.reflexive(), None);
+
+        // same mapping for the quorum.
+        stun.observe(server(3), mapped);
+        assert_eq!(stun.classify(local), NatType::PortRestricted);
+        assert_eq!(stun.reflexive(), Some(mapped));
+        assert!(!NatType::PortRestricted.is_pub());
+        assert!(NatType::PortRestricted.is_reflexive_usable());
+
+        // symmetric, other server get other mapping.
+        let mut stun = StunResult::default();
+        stun.observe(server(1), mapped);
+        stun.observe(server(2), "5.6.7.8:9000".parse().unwrap());
+        assert_eq!(stun.classify(local), NatType::Symmetric);
+        assert!(!NatType::Symmetric.is_pub());
+        assert!(!NatType::Symmetric.is_reflexive_usable());
+        // same server with the same mapping again, still symmetric.
+        stun.observe(server(1), mapped);
+        assert_eq!(stun.classify(local), NatType::Symmetric);
+        assert_eq!(stun.reflexive(), None);
+    }
+
+    #[test]
+    fn test_nat_independent_reporters() {
+        let local: SocketAddr = "192.168.1.2:7364".parse().unwrap();
+        let forged: SocketAddr = "6.6.6.6:7364".parse().unwrap();
+        let mut stun = StunResult::default();
+        // many reporters in one network is one vote.
+        for n in 1..=10 {
+            stun.observe(IpAddr::from([9, 9, 0, n]), forged);
+        }
+        assert_eq!(stun.classify(local), NatType::Unknown);
+        assert_eq!(stun.reflexive(), None);
+
+        // the reporters are bounded, and the oldest is dropped.
+        let mut stun = StunResult::default();
+        for n in 1..=20 {
+            stun.observe(server(n), forged);
+        }
+        assert_eq!(stun.reports.len(), REFLEXIVE_REPORTERS);
+        assert_eq!(stun.reflexive(), Some(forged));
+    }
+
+    #[test]
+    fn test_nat_claimed_pub() {
+        let remote: SocketAddr = "5.6.7.8:7364".parse().unwrap();
+        let mut peer = Peer::socket("192.168.1.2:7364".parse().unwrap());
+        assert!(nat(remote, peer).is_pub);
+        assert!(!nat("5.6.7.8:9000".parse().unwrap(), peer).is_pub);
+
+        // remote knows it is behind NAT.
+        peer.is_pub = false;
+        assert!(!nat(remote, peer).is_pub);
+    }
+}
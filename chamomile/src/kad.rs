@@ -46,6 +46,11 @@ impl Key for SocketAddr {
     }
 }
 
+/// the XOR distance of two peer ids.
+pub(crate) fn id_distance(base: &PeerId, target: &PeerId) -> Distance {
+    PeerId::calc_distance(base, target)
+}
+
 const MAX_LEVEL: usize = 8;
 
 // max peer-id is 4 * 160 = 640
@@ -184,14 +189,12 @@ impl DoubleKadTree {
         let p_v = self
             .peers
             .next_closest(key, prev)
-            .map(|(k, _p)| self.values.get(k))
-            .flatten()
+            .and_then(|(k, _p)| self.values.get(k))
             .map(|v| &(v.1)[0]);
         let a_v = self
             .assists
             .next_closest(key, prev)
-            .map(|(k, p)| self.values.get(k).map(|v| (v, p)))
-            .flatten()
+            .and_then(|(k, p)| self.values.get(k).map(|v| (v, p)))
             .map(|(v, p)| {
                 for va in &v.1 {
                     if &va.2.assist == p {
@@ -212,11 +215,22 @@ impl DoubleKadTree {
         }
     }
 
+    /// get the closest values with the key, sort by distance.
+    pub fn id_closest(&self, key: &PeerId, count: usize) -> Vec<&KadValue> {
+        let mut values: Vec<(Distance, &KadValue)> = self
+            .values
+            .values()
+            .filter_map(|(_, v)| v.first())
+            .map(|v| (PeerId::calc_distance(key, &v.2.id), v))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values.into_iter().take(count).map(|(_, v)| v).collect()
+    }
+
     pub fn _ip_next_closest(&self, key: &SocketAddr, prev: &[SocketAddr]) -> Option<&KadValue> {
         self.ips
             .next_closest(key, prev)
-            .map(|(k, _)| self.values.get(k))
-            .flatten()
+            .and_then(|(k, _)| self.values.get(k))
             .map(|v| &(v.1)[0])
     }
 
@@ -224,8 +238,7 @@ impl DoubleKadTree {
         if let Some((v, is_it)) = self
             .assists
             .search(key)
-            .map(|(_, k, is_it)| self.values.get(k).map(|(_, v)| (&v[0], is_it)))
-            .flatten()
+            .and_then(|(_, k, is_it)| self.values.get(k).map(|(_, v)| (&v[0], is_it)))
         {
             if is_it {
                 return Some((v, true));
@@ -234,8 +247,7 @@ impl DoubleKadTree {
 
         self.peers
             .search(key)
-            .map(|(_, k, is_it)| self.values.get(k).map(|(_, v)| (&v[0], is_it)))
-            .flatten()
+            .and_then(|(_, k, is_it)| self.values.get(k).map(|(_, v)| (&v[0], is_it)))
     }
 
     pub fn take(&mut self, key: &PeerId, assist: &PeerId) -> Option<Vec<KadValue>> {
@@ -267,7 +279,7 @@ impl DoubleKadTree {
                     }
                 }
                 v.remove(index);
-                if v.len() > 0 {
+                if !v.is_empty() {
                     return;
                 }
             }
@@ -281,6 +293,25 @@ impl DoubleKadTree {
         }
     }
 
+    /// update the socket of the value, when remote address changed.
+    pub fn update_socket(&mut self, assist: &PeerId, socket: SocketAddr) {
+        let k = match self.assists.search(assist) {
+            Some((_, k, true)) => *k,
+            _ => return,
+        };
+        if let Some((_, v)) = self.values.get_mut(&k) {
+            for (i, va) in v.iter_mut().enumerate() {
+                if &va.2.assist == assist {
+                    if i == 0 {
+                        self.ips.remove(&va.2.socket);
+                        self.ips.add(socket, k);
+                    }
+                    va.2.socket = socket;
+                }
+            }
+        }
+    }
+
     pub fn contains(&self, key: &PeerId) -> bool {
         self.peers.contains(key)
     }
@@ -312,7 +343,7 @@ impl<K: Key> KadTree<K> {
             }
             self.right
                 .as_mut()
-                .and_then(|v| Some(v.insert(Cell(key, value, distance), 1, K_BUCKET)))
+                .map(|v| v.insert(Cell(key, value, distance), 1, K_BUCKET))
                 .unwrap() // safe checked.
         } else {
             if self.left.is_none() {
@@ -320,25 +351,23 @@ impl<K: Key> KadTree<K> {
             }
             self.left
                 .as_mut()
-                .and_then(|v| Some(v.insert(Cell(key, value, distance), 1, K_BUCKET)))
+                .map(|v| v.insert(Cell(key, value, distance), 1, K_BUCKET))
                 .unwrap() // safe checked.
         }
     }
 
     fn next_closest(&self, key: &K, prev: &[K]) -> Option<(&u32, &K)> {
-        self.search(key)
-            .map(|v| {
-                if prev.contains(v.0) {
-                    None
-                } else {
-                    Some((v.1, v.0))
-                }
-            })
-            .flatten()
+        self.search(key).and_then(|v| {
+            if prev.contains(v.0) {
+                None
+            } else {
+                Some((v.1, v.0))
+            }
+        })
     }
 
     fn search(&self, key: &K) -> Option<(&K, &u32, bool)> {
-        let distance = K::calc_distance(&self.root_key, &key);
+        let distance = K::calc_distance(&self.root_key, key);
 
         if distance.get(0) {
             if self.right.is_none() {
@@ -347,13 +376,13 @@ impl<K: Key> KadTree<K> {
                 } else {
                     self.left
                         .as_ref()
-                        .and_then(|v| Some(v.search(key, &distance, 1)))
+                        .map(|v| v.search(key, &distance, 1))
                         .unwrap() // safe checked.
                 }
             } else {
                 self.right
                     .as_ref()
-                    .and_then(|v| Some(v.search(key, &distance, 1)))
+                    .map(|v| v.search(key, &distance, 1))
                     .unwrap() // safe chekced.
             }
         } else {
@@ -363,20 +392,20 @@ impl<K: Key> KadTree<K> {
                 } else {
                     self.right
                         .as_ref()
-                        .and_then(|v| Some(v.search(key, &distance, 1)))
+                        .map(|v| v.search(key, &distance, 1))
                         .unwrap() // safe checked.
                 }
             } else {
                 self.left
                     .as_ref()
-                    .and_then(|v| Some(v.search(key, &distance, 1)))
+                    .map(|v| v.search(key, &distance, 1))
                     .unwrap() // safe checked.
             }
         }
     }
 
     fn remove(&mut self, key: &K) -> Option<u32> {
-        let distance = K::calc_distance(&self.root_key, &key);
+        let distance = K::calc_distance(&self.root_key, key);
         if distance.get(0) {
             self.right
                 .as_mut()
@@ -387,34 +416,26 @@ impl<K: Key> KadTree<K> {
     }
 
     fn contains(&self, key: &K) -> bool {
-        if let Some((_, _, true)) = self.search(key) {
-            true
-        } else {
-            false
-        }
+        matches!(self.search(key), Some((_, _, true)))
     }
 
     fn keys(&self) -> Vec<K> {
         let mut vec = Vec::new();
-        if self.left.is_some() {
-            self.left.as_ref().unwrap().keys(&mut vec); // safe checked.
+        if let Some(ref left) = self.left {
+            left.keys(&mut vec);
         }
-        if self.right.is_some() {
-            self.right.as_ref().unwrap().keys(&mut vec); // safe checked.
+        if let Some(ref right) = self.right {
+            right.keys(&mut vec);
         }
         vec
     }
 
     fn is_empty(&self) -> bool {
-        if self.left.is_some() {
-            if !self.left.as_ref().unwrap().is_empty() {
-                return false;
-            }
+        if self.left.is_some() && !self.left.as_ref().unwrap().is_empty() {
+            return false;
         }
-        if self.right.is_some() {
-            if !self.right.as_ref().unwrap().is_empty() {
-                return false;
-            }
+        if self.right.is_some() && !self.right.as_ref().unwrap().is_empty() {
+            return false;
         }
 
         true
@@ -438,7 +459,7 @@ impl<K: Key> Node<K> {
                 }
                 self.right
                     .as_mut()
-                    .and_then(|v| Some(v.insert(cell, index + 1, k_bucket)))
+                    .map(|v| v.insert(cell, index + 1, k_bucket))
                     .unwrap() // safe checked.
             } else {
                 if self.left.is_none() {
@@ -446,7 +467,7 @@ impl<K: Key> Node<K> {
                 }
                 self.left
                     .as_mut()
-                    .and_then(|v| Some(v.insert(cell, index + 1, k_bucket)))
+                    .map(|v| v.insert(cell, index + 1, k_bucket))
                     .unwrap() // safe checked.
             }
         } else {
@@ -470,7 +491,7 @@ impl<K: Key> Node<K> {
                             return (true, v_index, removed);
                         }
                     }
-                    return (false, v_index, 0);
+                    (false, v_index, 0)
                 } else {
                     self.right = Some(Box::new(Node::default()));
                     self.left = Some(Box::new(Node::default()));
@@ -520,7 +541,7 @@ impl<K: Key> Node<K> {
 
         self.list
             .get(closest_index)
-            .and_then(|cell| Some((&cell.0, &cell.1, false)))
+            .map(|cell| (&cell.0, &cell.1, false))
     }
 
     pub fn remove(&mut self, key: &K, distance: &Distance, index: usize) -> Option<u32> {
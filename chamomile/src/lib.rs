@@ -2,6 +2,40 @@
 
 //! `chamomile` is a crate for building a solid and efficient p2p network.
 //!
+//! # Quick Start
+//!
+//! Start a peer on an ephemeral port, use the send handle to talk with it,
+//! and get the results from the receive handle (or the response channel).
+//!
+//! ```
+//! use chamomile::prelude::{start, Config, Peer, SendMessage, StateRequest, StateResponse};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut config = Config::default(Peer::socket("127.0.0.1:0".parse().unwrap()));
+//!     config.db_dir = std::env::temp_dir().join("chamomile-quick-start");
+//!
+//!     // start.
+//!     let (peer_id, send, _recv) = start(config).await.unwrap();
+//!     println!("peer id: {}", peer_id.to_hex());
+//!
+//!     // connect to others: `SendMessage::Connect(Peer::socket(remote_addr))`,
+//!     // send data to others: `SendMessage::Data(0, remote_peer_id, data)`,
+//!     // and the received data is `ReceiveMessage::Data(peer_id, data, is_direct)` in `_recv`.
+//!
+//!     let (res_send, mut res_recv) = tokio::sync::mpsc::channel(1);
+//!     send.send(SendMessage::NetworkState(StateRequest::DHT, res_send))
+//!         .await
+//!         .unwrap();
+//!     match res_recv.recv().await {
+//!         Some(StateResponse::DHT(peers)) => assert!(peers.is_empty()),
+//!         _ => panic!("network state failure"),
+//!     }
+//!
+//!     send.send(SendMessage::NetworkStop).await.unwrap();
+//! }
+//! ```
+//!
 //! # Example Use
 //!
 //! We running a p2p peer, and if others add, we will get the info.
@@ -68,7 +102,22 @@
 //! - Support permissionless network.
 //! - Support permissioned network (distributed network).
 //! - DHT-based & Relay connection.
-//! - Diff transports: QUIC(*default*) / TCP / UDP-Based Special Protocol.
+//! - Diff transports: QUIC(*default*) / TCP / UDT (UDP with selective-ack ARQ, reliable and in order as TCP).
+//!
+//! # Ordering
+//!
+//! The data (`Data`, `Metadata`) to the same peer in a direct session is received in
+//! send order (FIFO): the outside channel, the session lanes, the pre-handshake buffers
+//! and the transport stream are all FIFO, and the receiver delivers the frames one by
+//! one. The exceptions:
+//!
+//! - `PriorityData`, the order is only kept in the same priority, the higher may
+//!   overtake the lower queued ones.
+//! - Relay path, the relay may change (or the session upgrades to direct) in flight,
+//!   so it is unordered, use `Config::sequence_frames` to detect the gaps.
+//! - `LargeData` and `Stream` are not ordered with the data.
+//! - `ExpiringData` is dropped when expired, and the frames are dropped when the
+//!   session key changed in flight, the order of the rest is kept.
 
 #[macro_use]
 extern crate tracing;
@@ -83,25 +132,47 @@ mod peer_list;
 mod server;
 mod session;
 mod session_key;
+mod storage;
+mod ticket;
 
 pub mod primitives;
 pub mod transports;
 
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+
 pub mod prelude {
-    pub use chamomile_types::key::Key;
+    pub use chamomile_types::key::{Key, Signer};
     pub use chamomile_types::message::{
-        DeliveryType, ReceiveMessage, SendMessage, StateRequest, StateResponse, StreamType,
+        ConnectTrace, DataChunk, DeliveryType, HealthStatus, NetworkStats, PeerFilter, PeerInfo,
+        PeerState, ReceiveMessage, SendMessage, StateRequest, StateResponse, StreamType,
+        Throughput, TopologyPeer, TopologySnapshot,
+    };
+    pub use chamomile_types::types::{
+        elect_leader, Broadcast, CloseReason, Metadata, PeerId, PeerTier, Priority, SessionState,
+        TransportType,
     };
-    pub use chamomile_types::types::{Broadcast, PeerId, TransportType};
     pub use chamomile_types::Peer;
 
+    use std::sync::Arc;
     use tokio::{
         fs::create_dir_all,
         io::Result,
         sync::mpsc::{self, Receiver, Sender},
     };
 
-    pub use super::config::Config;
+    pub use super::global::{origin_proof, timestamp_valid};
+
+    pub use super::config::{
+        AddressFilter, BackoffPolicy, Config, DecryptPolicy, FramePadding, Maintenance, NodeConfig,
+        PinPolicy, SocketOptions,
+    };
+    pub use super::storage::{FileStorage, Storage, StorageFuture};
     use crate::primitives::STORAGE_NAME;
 
     /// new a channel for send message to the chamomile.
@@ -157,4 +228,27 @@ pub mod prelude {
 
         Ok((peer_id, send_send, recv_recv))
     }
+
+    /// main function. start a p2p service with given signer (e.g. remote signer or HSM).
+    pub async fn start_with_signer(
+        mut config: Config,
+        signer: Arc<dyn Signer>,
+    ) -> Result<(PeerId, Sender<SendMessage>, Receiver<ReceiveMessage>)> {
+        info!("start p2p service...");
+        let mut new_path = config.db_dir.clone();
+        new_path.push(STORAGE_NAME);
+        if !new_path.exists() {
+            create_dir_all(&new_path).await?;
+        }
+        config.db_dir = new_path;
+
+        let (send_send, send_recv) = new_send_channel();
+        let (recv_send, recv_recv) = new_receive_channel();
+
+        let peer_id =
+            super::server::start_with_signer(config, recv_send, send_recv, signer).await?;
+        info!("start p2p ok.");
+
+        Ok((peer_id, send_send, recv_recv))
+    }
 }
@@ -2,21 +2,36 @@ use std::collections::HashMap;
 use std::io::BufRead;
 use std::iter::Iterator;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
-use tokio::{fs, io::Result, sync::mpsc::Sender};
-
-use chamomile_types::{types::new_io_error, Peer, PeerId};
-
-use crate::kad::{DoubleKadTree, KadValue};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::{io::Result, sync::mpsc::Sender};
+
+use chamomile_types::{
+    message::{PeerInfo, PeerState},
+    types::new_io_error,
+    Peer, PeerId,
+};
+
+use crate::global::SessionStats;
+use crate::kad::{id_distance, DoubleKadTree, KadValue};
+use crate::primitives::STORAGE_PEER_LIST_KEY;
 use crate::session::SessionMessage;
+use crate::storage::Storage;
 use crate::transports::EndpointMessage;
 
+/// when choose a relay, the count of closest peers to compare the relay load.
+const RELAY_CANDIDATES: usize = 3;
+
+/// when find node, the count of closest peers to ask or answer.
+const FIND_NODE_COUNT: usize = 3;
+
 /// PeerList.
 /// contains: dhts(KadTree) & stables(HashMap)
 pub(crate) struct PeerList {
-    save_path: PathBuf,
+    storage: Arc<dyn Storage>,
     allows: Vec<Peer>,
-    blocks: (Vec<PeerId>, Vec<IpAddr>),
+    /// blocked peers (with the expire time, none is permanent) and ips.
+    blocks: (HashMap<PeerId, Option<Instant>>, Vec<IpAddr>),
 
     /// PeerId => KadValue(Sender<Sessionmessage>, Sender<EndpointMessage>, Peer)
     dhts: DoubleKadTree,
@@ -24,6 +39,16 @@ pub(crate) struct PeerList {
     stables: HashMap<PeerId, (KadValue, bool)>,
     /// Own assist-ids
     owns: Vec<PeerId>,
+    /// PeerId => relay load advertised by remote.
+    loads: HashMap<PeerId, u32>,
+    /// PeerId => the address verified reachable.
+    verified: HashMap<PeerId, SocketAddr>,
+    /// bootstrap address => priority, not in it is 0.
+    priorities: HashMap<SocketAddr, u32>,
+    /// bootstrap address => dial history (success, failure).
+    histories: HashMap<SocketAddr, (u32, u32)>,
+    /// PeerId => user state attached to the connected peer.
+    states: HashMap<PeerId, PeerState>,
 }
 
 impl PeerList {
@@ -34,52 +59,49 @@ impl PeerList {
                 file_string = format!("{}\n{}", file_string, addr.to_multiaddr_string());
             }
         }
-        let _ = fs::write(&self.save_path, file_string).await;
+        let _ = self
+            .storage
+            .put(STORAGE_PEER_LIST_KEY, file_string.into_bytes())
+            .await;
     }
 
+    /// `saved` is the peer list bytes read from the storage.
     pub fn load(
         peer_id: PeerId,
         assist_id: PeerId,
-        save_path: PathBuf,
+        storage: Arc<dyn Storage>,
+        saved: &[u8],
         mut allows: Vec<Peer>,
         blocks: (Vec<PeerId>, Vec<IpAddr>),
     ) -> Self {
         let default_socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-        match std::fs::File::open(&save_path) {
-            Ok(file) => {
-                let addrs = std::io::BufReader::new(file).lines();
-                for addr in addrs {
-                    if let Ok(addr) = addr {
-                        if let Ok(p) = Peer::from_multiaddr_string(&addr) {
-                            let mut is_new = true;
-                            for ap in allows.iter() {
-                                if ap.socket == p.socket {
-                                    is_new = false;
-                                }
-                            }
-                            if is_new {
-                                allows.push(p);
-                            }
-                        }
+        let blocks = (blocks.0.into_iter().map(|p| (p, None)).collect(), blocks.1);
+        for addr in saved.lines().map_while(std::result::Result::ok) {
+            if let Ok(p) = Peer::from_multiaddr_string(&addr) {
+                let mut is_new = true;
+                for ap in allows.iter() {
+                    if ap.socket == p.socket {
+                        is_new = false;
                     }
                 }
-                PeerList {
-                    save_path,
-                    allows: allows,
-                    blocks: blocks,
-                    dhts: DoubleKadTree::new(peer_id, assist_id, default_socket),
-                    stables: HashMap::new(),
-                    owns: vec![],
+                if is_new {
+                    allows.push(p);
                 }
             }
-            Err(_) => PeerList {
-                save_path,
-                allows: allows,
-                blocks: blocks,
-                dhts: DoubleKadTree::new(peer_id, assist_id, default_socket),
-                stables: HashMap::new(),
-                owns: vec![],
-            },
+        }
+
+        PeerList {
+            storage,
+            allows,
+            blocks,
+            dhts: DoubleKadTree::new(peer_id, assist_id, default_socket),
+            stables: HashMap::new(),
+            owns: vec![],
+            loads: HashMap::new(),
+            verified: HashMap::new(),
+            priorities: HashMap::new(),
+            histories: HashMap::new(),
+            states: HashMap::new(),
         }
     }
 
@@ -103,6 +125,35 @@ impl PeerList {
         peers
     }
 
+    /// get all peers info and the session in the peer list.
+    pub fn infos(&self, stats: &SessionStats) -> Vec<(PeerInfo, &Sender<SessionMessage>)> {
+        let info = |peer: Peer, is_stable: bool, is_direct: bool| {
+            let stats = stats.get(&peer.id);
+            PeerInfo {
+                peer,
+                is_stable,
+                is_direct,
+                last_seen: stats.as_ref().and_then(|s| s.last_seen()),
+                rtt: stats.as_ref().and_then(|s| s.rtt()),
+            }
+        };
+        let mut infos = vec![];
+        for key in self.dhts.keys().into_iter() {
+            if self.stables.contains_key(&key) {
+                continue;
+            }
+            if let Some((v, true)) = self.dhts.search(&key) {
+                infos.push((info(v.2, false, true), &v.0));
+            }
+        }
+
+        for (v, is_direct) in self.stables.values() {
+            infos.push((info(v.2, true, *is_direct), &v.0));
+        }
+
+        infos
+    }
+
     pub fn dht_keys(&self) -> Vec<PeerId> {
         self.dhts.keys()
     }
@@ -126,8 +177,7 @@ impl PeerList {
     /// search in stable list. result is stream channel sender.
     pub fn get_stable_stream(&self, peer_id: &PeerId) -> Option<&Sender<EndpointMessage>> {
         self.stable_get(peer_id)
-            .map(|(_ss, stream, is_it)| if is_it { Some(stream) } else { None })
-            .flatten()
+            .and_then(|(_ss, stream, is_it)| if is_it { Some(stream) } else { None })
     }
 
     pub fn next_closest(
@@ -141,6 +191,70 @@ impl PeerList {
             .or(self.dhts.id_next_closest(target, prev).map(|v| &v.0))
     }
 
+    /// search the relay to target. if not connected to target,
+    /// will choose the less-loaded one in the closest peers.
+    pub fn relay_get(&self, peer_id: &PeerId) -> Option<&Sender<SessionMessage>> {
+        if let Some((sender, _, _)) = self.stable_get(peer_id) {
+            return Some(sender);
+        }
+        if let Some((v, true)) = self.dhts.search(peer_id) {
+            return Some(&v.0);
+        }
+
+        self.dhts
+            .id_closest(peer_id, RELAY_CANDIDATES)
+            .into_iter()
+            .enumerate()
+            .min_by_key(|(i, v)| (self.loads.get(&v.2.id).copied().unwrap_or(0), *i))
+            .map(|(_, v)| &v.0)
+    }
+
+    /// answer the find node, the target if connected, or the closest peers.
+    pub fn find_node(&self, target: &PeerId) -> Vec<Peer> {
+        if let Some(peer) = self.peer(target) {
+            return vec![peer];
+        }
+
+        self.dhts
+            .id_closest(target, FIND_NODE_COUNT)
+            .into_iter()
+            .map(|v| v.2)
+            .collect()
+    }
+
+    /// the closest sessions to the target, used to send find node.
+    pub fn find_node_senders(&self, target: &PeerId) -> Vec<Sender<SessionMessage>> {
+        self.dhts
+            .id_closest(target, FIND_NODE_COUNT)
+            .into_iter()
+            .map(|v| v.0.clone())
+            .collect()
+    }
+
+    /// the connected peer info, in stable list or DHT table.
+    pub fn peer(&self, peer_id: &PeerId) -> Option<Peer> {
+        if let Some((v, _)) = self.stables.get(peer_id) {
+            return Some(v.2);
+        }
+        match self.dhts.search(peer_id) {
+            Some((v, true)) => Some(v.2),
+            _ => None,
+        }
+    }
+
+    /// update the remote socket, when the connection migrated.
+    pub fn update_socket(&mut self, peer_id: &PeerId, assist_id: &PeerId, socket: SocketAddr) {
+        if let Some((KadValue(_, _, p), _)) = self.stables.get_mut(peer_id) {
+            p.socket = socket;
+        }
+        self.dhts.update_socket(assist_id, socket);
+    }
+
+    /// update the relay load of remote.
+    pub fn update_load(&mut self, peer_id: PeerId, load: u32) {
+        self.loads.insert(peer_id, load);
+    }
+
     pub fn _ip_next_closest(
         &self,
         ip: &SocketAddr,
@@ -178,16 +292,46 @@ impl PeerList {
     pub fn is_relay(&self, peer_id: &PeerId) -> Option<&Sender<SessionMessage>> {
         self.stables
             .get(peer_id)
-            .map(|v| if !v.1 { Some(&(v.0).0) } else { None })
-            .flatten()
+            .and_then(|v| if !v.1 { Some(&(v.0).0) } else { None })
     }
 
-    /// get in DHT help
-    pub fn help_dht(&self, _peer_id: &PeerId) -> Vec<Peer> {
-        // TODO better closest peers
+    /// the peer is reachable at the address.
+    pub fn verify(&mut self, peer: &Peer) {
+        if self.contains(&peer.id) {
+            self.verified.insert(peer.id, peer.socket);
+        }
+    }
+
+    /// check the peer is verified at its current address.
+    pub fn is_verified(&self, peer: &Peer) -> bool {
+        self.verified.get(&peer.id) == Some(&peer.socket)
+    }
+
+    /// attach the user state to the connected peer, false if not connected.
+    pub fn set_state(&mut self, peer_id: PeerId, state: PeerState) -> bool {
+        if self.contains(&peer_id) {
+            self.states.insert(peer_id, state);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn state(&self, peer_id: &PeerId) -> Option<&PeerState> {
+        self.states.get(peer_id)
+    }
 
+    pub fn clear_state(&mut self, peer_id: &PeerId) -> Option<PeerState> {
+        self.states.remove(peer_id)
+    }
+
+    /// get in DHT help
+    /// the peers to help remote DHT, max `count` (0 is unlimited),
+    /// public peers first, and then the closest to remote.
+    /// if `verified_only`, skip the peers not verified at the address.
+    pub fn help_dht(&self, peer_id: &PeerId, count: usize, verified_only: bool) -> Vec<Peer> {
         let mut peers = vec![];
-        for (_, (_, v)) in &self.dhts.values {
+        for (_, v) in self.dhts.values.values() {
             for va in v.iter() {
                 peers.push(va.2);
             }
@@ -197,6 +341,11 @@ impl PeerList {
             peers.push((v.0).2);
         }
 
+        peers.retain(|p| &p.id != peer_id && (!verified_only || self.is_verified(p)));
+        peers.sort_by_cached_key(|p| (!p.is_pub, id_distance(peer_id, &p.id)));
+        if count > 0 {
+            peers.truncate(count);
+        }
         peers
     }
 
@@ -204,6 +353,11 @@ impl PeerList {
     /// 1. remove from kad;
     pub fn remove_peer(&mut self, peer_id: &PeerId, assist_id: &PeerId) {
         self.dhts.remove(peer_id, assist_id);
+        self.loads.remove(peer_id);
+        if !self.contains(peer_id) {
+            self.verified.remove(peer_id);
+            self.states.remove(peer_id);
+        }
     }
 
     /// Disconnect Step:
@@ -226,6 +380,44 @@ impl PeerList {
     /// 1. remove from stables.
     pub fn stable_leave(&mut self, peer_id: &PeerId) {
         self.stables.remove(peer_id);
+        if !self.contains(peer_id) {
+            self.verified.remove(peer_id);
+            self.states.remove(peer_id);
+        }
+    }
+
+    /// Prune the stale peers, which session is closed but still in the list,
+    /// and the expired blocks. return the pruned peers.
+    pub fn prune(&mut self) -> Vec<PeerId> {
+        let mut dhts = vec![];
+        for (_, values) in self.dhts.values.values() {
+            for KadValue(sender, _, peer) in values {
+                if sender.is_closed() {
+                    dhts.push((peer.id, peer.assist));
+                }
+            }
+        }
+        let stables: Vec<PeerId> = self
+            .stables
+            .iter()
+            .filter(|(_, (KadValue(sender, _, _), _))| sender.is_closed())
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut pruned = vec![];
+        for (id, assist) in dhts {
+            self.remove_peer(&id, &assist);
+            pruned.push(id);
+        }
+        for id in stables {
+            self.stable_leave(&id);
+            pruned.push(id);
+        }
+        self.clear_expired_blocks();
+        // a peer maybe both in DHT and stables.
+        pruned.sort_unstable();
+        pruned.dedup();
+        pruned
     }
 
     /// Step:
@@ -239,11 +431,7 @@ impl PeerList {
         }
 
         // 2. add to kad.
-        if self.dhts.add(v) {
-            true
-        } else {
-            false
-        }
+        self.dhts.add(v)
     }
 
     /// add inner-own device.
@@ -277,10 +465,8 @@ impl PeerList {
     pub fn stable_to_dht(&mut self, peer_id: &PeerId) -> Result<()> {
         self.remove_allow_peer(peer_id);
         if let Some((v, is_direct)) = self.stables.remove(peer_id) {
-            if is_direct {
-                if self.dhts.add(v) {
-                    return Ok(());
-                }
+            if is_direct && self.dhts.add(v) {
+                return Ok(());
             }
         }
         Err(new_io_error("stable is closed"))
@@ -309,10 +495,76 @@ impl PeerList {
     pub fn bootstrap(&self) -> Vec<&Peer> {
         self.allows
             .iter()
-            .filter_map(|p| if p.effective_socket() { Some(p) } else { None })
+            .filter(|p| p.effective_socket())
             .collect()
     }
 
+    /// the known peers, connected (true) with the last seen time, and the bootstraps
+    /// not connected (false).
+    pub fn topology(&self, stats: &SessionStats) -> Vec<(Peer, bool, Option<u64>)> {
+        let mut peers: Vec<(Peer, bool, Option<u64>)> = self
+            .infos(stats)
+            .into_iter()
+            .map(|(info, _)| (info.peer, true, info.last_seen))
+            .collect();
+        for p in self.bootstrap() {
+            if !peers
+                .iter()
+                .any(|(cp, _, _)| cp.socket == p.socket || cp.id == p.id)
+            {
+                peers.push((*p, false, None));
+            }
+        }
+        peers
+    }
+
+    /// merge the known peers (not sessions) to bootstraps, return the count of new.
+    pub async fn import(&mut self, self_id: &PeerId, peers: Vec<Peer>) -> usize {
+        let count = self.allows.len();
+        for peer in peers {
+            if &peer.id == self_id
+                || !peer.effective_socket()
+                || self.is_block_peer(&peer.id)
+                || self.is_block_addr(&peer.socket)
+            {
+                continue;
+            }
+            self.add_bootstrap(peer);
+        }
+        let count = self.allows.len() - count;
+        if count > 0 {
+            self.save().await;
+        }
+        count
+    }
+
+    pub fn set_priority(&mut self, socket: SocketAddr, priority: u32) {
+        self.priorities.insert(socket, priority);
+    }
+
+    /// the bootstraps in dial order, higher priority first, and then the success rate.
+    pub fn bootstrap_order(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.bootstrap().into_iter().copied().collect();
+        peers.sort_by_cached_key(|p| {
+            let priority = self.priorities.get(&p.socket).copied().unwrap_or(0);
+            let (success, failure) = self.histories.get(&p.socket).copied().unwrap_or((0, 0));
+            // success rate (per mille), unknown is 50%.
+            let rate = (success as u64 + 1) * 1000 / (success as u64 + failure as u64 + 2);
+            (std::cmp::Reverse(priority), std::cmp::Reverse(rate))
+        });
+        peers
+    }
+
+    /// record the dial result of the bootstrap.
+    pub fn bootstrap_result(&mut self, socket: SocketAddr, is_ok: bool) {
+        let (success, failure) = self.histories.entry(socket).or_default();
+        if is_ok {
+            *success = success.saturating_add(1);
+        } else {
+            *failure = failure.saturating_add(1);
+        }
+    }
+
     pub fn add_bootstrap(&mut self, peer: Peer) {
         let mut is_new = true;
         for ap in self.allows.iter() {
@@ -338,25 +590,42 @@ impl PeerList {
     }
 
     pub fn remove_allow_peer(&mut self, peer: &PeerId) -> Option<Peer> {
-        let pos = match self.allows.iter().position(|x| &x.id == peer) {
-            Some(x) => x,
-            None => return None,
-        };
+        let pos = self.allows.iter().position(|x| &x.id == peer)?;
         Some(self.allows.remove(pos))
     }
 
     pub fn is_block_peer(&self, peer: &PeerId) -> bool {
-        self.blocks.0.contains(peer)
+        match self.blocks.0.get(peer) {
+            Some(Some(until)) => *until > Instant::now(),
+            Some(None) => true,
+            None => false,
+        }
     }
 
     pub fn is_block_addr(&self, addr: &SocketAddr) -> bool {
         self.blocks.1.contains(&addr.ip())
     }
 
-    pub fn _add_block_peer(&mut self, peer: PeerId) {
-        if !self.blocks.0.contains(&peer) {
-            self.blocks.0.push(peer)
-        }
+    /// block the peer until the time, none is permanent. will replace the old one.
+    pub fn add_block_peer(&mut self, peer: PeerId, until: Option<Instant>) {
+        self.blocks.0.insert(peer, until);
+    }
+
+    /// the blocked peers, and clear the expired.
+    pub fn block_peers(&mut self) -> Vec<(PeerId, Option<Instant>)> {
+        self.clear_expired_blocks();
+        self.blocks
+            .0
+            .iter()
+            .map(|(p, until)| (*p, *until))
+            .collect()
+    }
+
+    fn clear_expired_blocks(&mut self) {
+        let now = Instant::now();
+        self.blocks
+            .0
+            .retain(|_, until| until.map(|t| t > now).unwrap_or(true));
     }
 
     pub fn _add_block_addr(&mut self, addr: SocketAddr) {
@@ -365,19 +634,159 @@ impl PeerList {
         }
     }
 
-    pub fn _remove_block_peer(&mut self, peer: &PeerId) -> Option<PeerId> {
-        let pos = match self.blocks.0.iter().position(|x| *x == *peer) {
-            Some(x) => x,
-            None => return None,
-        };
-        Some(self.blocks.0.remove(pos))
+    pub fn remove_block_peer(&mut self, peer: &PeerId) -> bool {
+        self.blocks.0.remove(peer).is_some()
     }
 
     pub fn _remove_block_addr(&mut self, addr: &SocketAddr) -> Option<IpAddr> {
-        let pos = match self.blocks.1.iter().position(|x| *x == addr.ip()) {
-            Some(x) => x,
-            None => return None,
-        };
+        let pos = self.blocks.1.iter().position(|x| *x == addr.ip())?;
         Some(self.blocks.1.remove(pos))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::new_session_channel;
+    use crate::storage::FileStorage;
+    use crate::transports::new_endpoint_channel;
+    use std::path::PathBuf;
+
+    fn candidate(id: u8, assist: u8) -> (Sender<SessionMessage>, KadValue) {
+        let (session_sender, _) = new_session_channel();
+        let (endpoint_sender, _) = new_endpoint_channel();
+        let mut peer = Peer::peer(PeerId([id; 20]));
+        peer.assist = PeerId([assist; 20]);
+        let value = KadValue(session_sender.clone(), endpoint_sender, peer);
+        (session_sender, value)
+    }
+
+    fn test_list(id: u8) -> PeerList {
+        PeerList::load(
+            PeerId([id; 20]),
+            PeerId([id; 20]),
+            Arc::new(FileStorage::new(PathBuf::from("/nonexistent"))),
+            &[],
+            vec![],
+            (vec![], vec![]),
+        )
+    }
+
+    #[test]
+    fn test_help_dht_capped() {
+        let remote = PeerId([0u8; 20]);
+        let mut peer_list = test_list(0xffu8);
+        for i in 1..=200u8 {
+            let (_, mut value) = candidate(i, i);
+            value.2.is_pub = i % 2 == 0;
+            peer_list.stables.insert(value.2.id, (value, true));
+        }
+        let (_, value) = candidate(0, 0);
+        peer_list.stables.insert(remote, (value, true));
+
+        assert_eq!(peer_list.help_dht(&remote, 0, false).len(), 200);
+        let peers = peer_list.help_dht(&remote, 8, false);
+        let ids: Vec<PeerId> = peers.iter().map(|p| p.id).collect();
+        let closest: Vec<PeerId> = (1..=8).map(|i| PeerId([i * 2; 20])).collect();
+        assert_eq!(ids, closest);
+    }
+
+    #[test]
+    fn test_infos_filter_pub() {
+        let mut peer_list = test_list(0u8);
+        for i in 1..=4u8 {
+            let (_, mut value) = candidate(i, i);
+            value.2.is_pub = i % 2 == 0;
+            peer_list.stables.insert(value.2.id, (value, true));
+        }
+        let stats = SessionStats::new();
+        let seen = Arc::new(crate::global::PeerStats::new(0));
+        seen.seen();
+        seen.set_rtt(std::time::Duration::from_millis(20));
+        stats.insert(PeerId([2u8; 20]), seen);
+
+        let filter = chamomile_types::message::PeerFilter::new(|info| info.peer.is_pub);
+        let mut infos: Vec<PeerInfo> = peer_list
+            .infos(&stats)
+            .into_iter()
+            .map(|(info, _)| info)
+            .filter(|info| filter.matches(info))
+            .collect();
+        infos.sort_by_key(|info| info.peer.id);
+        let ids: Vec<PeerId> = infos.iter().map(|info| info.peer.id).collect();
+        assert_eq!(ids, vec![PeerId([2u8; 20]), PeerId([4u8; 20])]);
+        assert!(infos[0].last_seen.is_some());
+        assert_eq!(infos[0].rtt, Some(std::time::Duration::from_millis(20)));
+        assert!(infos[1].last_seen.is_none() && infos[1].rtt.is_none());
+    }
+
+    #[test]
+    fn test_relay_prefer_less_loaded() {
+        let mut peer_list = test_list(0u8);
+
+        // target is not connected, `near` is closer than `far`.
+        let target = PeerId([0xf0u8; 20]);
+        let (near_sender, near) = candidate(0xf1, 0x11);
+        let (far_sender, far) = candidate(0x0f, 0x22);
+        assert!(peer_list.dhts.add(near));
+        assert!(peer_list.dhts.add(far));
+
+        // same load, use the closest one.
+        let relay = peer_list.relay_get(&target).unwrap();
+        assert!(relay.same_channel(&near_sender));
+
+        // closest relay is overloaded, use the less-loaded one.
+        peer_list.update_load(PeerId([0xf1; 20]), 512);
+        peer_list.update_load(PeerId([0x0f; 20]), 8);
+        let relay = peer_list.relay_get(&target).unwrap();
+        assert!(relay.same_channel(&far_sender));
+    }
+
+    #[test]
+    fn test_peer_state() {
+        let mut peer_list = test_list(0u8);
+        let (_, dht) = candidate(1, 1);
+        let (_, stable) = candidate(2, 2);
+        let (dht_id, stable_id) = (dht.2.id, stable.2.id);
+        assert!(peer_list.dhts.add(dht));
+        peer_list.add_stable(stable_id, stable, true);
+
+        // not connected peer.
+        assert!(!peer_list.set_state(PeerId([3u8; 20]), PeerState::new(0u8)));
+        assert!(peer_list.state(&PeerId([3u8; 20])).is_none());
+
+        assert!(peer_list.set_state(dht_id, PeerState::new("alice".to_owned())));
+        assert!(peer_list.set_state(stable_id, PeerState::new(7u32)));
+        let state = peer_list.state(&dht_id).unwrap();
+        assert_eq!(state.get::<String>().unwrap(), "alice");
+        assert!(state.get::<u32>().is_none());
+        assert_eq!(peer_list.state(&stable_id).unwrap().get::<u32>(), Some(&7));
+
+        // cleared by user.
+        assert!(peer_list.clear_state(&stable_id).is_some());
+        assert!(peer_list.state(&stable_id).is_none());
+
+        // cleared when leave.
+        assert!(peer_list.set_state(stable_id, PeerState::new(8u32)));
+        peer_list.stable_leave(&stable_id);
+        assert!(peer_list.state(&stable_id).is_none());
+        peer_list.remove_peer(&dht_id, &PeerId([1u8; 20]));
+        assert!(peer_list.state(&dht_id).is_none());
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut peer_list = test_list(0);
+        // the sessions are closed, peer 1 is both in DHT and stables.
+        let (_, both_dht) = candidate(1, 1);
+        let (_, other) = candidate(3, 3);
+        let (_, both_stable) = candidate(1, 1);
+        assert!(peer_list.dhts.add(both_dht));
+        assert!(peer_list.dhts.add(other));
+        peer_list.add_stable(PeerId([1u8; 20]), both_stable, true);
+
+        let pruned = peer_list.prune();
+        assert_eq!(pruned, vec![PeerId([1u8; 20]), PeerId([3u8; 20])]);
+        assert!(peer_list.prune().is_empty());
+    }
+}
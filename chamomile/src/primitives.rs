@@ -1,7 +1,11 @@
-pub const STORAGE_NAME: &'static str = "p2p";
+pub const STORAGE_NAME: &str = "p2p";
 
-pub const STORAGE_KEY_KEY: &'static str = "key";
+pub const STORAGE_KEY_KEY: &str = "key";
 
-pub const STORAGE_ASSIST: &'static str = "assist";
+pub const STORAGE_ASSIST: &str = "assist";
 
-pub const STORAGE_PEER_LIST_KEY: &'static str = "peer_list";
+pub const STORAGE_PEER_LIST_KEY: &str = "peer_list";
+
+pub const STORAGE_TICKETS_KEY: &str = "tickets";
+
+pub const STORAGE_PINS_KEY: &str = "pins";
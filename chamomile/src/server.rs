@@ -1,70 +1,101 @@
-use rand_chacha::{
-    rand_core::{RngCore, SeedableRng},
-    ChaChaRng,
+use rand_chacha::{rand_core::RngCore, ChaChaRng};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{
-    fs,
     io::Result,
     select,
     sync::mpsc::{Receiver, Sender},
-    sync::RwLock,
-    time::interval,
+    sync::{watch, RwLock, Semaphore},
+    time::{interval, timeout_at, Instant},
 };
 
 use chamomile_types::{
     delivery_split,
-    key::Key,
-    message::{DeliveryType, ReceiveMessage, SendMessage, StateRequest, StateResponse},
-    types::{Broadcast, PeerId, TransportType, PEER_ID_LENGTH},
+    key::{Key, Signer, SIGNATURE_LENGTH},
+    message::{
+        DeliveryType, HealthStatus, ReceiveMessage, SendMessage, StateRequest, StateResponse,
+        TopologyPeer, TopologySnapshot,
+    },
+    types::{
+        new_io_error, Broadcast, CloseReason, ConnectError, PeerId, Priority, SessionState,
+        TransportType, PEER_ID_LENGTH,
+    },
     Peer,
 };
 
 use crate::buffer::{Buffer, BufferKey};
-use crate::config::Config;
-use crate::global::Global;
-use crate::hole_punching::{nat, DHT};
+use crate::config::{Config, PinPolicy};
+use crate::global::{
+    origin_verify, pow_solve, relay_sign, Global, Health, NodeRng, Pins, RelayFlows, RelayLoad,
+    SeenIds, SessionStates, SessionStats, ORIGIN_HEAD_LENGTH, ORIGIN_PROOF_WINDOW,
+    ORIGIN_SEEN_CAPACITY, POW_MAX_DIFFICULTY, POW_SOLVE_TIMEOUT, RNG_STREAM_ASSIST, RNG_STREAM_KEY,
+    RNG_STREAM_SESSION, VERIFY_ADVERTISE_TIMEOUT,
+};
+use crate::hole_punching::{nat, External, StunResult, EXTERNAL_DEBOUNCE};
 use crate::kad::KadValue;
 use crate::peer_list::PeerList;
-use crate::primitives::{STORAGE_ASSIST, STORAGE_KEY_KEY, STORAGE_PEER_LIST_KEY};
+use crate::primitives::{
+    STORAGE_ASSIST, STORAGE_KEY_KEY, STORAGE_PEER_LIST_KEY, STORAGE_PINS_KEY, STORAGE_TICKETS_KEY,
+};
 use crate::session::{
-    direct_stable, new_session_channel, relay_stable, session_spawn, ConnectType, Session,
-    SessionMessage,
+    direct_stable, new_session_channel, relay_stable, session_spawn, Confirm, ConnectType,
+    CoreData, Session, SessionMessage,
 };
+use crate::session_key::SessionKey;
+use crate::storage::{storage_or_file, Storage};
+use crate::ticket::Tickets;
 use crate::transports::{
-    start as transport_start, EndpointMessage, RemotePublic, TransportRecvMessage,
-    TransportSendMessage,
+    compatible_version, start as transport_start, EndpointMessage, RemotePublic,
+    TransportRecvMessage, TransportSendMessage, PROTOCOL_MIN_VERSION,
 };
 
-async fn get_keypair(mut key_path: PathBuf) -> Key {
-    key_path.push(STORAGE_KEY_KEY);
+/// the timeout (seconds) when connect to a peer only by peer id.
+const FIND_NODE_TIMEOUT: u64 = 10;
 
-    let key_bytes = fs::read(&key_path).await.unwrap_or(vec![]); // safe.
-    let key = match Key::from_db_bytes(&key_bytes) {
-        Ok(keypair) => keypair,
-        Err(_) => {
-            let key = Key::generate(&mut ChaChaRng::from_entropy());
-            let key_bytes = key.to_db_bytes();
-            let _ = fs::write(key_path, key_bytes).await;
-            key
-        }
-    };
+/// the timeout (seconds) when connect to a peer and wait the result.
+const CONNECT_TIMEOUT: u64 = 10;
+
+/// get the key from the key file, if not exists, generate a new one.
+/// a corrupted key file returns error, not replaced by a new identity.
+async fn get_keypair(storage: &dyn Storage, legacy: bool, rng: &mut ChaChaRng) -> Result<Key> {
+    let key_bytes = storage.get(STORAGE_KEY_KEY).await?.unwrap_or_default();
+    if key_bytes.is_empty() {
+        let key = Key::generate(rng);
+        storage.put(STORAGE_KEY_KEY, key.to_db_bytes()).await?;
+        return Ok(key);
+    }
+
+    let key = Key::from_db_bytes_with(&key_bytes, legacy)?;
+    let new_bytes = key.to_db_bytes();
+    if new_bytes != key_bytes {
+        // upgrade the legacy key file.
+        let _ = storage.put(STORAGE_KEY_KEY, new_bytes).await;
+    }
 
-    key
+    Ok(key)
 }
 
-async fn get_assist(mut path: PathBuf) -> PeerId {
-    path.push(STORAGE_ASSIST);
-    let bytes = fs::read(&path).await.unwrap_or(vec![]); // safe.
+async fn get_assist(storage: &dyn Storage, rng: &mut ChaChaRng) -> PeerId {
+    let bytes = storage
+        .get(STORAGE_ASSIST)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default(); // safe.
     let mut id_bytes = [0u8; PEER_ID_LENGTH];
 
     if bytes.len() == PEER_ID_LENGTH {
         id_bytes.copy_from_slice(&bytes);
         PeerId(id_bytes)
     } else {
-        let rng = &mut ChaChaRng::from_entropy();
         rng.fill_bytes(&mut id_bytes);
-        let _ = fs::write(path, id_bytes).await;
+        let _ = storage.put(STORAGE_ASSIST, id_bytes.to_vec()).await;
         PeerId(id_bytes)
     }
 }
@@ -74,15 +105,17 @@ pub async fn start(
     out_sender: Sender<ReceiveMessage>,
     self_receiver: Receiver<SendMessage>,
 ) -> Result<PeerId> {
-    let key = get_keypair(config.db_dir.clone()).await;
+    let storage = storage_or_file(config.storage.clone(), config.db_dir.clone());
+    let rng = &mut NodeRng::new(config.rng_seed, RNG_STREAM_KEY).rng();
+    let key = get_keypair(storage.as_ref(), config.legacy_key, rng).await?;
     start_with_key(config, out_sender, self_receiver, key).await
 }
 
 async fn start_bootstrap_peers(
     config: Config,
     out_sender: Sender<ReceiveMessage>,
-    key: Key,
-) -> (Arc<Global>, Receiver<TransportRecvMessage>) {
+    key: Arc<dyn Signer>,
+) -> Result<(Arc<Global>, Receiver<TransportRecvMessage>)> {
     let peer_id = key.peer_id();
 
     let Config {
@@ -95,29 +128,125 @@ async fn start_bootstrap_peers(
         permission,
         only_stable_data: _,
         delivery_length,
+        session_resume,
+        address_filter,
+        keepalive,
+        max_sessions,
+        decrypt_policy,
+        relay_sign,
+        relay_verify,
+        socket_options,
+        pow_difficulty,
+        max_relay_flows,
+        static_network,
+        leader_election,
+        max_tmp_buffer,
+        legacy_key: _,
+        storage,
+        max_dht_help,
+        pin_policy,
+        server_mode,
+        verify_advertise,
+        require_encryption,
+        bootstrap_priority,
+        bootstrap_dials,
+        relay_on_behalf,
+        maintenance_interval: _,
+        maintenance: _,
+        seen_capacity,
+        seen_ttl,
+        sequence_frames,
+        session_lifetime,
+        frame_padding,
+        max_clock_skew,
+        rate_window,
+        strip_local_addrs,
+        rng_seed,
+        signed_dht,
     } = config;
+    let storage = storage_or_file(storage, db_dir.clone());
+    let address_filter = if strip_local_addrs {
+        address_filter.strip_local()
+    } else {
+        address_filter
+    };
 
     allowlist.extend(allow_peer_list.iter().map(|pid| Peer::peer(*pid)));
 
     peer.id = peer_id;
-    peer.assist = get_assist(db_dir.clone()).await;
+    let rng = &mut NodeRng::new(rng_seed, RNG_STREAM_ASSIST).rng();
+    peer.assist = get_assist(storage.as_ref(), rng).await;
     debug!("P2P ID: {} - {}", peer.id.to_hex(), peer.assist.to_hex());
 
-    let mut peer_list_path = db_dir;
-    peer_list_path.push(STORAGE_PEER_LIST_KEY);
-    let peer_list = Arc::new(RwLock::new(PeerList::load(
+    let saved_tickets = if session_resume {
+        storage
+            .get(STORAGE_TICKETS_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    } else {
+        vec![]
+    };
+    let tickets = Tickets::load(
+        key.as_ref(),
+        storage.clone(),
+        &saved_tickets,
+        session_resume,
+    );
+
+    let saved_peers = storage
+        .get(STORAGE_PEER_LIST_KEY)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let saved_pins = storage
+        .get(STORAGE_PINS_KEY)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let pins = Pins::load(pin_policy, storage.clone(), &saved_pins);
+
+    let mut peer_list = PeerList::load(
         peer_id,
         peer.assist,
-        peer_list_path,
+        storage,
+        &saved_peers,
         allowlist,
         (block_peer_list, blocklist),
-    )));
+    );
+    for (socket, priority) in bootstrap_priority {
+        peer_list.set_priority(socket, priority);
+    }
+    let peer_list = Arc::new(RwLock::new(peer_list));
+
+    let pow_difficulty = if pow_difficulty > POW_MAX_DIFFICULTY {
+        warn!("CHAMOMILE: pow difficulty capped to {}", POW_MAX_DIFFICULTY);
+        POW_MAX_DIFFICULTY
+    } else {
+        pow_difficulty
+    };
+    let pow_nonce = if pow_difficulty > 0 {
+        let deadline = std::time::Instant::now() + Duration::from_secs(POW_SOLVE_TIMEOUT);
+        tokio::task::spawn_blocking(move || pow_solve(&peer_id, pow_difficulty, deadline))
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "proof-of-work timeout")
+            })?
+    } else {
+        0
+    };
 
     let mut transports: HashMap<TransportType, Sender<TransportSendMessage>> = HashMap::new();
 
-    let (local_addr, trans_send, trans_option, main_option) = transport_start(&peer, None)
-        .await
-        .expect("Transport binding failure!");
+    let (local_addr, trans_send, trans_option, main_option) =
+        transport_start(&peer, None, &socket_options)
+            .await
+            .expect("Transport binding failure!");
     let trans_recv = trans_option.unwrap(); // safe
     let main_trans = main_option.unwrap(); // safe
 
@@ -131,46 +260,111 @@ async fn start_bootstrap_peers(
         delivery_length,
         trans: main_trans,
         transports: Arc::new(RwLock::new(transports)),
-        buffer: Arc::new(RwLock::new(Buffer::init())),
+        buffer: Arc::new(RwLock::new(Buffer::init(max_tmp_buffer))),
         peer_list: peer_list.clone(),
+        tickets: Arc::new(RwLock::new(tickets)),
         is_relay_data: !permission,
+        relay_load: RelayLoad::default(),
+        address_filter,
+        keepalive,
+        session_limit: if max_sessions > 0 {
+            Some(Arc::new(Semaphore::new(max_sessions)))
+        } else {
+            None
+        },
+        decrypt_policy,
+        relay_sign,
+        relay_verify,
+        connects: Arc::new(RwLock::new(HashMap::new())),
+        observer: Arc::new(RwLock::new(None)),
+        socket_options,
+        health: Health::default(),
+        draining: AtomicBool::new(false),
+        pow_difficulty,
+        pow_nonce,
+        connect_waits: Arc::new(RwLock::new(HashMap::new())),
+        relay_flows: RelayFlows::new(max_relay_flows),
+        static_network,
+        leader_election,
+        leader: Mutex::new(None),
+        external: Mutex::new(External::new(
+            peer.socket,
+            Duration::from_secs(EXTERNAL_DEBOUNCE),
+        )),
+        stun: Mutex::new(StunResult::default()),
+        max_dht_help,
+        pins,
+        server_mode,
+        verify_advertise,
+        require_encryption,
+        bootstrap_dials,
+        relay_on_behalf,
+        seen: SeenIds::new(seen_capacity, Duration::from_secs(seen_ttl)),
+        origin_seen: SeenIds::new(
+            ORIGIN_SEEN_CAPACITY,
+            Duration::from_secs(ORIGIN_PROOF_WINDOW + 2 * max_clock_skew),
+        ),
+        sequence_frames,
+        session_lifetime,
+        frame_padding,
+        delivery_paused: watch::Sender::new(false),
+        protocols: Arc::new(RwLock::new(HashMap::new())),
+        max_clock_skew,
+        rate_window,
+        tiers: Mutex::new(HashMap::new()),
+        rng: NodeRng::new(rng_seed, RNG_STREAM_SESSION),
+        session_states: SessionStates::new(),
+        session_stats: SessionStats::new(),
+        signed_dht,
+        records: Mutex::new(HashMap::new()),
+        record_seq: AtomicU64::new(0),
     });
 
     // bootstrap allow list.
-    for a in peer_list.read().await.bootstrap() {
-        let (session_key, remote_pk) = global.generate_remote();
-        let _ = global
-            .trans_send(
-                &a.transport,
-                TransportSendMessage::Connect(a.socket, remote_pk, session_key),
-            )
-            .await;
-    }
+    tokio::spawn(
+        global
+            .clone()
+            .bootstrap(Duration::from_secs(CONNECT_TIMEOUT)),
+    );
 
-    (global, trans_recv)
+    Ok((global, trans_recv))
 }
 
 /// start server
 pub async fn start_with_key(
     config: Config,
     out_sender: Sender<ReceiveMessage>,
-    mut self_receiver: Receiver<SendMessage>,
+    self_receiver: Receiver<SendMessage>,
     key: Key,
+) -> Result<PeerId> {
+    start_with_signer(config, out_sender, self_receiver, Arc::new(key)).await
+}
+
+/// start server with the signer of peer identity.
+pub async fn start_with_signer(
+    config: Config,
+    out_sender: Sender<ReceiveMessage>,
+    mut self_receiver: Receiver<SendMessage>,
+    key: Arc<dyn Signer>,
 ) -> Result<PeerId> {
     let peer_id = key.peer_id();
 
-    let (global, mut trans_recv) = start_bootstrap_peers(config.clone(), out_sender, key).await;
+    let (global, mut trans_recv) = start_bootstrap_peers(config.clone(), out_sender, key).await?;
 
     let only_stable_data = config.only_stable_data;
     let delivery_length = config.delivery_length;
+    let maintenance_interval = config.maintenance_interval;
+    let maintenance = config.maintenance;
 
     let recv_data = !only_stable_data;
     let inner_global = global.clone();
     let listen_task = tokio::spawn(async move {
+        #[allow(clippy::large_enum_variant)]
         enum FutureResult {
             Trans(TransportRecvMessage),
             Clear,
             Check,
+            Maintain,
         }
 
         // Check Timer: every 10s to check network. (read only).
@@ -179,10 +373,13 @@ pub async fn start_with_key(
         // Clear Timer: every 60s to check buffer.
         let mut clear_interval = interval(Duration::from_secs(60));
 
+        // Maintenance Timer: every `maintenance_interval` to prune and recompute.
+        let mut maintain_interval = interval(Duration::from_secs(maintenance_interval.max(1)));
+
         loop {
             let futres = select! {
                 v = async {
-                    trans_recv.recv().await.map(|msg| FutureResult::Trans(msg))
+                    trans_recv.recv().await.map(FutureResult::Trans)
                 } => v,
                 v = async {
                     check_interval.tick().await;
@@ -192,22 +389,42 @@ pub async fn start_with_key(
                     clear_interval.tick().await;
                     Some(FutureResult::Clear)
                 } => v,
+                v = async {
+                    maintain_interval.tick().await;
+                    Some(FutureResult::Maintain)
+                }, if maintenance_interval > 0 => v,
             };
 
             match futres {
                 Some(FutureResult::Trans(TransportRecvMessage(
                     addr,
-                    RemotePublic(remote_peer, dh_key),
+                    RemotePublic(remote_peer, dh_key, version),
                     is_self,
                     stream_sender,
                     stream_receiver,
                     endpoint_sender,
+                    mut timer,
                 ))) => {
                     debug!("Incoming remote peer...");
+                    // 0. check the protocol version right after handshake.
+                    if !compatible_version(version, PROTOCOL_MIN_VERSION) {
+                        warn!("Incoming remote version {} mismatch, close it.", version);
+                        let reason = Some(CloseReason::Version);
+                        let _ = endpoint_sender.send(EndpointMessage::Close(reason)).await;
+                        continue;
+                    }
+
+                    // 0. not accept new inbound when draining.
+                    if is_self.is_none() && inner_global.is_draining() {
+                        debug!("Incoming remote when draining, close it.");
+                        let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+                        continue;
+                    }
+
                     // 1. check is block ip.
                     if inner_global.peer_list.read().await.is_block_addr(&addr) {
                         debug!("Incoming remote ip is blocked, close it.");
-                        let _ = endpoint_sender.send(EndpointMessage::Close).await;
+                        let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
                         continue;
                     }
 
@@ -225,22 +442,68 @@ pub async fn start_with_key(
                             .is_block_peer(&remote_id)
                     {
                         debug!("Incoming remote peer is blocked, close it.");
-                        let _ = endpoint_sender.send(EndpointMessage::Close).await;
+                        let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+                        if is_self.is_some() {
+                            inner_global.session_failed(&remote_id);
+                            inner_global
+                                .connect_done(&addr, Err(ConnectError::Rejected))
+                                .await;
+                        }
                         continue;
                     }
 
+                    // check sessions limit, close it when overload.
+                    let permit = match inner_global.session_permit(&remote_id) {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            debug!("Incoming remote too many sessions, close it.");
+                            let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+                            if is_self.is_some() {
+                                inner_global.session_failed(&remote_id);
+                                inner_global
+                                    .connect_done(&addr, Err(ConnectError::Rejected))
+                                    .await;
+                            }
+                            continue;
+                        }
+                    };
+
                     // 3. check session key and send self info to remote.
-                    let session_key = if let Some(mut session_key) = is_self {
+                    let is_dial = is_self.is_some();
+                    let mut session_key = if let Some(mut session_key) = is_self {
+                        inner_global.buffer.write().await.remove_dht(&addr);
                         if session_key.complete(&remote_id, dh_key) {
+                            // check the pinned identity of the dialed address.
+                            if let Some(pinned) = inner_global.pins.check(addr, remote_id).await {
+                                warn!("CHAMOMILE: pinned peer id of {} changed.", addr);
+                                let _ = inner_global
+                                    .out_send(ReceiveMessage::PinMismatch(addr, pinned, remote_id))
+                                    .await;
+                                if inner_global.pins.policy == PinPolicy::Reject {
+                                    let _ =
+                                        endpoint_sender.send(EndpointMessage::Close(None)).await;
+                                    inner_global.session_failed(&remote_id);
+                                    inner_global
+                                        .connect_done(&addr, Err(ConnectError::Rejected))
+                                        .await;
+                                    continue;
+                                }
+                            }
+                            inner_global.save_ticket(&remote_id, &session_key).await;
                             session_key
                         } else {
                             debug!("Incoming remote session key is invalid, close it.");
-                            let _ = endpoint_sender.send(EndpointMessage::Close).await;
+                            let reason = Some(CloseReason::Crypto);
+                            let _ = endpoint_sender.send(EndpointMessage::Close(reason)).await;
+                            inner_global.session_failed(&remote_id);
+                            inner_global
+                                .connect_done(&addr, Err(ConnectError::Handshake))
+                                .await;
                             continue;
                         }
                     } else {
                         if let Some((session_key, remote_pk)) =
-                            inner_global.complete_remote(&remote_id, dh_key)
+                            inner_global.complete_remote(&remote_id, dh_key).await
                         {
                             let _ = endpoint_sender
                                 .send(EndpointMessage::Handshake(remote_pk))
@@ -248,15 +511,31 @@ pub async fn start_with_key(
                             session_key
                         } else {
                             debug!("Incoming remote session key is invalid, close it.");
-                            let _ = endpoint_sender.send(EndpointMessage::Close).await;
+                            let reason = Some(CloseReason::Crypto);
+                            let _ = endpoint_sender.send(EndpointMessage::Close(reason)).await;
+                            inner_global.session_failed(&remote_id);
                             continue;
                         }
                     };
 
+                    // the remote is authenticated by the session key, record it now.
+                    inner_global.session_state(&remote_id, SessionState::KeyExchange);
+                    timer.trace.session_key = timer.elapsed();
+                    if is_dial {
+                        // the dial is done, tell the waiter.
+                        inner_global.connect_done(&addr, Ok(remote_id)).await;
+                    }
+
                     // 4. check is stable relay connections.
-                    if let Some(ss) = inner_global.peer_list.read().await.is_relay(&remote_id) {
+                    let relay = inner_global
+                        .peer_list
+                        .read()
+                        .await
+                        .is_relay(&remote_id)
+                        .cloned();
+                    if let Some(ss) = relay {
                         debug!("Incoming remote upgrade to direct.");
-                        let _ = ss
+                        let upgrade = ss
                             .send(SessionMessage::DirectIncoming(
                                 remote_peer,
                                 stream_sender,
@@ -264,6 +543,10 @@ pub async fn start_with_key(
                                 endpoint_sender,
                             ))
                             .await;
+                        if upgrade.is_err() {
+                            // the relay session is gone, the connection is dropped.
+                            inner_global.session_failed(&remote_id);
+                        }
                         continue;
                     }
 
@@ -283,31 +566,66 @@ pub async fn start_with_key(
                         // 6. check if had connected.
                         if !is_new {
                             debug!("Incoming remote add dht failure, close it.");
-                            let _ = endpoint_sender.send(EndpointMessage::Close).await;
+                            let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+                            inner_global.session_failed(&remote_id);
                             continue;
                         }
+                        inner_global.connects.write().await.insert(remote_id, timer);
+
+                        // verify the address before advertise it, self dialed or
+                        // it connected from there is reachable, or dial back to it.
+                        if is_dial || remote_peer.socket == addr {
+                            inner_global.peer_list.write().await.verify(&remote_peer);
+                        } else if inner_global.verify_advertise {
+                            let verify_global = inner_global.clone();
+                            tokio::spawn(async move {
+                                let timeout = Duration::from_secs(VERIFY_ADVERTISE_TIMEOUT);
+                                verify_global.verify_advertised(&remote_peer, timeout).await
+                            });
+                        }
 
                         // 7. DHT help.
-                        let peers = inner_global.peer_list.read().await.help_dht(&remote_id);
-                        let _ = endpoint_sender.send(EndpointMessage::DHT(DHT(peers))).await;
-                    }
-
-                    session_spawn(
-                        Session::new(
-                            remote_peer,
-                            session_sender,
-                            stream_receiver,
-                            ConnectType::Direct(endpoint_sender),
-                            session_key,
-                            inner_global.clone(),
-                            is_own || recv_data,
-                            is_own,
-                        ),
-                        session_receiver,
+                        if let Some(dht) = inner_global.dht_help(&remote_id).await {
+                            let _ = endpoint_sender.send(EndpointMessage::DHT(dht)).await;
+                        }
+
+                        // tell the dialer its reflexive address.
+                        if !is_dial {
+                            let msg = EndpointMessage::Reflexive(remote_peer.socket);
+                            let _ = endpoint_sender.send(msg).await;
+                        }
+                    }
+
+                    // 8. early data, dialer resend it when remote not accepted.
+                    // the received is delivered by session after the key confirmed.
+                    if is_dial {
+                        if let Some(data) = session_key.take_early() {
+                            let msg = SessionMessage::Data(0, data, Priority::Normal);
+                            let _ = session_sender.send(msg).await;
+                        }
+                    }
+
+                    let mut session = Session::new(
+                        remote_peer,
+                        session_sender,
+                        stream_receiver,
+                        ConnectType::Direct(endpoint_sender),
+                        session_key,
+                        inner_global.clone(),
+                        is_own || recv_data,
+                        is_own,
                     );
+                    session.permit = permit;
+                    session.is_dial = is_dial;
+                    session.remote_version = version;
+                    session_spawn(session, session_receiver);
                     debug!("Incoming remote sessioned: {}.", remote_id.short_show());
                 }
                 Some(FutureResult::Check) => {
+                    inner_global.relay_load.tick();
+                    inner_global.settle_external().await;
+                    inner_global.relay_flows.tick();
+                    inner_global.check_leader().await;
                     if inner_global.peer_list.read().await.is_empty() {
                         let _ = inner_global.out_send(ReceiveMessage::NetworkLost).await;
                     }
@@ -315,14 +633,36 @@ pub async fn start_with_key(
                 Some(FutureResult::Clear) => {
                     inner_global.buffer.write().await.timer_clear().await;
                 }
+                Some(FutureResult::Maintain) => {
+                    inner_global.maintain(&maintenance).await;
+                }
                 None => break,
             }
         }
     });
 
     tokio::spawn(async move {
+        // when draining, the time to full stop.
+        let mut drain_deadline: Option<Instant> = None;
+
         loop {
-            match self_receiver.recv().await {
+            let recv = async {
+                match drain_deadline {
+                    Some(deadline) => timeout_at(deadline, self_receiver.recv())
+                        .await
+                        .unwrap_or(Some(SendMessage::NetworkStop)),
+                    None => self_receiver.recv().await,
+                }
+            };
+            let msg = select! {
+                msg = recv => msg,
+                _ = global.out_sender.closed() => {
+                    // no one receive the data, running is pointless.
+                    warn!("CHAMOMILE: OUTSIDE RECEIVER DROPPED, STOP THE NETWORK.");
+                    Some(SendMessage::NetworkStop)
+                }
+            };
+            match msg {
                 Some(SendMessage::StableConnect(tid, to, data)) => {
                     debug!("Outside: StableConnect to {}.", to.id.short_show());
                     if &to.id == global.peer_id() {
@@ -387,7 +727,7 @@ pub async fn start_with_key(
                         let _ = s.send(SessionMessage::StableConnect(tid, data)).await;
                         drop(peer_list_lock);
                     } else {
-                        let ss = s.clone();
+                        let ss = peer_list_lock.relay_get(&to.id).unwrap_or(s).clone();
                         drop(peer_list_lock);
 
                         // 3. check if had in buffer tmp.
@@ -481,7 +821,7 @@ pub async fn start_with_key(
                             continue;
                         }
 
-                        let ss = s.clone();
+                        let ss = peer_list_lock.relay_get(&to.id).unwrap_or(s).clone();
                         drop(peer_list_lock);
 
                         // 4. check if had in buffer tmp.
@@ -519,21 +859,104 @@ pub async fn start_with_key(
                 }
                 Some(SendMessage::StableDisconnect(pid)) => {
                     debug!("Outside: StableDisconnect to {}.", pid.short_show());
-                    if let Some((sender, _, is_it)) = global.peer_list.read().await.get(&pid) {
-                        if is_it {
-                            let _ = sender.send(SessionMessage::Close).await;
-                        }
+                    if let Some((sender, true)) = global.session_get(&pid).await {
+                        let _ = sender.send(SessionMessage::Close).await;
                     }
                 }
                 Some(SendMessage::Connect(peer)) => {
                     debug!("Outside: DHT Connect to {}.", peer.socket);
-                    let (session_key, remote_pk) = global.generate_remote();
-                    let _ = global
-                        .trans_send(
-                            &peer.transport,
-                            TransportSendMessage::Connect(peer.socket, remote_pk, session_key),
-                        )
-                        .await;
+                    let g = global.clone();
+                    tokio::spawn(async move {
+                        let _ = g.dht_connect(&peer).await;
+                    });
+                }
+                Some(SendMessage::ConnectWait(peer, res_sender)) => {
+                    debug!("Outside: DHT Connect to {} and wait.", peer.socket);
+                    let g = global.clone();
+                    tokio::spawn(async move {
+                        let timeout = Duration::from_secs(CONNECT_TIMEOUT);
+                        let res = g.connect_wait(&peer, timeout).await;
+                        let _ = res_sender.send(res).await;
+                    });
+                }
+                Some(SendMessage::DataFrom(from, to, mut proof, data)) => {
+                    if !global.relay_on_behalf {
+                        warn!("CHAMOMILE: DataFrom need relay_on_behalf, drop it.");
+                        continue;
+                    }
+                    if proof.len() != SIGNATURE_LENGTH + ORIGIN_HEAD_LENGTH {
+                        warn!("CHAMOMILE: DataFrom origin proof is invalid, drop it.");
+                        continue;
+                    }
+                    // the proof is signature + head, the head is sent before the data.
+                    let data = [&proof[SIGNATURE_LENGTH..], &data[..]].concat();
+                    proof.truncate(SIGNATURE_LENGTH);
+                    let sign = proof;
+                    match origin_verify(&from, &to, &sign, &data) {
+                        Some((gateway, timestamp))
+                            if &gateway == global.peer_id()
+                                && global.timestamp_valid(timestamp, ORIGIN_PROOF_WINDOW) => {}
+                        _ => {
+                            warn!("CHAMOMILE: DataFrom origin proof is invalid, drop it.");
+                            continue;
+                        }
+                    }
+                    debug!(
+                        "Outside: DataFrom {} to {}.",
+                        from.short_show(),
+                        to.short_show()
+                    );
+                    if let Some((sender, _)) = global.session_get(&to).await {
+                        let _ = sender
+                            .send(SessionMessage::RelaySigned(from, to, sign, data))
+                            .await;
+                    } else {
+                        warn!("CHAMOMILE: DataFrom CANNOT REACH NETWORK.");
+                    }
+                }
+                Some(SendMessage::Oneshot(peer, data, res_sender)) => {
+                    debug!("Outside: oneshot send to {}.", peer.socket);
+                    let g = global.clone();
+                    tokio::spawn(async move {
+                        let timeout = Duration::from_secs(CONNECT_TIMEOUT);
+                        let res = g.send_oneshot(&peer, data, timeout).await;
+                        let _ = res_sender.send(res).await;
+                    });
+                }
+                Some(SendMessage::ConnectData(peer, data)) => {
+                    debug!("Outside: DHT Connect to {} with data.", peer.socket);
+                    let g = global.clone();
+                    tokio::spawn(async move {
+                        let _ = g.connect_data(&peer, data).await;
+                    });
+                }
+                Some(SendMessage::ConnectPeerId(peer_id, res_sender)) => {
+                    debug!("Outside: DHT Connect to {}.", peer_id.short_show());
+                    let g = global.clone();
+                    tokio::spawn(async move {
+                        let timeout = Duration::from_secs(FIND_NODE_TIMEOUT);
+                        let res = g.find_node(&peer_id, timeout).await;
+                        let _ = res_sender.send(res).await;
+                    });
+                }
+                Some(SendMessage::PeerDisconnect(pid, reason)) => {
+                    debug!("Outside: Disconnect to {}.", pid.short_show());
+                    if let Some((sender, true)) = global.session_get(&pid).await {
+                        let _ = sender.send(SessionMessage::Disconnect(reason)).await;
+                    }
+                }
+                Some(SendMessage::Ban(pid, duration)) => {
+                    debug!("Outside: Ban {}.", pid.short_show());
+                    let until = duration.map(|d| std::time::Instant::now() + d);
+                    let mut peer_list = global.peer_list.write().await;
+                    peer_list.add_block_peer(pid, until);
+                    if let Some((sender, _, true)) = peer_list.get(&pid) {
+                        let _ = sender.try_send(SessionMessage::Disconnect(CloseReason::Kick));
+                    }
+                }
+                Some(SendMessage::Unban(pid)) => {
+                    debug!("Outside: Unban {}.", pid.short_show());
+                    global.peer_list.write().await.remove_block_peer(&pid);
                 }
                 Some(SendMessage::DisConnect(peer)) => {
                     debug!("Outside: DHT Disconnect to {}.", peer.socket);
@@ -544,7 +967,20 @@ pub async fn start_with_key(
                         .peer_disconnect(&peer.socket)
                         .await;
                 }
-                Some(SendMessage::Data(tid, to, data)) => {
+                Some(
+                    msg @ (SendMessage::Data(..)
+                    | SendMessage::PriorityData(..)
+                    | SendMessage::Metadata(..)),
+                ) => {
+                    let (tid, to, data, priority, meta) = match msg {
+                        SendMessage::Data(tid, to, data) => (tid, to, data, Priority::Normal, None),
+                        SendMessage::PriorityData(tid, to, data, p) => (tid, to, data, p, None),
+                        SendMessage::Metadata(tid, to, m, data) => {
+                            (tid, to, data, Priority::Normal, Some(m))
+                        }
+                        _ => unreachable!(),
+                    };
+
                     // check if send to self. better circle for application.
                     if &to == global.peer_id() {
                         warn!("CHAMOMILE: Data to self. PLEASE use SendMessage::OwnEvent.");
@@ -558,20 +994,115 @@ pub async fn start_with_key(
                                 ))
                                 .await;
                         }
-                        let _ = global.out_send(ReceiveMessage::Data(to, data)).await;
+                        let msg = if let Some(meta) = meta {
+                            ReceiveMessage::Metadata(to, meta, data)
+                        } else {
+                            ReceiveMessage::Data(to, data, true)
+                        };
+                        let _ = global.out_send(msg).await;
                         continue;
                     }
 
-                    if let Some((sender, _, is_it)) = global.peer_list.read().await.get(&to) {
-                        if is_it {
-                            let _ = sender.send(SessionMessage::Data(tid, data)).await;
+                    let delivery = delivery_split!(data, delivery_length);
+                    let is_sent = if let Some((sender, is_it)) = global.session_get(&to).await {
+                        let msg = if let Some(meta) = meta {
+                            if is_it {
+                                Some(SessionMessage::Metadata(tid, meta, data))
+                            } else {
+                                // relay data has no metadata.
+                                None
+                            }
+                        } else if is_it {
+                            Some(SessionMessage::Data(tid, data, priority))
+                        } else if global.require_encryption {
+                            // relay data is not encrypted end to end.
+                            None
                         } else {
                             // only happen on permissionless.
-                            let _ = sender
-                                .send(SessionMessage::RelayData(*global.peer_id(), to, data))
+                            let from = *global.peer_id();
+                            if global.relay_sign {
+                                let sign = relay_sign(global.key.as_ref(), &from, &to, &data);
+                                Some(SessionMessage::RelaySigned(from, to, sign, data))
+                            } else {
+                                Some(SessionMessage::RelayData(from, to, data))
+                            }
+                        };
+                        // the session maybe closed after lookup.
+                        match msg {
+                            Some(msg) => sender.send(msg).await.is_ok(),
+                            None => false,
+                        }
+                    } else {
+                        false
+                    };
+
+                    if !is_sent {
+                        warn!("CHAMOMILE: CANNOT REACH NETWORK.");
+                        if tid != 0 {
+                            let _ = global
+                                .out_send(ReceiveMessage::Delivery(
+                                    DeliveryType::Data,
+                                    tid,
+                                    false,
+                                    delivery,
+                                ))
+                                .await;
+                        }
+                    }
+                }
+                Some(SendMessage::ProtocolData(tid, to, protocol, data)) => {
+                    let delivery = delivery_split!(data, delivery_length);
+                    // relay data has no protocol id, only to the connected.
+                    let is_sent = match global.session_get(&to).await {
+                        Some((sender, true)) => sender
+                            .send(SessionMessage::Protocol(tid, protocol, data))
+                            .await
+                            .is_ok(),
+                        _ => false,
+                    };
+                    if !is_sent {
+                        warn!("CHAMOMILE: CANNOT REACH NETWORK.");
+                        if tid != 0 {
+                            let _ = global
+                                .out_send(ReceiveMessage::Delivery(
+                                    DeliveryType::Data,
+                                    tid,
+                                    false,
+                                    delivery,
+                                ))
                                 .await;
                         }
+                    }
+                }
+                Some(SendMessage::ConfirmData(tid, to, data, confirm)) => {
+                    let confirm = Confirm::new(confirm);
+                    if let Some((sender, true)) = global.session_get(&to).await {
+                        // if the session closed, the confirm is dropped with error.
+                        let _ = sender
+                            .send(SessionMessage::ConfirmData(tid, data, confirm))
+                            .await;
+                    } else {
+                        warn!("CHAMOMILE: CANNOT REACH NETWORK.");
+                        confirm.resolve(Err(new_io_error("peer not connected")));
+                    }
+                }
+                Some(SendMessage::ExpiringData(tid, to, data, ttl)) => {
+                    let deadline = Instant::now() + ttl;
+                    let delivery = delivery_split!(data, delivery_length);
+                    let session = match global.session_get(&to).await {
+                        Some((sender, true)) => Some(sender),
+                        // in handshake, queued in the tmp session, and expired there.
+                        _ => global.buffer.read().await.get_tmp_session(&to).cloned(),
+                    };
+                    let is_sent = if let Some(sender) = session {
+                        sender
+                            .send(SessionMessage::ExpiringData(tid, data, deadline))
+                            .await
+                            .is_ok()
                     } else {
+                        false
+                    };
+                    if !is_sent {
                         warn!("CHAMOMILE: CANNOT REACH NETWORK.");
                         if tid != 0 {
                             let _ = global
@@ -579,35 +1110,117 @@ pub async fn start_with_key(
                                     DeliveryType::Data,
                                     tid,
                                     false,
-                                    delivery_split!(data, delivery_length),
+                                    delivery,
                                 ))
                                 .await;
                         }
                     }
                 }
+                Some(SendMessage::Flush(to, done)) => {
+                    let done = Confirm::new(done);
+                    if let Some((sender, true)) = global.session_get(&to).await {
+                        let _ = sender.send(SessionMessage::Flush(done)).await;
+                    } else {
+                        done.resolve(Err(new_io_error("peer not connected")));
+                    }
+                }
+                Some(SendMessage::StopSession(pid, reason, done)) => {
+                    debug!("Outside: Stop session of {}.", pid.short_show());
+                    let done = Confirm::new(done);
+                    if let Some((sender, true)) = global.session_get(&pid).await {
+                        let _ = sender.send(SessionMessage::Stop(reason, done)).await;
+                    } else {
+                        done.resolve(Err(new_io_error("peer not connected")));
+                    }
+                }
+                Some(SendMessage::LargeData(to, data)) => {
+                    if let Some((sender, true)) = global.session_get(&to).await {
+                        let _ = sender.send(SessionMessage::LargeData(data)).await;
+                    } else {
+                        warn!("CHAMOMILE: CANNOT REACH NETWORK.");
+                    }
+                }
+                Some(SendMessage::SealedData(peers, data)) => {
+                    let mut content_key = [0u8; 32];
+                    global.rng.rng().fill_bytes(&mut content_key);
+                    let frame = match CoreData::Data(0, data).to_padded_bytes(&global.frame_padding)
+                    {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            warn!("CHAMOMILE: SEALED DATA: {}", e);
+                            continue;
+                        }
+                    };
+                    let sealed = Arc::new(SessionKey::seal(&content_key, &frame));
+                    for to in peers {
+                        if let Some((sender, true)) = global.session_get(&to).await {
+                            let _ = sender
+                                .send(SessionMessage::Sealed(content_key, sealed.clone()))
+                                .await;
+                        } else {
+                            warn!("CHAMOMILE: CANNOT REACH {}.", to.short_show());
+                        }
+                    }
+                }
+                Some(SendMessage::DataWhere(filter, data)) => {
+                    let senders: Vec<_> = global
+                        .peer_list
+                        .read()
+                        .await
+                        .infos(&global.session_stats)
+                        .into_iter()
+                        .filter(|(info, _)| filter.matches(info))
+                        .map(|(_, sender)| sender.clone())
+                        .collect();
+                    for sender in senders {
+                        let _ = sender
+                            .send(SessionMessage::Data(0, data.clone(), Priority::Normal))
+                            .await;
+                    }
+                }
                 Some(SendMessage::Broadcast(broadcast, data)) => match broadcast {
                     Broadcast::StableAll => {
                         for (_to, (sender, _)) in global.peer_list.read().await.stable_all() {
-                            let _ = sender.send(SessionMessage::Data(0, data.clone())).await;
+                            let _ = sender
+                                .send(SessionMessage::Data(0, data.clone(), Priority::Normal))
+                                .await;
                         }
                     }
                     Broadcast::Gossip => {
                         // TODO more Gossip base on Kad.
                         for (_to, sender) in global.peer_list.read().await.all() {
-                            let _ = sender.send(SessionMessage::Data(0, data.clone())).await;
+                            let _ = sender
+                                .send(SessionMessage::Data(0, data.clone(), Priority::Normal))
+                                .await;
                         }
                     }
                 },
                 Some(SendMessage::OwnEvent(data)) => {
                     let peer_list = global.peer_list.read().await;
                     for pid in peer_list.own() {
-                        if let Some((sender, _, is_it)) = peer_list.get(&pid) {
+                        if let Some((sender, _, is_it)) = peer_list.get(pid) {
                             if is_it {
-                                let _ = sender.send(SessionMessage::Data(0, data.clone())).await;
+                                let _ = sender
+                                    .send(SessionMessage::Data(0, data.clone(), Priority::Normal))
+                                    .await;
                             }
                         }
                     }
                 }
+                Some(SendMessage::Observe(observer)) => {
+                    *global.observer.write().await = observer;
+                }
+                Some(SendMessage::PeerTier(peer_id, tier)) => {
+                    global.set_peer_tier(peer_id, tier);
+                }
+                Some(SendMessage::Protocol(protocol, receiver)) => {
+                    let mut protocols = global.protocols.write().await;
+                    if let Some(receiver) = receiver {
+                        protocols.insert(protocol, receiver);
+                    } else {
+                        protocols.remove(&protocol);
+                    }
+                }
                 Some(SendMessage::Stream(_symbol, _stream_type, _data)) => {
                     // TODO WIP
                 }
@@ -638,20 +1251,119 @@ pub async fn start_with_key(
                             .collect();
                         let _ = res_sender.send(StateResponse::Seed(seeds)).await;
                     }
+                    StateRequest::Stats => {
+                        let stats = global.stats().await;
+                        let _ = res_sender.send(StateResponse::Stats(stats)).await;
+                    }
+                    StateRequest::Health => {
+                        let bound = global
+                            .transports
+                            .read()
+                            .await
+                            .get(&global.peer.transport)
+                            .is_some_and(|sender| !sender.is_closed());
+                        let peers = {
+                            let peer_list = global.peer_list.read().await;
+                            peer_list.dht_keys().len() + peer_list.stable_all().len()
+                        };
+                        let status = HealthStatus {
+                            bound,
+                            peers,
+                            bootstrapped: global.health.bootstrapped(),
+                            last_activity: global.health.last_active(),
+                        };
+                        let _ = res_sender.send(StateResponse::Health(status)).await;
+                    }
+                    StateRequest::Banned => {
+                        let banned = global.peer_list.write().await.block_peers();
+                        let _ = res_sender.send(StateResponse::Banned(banned)).await;
+                    }
+                    StateRequest::LocalAddrs => {
+                        let addrs = vec![global.peer.socket];
+                        let _ = res_sender.send(StateResponse::LocalAddrs(addrs)).await;
+                    }
+                    StateRequest::PeerReady(peer_id) => {
+                        // sessions join the peer list after key exchange,
+                        // and the closest one of other peer is not it.
+                        let ready = matches!(global.session_get(&peer_id).await, Some((_, true)));
+                        let _ = res_sender.send(StateResponse::PeerReady(ready)).await;
+                    }
+                    StateRequest::Topology => {
+                        let peers = global
+                            .peer_list
+                            .read()
+                            .await
+                            .topology(&global.session_stats)
+                            .into_iter()
+                            .map(|(p, is_connected, last_seen)| TopologyPeer {
+                                id: p.id,
+                                socket: p.socket,
+                                transport: p.transport,
+                                is_pub: p.is_pub,
+                                is_connected,
+                                last_seen,
+                            })
+                            .collect();
+                        let snapshot = TopologySnapshot { peers };
+                        let _ = res_sender.send(StateResponse::Topology(snapshot)).await;
+                    }
+                    StateRequest::PeerState(peer_id) => {
+                        let state = global.peer_list.read().await.state(&peer_id).cloned();
+                        let _ = res_sender.send(StateResponse::PeerState(state)).await;
+                    }
+                    StateRequest::Timestamp(remote, window) => {
+                        let valid = global.timestamp_valid(remote, window);
+                        let _ = res_sender.send(StateResponse::Timestamp(valid)).await;
+                    }
+                    StateRequest::SessionState(peer_id) => {
+                        let states = global.session_states.get(&peer_id);
+                        let _ = res_sender.send(StateResponse::SessionState(states)).await;
+                    }
                 },
+                Some(SendMessage::PeerState(peer_id, state)) => {
+                    let mut peer_list = global.peer_list.write().await;
+                    if let Some(state) = state {
+                        if !peer_list.set_state(peer_id, state) {
+                            debug!("Peer state of unconnected peer, skip it.");
+                        }
+                    } else {
+                        peer_list.clear_state(&peer_id);
+                    }
+                }
+                Some(SendMessage::ImportTopology(snapshot)) => {
+                    let peers = snapshot.peers.iter().map(|p| p.peer()).collect();
+                    let count = global
+                        .peer_list
+                        .write()
+                        .await
+                        .import(global.peer_id(), peers)
+                        .await;
+                    debug!("Import topology {} new peers.", count);
+                }
                 Some(SendMessage::NetworkReboot) => {
                     // rebootstrap allow list.
-                    for a in global.peer_list.read().await.bootstrap() {
-                        let (session_key, remote_pk) = global.generate_remote();
-                        let _ = global
-                            .trans_send(
-                                &a.transport,
-                                TransportSendMessage::Connect(a.socket, remote_pk, session_key),
-                            )
-                            .await;
-                    }
+                    tokio::spawn(
+                        global
+                            .clone()
+                            .bootstrap(Duration::from_secs(CONNECT_TIMEOUT)),
+                    );
+                }
+                Some(SendMessage::NetworkDrain(grace)) => {
+                    // existing sessions keep going in the grace time.
+                    global.drain();
+                    drain_deadline.get_or_insert(Instant::now() + grace);
+                }
+                Some(SendMessage::PauseDelivery) => {
+                    debug!("Outside: pause delivery.");
+                    global.pause_delivery();
+                }
+                Some(SendMessage::ResumeDelivery) => {
+                    debug!("Outside: resume delivery.");
+                    global.resume_delivery();
                 }
                 Some(SendMessage::NetworkStop) => {
+                    // the paused sessions can close.
+                    global.resume_delivery();
                     // clear all sessions
                     for (_, sender) in global.peer_list.read().await.all() {
                         let _ = sender.send(SessionMessage::Close).await;
@@ -672,3 +1384,1640 @@ pub async fn start_with_key(
 
     Ok(peer_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AddressFilter, FramePadding, NodeConfig};
+    use crate::global::origin_proof;
+    use crate::prelude::start;
+    use crate::storage::StorageFuture;
+    use chamomile_types::message::{ConnectTrace, PeerFilter};
+    use chamomile_types::types::{ConnectResult, Metadata};
+    use rand_chacha::rand_core::SeedableRng;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::OnceLock;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::sync::{mpsc, oneshot};
+
+    /// the db dir of a test node, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn local_addr(send: &Sender<SendMessage>) -> SocketAddr {
+        let (tx, mut rx) = mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::LocalAddrs, tx))
+            .await
+            .unwrap();
+        match rx.recv().await {
+            Some(StateResponse::LocalAddrs(addrs)) => addrs[0],
+            _ => panic!("local addrs response failure"),
+        }
+    }
+
+    /// start a node at a random port, `setup` overrides the default config.
+    /// return the listen address, the db dir is removed when the test runtime dropped.
+    async fn test_node(
+        name: &str,
+        setup: impl FnOnce(&mut Config),
+    ) -> (
+        PeerId,
+        Sender<SendMessage>,
+        Receiver<ReceiveMessage>,
+        SocketAddr,
+    ) {
+        let dir = TestDir(std::env::temp_dir().join(format!(
+            "chamomile-test-{}-{}",
+            std::process::id(),
+            name
+        )));
+        let mut config = Config::default(Peer::socket("127.0.0.1:0".parse().unwrap()));
+        config.db_dir = dir.0.clone();
+        setup(&mut config);
+        let (peer_id, send, recv) = start(config).await.unwrap();
+        let addr = local_addr(&send).await;
+        tokio::spawn(async move {
+            let _dir = dir;
+            std::future::pending::<()>().await
+        });
+        (peer_id, send, recv, addr)
+    }
+
+    fn drain(mut recv: Receiver<ReceiveMessage>) {
+        tokio::spawn(async move { while recv.recv().await.is_some() {} });
+    }
+
+    async fn dht_peers(send: &Sender<SendMessage>) -> Vec<PeerId> {
+        let (res_send, mut res_recv) = tokio::sync::mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::DHT, res_send))
+            .await
+            .unwrap();
+        match res_recv.recv().await {
+            Some(StateResponse::DHT(peers)) => peers,
+            _ => vec![],
+        }
+    }
+
+    async fn wait_dht(send: &Sender<SendMessage>, id: &PeerId) {
+        for _ in 0..50 {
+            if dht_peers(send).await.contains(id) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("DHT connect timeout");
+    }
+
+    #[tokio::test]
+    async fn test_connect_peer_id() {
+        // B keeps others to itself when handshake, so A - B - C, A donnot know C.
+        let gossip = Arc::new(AtomicBool::new(false));
+        let b_self = Arc::new(OnceLock::new());
+        let (b_gossip, b_own) = (gossip.clone(), b_self.clone());
+        let b_filter = AddressFilter::new(move |addrs| {
+            if b_gossip.load(Ordering::SeqCst) || b_own.get().is_some_and(|a| addrs.contains(a)) {
+                addrs
+            } else {
+                vec![]
+            }
+        });
+        let (a_id, a_send, a_recv, _) = test_node("a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("b", |c| c.address_filter = b_filter).await;
+        b_self.set(b_addr).unwrap();
+        let (c_id, c_send, c_recv, c_addr) = test_node("c", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+        drain(c_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        b_send
+            .send(SendMessage::Connect(Peer::socket(c_addr)))
+            .await
+            .unwrap();
+        wait_dht(&b_send, &c_id).await;
+        assert!(!dht_peers(&a_send).await.contains(&c_id));
+        gossip.store(true, Ordering::SeqCst);
+
+        // A connect to C only by peer id, B will answer it.
+        let (res_send, mut res_recv) = tokio::sync::mpsc::channel(1);
+        a_send
+            .send(SendMessage::ConnectPeerId(c_id, res_send))
+            .await
+            .unwrap();
+        let peer = res_recv.recv().await.unwrap().unwrap();
+        assert_eq!(peer.id, c_id);
+        assert!(dht_peers(&a_send).await.contains(&c_id));
+        assert!(dht_peers(&c_send).await.contains(&a_id));
+
+        for send in [a_send, b_send, c_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_data() {
+        let (_, a_send, a_recv, _) = test_node("large-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("large-b", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+
+        let len = 1024 * 1024 + 7;
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        a_send
+            .send(SendMessage::LargeData(b_id, data))
+            .await
+            .unwrap();
+
+        let mut chunks = loop {
+            match b_recv.recv().await.unwrap() {
+                ReceiveMessage::LargeData(_, _, chunks) => break chunks,
+                _ => continue,
+            }
+        };
+
+        // consume chunk by chunk, slow than sender, only check and drop them.
+        let mut received = 0;
+        loop {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let chunk = chunks.recv().await.unwrap();
+            assert_eq!(chunk.offset, received as u64);
+            assert!(chunk.data.len() <= 65536);
+            for (i, b) in chunk.data.iter().enumerate() {
+                assert_eq!(*b, ((received + i) % 251) as u8);
+            }
+            received += chunk.data.len();
+            if chunk.is_final {
+                break;
+            }
+        }
+        assert_eq!(received, len);
+
+        for send in [a_send, b_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    async fn wait_leave(recv: &mut Receiver<ReceiveMessage>) -> (PeerId, CloseReason) {
+        loop {
+            if let ReceiveMessage::PeerLeave(id, reason) = recv.recv().await.unwrap() {
+                return (id, reason);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_disconnect() {
+        let (a_id, a_send, mut a_recv, _) = test_node("kick-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("kick-b", |_| {}).await;
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&b_send, &a_id).await;
+
+        a_send
+            .send(SendMessage::PeerDisconnect(b_id, CloseReason::Kick))
+            .await
+            .unwrap();
+        assert_eq!(wait_leave(&mut a_recv).await, (b_id, CloseReason::Kick));
+        assert_eq!(wait_leave(&mut b_recv).await, (a_id, CloseReason::Kick));
+        for _ in 0..10 {
+            if !dht_peers(&a_send).await.contains(&b_id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!dht_peers(&a_send).await.contains(&b_id));
+
+        // already gone, do nothing.
+        a_send
+            .send(SendMessage::PeerDisconnect(b_id, CloseReason::Kick))
+            .await
+            .unwrap();
+        assert!(!dht_peers(&a_send).await.contains(&b_id));
+        assert!(a_recv.try_recv().is_err());
+
+        for send in [a_send, b_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    async fn session_states(send: &Sender<SendMessage>, id: &PeerId) -> Vec<SessionState> {
+        let (res_send, mut res_recv) = tokio::sync::mpsc::channel(1);
+        let request = StateRequest::SessionState(*id);
+        send.send(SendMessage::NetworkState(request, res_send))
+            .await
+            .unwrap();
+        match res_recv.recv().await {
+            Some(StateResponse::SessionState(states)) => states,
+            _ => panic!("session state response missing"),
+        }
+    }
+
+    async fn wait_state(send: &Sender<SendMessage>, id: &PeerId, state: SessionState) {
+        for _ in 0..50 {
+            if session_states(send, id).await.last() == Some(&state) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("session state {:?} missing", state);
+    }
+
+    #[tokio::test]
+    async fn test_session_state() {
+        use SessionState::*;
+        let (a_id, a_send, a_recv, _) = test_node("state-a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("state-b", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+        assert!(session_states(&a_send, &b_id).await.is_empty());
+
+        let mut b_peer = Peer::socket(b_addr);
+        b_peer.id = b_id;
+        a_send.send(SendMessage::Connect(b_peer)).await.unwrap();
+        wait_state(&a_send, &b_id, Ready).await;
+        wait_state(&b_send, &a_id, Ready).await;
+        let dialer = vec![Connecting, Handshaking, KeyExchange, Ready];
+        assert_eq!(session_states(&a_send, &b_id).await, dialer);
+        assert_eq!(
+            session_states(&b_send, &a_id).await,
+            vec![KeyExchange, Ready]
+        );
+
+        let (tx, rx) = oneshot::channel();
+        a_send
+            .send(SendMessage::StopSession(b_id, CloseReason::Normal, tx))
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().is_ok());
+        let closed = [dialer, vec![Closing, Closed]].concat();
+        assert_eq!(session_states(&a_send, &b_id).await, closed);
+        wait_state(&b_send, &a_id, Closed).await;
+        assert_eq!(
+            session_states(&b_send, &a_id).await,
+            vec![KeyExchange, Ready, Closing, Closed]
+        );
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_stop_session() {
+        let (a_id, a_send, mut a_recv, _) = test_node("stop-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("stop-b", |_| {}).await;
+        let (c_id, c_send, mut c_recv, c_addr) = test_node("stop-c", |_| {}).await;
+
+        for addr in [b_addr, c_addr] {
+            a_send
+                .send(SendMessage::Connect(Peer::socket(addr)))
+                .await
+                .unwrap();
+        }
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&a_send, &c_id).await;
+        wait_dht(&b_send, &a_id).await;
+        wait_dht(&c_send, &a_id).await;
+
+        let (tx, rx) = oneshot::channel();
+        a_send
+            .send(SendMessage::StopSession(b_id, CloseReason::Normal, tx))
+            .await
+            .unwrap();
+        // resolved when the session is closed, after the leave is told.
+        assert!(rx.await.unwrap().is_ok());
+        let mut left = false;
+        while let Ok(msg) = a_recv.try_recv() {
+            left |= matches!(msg, ReceiveMessage::PeerLeave(p, CloseReason::Normal) if p == b_id);
+        }
+        assert!(left);
+        assert_eq!(wait_leave(&mut b_recv).await, (a_id, CloseReason::Normal));
+
+        // the node and other sessions keep running.
+        a_send
+            .send(SendMessage::Data(0, c_id, vec![1, 2, 3]))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        assert_eq!(recv_data(&mut c_recv, wait).await, Some(vec![1, 2, 3]));
+        assert!(dht_peers(&a_send).await.contains(&c_id));
+
+        // not connected.
+        let (tx, rx) = oneshot::channel();
+        a_send
+            .send(SendMessage::StopSession(b_id, CloseReason::Normal, tx))
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().is_err());
+
+        for send in [a_send, b_send, c_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    async fn connect_trace(send: &Sender<SendMessage>, id: &PeerId) -> Option<ConnectTrace> {
+        let (res_send, mut res_recv) = tokio::sync::mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::Stats, res_send))
+            .await
+            .unwrap();
+        match res_recv.recv().await {
+            Some(StateResponse::Stats(stats)) => stats
+                .connects
+                .into_iter()
+                .find(|(pid, _)| pid == id)
+                .map(|(_, t)| t),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_trace() {
+        let (a_id, a_send, a_recv, _) = test_node("trace-a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("trace-b", |_| {}).await;
+        let (c_id, c_send, c_recv, c_addr) = test_node("trace-c", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+        drain(c_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&b_send, &a_id).await;
+        b_send
+            .send(SendMessage::Data(0, a_id, vec![1, 2, 3]))
+            .await
+            .unwrap();
+
+        let mut trace = None;
+        for _ in 0..50 {
+            trace = connect_trace(&a_send, &b_id)
+                .await
+                .filter(|t| t.first_data.is_some());
+            if trace.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let trace = trace.expect("connect trace missing");
+        assert!(trace.transport > Duration::ZERO);
+        assert!(trace.handshake >= trace.transport);
+        assert!(trace.session_key >= trace.handshake);
+        assert!(trace.first_data.unwrap() >= trace.session_key);
+
+        // accepted side is measured from the first transport event.
+        let trace = connect_trace(&b_send, &a_id).await.expect("inbound trace");
+        assert!(trace.transport > Duration::ZERO);
+        assert!(trace.handshake >= trace.transport);
+
+        // stable connect is recorded too.
+        let mut c_peer = Peer::socket(c_addr);
+        c_peer.id = c_id;
+        a_send
+            .send(SendMessage::StableConnect(0, c_peer, vec![]))
+            .await
+            .unwrap();
+        let mut trace = None;
+        for _ in 0..50 {
+            trace = connect_trace(&a_send, &c_id).await;
+            if trace.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let trace = trace.expect("stable connect trace missing");
+        assert!(trace.transport > Duration::ZERO);
+        assert!(trace.session_key >= trace.handshake);
+
+        // removed when the session closed.
+        let (tx, rx) = oneshot::channel();
+        a_send
+            .send(SendMessage::StopSession(b_id, CloseReason::Normal, tx))
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().is_ok());
+        assert!(connect_trace(&a_send, &b_id).await.is_none());
+
+        for send in [a_send, b_send, c_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        let (a_id, a_send, a_recv, _) = test_node("observe-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("observe-b", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&b_send, &a_id).await;
+
+        // observer only has room for one message, and not read until the end.
+        let (observer, mut observe_recv) = tokio::sync::mpsc::channel(1);
+        b_send
+            .send(SendMessage::Observe(Some(observer)))
+            .await
+            .unwrap();
+        for i in 0..5u8 {
+            a_send
+                .send(SendMessage::Data(0, b_id, vec![i]))
+                .await
+                .unwrap();
+        }
+
+        let mut received = vec![];
+        let mut first = None;
+        while received.len() < 5 {
+            let msg = tokio::time::timeout(Duration::from_secs(5), b_recv.recv())
+                .await
+                .expect("delivery blocked by observer")
+                .unwrap();
+            if let ReceiveMessage::Data(from, data, _) = msg {
+                assert_eq!(from, a_id);
+                if first.is_none() {
+                    first = Some(data.clone());
+                }
+                received.push(data[0]);
+            }
+        }
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+
+        // observer keeps the first data, the later ones are dropped.
+        let mut observed = vec![];
+        while let Ok(msg) = observe_recv.try_recv() {
+            observed.push(msg);
+        }
+        assert_eq!(observed.len(), 1);
+        match &observed[0] {
+            ReceiveMessage::Data(from, data, _) => {
+                assert_eq!(from, &a_id);
+                assert_eq!(Some(data), first.as_ref());
+            }
+            msg => panic!("unexpected observed message: {:?}", msg),
+        }
+
+        for send in [a_send, b_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocols() {
+        let (a_id, a_send, a_recv, _) = test_node("protocol-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("protocol-b", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&b_send, &a_id).await;
+
+        let (chat, mut chat_recv) = mpsc::channel(8);
+        let (file, mut file_recv) = mpsc::channel(8);
+        b_send
+            .send(SendMessage::Protocol(1, Some(chat)))
+            .await
+            .unwrap();
+        b_send
+            .send(SendMessage::Protocol(2, Some(file)))
+            .await
+            .unwrap();
+        // the registration is handled before the state request.
+        health(&b_send).await;
+
+        for (protocol, data) in [
+            (1, b"hello".to_vec()),
+            (2, b"file".to_vec()),
+            (3, b"unknown".to_vec()),
+            (1, b"bye".to_vec()),
+        ] {
+            a_send
+                .send(SendMessage::ProtocolData(0, b_id, protocol, data))
+                .await
+                .unwrap();
+        }
+        // plain data is never routed by its bytes.
+        a_send
+            .send(SendMessage::Data(0, b_id, vec![0, 1, 7]))
+            .await
+            .unwrap();
+
+        let wait = Duration::from_secs(5);
+        assert_eq!(
+            recv_data(&mut chat_recv, wait).await,
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(recv_data(&mut chat_recv, wait).await, Some(b"bye".to_vec()));
+        assert_eq!(
+            recv_data(&mut file_recv, wait).await,
+            Some(b"file".to_vec())
+        );
+        // not registered protocol goes to the default receiver with its id.
+        assert_eq!(
+            recv_protocol(&mut b_recv, wait).await,
+            Some((3, b"unknown".to_vec()))
+        );
+        assert_eq!(recv_data(&mut b_recv, wait).await, Some(vec![0, 1, 7]));
+        assert!(chat_recv.try_recv().is_err());
+        assert!(file_recv.try_recv().is_err());
+
+        // the full receiver is not waited, the overflow goes to the default receiver.
+        for i in 0..10u8 {
+            a_send
+                .send(SendMessage::ProtocolData(0, b_id, 2, vec![i]))
+                .await
+                .unwrap();
+        }
+        for i in 8..10u8 {
+            assert_eq!(recv_protocol(&mut b_recv, wait).await, Some((2, vec![i])));
+        }
+        for i in 0..8u8 {
+            assert_eq!(recv_data(&mut file_recv, wait).await, Some(vec![i]));
+        }
+        assert!(file_recv.try_recv().is_err());
+
+        for send in [a_send, b_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outside_dropped() {
+        let (_, send, recv, _) = test_node("outside-dropped", |_| {}).await;
+        drop(recv);
+
+        // the node stops, and not receive the outside messages anymore.
+        tokio::time::timeout(Duration::from_secs(5), send.closed())
+            .await
+            .expect("node still running without outside receiver");
+        assert!(send.send(SendMessage::NetworkStop).await.is_err());
+    }
+
+    async fn health(send: &Sender<SendMessage>) -> HealthStatus {
+        let (res_send, mut res_recv) = tokio::sync::mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::Health, res_send))
+            .await
+            .unwrap();
+        match res_recv.recv().await {
+            Some(StateResponse::Health(status)) => status,
+            _ => panic!("health response missing"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let (_, a_send, a_recv, _) = test_node("health-a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("health-b", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+
+        // isolated node, bound but no peers.
+        let status = health(&a_send).await;
+        assert!(status.bound);
+        assert_eq!(status.peers, 0);
+        assert!(!status.bootstrapped);
+        assert!(status.last_activity.is_none());
+        assert!(!status.is_healthy());
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+
+        // session maybe not running yet when added to DHT.
+        let mut status = health(&a_send).await;
+        for _ in 0..50 {
+            if status.bootstrapped {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            status = health(&a_send).await;
+        }
+        assert!(status.bound);
+        assert_eq!(status.peers, 1);
+        assert!(status.bootstrapped);
+        assert!(status.last_activity.is_some());
+        assert!(status.is_healthy());
+
+        for send in [a_send, b_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_self() {
+        let (_, a_send, a_recv, a_addr) = test_node("self-a", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(a_addr)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert!(dht_peers(&a_send).await.is_empty());
+        let status = health(&a_send).await;
+        assert_eq!(status.peers, 0);
+        assert!(!status.bootstrapped);
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+    }
+
+    async fn connect_wait(send: &Sender<SendMessage>, addr: SocketAddr) -> ConnectResult {
+        let (tx, mut rx) = mpsc::channel(1);
+        send.send(SendMessage::ConnectWait(Peer::socket(addr), tx))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_wait() {
+        let (_, a_send, a_recv, a_addr) = test_node("wait-a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("wait-b", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+
+        // connected.
+        assert_eq!(connect_wait(&a_send, b_addr).await, Ok(b_id));
+        // connect to self.
+        assert_eq!(
+            connect_wait(&a_send, a_addr).await,
+            Err(ConnectError::Rejected)
+        );
+        // nobody listen, the port is bound and not listening.
+        let closed =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        closed.bind(&any.into()).unwrap();
+        let closed_addr = closed.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(
+            connect_wait(&a_send, closed_addr).await,
+            Err(ConnectError::Unreachable)
+        );
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_data() {
+        let (a_id, a_send, a_recv, _) = test_node("data-a", |_| {}).await;
+        let (_, b_send, mut b_recv, b_addr) = test_node("data-b", |_| {}).await;
+        drain(a_recv);
+
+        // no ticket, data is sent after connected.
+        let data = b"first".to_vec();
+        a_send
+            .send(SendMessage::ConnectData(Peer::socket(b_addr), data.clone()))
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(ReceiveMessage::Data(from, bytes, _)) = b_recv.recv().await {
+                    return (from, bytes);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, (a_id, data));
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let (a_id, a_send, a_recv, _) = test_node("meta-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("meta-b", |_| {}).await;
+        drain(a_recv);
+        assert_eq!(connect_wait(&a_send, b_addr).await, Ok(b_id));
+
+        let meta = Metadata::from([
+            ("content-type".to_owned(), b"application/json".to_vec()),
+            ("seq".to_owned(), 1u64.to_le_bytes().to_vec()),
+        ]);
+        let data = b"{}".to_vec();
+        a_send
+            .send(SendMessage::Metadata(0, b_id, meta.clone(), data.clone()))
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(ReceiveMessage::Metadata(from, m, bytes)) = b_recv.recv().await {
+                    return (from, m, bytes);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, (a_id, meta, data));
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_stable_buffer_order() {
+        let (a_id, a_send, a_recv, _) = test_node("order-a", |_| {}).await;
+        let (_, b_send, mut b_recv, b_addr) = test_node("order-b", |_| {}).await;
+        drain(a_recv);
+
+        // all sent before the stable handshake completed, buffered in order.
+        for i in 0..5u8 {
+            a_send
+                .send(SendMessage::StableConnect(0, Peer::socket(b_addr), vec![i]))
+                .await
+                .unwrap();
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut received = vec![];
+            while received.len() < 5 {
+                if let Some(ReceiveMessage::StableConnect(peer, data)) = b_recv.recv().await {
+                    assert_eq!(peer.id, a_id);
+                    received.push(data[0]);
+                }
+            }
+            received
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_network_drain() {
+        let (a_id, a_send, a_recv, _) = test_node("drain-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("drain-b", |_| {}).await;
+        let (_, c_send, c_recv, _) = test_node("drain-c", |_| {}).await;
+        drain(a_recv);
+        drain(c_recv);
+        assert_eq!(connect_wait(&a_send, b_addr).await, Ok(b_id));
+
+        b_send
+            .send(SendMessage::NetworkDrain(Duration::from_millis(800)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // new inbound is refused.
+        assert_eq!(
+            connect_wait(&c_send, b_addr).await,
+            Err(ConnectError::Rejected)
+        );
+
+        // the existing session keep going.
+        a_send
+            .send(SendMessage::Data(0, b_id, b"in-flight".to_vec()))
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(ReceiveMessage::Data(from, bytes, _)) = b_recv.recv().await {
+                    return (from, bytes);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, (a_id, b"in-flight".to_vec()));
+
+        // stopped after the grace time.
+        let stopped = tokio::time::timeout(Duration::from_secs(3), async {
+            while !b_send.is_closed() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(stopped.is_ok());
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = c_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_addrs() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (_, send, recv, _) = test_node("local-addrs", |_| {}).await;
+        drain(recv);
+
+        let (tx, mut rx) = mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::LocalAddrs, tx))
+            .await
+            .unwrap();
+        match rx.recv().await {
+            Some(StateResponse::LocalAddrs(addrs)) => {
+                assert_eq!(addrs.len(), 1);
+                assert_eq!(addrs[0].ip(), addr.ip());
+                assert_ne!(addrs[0].port(), 0);
+            }
+            _ => panic!("local addrs response failure"),
+        }
+
+        let _ = send.send(SendMessage::NetworkStop).await;
+    }
+
+    async fn peer_ready(send: &Sender<SendMessage>, peer_id: PeerId) -> bool {
+        let (tx, mut rx) = mpsc::channel(1);
+        send.send(SendMessage::NetworkState(
+            StateRequest::PeerReady(peer_id),
+            tx,
+        ))
+        .await
+        .unwrap();
+        match rx.recv().await {
+            Some(StateResponse::PeerReady(ready)) => ready,
+            _ => panic!("peer ready response failure"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_ready() {
+        let (_, a_send, a_recv, _) = test_node("ready-a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("ready-b", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        assert!(!peer_ready(&a_send, b_id).await);
+
+        let ready = tokio::time::timeout(Duration::from_secs(5), async {
+            while !peer_ready(&a_send, b_id).await {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(ready.is_ok());
+
+        // other peer is not connected, not the connected closest one.
+        assert!(!peer_ready(&a_send, PeerId([7u8; 20])).await);
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    async fn recv_data(recv: &mut Receiver<ReceiveMessage>, wait: Duration) -> Option<Vec<u8>> {
+        tokio::time::timeout(wait, async {
+            loop {
+                match recv.recv().await {
+                    Some(ReceiveMessage::Data(_, data, _)) => return Some(data),
+                    Some(_) => continue,
+                    None => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    async fn recv_protocol(
+        recv: &mut Receiver<ReceiveMessage>,
+        wait: Duration,
+    ) -> Option<(u16, Vec<u8>)> {
+        tokio::time::timeout(wait, async {
+            loop {
+                match recv.recv().await {
+                    Some(ReceiveMessage::Protocol(_, protocol, data, _)) => {
+                        return Some((protocol, data))
+                    }
+                    Some(_) => continue,
+                    None => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_data_order() {
+        let (a_id, a_send, a_recv, _) = test_node("data-order-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("data-order-b", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&b_send, &a_id).await;
+
+        const COUNT: u32 = 5000;
+        let sender = a_send.clone();
+        tokio::spawn(async move {
+            for i in 0..COUNT {
+                let msg = SendMessage::Data(0, b_id, i.to_be_bytes().to_vec());
+                sender.send(msg).await.unwrap();
+            }
+        });
+
+        // strictly in send order over the direct session.
+        for i in 0..COUNT {
+            let data = recv_data(&mut b_recv, Duration::from_secs(10)).await;
+            assert_eq!(data, Some(i.to_be_bytes().to_vec()));
+        }
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_sealed_data() {
+        let (_, a_send, a_recv, _) = test_node("sealed-a", |_| {}).await;
+        drain(a_recv);
+        let mut peers = vec![];
+        for name in ["sealed-b", "sealed-c", "sealed-d"] {
+            let (id, send, recv, addr) = test_node(name, |_| {}).await;
+            a_send
+                .send(SendMessage::Connect(Peer::socket(addr)))
+                .await
+                .unwrap();
+            wait_dht(&a_send, &id).await;
+            peers.push((id, send, recv));
+        }
+
+        let data = vec![9u8; 1000];
+        let ids = peers.iter().map(|(id, _, _)| *id).collect();
+        a_send
+            .send(SendMessage::SealedData(ids, data.clone()))
+            .await
+            .unwrap();
+        for (_, send, mut recv) in peers {
+            let received = recv_data(&mut recv, Duration::from_secs(5)).await;
+            assert_eq!(received, Some(data.clone()));
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_data_where() {
+        let (_, a_send, a_recv, a_addr) = test_node("where-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("where-b", |_| {}).await;
+        let (c_id, c_send, mut c_recv, _) = test_node("where-c", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        c_send
+            .send(SendMessage::Connect(Peer::socket(a_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&a_send, &c_id).await;
+
+        let filter = PeerFilter::new(move |info| info.peer.id == b_id && info.is_direct);
+        a_send
+            .send(SendMessage::DataWhere(filter, vec![1, 2, 3]))
+            .await
+            .unwrap();
+
+        let wait = Duration::from_millis(500);
+        assert_eq!(recv_data(&mut b_recv, wait).await, Some(vec![1, 2, 3]));
+        assert_eq!(recv_data(&mut c_recv, wait).await, None);
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+        let _ = c_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_udt_transport() {
+        let udt = |c: &mut Config| c.peer.transport = TransportType::UDT;
+        let (_, a_send, a_recv, _) = test_node("udt-a", udt).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("udt-b", udt).await;
+        drain(a_recv);
+        let mut b_peer = Peer::socket(b_addr);
+        b_peer.transport = TransportType::UDT;
+
+        a_send.send(SendMessage::Connect(b_peer)).await.unwrap();
+        wait_dht(&a_send, &b_id).await;
+        let large = vec![7u8; 100_000];
+        a_send
+            .send(SendMessage::Data(0, b_id, large.clone()))
+            .await
+            .unwrap();
+        let wait = Duration::from_secs(5);
+        assert_eq!(recv_data(&mut b_recv, wait).await, Some(large));
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_server_mode() {
+        // S not tell others in handshake, so A - S - C, A send to C by S relay.
+        let s_self = Arc::new(OnceLock::new());
+        let s_own = s_self.clone();
+        let (s_id, s_send, mut s_recv, s_addr) = test_node("server-s", |c| {
+            *c = Config {
+                db_dir: c.db_dir.clone(),
+                address_filter: AddressFilter::new(move |addrs| {
+                    if s_own.get().is_some_and(|a| addrs.contains(a)) {
+                        addrs
+                    } else {
+                        vec![]
+                    }
+                }),
+                ..Config::server(c.peer)
+            }
+        })
+        .await;
+        s_self.set(s_addr).unwrap();
+        let (_, a_send, a_recv, _) = test_node("server-a", |_| {}).await;
+        let (c_id, c_send, mut c_recv, _) = test_node("server-c", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(s_addr)))
+            .await
+            .unwrap();
+        c_send
+            .send(SendMessage::Connect(Peer::socket(s_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &s_id).await;
+        wait_dht(&c_send, &s_id).await;
+        assert!(!dht_peers(&a_send).await.contains(&c_id));
+
+        a_send
+            .send(SendMessage::Data(0, c_id, vec![1, 2, 3]))
+            .await
+            .unwrap();
+        a_send
+            .send(SendMessage::Data(0, s_id, vec![4, 5, 6]))
+            .await
+            .unwrap();
+
+        let wait = Duration::from_millis(500);
+        assert_eq!(recv_data(&mut c_recv, wait).await, Some(vec![1, 2, 3]));
+        assert_eq!(recv_data(&mut s_recv, wait).await, None);
+
+        for send in [a_send, s_send, c_send] {
+            let _ = send.send(SendMessage::NetworkStop).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let (_, a_send, a_recv, _) = test_node("flush-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("flush-b", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+
+        for i in 0..20u8 {
+            a_send
+                .send(SendMessage::Data(0, b_id, vec![i; 1024]))
+                .await
+                .unwrap();
+        }
+        let (tx, rx) = oneshot::channel();
+        a_send.send(SendMessage::Flush(b_id, tx)).await.unwrap();
+        assert!(rx.await.unwrap().is_ok());
+        a_send
+            .send(SendMessage::PeerDisconnect(b_id, CloseReason::Normal))
+            .await
+            .unwrap();
+
+        for i in 0..20u8 {
+            let data = recv_data(&mut b_recv, Duration::from_secs(2)).await;
+            assert_eq!(data, Some(vec![i; 1024]));
+        }
+
+        // not connected.
+        let (tx, rx) = oneshot::channel();
+        a_send.send(SendMessage::Flush(b_id, tx)).await.unwrap();
+        assert!(rx.await.unwrap().is_err());
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    /// wait the live sessions is the count.
+    async fn wait_sessions(send: &Sender<SendMessage>, count: usize) {
+        for _ in 0..50 {
+            let (tx, mut rx) = mpsc::channel(1);
+            send.send(SendMessage::NetworkState(StateRequest::Stats, tx))
+                .await
+                .unwrap();
+            if let Some(StateResponse::Stats(stats)) = rx.recv().await {
+                if stats.sessions == count {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("live sessions not {}", count);
+    }
+
+    #[tokio::test]
+    async fn test_node_config() {
+        let (a_id, a_send, a_recv, _) = test_node("node-config-a", |c| {
+            *c = NodeConfig::new(c.peer)
+                .db_dir(c.db_dir.clone())
+                .frame_padding(FramePadding::PowerOfTwo)
+                .max_clock_skew(5)
+                .build()
+                .unwrap()
+        })
+        .await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("node-config-b", |_| {}).await;
+        drain(a_recv);
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+        wait_dht(&b_send, &a_id).await;
+        a_send
+            .send(SendMessage::Data(0, b_id, vec![1; 100]))
+            .await
+            .unwrap();
+        let data = recv_data(&mut b_recv, Duration::from_secs(5)).await;
+        assert_eq!(data, Some(vec![1; 100]));
+
+        // the frames are padded.
+        let (tx, mut rx) = mpsc::channel(1);
+        a_send
+            .send(SendMessage::NetworkState(StateRequest::Stats, tx))
+            .await
+            .unwrap();
+        let stats = match rx.recv().await {
+            Some(StateResponse::Stats(stats)) => stats,
+            _ => panic!("no stats"),
+        };
+        let (_, sizes) = stats
+            .frame_sizes
+            .iter()
+            .find(|(p, _)| p == &b_id)
+            .expect("no frame sizes");
+        assert!(sizes.iter().all(|(size, _)| size.is_power_of_two()));
+
+        // the clock skew is 5s.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for (remote, valid) in [(now + 3, true), (now + 10, false)] {
+            let (tx, mut rx) = mpsc::channel(1);
+            a_send
+                .send(SendMessage::NetworkState(
+                    StateRequest::Timestamp(remote, 60),
+                    tx,
+                ))
+                .await
+                .unwrap();
+            assert!(matches!(
+                rx.recv().await,
+                Some(StateResponse::Timestamp(v)) if v == valid
+            ));
+        }
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_oneshot() {
+        let (_, a_send, a_recv, _) = test_node("oneshot-a", |_| {}).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("oneshot-b", |_| {}).await;
+        drain(a_recv);
+
+        let (tx, mut rx) = mpsc::channel(1);
+        a_send
+            .send(SendMessage::Oneshot(
+                Peer::socket(b_addr),
+                vec![1, 2, 3],
+                tx,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Ok(b_id));
+        let wait = Duration::from_secs(2);
+        assert_eq!(recv_data(&mut b_recv, wait).await, Some(vec![1, 2, 3]));
+
+        // no session remains in both.
+        assert!(dht_peers(&a_send).await.is_empty());
+        wait_sessions(&a_send, 0).await;
+        wait_sessions(&b_send, 0).await;
+        assert!(dht_peers(&b_send).await.is_empty());
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_data_from() {
+        let (g_id, g_send, g_recv, g_addr) =
+            test_node("from-g", |c| c.relay_on_behalf = true).await;
+        let (b_id, b_send, mut b_recv, _) = test_node("from-b", |_| {}).await;
+        let (n_id, n_send, n_recv, n_addr) = test_node("from-n", |_| {}).await;
+        drain(g_recv);
+        drain(n_recv);
+        assert!(connect_wait(&b_send, g_addr).await.is_ok());
+        assert!(connect_wait(&b_send, n_addr).await.is_ok());
+
+        let x_key = Key::generate(&mut ChaChaRng::from_seed([7; 32]));
+        let x_id = x_key.peer_id();
+        let proof = origin_proof(&x_key, &g_id, &b_id, &[1, 2, 3]);
+
+        // forged proof is dropped by the gateway.
+        let bad = origin_proof(&x_key, &g_id, &b_id, &[9]);
+        let msg = SendMessage::DataFrom(x_id, b_id, bad, vec![1, 2, 3]);
+        g_send.send(msg).await.unwrap();
+        // the proof is for other gateway, dropped.
+        let other = origin_proof(&x_key, &n_id, &b_id, &[1, 2, 3]);
+        let msg = SendMessage::DataFrom(x_id, b_id, other, vec![1, 2, 3]);
+        g_send.send(msg).await.unwrap();
+        // no relay_on_behalf, dropped.
+        let msg = SendMessage::DataFrom(x_id, b_id, proof.clone(), vec![8]);
+        n_send.send(msg).await.unwrap();
+
+        let msg = SendMessage::DataFrom(x_id, b_id, proof, vec![1, 2, 3]);
+        g_send.send(msg).await.unwrap();
+        let first = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(ReceiveMessage::Data(from, data, direct)) = b_recv.recv().await {
+                    return (from, data, direct);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(first, (x_id, vec![1, 2, 3], false));
+        let wait = Duration::from_millis(500);
+        assert_eq!(recv_data(&mut b_recv, wait).await, None);
+
+        let _ = g_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+        let _ = n_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_pause_delivery() {
+        let keepalive = |c: &mut Config| c.keepalive = 1;
+        let (a_id, a_send, mut a_recv, _) = test_node("pause-a", keepalive).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("pause-b", keepalive).await;
+        assert_eq!(connect_wait(&a_send, b_addr).await, Ok(b_id));
+        wait_dht(&b_send, &a_id).await;
+
+        b_send.send(SendMessage::PauseDelivery).await.unwrap();
+        // the pause is handled before the state request.
+        assert_eq!(dht_peers(&b_send).await, vec![a_id]);
+        // more than the session delivery queue.
+        for i in 0..200u8 {
+            a_send
+                .send(SendMessage::Data(0, b_id, vec![i]))
+                .await
+                .unwrap();
+        }
+        let wait = Duration::from_millis(500);
+        assert_eq!(recv_data(&mut b_recv, wait).await, None);
+
+        // the paused session still sends, and keeps alive over the keepalive timeout.
+        b_send
+            .send(SendMessage::Data(0, a_id, vec![7]))
+            .await
+            .unwrap();
+        assert_eq!(recv_data(&mut a_recv, wait).await, Some(vec![7]));
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(dht_peers(&b_send).await, vec![a_id]);
+        assert_eq!(recv_data(&mut b_recv, wait).await, None);
+
+        // nothing dropped, all in order.
+        b_send.send(SendMessage::ResumeDelivery).await.unwrap();
+        let wait = Duration::from_secs(2);
+        for i in 0..200u8 {
+            assert_eq!(recv_data(&mut b_recv, wait).await, Some(vec![i]));
+        }
+        drain(a_recv);
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_session_lifetime() {
+        let (a_id, a_send, a_recv, _) = test_node("lifetime-a", |c| c.session_lifetime = 1).await;
+        let (b_id, b_send, mut b_recv, b_addr) = test_node("lifetime-b", |_| {}).await;
+        drain(a_recv);
+        assert_eq!(connect_wait(&a_send, b_addr).await, Ok(b_id));
+        wait_dht(&b_send, &a_id).await;
+
+        // torn down by the dialer with the reason, and re-established,
+        // so the new session is torn down again.
+        for _ in 0..2 {
+            let leave = tokio::time::timeout(Duration::from_secs(4), async {
+                loop {
+                    if let Some(ReceiveMessage::PeerLeave(p, r)) = b_recv.recv().await {
+                        return (p, r);
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(leave, (a_id, CloseReason::Lifetime));
+        }
+        wait_dht(&a_send, &b_id).await;
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_session_lifetime_acceptor() {
+        let (b_id, b_send, b_recv, b_addr) =
+            test_node("lifetime-acceptor-b", |c| c.session_lifetime = 1).await;
+        let (a_id, a_send, mut a_recv, _) = test_node("lifetime-acceptor-a", |_| {}).await;
+        drain(b_recv);
+        assert_eq!(connect_wait(&a_send, b_addr).await, Ok(b_id));
+        wait_dht(&b_send, &a_id).await;
+
+        // torn down by the acceptor, and the dialer redials.
+        for _ in 0..2 {
+            let leave = tokio::time::timeout(Duration::from_secs(4), async {
+                loop {
+                    if let Some(ReceiveMessage::PeerLeave(p, r)) = a_recv.recv().await {
+                        return (p, r);
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(leave, (b_id, CloseReason::Lifetime));
+        }
+        wait_dht(&a_send, &b_id).await;
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[tokio::test]
+    async fn test_live_sessions() {
+        let (s_id, s_send, s_recv, s_addr) = test_node("sessions-s", |c| c.max_sessions = 1).await;
+        let (_, a_send, a_recv, _) = test_node("sessions-a", |_| {}).await;
+        let (_, c_send, c_recv, _) = test_node("sessions-c", |_| {}).await;
+        drain(s_recv);
+        drain(a_recv);
+        drain(c_recv);
+        wait_sessions(&s_send, 0).await;
+
+        assert_eq!(connect_wait(&a_send, s_addr).await, Ok(s_id));
+        wait_sessions(&s_send, 1).await;
+
+        // full, the overflow is rejected.
+        assert!(connect_wait(&c_send, s_addr).await.is_err());
+        wait_sessions(&s_send, 1).await;
+
+        a_send
+            .send(SendMessage::PeerDisconnect(s_id, CloseReason::Normal))
+            .await
+            .unwrap();
+        wait_sessions(&s_send, 0).await;
+        assert_eq!(connect_wait(&c_send, s_addr).await, Ok(s_id));
+        wait_sessions(&s_send, 1).await;
+
+        let _ = s_send.send(SendMessage::NetworkStop).await;
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = c_send.send(SendMessage::NetworkStop).await;
+    }
+
+    async fn topology(send: &Sender<SendMessage>) -> TopologySnapshot {
+        let (tx, mut rx) = mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::Topology, tx))
+            .await
+            .unwrap();
+        match rx.recv().await {
+            Some(StateResponse::Topology(snapshot)) => snapshot,
+            _ => panic!("topology response failure"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_topology() {
+        let (_, a_send, a_recv, _) = test_node("topology-a", |_| {}).await;
+        let (b_id, b_send, b_recv, b_addr) = test_node("topology-b", |_| {}).await;
+        let (_, c_send, c_recv, _) = test_node("topology-c", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+        drain(c_recv);
+        let secs = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        let start = secs();
+
+        a_send
+            .send(SendMessage::Connect(Peer::socket(b_addr)))
+            .await
+            .unwrap();
+        wait_dht(&a_send, &b_id).await;
+
+        // the time of last received frame in the session.
+        let snapshot = topology(&a_send).await;
+        let b = snapshot.peers.iter().find(|p| p.id == b_id).unwrap();
+        assert!(b.is_connected);
+        assert!(b.last_seen.is_some_and(|t| t >= start && t <= secs()));
+        assert_eq!(b.socket, b_addr);
+
+        // fresh node knows the peers, not connected.
+        c_send
+            .send(SendMessage::ImportTopology(snapshot))
+            .await
+            .unwrap();
+        let peers = topology(&c_send).await.peers;
+        let b = peers.iter().find(|p| p.socket == b_addr).unwrap();
+        assert!(!b.is_connected && b.last_seen.is_none());
+        assert_eq!(b.id, b_id);
+        assert!(dht_peers(&c_send).await.is_empty());
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+        let _ = c_send.send(SendMessage::NetworkStop).await;
+    }
+
+    #[derive(Default)]
+    struct MemoryStorage(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl Storage for MemoryStorage {
+        fn get<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<Vec<u8>>> {
+            Box::pin(async move { Ok(self.0.lock().unwrap().get(key).cloned()) })
+        }
+
+        fn put<'a>(&'a self, key: &'a str, value: Vec<u8>) -> StorageFuture<'a, ()> {
+            Box::pin(async move {
+                self.0.lock().unwrap().insert(key.to_owned(), value);
+                Ok(())
+            })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+            Box::pin(async move {
+                self.0.lock().unwrap().remove(key);
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rng_seed() {
+        let seeded = |c: &mut Config| c.rng_seed = Some([3u8; 32]);
+        let (a_id, a_send, _a_recv, _) = test_node("rng-seed-a", seeded).await;
+        let (b_id, b_send, _b_recv, _) = test_node("rng-seed-b", seeded).await;
+        assert_eq!(a_id, b_id);
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+
+        let (c_id, _, _, _) = test_node("rng-seed-c", |_| {}).await;
+        assert_ne!(a_id, c_id);
+    }
+
+    #[tokio::test]
+    async fn test_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+
+        // key is generated once, and loaded after.
+        let rng = &mut ChaChaRng::from_entropy();
+        let key = get_keypair(storage.as_ref(), false, rng).await.unwrap();
+        let key2 = get_keypair(storage.as_ref(), false, rng).await.unwrap();
+        assert_eq!(key.peer_id(), key2.peer_id());
+        let assist = get_assist(storage.as_ref(), rng).await;
+        assert_eq!(assist, get_assist(storage.as_ref(), rng).await);
+
+        // peer list.
+        let peer = Peer::socket("1.2.3.4:7364".parse().unwrap());
+        let peer_list = PeerList::load(
+            key.peer_id(),
+            assist,
+            storage.clone(),
+            &[],
+            vec![peer],
+            (vec![], vec![]),
+        );
+        peer_list.save().await;
+
+        let saved = storage.get(STORAGE_PEER_LIST_KEY).await.unwrap().unwrap();
+        let peer_list = PeerList::load(
+            key.peer_id(),
+            assist,
+            storage.clone(),
+            &saved,
+            vec![],
+            (vec![], vec![]),
+        );
+        let sockets: Vec<_> = peer_list.bootstrap().iter().map(|p| p.socket).collect();
+        assert_eq!(sockets, vec![peer.socket]);
+
+        // tickets.
+        let remote = Key::generate(rng).peer_id();
+        let mut tickets = Tickets::load(&key, storage.clone(), &[], true);
+        tickets.add(remote, [4u8; 32]).await;
+        let saved = storage.get(STORAGE_TICKETS_KEY).await.unwrap().unwrap();
+        let tickets = Tickets::load(&key, storage.clone(), &saved, true);
+        assert_eq!(tickets.get(&remote), Some([4u8; 32]));
+
+        storage.delete(STORAGE_KEY_KEY).await.unwrap();
+        assert!(storage.get(STORAGE_KEY_KEY).await.unwrap().is_none());
+    }
+
+    async fn banned(send: &Sender<SendMessage>) -> Vec<PeerId> {
+        let (tx, mut rx) = mpsc::channel(1);
+        send.send(SendMessage::NetworkState(StateRequest::Banned, tx))
+            .await
+            .unwrap();
+        match rx.recv().await {
+            Some(StateResponse::Banned(peers)) => peers.into_iter().map(|(p, _)| p).collect(),
+            _ => panic!("banned response failure"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ban() {
+        let (a_id, a_send, a_recv, a_addr) = test_node("ban-a", |_| {}).await;
+        let (b_id, b_send, b_recv, _) = test_node("ban-b", |_| {}).await;
+        drain(a_recv);
+        drain(b_recv);
+
+        // manual ban, permanent.
+        a_send.send(SendMessage::Ban(b_id, None)).await.unwrap();
+        assert_eq!(banned(&a_send).await, vec![b_id]);
+        assert_eq!(
+            connect_wait(&b_send, a_addr).await,
+            Err(ConnectError::Rejected)
+        );
+
+        // unban, can connect again.
+        a_send.send(SendMessage::Unban(b_id)).await.unwrap();
+        assert!(banned(&a_send).await.is_empty());
+        assert_eq!(connect_wait(&b_send, a_addr).await, Ok(a_id));
+        wait_dht(&a_send, &b_id).await;
+
+        // timed ban, kick the connected, and expired later.
+        let duration = Duration::from_secs(1);
+        a_send
+            .send(SendMessage::Ban(b_id, Some(duration)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!dht_peers(&a_send).await.contains(&b_id));
+        assert_eq!(
+            connect_wait(&b_send, a_addr).await,
+            Err(ConnectError::Rejected)
+        );
+
+        tokio::time::sleep(duration).await;
+        assert!(banned(&a_send).await.is_empty());
+        assert_eq!(connect_wait(&b_send, a_addr).await, Ok(a_id));
+
+        let _ = a_send.send(SendMessage::NetworkStop).await;
+        let _ = b_send.send(SendMessage::NetworkStop).await;
+    }
+}
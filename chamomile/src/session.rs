@@ -2,26 +2,63 @@ use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::{
     io::Result,
     select,
-    sync::mpsc::{self, Receiver, Sender},
-    time::{interval, sleep},
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        oneshot, OwnedSemaphorePermit,
+    },
+    time::{interval, sleep, sleep_until, timeout_at, Instant},
 };
 
 use chamomile_types::{
     delivery_split,
-    message::{DeliveryType, ReceiveMessage},
-    types::new_io_error,
+    message::{DataChunk, DeliveryType, ReceiveMessage},
+    types::{new_io_error, CloseReason, Metadata, PeerTier, Priority, SessionState},
     Peer, PeerId,
 };
 
 use crate::buffer::BufferKey;
-use crate::global::Global;
+use crate::config::FramePadding;
+use crate::global::{
+    origin_verify, peer_record_verify, relay_verify, seen_id, Global, PeerStats,
+    ORIGIN_HEAD_LENGTH, ORIGIN_PROOF_WINDOW,
+};
 use crate::hole_punching::{nat, DHT};
 use crate::kad::KadValue;
 use crate::session_key::SessionKey;
 use crate::transports::{
-    new_endpoint_channel, EndpointMessage, RemotePublic, TransportSendMessage,
+    compatible_version, new_endpoint_channel, ConnectTimer, EndpointMessage, RejectReason,
+    RemotePublic, TransportSendMessage, HANDSHAKE_WAITING, PROTOCOL_MIN_VERSION,
 };
 
+/// the max bytes of a large data chunk.
+const CHUNK_SIZE: usize = 65536;
+
+/// the chunks buffered for outside, when full, the large data is dropped.
+const CHUNK_BUFFER: usize = 32;
+
+/// the max receiving large datas in a session, new one is dropped when full.
+const LARGE_DATAS_LIMIT: usize = 4;
+
+/// the queued frames of every send lane, when full, stop reading outside messages.
+const LANE_BUFFER: usize = 256;
+
+/// the remote version can read the sequenced frames (`CoreData::Sequenced`).
+pub(crate) const SEQUENCE_VERSION: u16 = 2;
+
+/// the remote version can strip the padded frames (`CoreData` tag 12).
+pub(crate) const PADDING_VERSION: u16 = 2;
+
+/// the remote version can read the sub-protocol frames (`CoreData::Protocol`).
+pub(crate) const PROTOCOL_DATA_VERSION: u16 = 2;
+
+/// the remote version can read the sealed frames (`EndpointMessage::Sealed`), the
+/// sequence is in the wrapped key.
+pub(crate) const SEALED_VERSION: u16 = 2;
+
+/// the queued events to outside of a session, when full (delivery is paused or slow),
+/// stop reading the remote.
+const DELIVERY_BUFFER: usize = 64;
+
 /// To solve the tokio async cycle.
 fn own_spawn(p: Peer, global: Arc<Global>) {
     tokio::spawn(async move {
@@ -36,6 +73,38 @@ fn own_spawn(p: Peer, global: Arc<Global>) {
     });
 }
 
+/// waiting the remote handshake of the new connection, none if the connection closed.
+/// other messages before handshake are not fatal, skip them. the transport timer
+/// is kept in `timer`.
+pub(crate) async fn wait_handshake(
+    stream_receiver: &mut Receiver<EndpointMessage>,
+    timer: &mut Option<ConnectTimer>,
+) -> Option<RemotePublic> {
+    while let Some(msg) = stream_receiver.recv().await {
+        match msg {
+            EndpointMessage::Handshake(remote) => return Some(remote),
+            EndpointMessage::Connected(t) => *timer = Some(t),
+            EndpointMessage::Close(_) => return None,
+            EndpointMessage::Migrated(_) | EndpointMessage::Reflexive(_) => {
+                debug!("Session: skip address signal before handshake.");
+            }
+            EndpointMessage::DHT(_)
+            | EndpointMessage::Hole(_)
+            | EndpointMessage::HoleConnect
+            | EndpointMessage::Data(_)
+            | EndpointMessage::Sealed(..)
+            | EndpointMessage::RelayHandshake(..)
+            | EndpointMessage::RelayData(..)
+            | EndpointMessage::FindNode(_)
+            | EndpointMessage::RelaySigned(..)
+            | EndpointMessage::RelayReject(..) => {
+                debug!("Session: skip unexpected message before handshake.");
+            }
+        }
+    }
+    None
+}
+
 /// direct start stable connection, if had IP.
 pub(crate) async fn direct_stable(
     tid: u64,
@@ -46,41 +115,86 @@ pub(crate) async fn direct_stable(
     is_own: bool,
 ) -> Result<()> {
     debug!("Session want to connect directly.");
-    let (endpoint_sender, endpoint_receiver) = new_endpoint_channel(); // transpot's use.
-    let (stream_sender, mut stream_receiver) = new_endpoint_channel(); // session's use.
-    let (mut session_key, remote_pk) = global.generate_remote();
-
     let bufferkey = if to.effective_id() {
         BufferKey::Peer(to.id)
     } else {
         BufferKey::Addr(to.socket)
     };
 
-    // 1. send stable connect.
-    global
-        .trans_send(
-            &to.transport,
-            TransportSendMessage::StableConnect(
-                stream_sender.clone(),
-                endpoint_receiver,
-                to.socket,
-                remote_pk,
-            ),
-        )
-        .await?;
+    // 1. send stable connect, try the candidate addresses in order.
+    let mut connected = None;
+    let mut timer = None;
+    for (socket, transport) in to.addrs() {
+        global.session_state(&to.id, SessionState::Connecting);
+        let (endpoint_sender, endpoint_receiver) = new_endpoint_channel(); // transpot's use.
+        let (stream_sender, mut stream_receiver) = new_endpoint_channel(); // session's use.
+        let (session_key, remote_pk) = global.generate_remote_for(&to.id).await;
+
+        global
+            .trans_send(
+                &transport,
+                TransportSendMessage::StableConnect(
+                    stream_sender.clone(),
+                    endpoint_receiver,
+                    socket,
+                    remote_pk,
+                ),
+            )
+            .await?;
+        global.session_state(&to.id, SessionState::Handshaking);
+
+        // 2. waiting remote send remote info.
+        if let Some(remote) = wait_handshake(&mut stream_receiver, &mut timer).await {
+            connected = Some((
+                socket,
+                remote,
+                session_key,
+                endpoint_sender,
+                stream_sender,
+                stream_receiver,
+            ));
+            break;
+        }
+        debug!("Session cannot connect to {}, try next address.", socket);
+    }
 
-    // 2. waiting remote send remote info.
-    if let Some(EndpointMessage::Handshake(RemotePublic(remote_peer, dh_key))) =
-        stream_receiver.recv().await
+    if let Some((
+        socket,
+        RemotePublic(remote_peer, dh_key, version),
+        mut session_key,
+        endpoint_sender,
+        stream_sender,
+        stream_receiver,
+    )) = connected
     {
         // 3.1.1 if ok connected. keep it and update to stable.
         let remote_id = remote_peer.id;
         if to.effective_id() && remote_id != to.id {
             warn!("CHAMOMILE: STABLE CONNECT FAILURE UNKNOWN PEER.");
-            let _ = endpoint_sender.send(EndpointMessage::Close).await;
+            let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
+            global.session_failed(&to.id);
             return Err(new_io_error("session stable unknown peer."));
         }
 
+        if !compatible_version(version, PROTOCOL_MIN_VERSION) {
+            warn!("CHAMOMILE: STABLE CONNECT VERSION MISMATCH: {}.", version);
+            let reason = Some(CloseReason::Version);
+            let _ = endpoint_sender.send(EndpointMessage::Close(reason)).await;
+            if tid != 0 {
+                global
+                    .out_send(ReceiveMessage::Delivery(
+                        DeliveryType::StableConnect,
+                        tid,
+                        false,
+                        delivery,
+                    ))
+                    .await?;
+            }
+            global.buffer.write().await.remove_connect(bufferkey);
+            global.session_failed(&remote_id);
+            return Err(new_io_error("session stable version mismatch."));
+        }
+
         let mut is_own = false;
         if &remote_id == global.peer_id() {
             if &remote_peer.assist == global.assist_id() {
@@ -95,7 +209,7 @@ pub(crate) async fn direct_stable(
                         .await?;
                 }
                 warn!("CHAMOMILE: STABLE CONNECT NERVER TO SELF.");
-                let _ = endpoint_sender.send(EndpointMessage::Close).await;
+                let _ = endpoint_sender.send(EndpointMessage::Close(None)).await;
                 return Err(new_io_error("session stable self failure."));
             }
             is_own = true;
@@ -108,11 +222,30 @@ pub(crate) async fn direct_stable(
 
         // 3.1.2 check & update session key.
         if !session_key.complete(&remote_id, dh_key) {
+            warn!("CHAMOMILE: STABLE CONNECT SESSION KEY MISMATCH.");
+            let reason = Some(CloseReason::Crypto);
+            let _ = endpoint_sender.send(EndpointMessage::Close(reason)).await;
+            if tid != 0 {
+                global
+                    .out_send(ReceiveMessage::Delivery(
+                        DeliveryType::StableConnect,
+                        tid,
+                        false,
+                        delivery,
+                    ))
+                    .await?;
+            }
             global.buffer.write().await.remove_connect(bufferkey);
+            global.session_failed(&remote_id);
             return Err(new_io_error("session stable key failure."));
         }
+        global.session_state(&remote_id, SessionState::KeyExchange);
+        global.save_ticket(&remote_id, &session_key).await;
+        if let Some(timer) = timer.as_mut() {
+            timer.trace.session_key = timer.elapsed();
+        }
 
-        let remote_peer = nat(to.socket, remote_peer);
+        let remote_peer = nat(socket, remote_peer);
         let (session_sender, session_receiver) = new_session_channel(); // server's use.
 
         // 3.1.3 save to tmp buffer.
@@ -135,11 +268,14 @@ pub(crate) async fn direct_stable(
             is_recv_data,
             is_own,
         );
+        session.is_dial = true;
+        session.remote_version = version;
 
         // 3.1.4 send all connect info to remote.
         for buffer in buffers {
+            let data = session.global.pow_join(buffer.1);
             session
-                .send_core_data(CoreData::StableConnect(buffer.0, buffer.1))
+                .send_core_data(CoreData::StableConnect(buffer.0, data))
                 .await?;
         }
 
@@ -148,20 +284,20 @@ pub(crate) async fn direct_stable(
             session.upgrade().await?;
         }
 
-        // 3.1.6 session listen.
+        // 3.1.6 session listen, it removes the timer when closed.
+        if let Some(timer) = timer {
+            session
+                .global
+                .connects
+                .write()
+                .await
+                .insert(remote_id, timer);
+        }
         session.listen(session_receiver).await
     } else {
-        drop(stream_sender);
-        drop(stream_receiver);
-        drop(endpoint_sender);
-
         // 3.2.1 try start relay stable.
         let toid = if is_own { &to.assist } else { &to.id };
-        let ss = if let Some((s, _, _)) = global.peer_list.read().await.get(toid) {
-            Some(s.clone())
-        } else {
-            None
-        };
+        let ss = global.peer_list.read().await.relay_get(toid).cloned();
 
         if let Some(ss) = ss {
             relay_stable(tid, delivery, to, ss, global, is_recv_data, is_own).await
@@ -182,6 +318,7 @@ pub(crate) async fn direct_stable(
                 BufferKey::Addr(to.socket)
             };
             global.buffer.write().await.remove_connect(key);
+            global.session_failed(toid);
             Err(new_io_error("no closest peer."))
         }
     }
@@ -204,7 +341,7 @@ pub(crate) async fn relay_stable(
 
     let (stream_sender, stream_receiver) = new_endpoint_channel(); // session's use.
     let (session_sender, mut session_receiver) = new_session_channel(); // server's use.
-    let (mut session_key, remote_pk) = global.generate_remote();
+    let (mut session_key, remote_pk) = global.generate_remote_for(&to.id).await;
     let toid = if is_own { to.assist } else { to.id };
 
     let (connects, results) = global
@@ -215,11 +352,13 @@ pub(crate) async fn relay_stable(
         )
         .await;
 
+    global.session_state(&toid, SessionState::Connecting);
     relay_sender
         .send(SessionMessage::RelayConnect(remote_pk, toid))
         .await
         .map_err(|_e| new_io_error("Session missing"))?;
     drop(relay_sender);
+    global.session_state(&toid, SessionState::Handshaking);
 
     let msg = select! {
         v = session_receiver
@@ -231,12 +370,19 @@ pub(crate) async fn relay_stable(
     };
 
     if let Some(SessionMessage::RelayResult(remote, recv_ss)) = msg {
-        let RemotePublic(remote_peer, dh_key) = remote;
+        if !remote.is_compatible() {
+            warn!("CHAMOMILE: STABLE CONNECT VERSION MISMATCH: {}.", remote.2);
+            global.buffer.write().await.remove_tmp(&to.id);
+            global.session_failed(&to.id);
+            return Err(new_io_error("session stable version mismatch."));
+        }
+        let RemotePublic(remote_peer, dh_key, remote_version) = remote;
 
         let remote_id = remote_peer.id;
         if remote_id != to.id {
             warn!("CHAMOMILE: STABLE CONNECT FAILURE UNKNOWN PEER.");
             global.buffer.write().await.remove_tmp(&to.id);
+            global.session_failed(&to.id);
             return Err(new_io_error("session stable unknown peer."));
         }
 
@@ -263,8 +409,11 @@ pub(crate) async fn relay_stable(
 
         if !session_key.complete(&remote_id, dh_key) {
             global.buffer.write().await.remove_tmp(&toid);
+            global.session_failed(&remote_id);
             return Err(new_io_error("session stable key failure."));
         }
+        global.session_state(&remote_id, SessionState::KeyExchange);
+        global.save_ticket(&remote_id, &session_key).await;
 
         global.buffer.write().await.update_peer(&toid, remote_peer);
         let mut session = Session::new(
@@ -277,16 +426,19 @@ pub(crate) async fn relay_stable(
             is_recv_data,
             is_own,
         );
+        session.remote_version = remote_version;
 
         for buffer in connects {
+            let data = session.global.pow_join(buffer.1);
             session
-                .send_core_data(CoreData::StableConnect(buffer.0, buffer.1))
+                .send_core_data(CoreData::StableConnect(buffer.0, data))
                 .await?;
         }
 
         for buffer in results {
+            let data = session.global.pow_join(buffer.1);
             session
-                .send_core_data(CoreData::ResultConnect(buffer.0, buffer.1))
+                .send_core_data(CoreData::ResultConnect(buffer.0, data))
                 .await?;
         }
 
@@ -308,6 +460,7 @@ pub(crate) async fn relay_stable(
                 .await?;
         }
         global.buffer.write().await.remove_tmp(&toid);
+        global.session_failed(&toid);
         debug!("Session clear stable buffer.");
         Err(new_io_error("session relay reach faiure."))
     }
@@ -333,17 +486,180 @@ pub(crate) struct Session {
     pub is_stable: bool,
     pub is_own: bool,
     pub heartbeat: u32,
+    /// if received frames from remote in this keepalive interval.
+    pub is_active: bool,
     pub relay_sessions: HashMap<PeerId, Sender<SessionMessage>>,
+    /// the sessions limit permit, release when session closed.
+    pub permit: Option<OwnedSemaphorePermit>,
+    /// consecutive decrypt failures.
+    pub decrypt_failures: u32,
+    /// the new session key when waiting remote rekey.
+    pub rekey: Option<SessionKey>,
+    /// our handshake bytes of the waiting rekey, resend it when lost.
+    pub rekey_dh: Vec<u8>,
+    /// heartbeats the session key is not ready, and the resent times.
+    pub key_wait: (u32, u32),
+    /// next id of large data sending.
+    pub large_id: u64,
+    /// receiving large datas, id => outside chunks sender.
+    pub large_datas: HashMap<u64, Sender<DataChunk>>,
+    /// if had received data, for connection trace.
+    pub has_data: bool,
+    /// the send lanes by priority.
+    pub lanes: Lanes,
+    /// last sequence of sent lane frames.
+    pub send_seq: u64,
+    /// max sequence of received frames.
+    pub recv_seq: u64,
+    /// last sequence of sent sealed frames.
+    pub sealed_send: u64,
+    /// last sequence of received sealed frames, the not newer one is a replay.
+    pub sealed_recv: u64,
+    /// self dialed the remote directly, it redials when the lifetime is over.
+    pub is_dial: bool,
+    /// the session start time, for the lifetime.
+    pub started: Instant,
+    /// the lifetime is over, redial after closed.
+    pub expired: bool,
+    /// the remote is lost (keepalive timeout).
+    pub lost: bool,
+    /// stopped by outside, confirm it when closed.
+    stopped: Option<Confirm>,
+    /// the live stats (last seen, rtt) of this session.
+    pub stats: Arc<PeerStats>,
+    /// the sent time of the waiting ping.
+    ping_at: Option<Instant>,
+    /// the events queue to outside, drained by the delivery task when listening.
+    delivery: Option<Sender<ReceiveMessage>>,
+    /// the remote protocol version of the handshake, only send what it can read.
+    pub remote_version: u16,
+    /// told outside the remote leaves.
+    left: bool,
+}
+
+/// the session send queue split by priority, every lane is a bounded channel, and
+/// drained in priority order, so small control frames will not wait bulk data.
+pub(crate) struct Lanes {
+    senders: [Sender<Queued>; 3],
+    receivers: [Receiver<Queued>; 3],
+    /// the large data chunks filled by the sending task, drained after low lane.
+    chunk_sender: Sender<Queued>,
+    chunk_receiver: Receiver<Queued>,
+}
+
+/// the queued data in lanes, with the send confirm, and the deadline to expire it.
+pub(crate) struct Queued {
+    data: CoreData,
+    confirm: Option<Confirm>,
+    deadline: Option<Instant>,
+}
+
+impl Lanes {
+    fn new() -> Lanes {
+        let (high_sender, high_receiver) = mpsc::channel(LANE_BUFFER);
+        let (normal_sender, normal_receiver) = mpsc::channel(LANE_BUFFER);
+        let (low_sender, low_receiver) = mpsc::channel(LANE_BUFFER);
+        let (chunk_sender, chunk_receiver) = mpsc::channel(CHUNK_BUFFER);
+        Lanes {
+            senders: [high_sender, normal_sender, low_sender],
+            receivers: [high_receiver, normal_receiver, low_receiver],
+            chunk_sender,
+            chunk_receiver,
+        }
+    }
+
+    fn push(&self, priority: Priority, data: CoreData) -> std::result::Result<(), Queued> {
+        self.push_queued(priority, data, None, None)
+    }
+
+    fn push_confirm(
+        &self,
+        priority: Priority,
+        data: CoreData,
+        confirm: Option<Confirm>,
+    ) -> std::result::Result<(), Queued> {
+        self.push_queued(priority, data, confirm, None)
+    }
+
+    /// queue the data, return it back when the lane is full.
+    fn push_queued(
+        &self,
+        priority: Priority,
+        data: CoreData,
+        confirm: Option<Confirm>,
+        deadline: Option<Instant>,
+    ) -> std::result::Result<(), Queued> {
+        let queued = Queued {
+            data,
+            confirm,
+            deadline,
+        };
+        self.senders[Self::lane(priority)]
+            .try_send(queued)
+            .map_err(|e| match e {
+                TrySendError::Full(q) | TrySendError::Closed(q) => q,
+            })
+    }
+
+    fn lane(priority: Priority) -> usize {
+        match priority {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+
+    /// every lane has room for a new outside message.
+    fn has_room(&self) -> bool {
+        self.senders.iter().all(|s| s.capacity() > 0)
+    }
+}
+
+/// the send confirmation, if dropped before resolved (e.g. session closed),
+/// it will be resolved with error.
+pub(crate) struct Confirm(Option<oneshot::Sender<Result<()>>>);
+
+impl Confirm {
+    pub fn new(sender: oneshot::Sender<Result<()>>) -> Self {
+        Self(Some(sender))
+    }
+
+    pub fn resolve(mut self, res: Result<()>) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(res);
+        }
+    }
+
+    /// resolve with the copy of the result.
+    pub fn resolve_by(self, res: &Result<()>) {
+        match res {
+            Ok(()) => self.resolve(Ok(())),
+            Err(e) => self.resolve(Err(new_io_error(&e.to_string()))),
+        }
+    }
+}
+
+impl Drop for Confirm {
+    fn drop(&mut self) {
+        if let Some(sender) = self.0.take() {
+            let _ = sender.send(Err(new_io_error("session closed")));
+        }
+    }
 }
 
 enum FutureResult {
+    Lane(Queued),
     Out(SessionMessage),
     Endpoint(EndpointMessage),
     HeartBeat,
     Robust,
+    Lifetime,
+    /// the delivery has room again.
+    Delivery,
 }
 
 impl Session {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         remote_peer: Peer,
         session_sender: Sender<SessionMessage>,
@@ -354,6 +670,7 @@ impl Session {
         is_recv_data: bool,
         is_own: bool,
     ) -> Session {
+        let stats = Arc::new(PeerStats::new(global.rate_window));
         Session {
             remote_peer,
             session_sender,
@@ -365,7 +682,31 @@ impl Session {
             is_own,
             is_stable: false,
             heartbeat: 0,
+            is_active: false,
             relay_sessions: HashMap::new(),
+            permit: None,
+            decrypt_failures: 0,
+            rekey: None,
+            rekey_dh: vec![],
+            key_wait: (0, 0),
+            large_id: 0,
+            large_datas: HashMap::new(),
+            has_data: false,
+            lanes: Lanes::new(),
+            send_seq: 0,
+            recv_seq: 0,
+            sealed_send: 0,
+            sealed_recv: 0,
+            is_dial: false,
+            started: Instant::now(),
+            expired: false,
+            lost: false,
+            stopped: None,
+            stats,
+            ping_at: None,
+            delivery: None,
+            remote_version: PROTOCOL_MIN_VERSION,
+            left: false,
         }
     }
 
@@ -373,10 +714,34 @@ impl Session {
         self.global.peer_id() == to || self.global.assist_id() == to
     }
 
+    /// close by the local, flush both sides, tell the remote and outside the reason.
+    async fn force_disconnect(&mut self, reason: CloseReason) -> Result<()> {
+        debug!("Session force disconnect: {:?}", reason);
+        let _ = self.flush().await;
+        self.flush_received().await;
+        let _ = self.direct_send(EndpointMessage::Close(Some(reason))).await;
+        // the remote close may be flushed before, told already.
+        self.peer_leave(reason).await?;
+        Err(new_io_error("force disconnect"))
+    }
+
+    /// relay the flow via this session, the `from` is not verified, so only the direct
+    /// (authenticated) remote of high tier is exempt from the flows limit.
+    fn relay_allow(&self, from: &PeerId, to: &PeerId) -> bool {
+        (self.is_direct() && self.global.peer_tier(&self.remote_peer.id) == PeerTier::High)
+            || self.global.relay_flows.allow(from, to)
+    }
+
     fn is_from_remote(&self, from: &PeerId) -> bool {
         &self.remote_peer.id == from || &self.remote_peer.assist == from
     }
 
+    /// remote is self (same id and assist), e.g. dial to self advertised address.
+    fn is_self_remote(&self) -> bool {
+        self.global.peer_id() == &self.remote_peer.id
+            && self.global.assist_id() == &self.remote_peer.assist
+    }
+
     fn is_own_remote(&self, peer: &Peer) -> bool {
         self.global.peer_id() == &peer.id && self.global.assist_id() != &peer.assist
     }
@@ -386,14 +751,18 @@ impl Session {
     }
 
     fn remote_assist(&self) -> Peer {
-        let mut new_p = self.remote_peer.clone();
+        let mut new_p = self.remote_peer;
         new_p.id = new_p.assist;
         new_p
     }
 
     async fn close(&mut self, is_leave: bool) -> Result<()> {
+        self.fail_lanes().await;
         let peer_id = &self.remote_peer.id;
         let assist_id = &self.remote_peer.assist;
+        if is_leave {
+            self.global.session_state(peer_id, SessionState::Closing);
+        }
 
         if self.is_stable {
             if self.is_own {
@@ -414,7 +783,8 @@ impl Session {
 
             if is_leave {
                 self.global.peer_list.write().await.stable_leave(peer_id);
-                let _ = self.direct_send(EndpointMessage::Close).await;
+                self.global.check_leader().await;
+                let _ = self.direct_send(EndpointMessage::Close(None)).await;
             } else if self.is_direct() {
                 self.global.stable_to_dht(peer_id).await?;
             }
@@ -434,25 +804,34 @@ impl Session {
             self.global.buffer.write().await.remove_tmp(peer_id);
         }
 
+        if is_leave {
+            self.global.connects.write().await.remove(peer_id);
+            self.global.records.lock().unwrap().remove(peer_id);
+            self.global.session_stats.remove(peer_id, &self.stats);
+            self.global.session_state(peer_id, SessionState::Closed);
+        }
+
         Err(new_io_error("close session"))
     }
 
     fn is_direct(&self) -> bool {
-        match self.endpoint {
-            ConnectType::Direct(..) => true,
-            _ => false,
-        }
+        matches!(self.endpoint, ConnectType::Direct(..))
     }
 
     async fn failure_send(&self, e_data: Vec<u8>) -> Result<()> {
         if let Ok(bytes) = self.session_key.decrypt(e_data) {
             if let Ok(msg) = CoreData::from_bytes(bytes) {
+                let (_, msg) = msg.unsequenced();
                 match msg {
                     CoreData::Ping => {}
-                    CoreData::Pong => {}
+                    CoreData::Sequenced(..) => {}
+                    CoreData::Pong(_) => {}
                     CoreData::Unstable => {}
+                    CoreData::Chunk(..) => {}
                     CoreData::Delivery(..) => {}
-                    CoreData::Data(tid, data) => {
+                    CoreData::Data(tid, data)
+                    | CoreData::Metadata(tid, _, data)
+                    | CoreData::Protocol(tid, _, data) => {
                         if tid != 0 {
                             self.out_send(ReceiveMessage::Delivery(
                                 DeliveryType::Data,
@@ -503,7 +882,42 @@ impl Session {
     }
 
     async fn out_send(&self, msg: ReceiveMessage) -> Result<()> {
-        self.global.out_send(msg).await
+        match &self.delivery {
+            Some(delivery) => delivery
+                .send(msg)
+                .await
+                .map_err(|_e| new_io_error("Delivery missing")),
+            None => self.global.out_send(msg).await,
+        }
+    }
+
+    /// the events queue has room, or not queued.
+    fn can_deliver(&self) -> bool {
+        self.delivery.as_ref().is_none_or(|d| d.capacity() > 0)
+    }
+
+    /// start the delivery task, the events to outside are queued in order, and the
+    /// remote data waits when the delivery is paused, the session loop keeps running.
+    fn start_delivery(&mut self) {
+        let (sender, mut receiver) = mpsc::channel(DELIVERY_BUFFER);
+        let global = self.global.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = receiver.recv().await {
+                if matches!(
+                    msg,
+                    ReceiveMessage::Data(..)
+                        | ReceiveMessage::Metadata(..)
+                        | ReceiveMessage::Protocol(..)
+                        | ReceiveMessage::OwnEvent(..)
+                ) {
+                    global.delivery_wait().await;
+                }
+                if global.out_send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.delivery = Some(sender);
     }
 
     async fn direct_send(&self, msg: EndpointMessage) -> Result<()> {
@@ -527,7 +941,13 @@ impl Session {
     }
 
     async fn send_core_data(&self, data: CoreData) -> Result<()> {
-        let e_data = self.session_key.encrypt(data.to_bytes());
+        let bytes = if self.remote_version >= PADDING_VERSION {
+            data.to_padded_bytes(&self.global.frame_padding)?
+        } else {
+            data.to_bytes()?
+        };
+        let e_data = self.session_key.encrypt(bytes);
+        self.stats.send(e_data.len());
         if self.is_direct() {
             self.direct_send(EndpointMessage::Data(e_data)).await
         } else {
@@ -541,40 +961,66 @@ impl Session {
         }
     }
 
+    /// send the sealed frame (padded `CoreData::Data`) after the queued data, only wrap
+    /// the content key for remote. the relay session has no own frame, and the old
+    /// version cannot read it, send it as normal data in the lane.
+    async fn send_sealed(&mut self, content_key: [u8; 32], sealed: &[u8]) -> Result<()> {
+        if !self.is_direct() || self.remote_version < SEALED_VERSION {
+            let bytes = SessionKey::unseal(&content_key, sealed)?;
+            let data = CoreData::from_bytes(bytes).map_err(|_| new_io_error("sealed failure"))?;
+            let res = self.lanes.push(Priority::Normal, data);
+            return self.queue_result(res).await;
+        }
+
+        self.flush().await?;
+        self.sealed_send += 1;
+        let wrapped = self.session_key.wrap(&content_key, self.sealed_send);
+        let len = wrapped.len() + sealed.len();
+        self.stats.send(len);
+        self.direct_send(EndpointMessage::Sealed(wrapped, sealed.to_vec()))
+            .await
+    }
+
     async fn handle_core_data(&mut self, e_data: Vec<u8>) -> Result<()> {
         if let Ok(bytes) = self.session_key.decrypt(e_data) {
+            self.decrypt_failures = 0;
+            // remote had the session key (key confirmed), deliver the early data.
+            if let Some(data) = self.session_key.take_early() {
+                if self.is_recv_data {
+                    let remote_id = self.remote_peer.id;
+                    self.out_send(ReceiveMessage::Data(remote_id, data, true))
+                        .await?;
+                }
+            }
             if let Ok(msg) = CoreData::from_bytes(bytes) {
+                let (seq, msg) = msg.unsequenced();
+                if let Some(seq) = seq {
+                    self.check_sequence(seq).await?;
+                }
                 match msg {
                     CoreData::Ping => {
-                        self.send_core_data(CoreData::Pong).await?;
+                        let load = self.global.relay_load.load();
+                        self.send_core_data(CoreData::Pong(load)).await?;
                     }
-                    CoreData::Pong => {
+                    CoreData::Pong(load) => {
                         self.heartbeat = 0;
-                    }
-                    CoreData::Data(tid, p_data) => {
-                        if self.is_recv_data {
-                            let delivery_data =
-                                delivery_split!(p_data, self.global.delivery_length);
-                            if self.is_own {
-                                self.out_send(ReceiveMessage::OwnEvent(
-                                    self.remote_peer.assist,
-                                    p_data,
-                                ))
-                                .await?;
-                            } else {
-                                self.out_send(ReceiveMessage::Data(self.remote_peer.id, p_data))
-                                    .await?;
-                            }
-
-                            if tid != 0 {
-                                self.send_core_data(CoreData::Delivery(
-                                    DeliveryType::Data,
-                                    tid,
-                                    delivery_data,
-                                ))
-                                .await?;
-                            }
+                        if let Some(at) = self.ping_at.take() {
+                            self.stats.set_rtt(at.elapsed());
                         }
+                        if !self.is_own {
+                            self.global
+                                .peer_list
+                                .write()
+                                .await
+                                .update_load(self.remote_peer.id, load);
+                        }
+                    }
+                    CoreData::Data(tid, p_data) => self.recv_data(tid, None, p_data).await?,
+                    CoreData::Protocol(tid, protocol, p_data) => {
+                        self.recv_protocol(tid, protocol, p_data).await?
+                    }
+                    CoreData::Metadata(tid, meta, p_data) => {
+                        self.recv_data(tid, Some(meta), p_data).await?
                     }
                     CoreData::Delivery(t, tid, data) => {
                         if tid != 0 {
@@ -593,6 +1039,12 @@ impl Session {
                         }
                     }
                     CoreData::StableConnect(tid, data) => {
+                        let Some(data) = self.global.pow_check(&self.remote_peer.id, data) else {
+                            debug!("Session: stable connect proof-of-work failure.");
+                            self.send_core_data(CoreData::StableResult(tid, false, vec![]))
+                                .await?;
+                            return Ok(());
+                        };
                         let delivery_data = delivery_split!(data, self.global.delivery_length);
                         if self.is_own {
                             self.out_send(ReceiveMessage::OwnConnect(self.remote_assist()))
@@ -637,6 +1089,12 @@ impl Session {
                         }
                     }
                     CoreData::ResultConnect(tid, data) => {
+                        let Some(data) = self.global.pow_check(&self.remote_peer.id, data) else {
+                            debug!("Session: result connect proof-of-work failure.");
+                            self.send_core_data(CoreData::StableResult(tid, false, vec![]))
+                                .await?;
+                            return Ok(());
+                        };
                         let delivery_data = delivery_split!(data, self.global.delivery_length);
                         if self.is_own {
                             self.out_send(ReceiveMessage::OwnConnect(self.remote_assist()))
@@ -659,10 +1117,219 @@ impl Session {
                         }
                     }
                     CoreData::Unstable => self.close(false).await?,
+                    // nested is rejected when decode.
+                    CoreData::Sequenced(..) => {}
+                    CoreData::Chunk(id, offset, is_final, data) => {
+                        if self.is_recv_data {
+                            self.handle_chunk(id, offset, is_final, data).await?;
+                        }
+                    }
                 }
+            } else {
+                // maybe new tag from newer version, skip it and keep session.
+                debug!("Session: unknown core data, skip it.");
             }
         } else {
             warn!("Session Key decrypt failure!");
+            self.handle_decrypt_failure().await?;
+        }
+
+        Ok(())
+    }
+
+    /// send large data as chunks, a task fills the bounded chunk lane,
+    /// so it will wait when remote is slow, and not block the session.
+    fn send_large_data(&mut self, data: Vec<u8>) {
+        let id = self.large_id;
+        self.large_id += 1;
+
+        let sender = self.lanes.chunk_sender.clone();
+        tokio::spawn(async move {
+            let len = data.len();
+            let mut offset = 0;
+            loop {
+                let end = len.min(offset + CHUNK_SIZE);
+                let is_final = end == len;
+                let chunk = data[offset..end].to_vec();
+                let queued = Queued {
+                    data: CoreData::Chunk(id, offset as u64, is_final, chunk),
+                    confirm: None,
+                    deadline: None,
+                };
+                // session closed.
+                if sender.send(queued).await.is_err() || is_final {
+                    break;
+                }
+                offset = end;
+            }
+        });
+    }
+
+    /// receive the large data chunk, send to outside by the data channel,
+    /// never wait outside, when it is full, drop the large data (outside get the
+    /// channel closed without the final chunk).
+    async fn handle_chunk(
+        &mut self,
+        id: u64,
+        offset: u64,
+        is_final: bool,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        if offset == 0 && !self.large_datas.contains_key(&id) {
+            if self.large_datas.len() >= LARGE_DATAS_LIMIT {
+                debug!("Large datas is full, drop the new one.");
+                return Ok(());
+            }
+            let (sender, receiver) = mpsc::channel(CHUNK_BUFFER);
+            let remote_id = if self.is_own {
+                self.remote_peer.assist
+            } else {
+                self.remote_peer.id
+            };
+            self.out_send(ReceiveMessage::LargeData(remote_id, id, receiver))
+                .await?;
+            self.large_datas.insert(id, sender);
+        }
+
+        if let Some(sender) = self.large_datas.get(&id) {
+            let chunk = DataChunk {
+                id,
+                offset,
+                is_final,
+                data,
+            };
+            match sender.try_send(chunk) {
+                Ok(()) if !is_final => {}
+                Ok(()) => {
+                    self.large_datas.remove(&id);
+                }
+                Err(TrySendError::Full(_)) => {
+                    warn!("CHAMOMILE: large data {} outside is slow, drop it.", id);
+                    self.large_datas.remove(&id);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    // outside dropped it.
+                    self.large_datas.remove(&id);
+                }
+            }
+        } else {
+            debug!("Large data chunk missing the head, drop it.");
+        }
+        Ok(())
+    }
+
+    /// continuous decrypt failures is key desync or attack,
+    /// first try to rekey, if still failure, close it.
+    async fn handle_decrypt_failure(&mut self) -> Result<()> {
+        self.decrypt_failures += 1;
+        let policy = self.global.decrypt_policy;
+        if policy.close_after != 0 && self.decrypt_failures >= policy.close_after {
+            warn!(
+                "CHAMOMILE: {} decrypt failure too many, close it.",
+                self.remote_peer.id.short_show()
+            );
+            return self.close_crypto("session key decrypt failure").await;
+        }
+
+        if policy.rekey_after != 0 && self.decrypt_failures == policy.rekey_after {
+            self.start_rekey().await?;
+        }
+
+        Ok(())
+    }
+
+    /// close by the session key failure, and tell remote and outside.
+    async fn close_crypto(&mut self, error: &str) -> Result<()> {
+        let reason = CloseReason::Crypto;
+        let _ = self.direct_send(EndpointMessage::Close(Some(reason))).await;
+        let _ = self.peer_leave(reason).await;
+        Err(new_io_error(error))
+    }
+
+    /// tell outside the remote leaves with the reason, only once in a session.
+    async fn peer_leave(&mut self, reason: CloseReason) -> Result<()> {
+        if self.left {
+            return Ok(());
+        }
+        self.left = true;
+        self.out_send(ReceiveMessage::PeerLeave(self.remote_peer.id, reason))
+            .await
+    }
+
+    /// the session key not ready (remote never completed it, or the handshake of
+    /// our rekey is lost) but heartbeats pass, the session looks alive but useless.
+    /// resend our handshake when waited `resend_key_after` heartbeats, and close
+    /// when resent `resend_key_times`.
+    async fn check_key(&mut self) -> Result<()> {
+        let policy = self.global.decrypt_policy;
+        if (self.session_key.is_ok() && self.rekey.is_none())
+            || policy.resend_key_after == 0
+            || !self.is_direct()
+        {
+            self.key_wait = (0, 0);
+            return Ok(());
+        }
+
+        self.key_wait.0 += 1;
+        if self.key_wait.0 < policy.resend_key_after {
+            return Ok(());
+        }
+        if self.key_wait.1 >= policy.resend_key_times {
+            warn!(
+                "CHAMOMILE: {} session key not ready, close it.",
+                self.remote_peer.id.short_show()
+            );
+            return self.close_crypto("session key not ready").await;
+        }
+
+        self.key_wait = (0, self.key_wait.1 + 1);
+        if self.rekey.is_some() {
+            debug!(
+                "Session resend key to: {}",
+                self.remote_peer.id.short_show()
+            );
+            let remote_pk = RemotePublic::new(self.global.advertised_peer(), self.rekey_dh.clone());
+            self.direct_send(EndpointMessage::Handshake(remote_pk))
+                .await
+        } else {
+            self.start_rekey().await
+        }
+    }
+
+    /// start a new key exchange with remote, wait remote's handshake to complete it.
+    async fn start_rekey(&mut self) -> Result<()> {
+        debug!("Session rekey to: {}", self.remote_peer.id.short_show());
+        let (session_key, remote_pk) = self.global.generate_remote();
+        self.rekey = Some(session_key);
+        self.rekey_dh = remote_pk.1.clone();
+        self.direct_send(EndpointMessage::Handshake(remote_pk))
+            .await
+    }
+
+    /// remote rekey, or remote answer our rekey.
+    /// it is symmetric: when both sides start rekey at the same time, every side
+    /// completes its pending key with the remote's handshake (not answer it again),
+    /// ECDH is same in both directions, so both get the same key without more round.
+    async fn handle_rekey(&mut self, remote: RemotePublic) -> Result<()> {
+        let RemotePublic(peer, dh_key, _) = remote;
+        if !self.is_from_remote(&peer.id) {
+            warn!("CHAMOMILE: rekey from other peer.");
+            return Ok(());
+        }
+
+        if let Some(mut session_key) = self.rekey.take() {
+            if session_key.complete(&peer.id, dh_key) {
+                self.global.save_ticket(&peer.id, &session_key).await;
+                self.session_key = session_key;
+                self.decrypt_failures = 0;
+            }
+        } else if let Some((session_key, remote_pk)) =
+            self.global.complete_remote(&peer.id, dh_key).await
+        {
+            self.direct_send(EndpointMessage::Handshake(remote_pk))
+                .await?;
+            self.session_key = session_key;
+            self.decrypt_failures = 0;
         }
 
         Ok(())
@@ -682,27 +1349,178 @@ impl Session {
         }
     }
 
+    /// send the data from lanes, and resolve its confirm.
+    /// if it is expired, drop it, and tell outside delivery failure.
+    async fn send_lane(&mut self, queued: Queued) -> Result<()> {
+        let Queued {
+            data,
+            confirm,
+            deadline,
+        } = queued;
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            debug!("Session data expired, drop it.");
+            let queued = Queued {
+                data,
+                confirm,
+                deadline,
+            };
+            return self.fail_queued(queued, "expired").await;
+        }
+
+        let data = if self.global.sequence_frames && self.remote_version >= SEQUENCE_VERSION {
+            self.send_seq += 1;
+            CoreData::Sequenced(self.send_seq, Box::new(data))
+        } else {
+            data
+        };
+        let res = self.send_core_data(data).await;
+        if let Some(confirm) = confirm {
+            confirm.resolve_by(&res);
+        }
+        res
+    }
+
+    /// drop the queued data not sent, tell outside delivery failure, and resolve its confirm.
+    async fn fail_queued(&self, queued: Queued, reason: &str) -> Result<()> {
+        let Queued { data, confirm, .. } = queued;
+        if let Some(confirm) = confirm {
+            confirm.resolve(Err(new_io_error(reason)));
+        }
+        if let CoreData::Data(tid, data) | CoreData::Metadata(tid, _, data) = data {
+            if tid != 0 {
+                let delivery = delivery_split!(data, self.global.delivery_length);
+                self.out_send(ReceiveMessage::Delivery(
+                    DeliveryType::Data,
+                    tid,
+                    false,
+                    delivery,
+                ))
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the lane is full (or closed), the data is failed.
+    async fn queue_result(&self, res: std::result::Result<(), Queued>) -> Result<()> {
+        match res {
+            Ok(()) => Ok(()),
+            Err(queued) => {
+                debug!("Session lane is full, drop it.");
+                self.fail_queued(queued, "lane full").await
+            }
+        }
+    }
+
+    /// fail all queued data in lanes, the session is closed before they are sent.
+    async fn fail_lanes(&mut self) {
+        self.lanes.chunk_receiver.close();
+        while self.lanes.chunk_receiver.try_recv().is_ok() {}
+        for lane in 0..3 {
+            self.lanes.receivers[lane].close();
+            while let Ok(queued) = self.lanes.receivers[lane].try_recv() {
+                let _ = self.fail_queued(queued, "session closed").await;
+            }
+        }
+    }
+
+    /// send all queued data in lanes (by priority) to endpoint.
+    async fn flush(&mut self) -> Result<()> {
+        for lane in 0..3 {
+            while let Ok(queued) = self.lanes.receivers[lane].try_recv() {
+                self.send_lane(queued).await?;
+            }
+        }
+        while let Ok(queued) = self.lanes.chunk_receiver.try_recv() {
+            self.send_lane(queued).await?;
+        }
+        Ok(())
+    }
+
+    /// deliver the remote data already received (not handled) to outside before close,
+    /// so it is not lost. stop at the remote close.
+    async fn flush_received(&mut self) {
+        // not wait the paused delivery when closing, the rest is dropped.
+        while self.can_deliver() {
+            let Ok(msg) = self.stream_receiver.try_recv() else {
+                break;
+            };
+            match msg {
+                EndpointMessage::Data(_)
+                | EndpointMessage::Sealed(..)
+                | EndpointMessage::RelayData(..) => {
+                    let _ = self.handle_endpoint(msg).await;
+                }
+                EndpointMessage::Close(_) => {
+                    let _ = self.handle_endpoint(msg).await;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// wait the remote answer the lifetime close, so the redial will not meet the old
+    /// session. deliver the received data meanwhile.
+    async fn wait_close(&mut self) {
+        let deadline = Instant::now() + Duration::from_secs(HANDSHAKE_WAITING);
+        while let Ok(Some(msg)) = timeout_at(deadline, self.stream_receiver.recv()).await {
+            match msg {
+                EndpointMessage::Data(_)
+                | EndpointMessage::Sealed(..)
+                | EndpointMessage::RelayData(..) => {
+                    let _ = self.handle_endpoint(msg).await;
+                }
+                EndpointMessage::Close(_) => break,
+                _ => {}
+            }
+        }
+    }
+
     async fn forever(&mut self, mut session_receiver: Receiver<SessionMessage>) -> Result<()> {
-        // 2s to check connection is actived
-        let mut heatbeat_interval = interval(Duration::from_secs(2));
+        // keepalive (default 2s) to check connection is actived
+        let mut heatbeat_interval = interval(Duration::from_secs(self.global.keepalive.max(1)));
 
         // 60s to check all connection channels is ok.
         let mut robust_interval = interval(Duration::from_secs(60));
 
+        // both sides close when the lifetime is over, the dialer redials.
+        let lifetime = self.global.session_lifetime;
+        let has_lifetime = lifetime > 0 && self.is_direct();
+        let lifetime_deadline = self.started + Duration::from_secs(lifetime);
+
         loop {
+            // lanes are full, stop reading outside, so the outside senders wait.
+            let has_room = self.lanes.has_room();
+            // delivery is full, stop reading remote, so the transport slows the remote.
+            let can_deliver = self.can_deliver();
+            let delivery = &self.delivery;
+            let [high, normal, low] = &mut self.lanes.receivers;
+            let chunks = &mut self.lanes.chunk_receiver;
+            let stream_receiver = &mut self.stream_receiver;
+            // polling in order, the queued lanes first, and low lane at last.
             let res = select! {
+                biased;
+                Some(queued) = high.recv() => Some(FutureResult::Lane(queued)),
+                Some(queued) = normal.recv() => Some(FutureResult::Lane(queued)),
                 v = async {
                     session_receiver
                         .recv()
                         .await
-                        .map(|msg| FutureResult::Out(msg))
-                } => v,
+                        .map(FutureResult::Out)
+                }, if has_room => v,
                 v = async {
-                    self.stream_receiver
+                    stream_receiver
                         .recv()
                         .await
-                        .map(|msg| FutureResult::Endpoint(msg))
-                } => v,
+                        .map(FutureResult::Endpoint)
+                }, if can_deliver => v,
+                Some(_) = async {
+                    match delivery {
+                        Some(d) => d.reserve().await.ok(),
+                        None => None,
+                    }
+                }, if !can_deliver => Some(FutureResult::Delivery),
 
                 v = async {
                     heatbeat_interval.tick().await;
@@ -712,20 +1530,41 @@ impl Session {
                     robust_interval.tick().await;
                     Some(FutureResult::Robust)
                 } => v,
+                _ = sleep_until(lifetime_deadline), if has_lifetime => {
+                    Some(FutureResult::Lifetime)
+                }
+                Some(queued) = low.recv() => Some(FutureResult::Lane(queued)),
+                Some(queued) = chunks.recv() => Some(FutureResult::Lane(queued)),
             };
             match res {
+                Some(FutureResult::Lane(queued)) => {
+                    self.send_lane(queued).await?;
+                }
                 Some(FutureResult::Out(msg)) => {
                     self.handle_outside(msg).await?;
                 }
                 Some(FutureResult::Endpoint(msg)) => {
+                    self.is_active = true;
+                    self.stats.seen();
+                    self.global.health.active();
                     self.handle_endpoint(msg).await?;
                 }
                 Some(FutureResult::HeartBeat) => {
+                    self.check_key().await?;
                     self.handle_heartbeat().await?;
                 }
                 Some(FutureResult::Robust) => {
                     self.handle_robust().await?;
                 }
+                Some(FutureResult::Delivery) => {}
+                Some(FutureResult::Lifetime) => {
+                    debug!("Session lifetime is over, close it.");
+                    let reason = Some(CloseReason::Lifetime);
+                    let _ = self.direct_send(EndpointMessage::Close(reason)).await;
+                    self.expired = self.is_dial;
+                    self.wait_close().await;
+                    break;
+                }
                 None => break,
             }
         }
@@ -733,16 +1572,165 @@ impl Session {
     }
 
     pub async fn listen(&mut self, session_receiver: Receiver<SessionMessage>) -> Result<()> {
-        debug!("Session running: {}.", self.remote_peer.id.short_show());
-        let _ = self.forever(session_receiver).await;
-        debug!("Session broke: {}.", self.remote_peer.id.short_show());
-        self.close(true).await
-    }
-
+        if self.is_self_remote() {
+            warn!("CHAMOMILE: SESSION NERVER TO SELF, close it.");
+            let _ = self.direct_send(EndpointMessage::Close(None)).await;
+            self.global
+                .connects
+                .write()
+                .await
+                .remove(&self.remote_peer.id);
+            self.global
+                .buffer
+                .write()
+                .await
+                .remove_tmp(&self.remote_peer.id);
+            return Ok(());
+        }
+
+        debug!("Session running: {}.", self.remote_peer.id.short_show());
+        self.global
+            .session_state(&self.remote_peer.id, SessionState::Ready);
+        // the handshake is the first received frame.
+        self.stats.seen();
+        self.global
+            .session_stats
+            .insert(self.remote_peer.id, self.stats.clone());
+        self.global.health.connected();
+        self.global.health.session_start();
+        self.start_delivery();
+        let _ = self.forever(session_receiver).await;
+        self.flush_received().await;
+        self.global.health.session_end();
+        debug!("Session broke: {}.", self.remote_peer.id.short_show());
+        let res = self.close(true).await;
+        let is_high = self.global.peer_tier(&self.remote_peer.id) == PeerTier::High;
+        if self.expired || (self.lost && is_high && self.is_direct()) {
+            // lifetime is fresh handshake, not resume by ticket.
+            let global = self.global.clone();
+            let peer = self.remote_peer;
+            let fresh = self.expired;
+            tokio::spawn(async move { global.redial(&peer, fresh).await });
+        }
+        if let Some(confirm) = self.stopped.take() {
+            confirm.resolve(Ok(()));
+        }
+        res
+    }
+
+    /// check the sequence of remote frame, tell outside when some frames are missing,
+    /// the late (out of order) frame is still delivered.
+    async fn check_sequence(&mut self, seq: u64) -> Result<()> {
+        let expected = self.recv_seq + 1;
+        if seq > expected {
+            let missing = seq - expected;
+            debug!("Session sequence gap: {} missing.", missing);
+            self.out_send(ReceiveMessage::SequenceGap(self.remote_peer.id, missing))
+                .await?;
+        }
+        self.recv_seq = self.recv_seq.max(seq);
+        Ok(())
+    }
+
+    /// received the data (maybe with metadata) from remote, send to outside.
+    async fn recv_data(&mut self, tid: u64, meta: Option<Metadata>, data: Vec<u8>) -> Result<()> {
+        if !self.has_data {
+            self.has_data = true;
+            self.global.trace_first_data(&self.remote_peer.id).await;
+        }
+        if self.is_recv_data {
+            let delivery_data = delivery_split!(data, self.global.delivery_length);
+            let msg = if self.is_own {
+                ReceiveMessage::OwnEvent(self.remote_peer.assist, data)
+            } else if let Some(meta) = meta {
+                ReceiveMessage::Metadata(self.remote_peer.id, meta, data)
+            } else {
+                ReceiveMessage::Data(self.remote_peer.id, data, self.is_direct())
+            };
+            self.out_send(msg).await?;
+
+            if tid != 0 {
+                self.send_core_data(CoreData::Delivery(DeliveryType::Data, tid, delivery_data))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// received the sub-protocol data from remote, send to outside (or its receiver).
+    async fn recv_protocol(&mut self, tid: u64, protocol: u16, data: Vec<u8>) -> Result<()> {
+        if self.is_recv_data {
+            let delivery_data = delivery_split!(data, self.global.delivery_length);
+            let msg =
+                ReceiveMessage::Protocol(self.remote_peer.id, protocol, data, self.is_direct());
+            self.out_send(msg).await?;
+            if tid != 0 {
+                self.send_core_data(CoreData::Delivery(DeliveryType::Data, tid, delivery_data))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn handle_outside(&mut self, msg: SessionMessage) -> Result<()> {
         match msg {
-            SessionMessage::Data(tid, data) => {
-                self.send_core_data(CoreData::Data(tid, data)).await?;
+            SessionMessage::Protocol(tid, protocol, data) => {
+                let data = CoreData::Protocol(tid, protocol, data);
+                if self.remote_version < PROTOCOL_DATA_VERSION {
+                    warn!("CHAMOMILE: remote cannot read the protocol data, drop it.");
+                    let queued = Queued {
+                        data,
+                        confirm: None,
+                        deadline: None,
+                    };
+                    self.fail_queued(queued, "protocol data not supported")
+                        .await?;
+                } else {
+                    let res = self.lanes.push(Priority::Normal, data);
+                    self.queue_result(res).await?;
+                }
+            }
+            SessionMessage::Data(tid, data, priority) => {
+                let res = self.lanes.push(priority, CoreData::Data(tid, data));
+                self.queue_result(res).await?;
+            }
+            SessionMessage::ConfirmData(tid, data, confirm) => {
+                let res = self.lanes.push_confirm(
+                    Priority::Normal,
+                    CoreData::Data(tid, data),
+                    Some(confirm),
+                );
+                self.queue_result(res).await?;
+            }
+            SessionMessage::ExpiringData(tid, data, deadline) => {
+                let res = self.lanes.push_queued(
+                    Priority::Normal,
+                    CoreData::Data(tid, data),
+                    None,
+                    Some(deadline),
+                );
+                self.queue_result(res).await?;
+            }
+            SessionMessage::Metadata(tid, meta, data) => {
+                // cannot encode it, fail it and keep the session.
+                let is_err = metadata_to_bytes(&meta).is_err();
+                let data = CoreData::Metadata(tid, meta, data);
+                if is_err {
+                    warn!("CHAMOMILE: metadata too large, drop it.");
+                    let queued = Queued {
+                        data,
+                        confirm: None,
+                        deadline: None,
+                    };
+                    self.fail_queued(queued, "metadata too large").await?;
+                } else {
+                    let res = self.lanes.push(Priority::Normal, data);
+                    self.queue_result(res).await?;
+                }
+            }
+            SessionMessage::LargeData(data) => self.send_large_data(data),
+            SessionMessage::Sealed(content_key, sealed) => {
+                self.send_sealed(content_key, &sealed).await?;
             }
             SessionMessage::StableConnect(tid, data) => {
                 debug!(
@@ -750,6 +1738,7 @@ impl Session {
                     self.remote_peer.id.short_show()
                 );
 
+                let data = self.global.pow_join(data);
                 self.send_core_data(CoreData::StableConnect(tid, data))
                     .await?;
 
@@ -792,13 +1781,26 @@ impl Session {
                         .await?;
                 } else {
                     debug!("SessionMessage RelayData need relay again");
-                    if let Some((ss, _, _)) = self.global.peer_list.read().await.dht_get(&to) {
+                    if let Some(ss) = self.global.dht_session(&to).await {
                         let _ = ss.send(SessionMessage::RelayData(from, to, data)).await;
                     } else {
                         warn!("CHAMOMILE: CANNOT REACH NETWORK.");
                     }
                 }
             }
+            SessionMessage::RelaySigned(from, to, sign, data) => {
+                debug!("SessionMessage RelaySigned to: {:?}", to.short_show());
+                if self.is_direct() {
+                    self.direct_send(EndpointMessage::RelaySigned(from, to, sign, data))
+                        .await?;
+                } else if let Some(ss) = self.global.dht_session(&to).await {
+                    let _ = ss
+                        .send(SessionMessage::RelaySigned(from, to, sign, data))
+                        .await;
+                } else {
+                    warn!("CHAMOMILE: CANNOT REACH NETWORK.");
+                }
+            }
             SessionMessage::RelayConnect(from_peer, to) => {
                 debug!("SessionMessage RelayConnect to: {:?}", to.short_show());
                 if !self.is_own
@@ -818,7 +1820,7 @@ impl Session {
                         .await?;
                 } else {
                     debug!("SessionMessage RelayData need relay again");
-                    if let Some((ss, _, _)) = self.global.peer_list.read().await.dht_get(&to) {
+                    if let Some(ss) = self.global.dht_session(&to).await {
                         let _ = ss.send(SessionMessage::RelayConnect(from_peer, to)).await;
                     } else {
                         warn!("CHAMOMILE: CANNOT REACH NETWORK.");
@@ -831,9 +1833,30 @@ impl Session {
             SessionMessage::RelayClose(peer_id) => {
                 self.relay_sessions.remove(&peer_id);
             }
+            SessionMessage::RelayReject(from, to, reason) => {
+                self.direct_send(EndpointMessage::RelayReject(from, to, reason))
+                    .await?;
+            }
+            SessionMessage::FindNode(target) => {
+                self.direct_send(EndpointMessage::FindNode(target)).await?;
+            }
+            SessionMessage::Flush(confirm) => {
+                let res = self.flush().await;
+                confirm.resolve_by(&res);
+                res?;
+            }
             SessionMessage::Close => {
+                let _ = self.flush().await;
+                self.flush_received().await;
                 self.close(false).await?;
             }
+            SessionMessage::Disconnect(reason) => {
+                return self.force_disconnect(reason).await;
+            }
+            SessionMessage::Stop(reason, confirm) => {
+                self.stopped = Some(confirm);
+                return self.force_disconnect(reason).await;
+            }
             SessionMessage::DirectIncoming(
                 remote_peer,
                 _stream_sender,
@@ -855,33 +1878,111 @@ impl Session {
         Ok(())
     }
 
-    async fn handle_endpoint(&mut self, msg: EndpointMessage) -> Result<()> {
+    pub(crate) async fn handle_endpoint(&mut self, msg: EndpointMessage) -> Result<()> {
         match msg {
-            EndpointMessage::Close => {
+            EndpointMessage::Close(reason) => {
+                // the remote lifetime is over, answer it, the dialer redials.
+                if reason == Some(CloseReason::Lifetime) {
+                    self.expired = self.is_dial;
+                    let _ = self.direct_send(EndpointMessage::Close(None)).await;
+                }
+                if let Some(reason) = reason {
+                    self.peer_leave(reason).await?;
+                }
                 return Err(new_io_error("close"));
             }
-            EndpointMessage::Handshake(_) => {
-                error!("endpoint handshake only happen once.");
+            EndpointMessage::Handshake(remote) => {
+                self.handle_rekey(remote).await?;
+            }
+            EndpointMessage::Reflexive(addr) => {
+                if self.is_direct() {
+                    self.global
+                        .observe_external(addr, self.remote_peer.socket.ip());
+                }
+            }
+            EndpointMessage::Connected(_) => {
+                debug!("Session: skip connect timer after handshake.");
+            }
+            EndpointMessage::Migrated(socket) => {
+                // same connection and session key, only the address changed.
+                debug!("Session remote migrated to {}.", socket);
+                self.remote_peer.socket = socket;
+                let key = if self.is_own {
+                    self.remote_peer.assist
+                } else {
+                    self.remote_peer.id
+                };
+                self.global.peer_list.write().await.update_socket(
+                    &key,
+                    &self.remote_peer.assist,
+                    socket,
+                );
+            }
+            EndpointMessage::DHT(_) if self.global.static_network => {
+                debug!("Static network, ignore DHT.");
             }
-            EndpointMessage::DHT(DHT(peers)) => {
-                if peers.len() > 0 {
-                    for p in peers {
+            EndpointMessage::FindNode(_) if self.global.static_network => {
+                debug!("Static network, ignore FindNode.");
+            }
+            EndpointMessage::DHT(DHT(peers, load, signs)) => {
+                if !self.is_own {
+                    self.global
+                        .peer_list
+                        .write()
+                        .await
+                        .update_load(self.remote_peer.id, load);
+                }
+                if !peers.is_empty() {
+                    for (i, p) in peers.into_iter().enumerate() {
+                        if self.global.signed_dht {
+                            let (seq, sign) = match signs.get(i) {
+                                Some((seq, sign)) => (*seq, sign.as_slice()),
+                                None => (0, &[][..]),
+                            };
+                            if !peer_record_verify(&p, seq, sign)
+                                || !self.global.record_seq_valid(seq)
+                            {
+                                warn!(
+                                    "CHAMOMILE: DHT entry {} not self signed, drop it.",
+                                    p.id.short_show()
+                                );
+                                continue;
+                            }
+                            if self.is_from_remote(&p.id) {
+                                // remote's own record, keep the newest to gossip.
+                                self.global.record_update(p, seq, sign.to_vec());
+                                continue;
+                            }
+                            if self.global.record_stale(&p.id, seq) {
+                                debug!("DHT entry {} is stale, drop it.", p.id.short_show());
+                                continue;
+                            }
+                        }
                         if self.is_own_remote(&p) {
                             let new_g = self.global.clone();
                             own_spawn(p, new_g);
                         } else if self.is_new_remote(&p).await {
-                            let (session_key, remote_pk) = self.global.generate_remote();
-                            let _ = self
-                                .global
-                                .trans_send(
-                                    &p.transport,
-                                    TransportSendMessage::Connect(p.socket, remote_pk, session_key),
-                                )
-                                .await;
+                            let g = self.global.clone();
+                            tokio::spawn(async move {
+                                let _ = g.dht_connect(&p).await;
+                            });
                         }
                     }
                 }
             }
+            EndpointMessage::FindNode(target) => {
+                let peers = self
+                    .global
+                    .peer_list
+                    .read()
+                    .await
+                    .find_node(&target)
+                    .into_iter()
+                    .filter(|p| !self.is_from_remote(&p.id))
+                    .collect();
+                let dht = self.global.dht(peers);
+                self.direct_send(EndpointMessage::DHT(dht)).await?;
+            }
             EndpointMessage::Hole(_hole) => {
                 // TODO
             }
@@ -889,53 +1990,162 @@ impl Session {
                 // TODO
             }
             EndpointMessage::Data(e_data) => {
+                self.stats.recv(e_data.len());
                 self.handle_core_data(e_data).await?;
             }
+            EndpointMessage::Sealed(wrapped, sealed) => {
+                let len = wrapped.len() + sealed.len();
+                self.stats.recv(len);
+                match self.session_key.open(wrapped, &sealed) {
+                    Ok((seq, bytes)) => {
+                        self.decrypt_failures = 0;
+                        // only on the direct stream in order, so the not newer is a replay.
+                        if seq <= self.sealed_recv {
+                            debug!("Sealed data is replayed, drop it.");
+                        } else {
+                            self.sealed_recv = seq;
+                            if let Ok(CoreData::Data(tid, data)) = CoreData::from_bytes(bytes) {
+                                self.recv_data(tid, None, data).await?;
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        warn!("Session Key decrypt failure!");
+                        self.handle_decrypt_failure().await?;
+                    }
+                }
+            }
             EndpointMessage::RelayData(from, to, data) => {
                 debug!(
                     "Endpoint RelayData from: {}, to: {}",
                     from.to_hex(),
                     to.to_hex()
                 );
+                if !self.global.seen.check(seen_id(&from, &to, &data)) {
+                    debug!("RelayData is duplicate, drop it.");
+                    return Ok(());
+                }
                 if self.is_to_me(&to) {
                     debug!("Got self RelayData");
                     if self.is_from_remote(&from) {
                         self.handle_core_data(data).await?;
                     } else {
-                        if let Some(stream_sender) =
-                            self.global.peer_list.read().await.get_stable_stream(&from)
-                        {
+                        let stable_stream = self
+                            .global
+                            .peer_list
+                            .read()
+                            .await
+                            .get_stable_stream(&from)
+                            .cloned();
+                        if let Some(stream_sender) = stable_stream {
                             debug!("RelayData is in STABLE.");
                             let _ = stream_sender.send(EndpointMessage::Data(data)).await;
-                        } else if let Some(stream_sender) =
-                            self.global.buffer.read().await.get_tmp_stream(&from)
-                        {
+                        } else if self.global.buffer.read().await.has_tmp(&from) {
                             debug!("RelayData is in TMP.");
-                            let _ = stream_sender.send(EndpointMessage::Data(data)).await;
+                            let stream = self.global.buffer.write().await.buffer_tmp(
+                                &from,
+                                data.len(),
+                                &self.global.tiers,
+                            );
+                            if let Some(stream_sender) = stream {
+                                let _ = stream_sender.send(EndpointMessage::Data(data)).await;
+                            }
+                        } else if self.global.relay_verify {
+                            warn!("CHAMOMILE: RelayData cannot verify origin, drop it.");
+                        } else if self.global.require_encryption {
+                            warn!("CHAMOMILE: RelayData is not encrypted by origin, drop it.");
                         } else {
                             debug!("RelayData is MISSING.");
                             if self.is_recv_data {
                                 // only happen permissionless
-                                self.out_send(ReceiveMessage::Data(from, data)).await?;
+                                self.out_send(ReceiveMessage::Data(from, data, false))
+                                    .await?;
                             }
                         }
                     }
                 } else {
                     if self.global.is_relay_data {
-                        if let Some(sender) = self
+                        if self.global.relay_verify && !self.is_from_remote(&from) {
+                            // only the origin's own session proves the unsigned from.
+                            warn!("CHAMOMILE: RelayData cannot verify origin, drop it.");
+                        } else if !self.relay_allow(&from, &to) {
+                            debug!("RelayData flows is full, drop it.");
+                        } else if let Some(sender) = self
                             .global
-                            .peer_list
-                            .read()
-                            .await
                             .next_closest(&to, &[self.remote_peer.id, self.remote_peer.assist])
+                            .await
                         {
-                            let _ = sender.send(SessionMessage::RelayData(from, to, data)).await;
+                            self.global.relay_load.add(data.len());
+                            let msg = SessionMessage::RelayData(from, to, data);
+                            if sender.send(msg).await.is_err() {
+                                debug!("RelayData next closest is closed, tell the source.");
+                                let reason = RejectReason::Unreachable;
+                                self.direct_send(EndpointMessage::RelayReject(from, to, reason))
+                                    .await?;
+                            }
                         } else {
                             debug!("RelayData not found next closest!");
                         }
                     }
                 }
             }
+            EndpointMessage::RelaySigned(from, to, sign, data) => {
+                // signed by the origin, or the origin proof sent by a gateway.
+                let origin = if relay_verify(&from, &to, &sign, &data) {
+                    None
+                } else if let Some(proof) = origin_verify(&from, &to, &sign, &data) {
+                    Some(proof)
+                } else {
+                    warn!("CHAMOMILE: RelaySigned origin is forged, drop it.");
+                    return Ok(());
+                };
+                if !self.global.seen.check(seen_id(&from, &to, &data)) {
+                    debug!("RelaySigned is duplicate, drop it.");
+                    return Ok(());
+                }
+
+                if self.is_to_me(&to) {
+                    let data = if let Some((gateway, timestamp)) = origin {
+                        if !self.global.timestamp_valid(timestamp, ORIGIN_PROOF_WINDOW) {
+                            warn!("CHAMOMILE: RelaySigned origin proof is expired, drop it.");
+                            return Ok(());
+                        }
+                        if !self.global.origin_seen.check(seen_id(&from, &to, &data)) {
+                            warn!("CHAMOMILE: RelaySigned origin proof is replayed, drop it.");
+                            return Ok(());
+                        }
+                        debug!("RelaySigned via gateway {}.", gateway.short_show());
+                        data[ORIGIN_HEAD_LENGTH..].to_vec()
+                    } else {
+                        data
+                    };
+                    if self.global.require_encryption {
+                        warn!("CHAMOMILE: RelaySigned is not encrypted by origin, drop it.");
+                    } else if self.is_recv_data {
+                        self.out_send(ReceiveMessage::Data(from, data, false))
+                            .await?;
+                    }
+                } else if self.global.is_relay_data {
+                    if !self.relay_allow(&from, &to) {
+                        debug!("RelaySigned flows is full, drop it.");
+                    } else if let Some(sender) = self
+                        .global
+                        .next_closest(&to, &[self.remote_peer.id, self.remote_peer.assist])
+                        .await
+                    {
+                        self.global.relay_load.add(data.len());
+                        let msg = SessionMessage::RelaySigned(from, to, sign, data);
+                        if sender.send(msg).await.is_err() {
+                            debug!("RelaySigned next closest is closed, tell the source.");
+                            let reason = RejectReason::Unreachable;
+                            self.direct_send(EndpointMessage::RelayReject(from, to, reason))
+                                .await?;
+                        }
+                    } else {
+                        debug!("RelaySigned not found next closest!");
+                    }
+                }
+            }
             EndpointMessage::RelayHandshake(from_peer, to) => {
                 debug!(
                     "Relay Handshake to: {:?}, is me: {}",
@@ -943,14 +2153,14 @@ impl Session {
                     self.is_to_me(&to),
                 );
                 if self.is_to_me(&to) {
-                    let mut remote_peer_id = from_peer.id().clone();
+                    let mut remote_peer_id = *from_peer.id();
                     let mut is_own = false;
                     if &remote_peer_id == self.global.peer_id() {
                         if from_peer.assist() == self.global.assist_id() {
                             warn!("CHAMOMILE: RELAY NERVER TO SELF.");
                             return Ok(());
                         }
-                        remote_peer_id = from_peer.assist().clone();
+                        remote_peer_id = *from_peer.assist();
                         is_own = true;
                     }
 
@@ -973,9 +2183,20 @@ impl Session {
                     }
 
                     // this is relay connect receiver.
-                    let RemotePublic(remote_peer, dh_key) = from_peer;
+                    if !from_peer.is_compatible() {
+                        debug!("Relay Handshake version mismatch, skip it.");
+                        return Ok(());
+                    }
+                    let RemotePublic(remote_peer, dh_key, remote_version) = from_peer;
+                    let permit = match self.global.session_permit(&remote_peer.id) {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            debug!("Relay Handshake too many sessions, skip it.");
+                            return Ok(());
+                        }
+                    };
 
-                    let result = self.global.complete_remote(&remote_peer.id, dh_key);
+                    let result = self.global.complete_remote(&remote_peer.id, dh_key).await;
                     if result.is_none() {
                         return Ok(());
                     }
@@ -990,17 +2211,20 @@ impl Session {
                         false,
                     );
 
-                    let new_session = Session::new(
+                    let mut new_session = Session::new(
                         remote_peer,
                         new_session_sender,
                         new_stream_receiver,
                         ConnectType::Relay(self.session_sender.clone()),
                         new_session_key,
                         self.global.clone(),
-                        is_own || false, // default is not recv data.
+                        is_own, // default is not recv data.
                         is_own,
                     );
 
+                    new_session.permit = permit;
+                    new_session.remote_version = remote_version;
+
                     // if use session_run directly, it will cycle error in rust check.
                     session_spawn(new_session, new_session_receiver);
 
@@ -1011,33 +2235,82 @@ impl Session {
                     .await?;
                 } else {
                     if self.global.is_relay_data {
-                        if let Some(sender) = self
+                        let from = *from_peer.id();
+                        if !self.relay_allow(&from, &to) {
+                            debug!("RelayHandshake flows is full, decline it.");
+                            let reason = RejectReason::Full;
+                            self.direct_send(EndpointMessage::RelayReject(from, to, reason))
+                                .await?;
+                        } else if let Some(sender) = self
                             .global
-                            .peer_list
-                            .read()
-                            .await
                             .next_closest(&to, &[self.remote_peer.id, self.remote_peer.assist])
+                            .await
                         {
-                            let _ = sender
-                                .send(SessionMessage::RelayConnect(from_peer, to))
-                                .await;
+                            let msg = SessionMessage::RelayConnect(from_peer, to);
+                            if sender.send(msg).await.is_err() {
+                                debug!("RelayHandshake next closest is closed, decline it.");
+                                let reason = RejectReason::Unreachable;
+                                self.direct_send(EndpointMessage::RelayReject(from, to, reason))
+                                    .await?;
+                            }
                         } else {
                             debug!("RelayHandshake not found next closest!");
                         }
                     }
                 }
             }
+            EndpointMessage::RelayReject(from, to, reason) => {
+                debug!("Relay Reject to: {:?} {:?}", to.short_show(), reason);
+                if self.is_to_me(&from) {
+                    // the relay is full, mark it, next time will choose other relays.
+                    // unreachable is the next hop gone, not the relay load.
+                    if reason == RejectReason::Full {
+                        self.global
+                            .peer_list
+                            .write()
+                            .await
+                            .update_load(self.remote_peer.id, u32::MAX);
+                    }
+                    if let Some(sender) = self.global.buffer.read().await.get_tmp_session(&to) {
+                        let _ = sender.send(SessionMessage::Close).await;
+                    }
+                } else if let Some(sender) = self
+                    .global
+                    .next_closest(&from, &[self.remote_peer.id, self.remote_peer.assist])
+                    .await
+                {
+                    let msg = SessionMessage::RelayReject(from, to, reason);
+                    let _ = sender.send(msg).await;
+                }
+            }
         }
 
         Ok(())
     }
 
     async fn handle_heartbeat(&mut self) -> Result<()> {
+        // the link is busy, no need ping.
+        if self.is_active {
+            self.is_active = false;
+            self.heartbeat = 0;
+            return Ok(());
+        }
+
+        // the remote is not read when delivery is full, only keep the remote alive.
+        if !self.can_deliver() {
+            self.heartbeat = 0;
+            return self.send_core_data(CoreData::Ping).await;
+        }
+
         if self.heartbeat > 3 {
+            self.lost = true;
             return Err(new_io_error("timeout"));
         }
 
         self.heartbeat += 1;
+        // the rtt is measured from the first unanswered ping.
+        self.ping_at.get_or_insert_with(Instant::now);
+        // ping is sent directly, ahead of all queued lanes.
         self.send_core_data(CoreData::Ping).await
     }
 
@@ -1051,22 +2324,48 @@ impl Session {
 
 /// server send to session message in channel.
 pub(crate) enum SessionMessage {
-    /// send bytes to session what want to send to peer..
-    Data(u64, Vec<u8>),
+    /// send bytes to session what want to send to peer, with the priority.
+    Data(u64, Vec<u8>, Priority),
+    /// send bytes to session, and confirm when handed to the endpoint.
+    ConfirmData(u64, Vec<u8>, Confirm),
+    /// send bytes to session, drop it if not sent before the deadline.
+    ExpiringData(u64, Vec<u8>, Instant),
+    /// send all queued data to endpoint, and confirm when done.
+    Flush(Confirm),
+    /// send bytes with metadata to session.
+    Metadata(u64, Metadata, Vec<u8>),
+    /// send bytes of a sub-protocol to session.
+    Protocol(u64, u16, Vec<u8>),
+    /// send large bytes to peer as chunks.
+    LargeData(Vec<u8>),
+    /// send the sealed data (content key, sealed data) shared with other sessions.
+    Sealed([u8; 32], Arc<Vec<u8>>),
     /// when need build a stable connection.
     StableConnect(u64, Vec<u8>),
     /// when receive a stable result.
     StableResult(u64, bool, bool, Vec<u8>),
     /// relay data help.
     RelayData(PeerId, PeerId, Vec<u8>),
+    /// relay data with origin signature help.
+    RelaySigned(PeerId, PeerId, Vec<u8>, Vec<u8>),
     /// relay connect help.
     RelayConnect(RemotePublic, PeerId),
     /// relay connect result from other sessions.
     RelayResult(RemotePublic, Sender<SessionMessage>),
     /// relay closed.
     RelayClose(PeerId),
+    /// relay declined or undeliverable, send back to the source.
+    RelayReject(PeerId, PeerId, RejectReason),
+    /// find node help, ask remote for the target.
+    FindNode(PeerId),
     /// close the session.
     Close,
+    /// force close the session, and tell remote the reason.
+    Disconnect(CloseReason),
+    /// same as `Disconnect`, and confirm when the session is fully closed. no other
+    /// cancellation handle, it is a message in the session channel (the sender kept
+    /// in the peer list), so handled after the messages queued before it.
+    Stop(CloseReason, Confirm),
     /// Directly incoming.
     DirectIncoming(
         Peer,
@@ -1081,27 +2380,69 @@ pub(crate) fn new_session_channel() -> (Sender<SessionMessage>, Receiver<Session
     mpsc::channel(1024)
 }
 
+/// the head of padded core data: tag (1) + inner length (4).
+const PADDING_HEAD: usize = 5;
+/// the AEAD tag length of the encrypted frame.
+const AEAD_TAG: usize = 16;
+
 /// core data transfer and encrypted.
+/// the data in session, first byte is the tag.
+/// unknown tag (from newer version) is skipped by the session, so new tag can be added.
 pub(crate) enum CoreData {
     Ping,
-    Pong,
+    /// pong with self relay load.
+    Pong(u32),
     Data(u64, Vec<u8>),
     Delivery(DeliveryType, u64, Vec<u8>),
     StableConnect(u64, Vec<u8>),
     StableResult(u64, bool, Vec<u8>),
     ResultConnect(u64, Vec<u8>),
     Unstable,
+    /// large data chunk: id, offset, is_final, data.
+    Chunk(u64, u64, bool, Vec<u8>),
+    /// data with metadata: tid, metadata, data.
+    Metadata(u64, Metadata, Vec<u8>),
+    /// the lane frame with the session sequence (from 1), for gap detection.
+    Sequenced(u64, Box<CoreData>),
+    /// the data of a sub-protocol (tid, protocol, data).
+    Protocol(u64, u16, Vec<u8>),
+}
+
+/// metadata bytes: count (u16), every is key_len (u16), key, value_len (u32), value.
+/// error when the count or a length is out of range.
+fn metadata_to_bytes(meta: &Metadata) -> Result<Vec<u8>> {
+    let error = || new_io_error("Metadata too large");
+    let mut bytes = vec![];
+    bytes.extend(
+        &u16::try_from(meta.len())
+            .map_err(|_| error())?
+            .to_le_bytes()[..],
+    );
+    for (key, value) in meta {
+        bytes.extend(&u16::try_from(key.len()).map_err(|_| error())?.to_le_bytes()[..]);
+        bytes.extend(key.as_bytes());
+        bytes.extend(
+            &u32::try_from(value.len())
+                .map_err(|_| error())?
+                .to_le_bytes()[..],
+        );
+        bytes.extend(value);
+    }
+    Ok(bytes)
 }
 
+#[allow(clippy::wrong_self_convention)]
 impl CoreData {
-    pub fn to_bytes(self) -> Vec<u8> {
+    /// error when the metadata is too large to encode.
+    pub fn to_bytes(self) -> Result<Vec<u8>> {
         let mut bytes = vec![0u8];
         match self {
             CoreData::Ping => {
                 bytes[0] = 1u8;
             }
-            CoreData::Pong => {
+            CoreData::Pong(load) => {
                 bytes[0] = 2u8;
+                bytes.extend(&load.to_le_bytes()[..]);
             }
             CoreData::Data(tid, mut data) => {
                 bytes[0] = 3u8;
@@ -1138,20 +2479,81 @@ impl CoreData {
             CoreData::Unstable => {
                 bytes[0] = 8u8;
             }
+            CoreData::Chunk(id, offset, is_final, mut data) => {
+                bytes[0] = 9u8;
+                bytes.extend(&id.to_le_bytes()[..]);
+                bytes.extend(&offset.to_le_bytes()[..]);
+                bytes.push(if is_final { 1u8 } else { 0u8 });
+                bytes.append(&mut data);
+            }
+            CoreData::Metadata(tid, meta, mut data) => {
+                bytes[0] = 10u8;
+                bytes.extend(&tid.to_le_bytes()[..]);
+                bytes.append(&mut metadata_to_bytes(&meta)?);
+                bytes.append(&mut data);
+            }
+            CoreData::Sequenced(seq, inner) => {
+                bytes[0] = 11u8;
+                bytes.extend(&seq.to_le_bytes()[..]);
+                bytes.append(&mut inner.to_bytes()?);
+            }
+            CoreData::Protocol(tid, protocol, mut data) => {
+                bytes[0] = 13u8;
+                bytes.extend(&tid.to_le_bytes()[..]);
+                bytes.extend(&protocol.to_le_bytes()[..]);
+                bytes.append(&mut data);
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    /// the bytes padded to the frame bucket: tag, inner length (u32), inner bytes and
+    /// zeros, the encrypted frame size is the bucket.
+    pub fn to_padded_bytes(self, padding: &FramePadding) -> Result<Vec<u8>> {
+        let inner = self.to_bytes()?;
+        if padding == &FramePadding::Off {
+            return Ok(inner);
         }
+        let inner_len =
+            u32::try_from(inner.len()).map_err(|_| new_io_error("CoreData too large"))?;
+        let len = inner.len() + PADDING_HEAD + AEAD_TAG;
+        let size = padding.target(len).max(len);
+        let mut bytes = Vec::with_capacity(size - AEAD_TAG);
+        bytes.push(12u8);
+        bytes.extend(&inner_len.to_le_bytes()[..]);
+        bytes.extend(inner);
+        bytes.resize(size - AEAD_TAG, 0);
+        Ok(bytes)
+    }
 
-        bytes
+    /// split the sequence (if has) and the frame.
+    pub fn unsequenced(self) -> (Option<u64>, CoreData) {
+        match self {
+            CoreData::Sequenced(seq, inner) => (Some(seq), *inner),
+            data => (None, data),
+        }
     }
 
-    fn from_bytes(mut bytes: Vec<u8>) -> std::result::Result<Self, ()> {
-        if bytes.len() < 1 {
+    pub fn from_bytes(mut bytes: Vec<u8>) -> std::result::Result<Self, ()> {
+        if bytes.is_empty() {
             return Err(());
         }
 
         let t: Vec<u8> = bytes.drain(0..1).collect();
         match t[0] {
             1u8 => Ok(CoreData::Ping),
-            2u8 => Ok(CoreData::Pong),
+            2u8 => {
+                // relay load is optional, old version donnot have it.
+                let load = if bytes.len() >= 4 {
+                    let mut load_bytes = [0u8; 4];
+                    load_bytes.copy_from_slice(&bytes[0..4]);
+                    u32::from_le_bytes(load_bytes)
+                } else {
+                    0
+                };
+                Ok(CoreData::Pong(load))
+            }
             3u8 => {
                 if bytes.len() < 8 {
                     return Err(());
@@ -1205,7 +2607,1715 @@ impl CoreData {
                 Ok(CoreData::ResultConnect(tid, bytes))
             }
             8u8 => Ok(CoreData::Unstable),
+            9u8 => {
+                if bytes.len() < 17 {
+                    return Err(());
+                }
+                let mut id_bytes = [0u8; 8];
+                id_bytes.copy_from_slice(bytes.drain(0..8).as_slice());
+                let mut offset_bytes = [0u8; 8];
+                offset_bytes.copy_from_slice(bytes.drain(0..8).as_slice());
+                let is_final = bytes.drain(0..1).as_slice()[0] == 1u8;
+                Ok(CoreData::Chunk(
+                    u64::from_le_bytes(id_bytes),
+                    u64::from_le_bytes(offset_bytes),
+                    is_final,
+                    bytes,
+                ))
+            }
+            10u8 => {
+                if bytes.len() < 10 {
+                    return Err(());
+                }
+                let mut tid_bytes = [0u8; 8];
+                tid_bytes.copy_from_slice(bytes.drain(0..8).as_slice());
+                let tid = u64::from_le_bytes(tid_bytes);
+                let mut count_bytes = [0u8; 2];
+                count_bytes.copy_from_slice(bytes.drain(0..2).as_slice());
+                let mut meta = Metadata::new();
+                for _ in 0..u16::from_le_bytes(count_bytes) {
+                    if bytes.len() < 2 {
+                        return Err(());
+                    }
+                    let mut len_bytes = [0u8; 2];
+                    len_bytes.copy_from_slice(bytes.drain(0..2).as_slice());
+                    let len = u16::from_le_bytes(len_bytes) as usize;
+                    if bytes.len() < len + 4 {
+                        return Err(());
+                    }
+                    let key = String::from_utf8(bytes.drain(0..len).collect()).map_err(|_| ())?;
+                    let mut len_bytes = [0u8; 4];
+                    len_bytes.copy_from_slice(bytes.drain(0..4).as_slice());
+                    let len = u32::from_le_bytes(len_bytes) as usize;
+                    if bytes.len() < len {
+                        return Err(());
+                    }
+                    meta.insert(key, bytes.drain(0..len).collect());
+                }
+                Ok(CoreData::Metadata(tid, meta, bytes))
+            }
+            11u8 => {
+                if bytes.len() < 8 {
+                    return Err(());
+                }
+                let mut seq_bytes = [0u8; 8];
+                seq_bytes.copy_from_slice(bytes.drain(0..8).as_slice());
+                let seq = u64::from_le_bytes(seq_bytes);
+                // check the inner tag before parse it, never nested.
+                if matches!(bytes.first(), Some(&11u8) | Some(&12u8)) {
+                    return Err(());
+                }
+                let inner = CoreData::from_bytes(bytes)?;
+                Ok(CoreData::Sequenced(seq, Box::new(inner)))
+            }
+            12u8 => {
+                // padded, strip it. the inner is one sequenced (checked by itself) or plain.
+                if bytes.len() < 4 {
+                    return Err(());
+                }
+                let mut len_bytes = [0u8; 4];
+                len_bytes.copy_from_slice(bytes.drain(0..4).as_slice());
+                let len = u32::from_le_bytes(len_bytes) as usize;
+                if bytes.len() < len || bytes.first() == Some(&12u8) {
+                    return Err(());
+                }
+                bytes.truncate(len);
+                CoreData::from_bytes(bytes)
+            }
+            13u8 => {
+                if bytes.len() < 10 {
+                    return Err(());
+                }
+                let mut tid_bytes = [0u8; 8];
+                tid_bytes.copy_from_slice(bytes.drain(0..8).as_slice());
+                let tid = u64::from_le_bytes(tid_bytes);
+                let mut protocol_bytes = [0u8; 2];
+                protocol_bytes.copy_from_slice(bytes.drain(0..2).as_slice());
+                let protocol = u16::from_le_bytes(protocol_bytes);
+                Ok(CoreData::Protocol(tid, protocol, bytes))
+            }
             _ => Err(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::RelayFlows;
+    use crate::transports::PROTOCOL_DEFAULT_VERSION;
+    use chamomile_types::key::{Key, SIGNATURE_LENGTH};
+    use chamomile_types::types::TransportType;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// golden bytes, all integers are fixed endianness, not depend on target.
+    #[test]
+    fn test_core_data_golden_bytes() {
+        let tid = 0x0102030405060708u64;
+        let tid_bytes = [8u8, 7, 6, 5, 4, 3, 2, 1];
+
+        assert_eq!(CoreData::Ping.to_bytes().unwrap(), vec![1u8]);
+        assert_eq!(
+            CoreData::Pong(0x0a0b).to_bytes().unwrap(),
+            vec![2u8, 0x0b, 0x0a, 0, 0]
+        );
+        assert_eq!(CoreData::Unstable.to_bytes().unwrap(), vec![8u8]);
+
+        let expected = [&[3u8][..], &tid_bytes, &[9, 9]].concat();
+        assert_eq!(
+            CoreData::Data(tid, vec![9, 9]).to_bytes().unwrap(),
+            expected
+        );
+        match CoreData::from_bytes(expected).unwrap() {
+            CoreData::Data(t, data) => assert_eq!((t, data), (tid, vec![9, 9])),
+            _ => panic!("core data decode failure"),
+        }
+
+        let expected = [&[4u8, 1][..], &tid_bytes, &[9]].concat();
+        let data = CoreData::Delivery(DeliveryType::StableConnect, tid, vec![9]);
+        assert_eq!(data.to_bytes().unwrap(), expected);
+
+        let expected = [&[5u8][..], &tid_bytes, &[9]].concat();
+        assert_eq!(
+            CoreData::StableConnect(tid, vec![9]).to_bytes().unwrap(),
+            expected
+        );
+
+        let expected = [&[6u8][..], &tid_bytes, &[1, 9]].concat();
+        assert_eq!(
+            CoreData::StableResult(tid, true, vec![9])
+                .to_bytes()
+                .unwrap(),
+            expected
+        );
+
+        let expected = [&[7u8][..], &tid_bytes, &[9]].concat();
+        assert_eq!(
+            CoreData::ResultConnect(tid, vec![9]).to_bytes().unwrap(),
+            expected
+        );
+
+        let expected = [&[9u8][..], &tid_bytes, &[2, 0, 0, 0, 0, 0, 0, 0, 1, 9]].concat();
+        assert_eq!(
+            CoreData::Chunk(tid, 2, true, vec![9]).to_bytes().unwrap(),
+            expected
+        );
+        match CoreData::from_bytes(expected).unwrap() {
+            CoreData::Chunk(i, o, f, data) => assert_eq!((i, o, f, data), (tid, 2, true, vec![9])),
+            _ => panic!("core data decode failure"),
+        }
+
+        let expected = [&[13u8][..], &tid_bytes, &[2, 0, 9]].concat();
+        assert_eq!(
+            CoreData::Protocol(tid, 2, vec![9]).to_bytes().unwrap(),
+            expected
+        );
+        match CoreData::from_bytes(expected).unwrap() {
+            CoreData::Protocol(t, p, data) => assert_eq!((t, p, data), (tid, 2, vec![9])),
+            _ => panic!("core data decode failure"),
+        }
+    }
+
+    #[test]
+    fn test_nested_core_data() {
+        // the padded sequenced frame is fine.
+        let data = CoreData::Sequenced(1, Box::new(CoreData::Ping));
+        let bytes = data.to_padded_bytes(&FramePadding::PowerOfTwo).unwrap();
+        assert!(matches!(
+            CoreData::from_bytes(bytes),
+            Ok(CoreData::Sequenced(1, inner)) if matches!(*inner, CoreData::Ping)
+        ));
+
+        // deep nested headers are rejected, not overflow the stack.
+        let mut sequenced = CoreData::Ping.to_bytes().unwrap();
+        let mut mixed = sequenced.clone();
+        for _ in 0..10_000 {
+            let mut head = vec![11u8];
+            head.extend(&[0u8; 8]);
+            sequenced.splice(0..0, head.clone());
+
+            mixed.splice(0..0, head);
+            let mut head = vec![12u8];
+            head.extend(&(mixed.len() as u32).to_le_bytes());
+            mixed.splice(0..0, head);
+        }
+        assert!(CoreData::from_bytes(sequenced).is_err());
+        assert!(CoreData::from_bytes(mixed).is_err());
+    }
+
+    /// every variant decode and encode back to the same bytes.
+    #[test]
+    fn test_core_data_round_trip() {
+        let datas = vec![
+            CoreData::Ping,
+            CoreData::Pong(u32::MAX),
+            CoreData::Protocol(u64::MAX, u16::MAX, vec![]),
+            CoreData::Data(1, vec![]),
+            CoreData::Data(u64::MAX, vec![1, 2, 3]),
+            CoreData::Delivery(DeliveryType::Data, 2, vec![1]),
+            CoreData::Delivery(DeliveryType::StableConnect, 3, vec![]),
+            CoreData::Delivery(DeliveryType::StableResult, 4, vec![1, 2]),
+            CoreData::StableConnect(5, vec![1]),
+            CoreData::StableResult(6, false, vec![]),
+            CoreData::StableResult(7, true, vec![1, 2]),
+            CoreData::ResultConnect(8, vec![1]),
+            CoreData::Unstable,
+            CoreData::Chunk(9, 65536, false, vec![0; 16]),
+            CoreData::Chunk(10, 0, true, vec![]),
+            CoreData::Metadata(11, Metadata::new(), vec![1]),
+            CoreData::Metadata(
+                12,
+                Metadata::from([
+                    ("content-type".to_owned(), b"text/plain".to_vec()),
+                    ("seq".to_owned(), 7u64.to_le_bytes().to_vec()),
+                ]),
+                vec![1, 2, 3],
+            ),
+        ];
+        for data in datas {
+            let bytes = data.to_bytes().unwrap();
+            let decoded = CoreData::from_bytes(bytes.clone()).unwrap();
+            assert_eq!(decoded.to_bytes().unwrap(), bytes);
+        }
+
+        assert!(CoreData::from_bytes(vec![]).is_err());
+        assert!(CoreData::from_bytes(vec![0u8]).is_err());
+
+        // key length is out of u16, not truncated.
+        let long_key = "k".repeat(u16::MAX as usize + 1);
+        let meta = Metadata::from([(long_key, vec![1])]);
+        assert!(CoreData::Metadata(13, meta, vec![]).to_bytes().is_err());
+    }
+
+    fn test_session() -> (Session, Receiver<EndpointMessage>) {
+        let (global, _) = crate::global::tests::test_global();
+        test_session_with(global, false)
+    }
+
+    fn test_session_with(
+        global: Global,
+        is_recv_data: bool,
+    ) -> (Session, Receiver<EndpointMessage>) {
+        let (session_key, _) = SessionKey::generate(global.key.as_ref());
+        let (session_sender, _) = new_session_channel();
+        let (_, stream_receiver) = new_endpoint_channel();
+        let (endpoint_sender, endpoint_receiver) = new_endpoint_channel();
+        let session = Session::new(
+            Peer::peer(PeerId([1u8; 20])),
+            session_sender,
+            stream_receiver,
+            ConnectType::Direct(endpoint_sender),
+            session_key,
+            Arc::new(global),
+            is_recv_data,
+            false,
+        );
+        (session, endpoint_receiver)
+    }
+
+    #[tokio::test]
+    async fn test_self_session() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        session.remote_peer = session.global.peer;
+        let (_session_sender, session_receiver) = new_session_channel();
+        session.listen(session_receiver).await.unwrap();
+
+        assert!(matches!(
+            endpoint_receiver.try_recv(),
+            Ok(EndpointMessage::Close(None))
+        ));
+        assert!(!session.global.health.bootstrapped());
+    }
+
+    #[tokio::test]
+    async fn test_require_encryption() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        global.require_encryption = true;
+        let me = *global.peer_id();
+        let (mut session, _) = test_session_with(global, true);
+
+        let origin = Key::generate(&mut ChaChaRng::from_seed([2u8; 32]));
+        let origin_id = origin.peer_id();
+        let data = vec![1u8, 2, 3];
+        let sign = crate::global::relay_sign(&origin, &origin_id, &me, &data);
+
+        // the relayed plaintext of origin (no session with it) is dropped.
+        let msg = EndpointMessage::RelayData(origin_id, me, data.clone());
+        session.handle_endpoint(msg).await.unwrap();
+        let msg = EndpointMessage::RelaySigned(origin_id, me, sign.clone(), data.clone());
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(out_receiver.try_recv().is_err());
+
+        // not strict, delivered.
+        Arc::get_mut(&mut session.global)
+            .unwrap()
+            .require_encryption = false;
+        let msg = EndpointMessage::RelaySigned(origin_id, me, sign, data.clone());
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            out_receiver.try_recv(),
+            Ok(ReceiveMessage::Data(from, d, false)) if from == origin_id && d == data
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_active() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        for _ in 0..10 {
+            // received frame in every interval.
+            session.is_active = true;
+            session.handle_heartbeat().await.unwrap();
+        }
+        assert!(endpoint_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_confirm_data() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        let (session_sender, session_receiver) = new_session_channel();
+        let (_stream_sender, stream_receiver) = new_endpoint_channel();
+        session.stream_receiver = stream_receiver;
+        let task = tokio::spawn(async move {
+            let _ = session.forever(session_receiver).await;
+            session
+        });
+
+        // healthy session, resolved when handed to endpoint.
+        let (tx, rx) = oneshot::channel();
+        session_sender
+            .send(SessionMessage::ConfirmData(0, vec![1], Confirm::new(tx)))
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().is_ok());
+        assert!(matches!(
+            endpoint_receiver.recv().await,
+            Some(EndpointMessage::Data(_))
+        ));
+
+        // session closed before send.
+        drop(session_sender);
+        let session = task.await.unwrap();
+        let (tx, rx) = oneshot::channel();
+        let pushed = session.lanes.push_confirm(
+            Priority::Normal,
+            CoreData::Data(0, vec![2]),
+            Some(Confirm::new(tx)),
+        );
+        assert!(pushed.is_ok());
+        drop(session);
+        assert!(rx.await.unwrap().is_err());
+
+        // endpoint closed, send failure.
+        let (mut session, endpoint_receiver) = test_session();
+        drop(endpoint_receiver);
+        let (tx, rx) = oneshot::channel();
+        let pushed = session.lanes.push_confirm(
+            Priority::High,
+            CoreData::Data(0, vec![3]),
+            Some(Confirm::new(tx)),
+        );
+        assert!(pushed.is_ok());
+        let (_session_sender, session_receiver) = new_session_channel();
+        let (_stream_sender, stream_receiver) = new_endpoint_channel();
+        session.stream_receiver = stream_receiver;
+        assert!(session.forever(session_receiver).await.is_err());
+        assert!(rx.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_priority_lanes() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        let (session_sender, session_receiver) = new_session_channel();
+        let (_stream_sender, stream_receiver) = new_endpoint_channel();
+        session.stream_receiver = stream_receiver;
+
+        // bulk transfer (16 chunks) is queued before the high data.
+        let large = vec![7u8; CHUNK_SIZE * 16];
+        session_sender
+            .send(SessionMessage::LargeData(large))
+            .await
+            .unwrap();
+        session_sender
+            .send(SessionMessage::Data(0, vec![1], Priority::High))
+            .await
+            .unwrap();
+        let task = tokio::spawn(async move {
+            let _ = session.forever(session_receiver).await;
+            session
+        });
+
+        let mut frames = vec![];
+        let mut chunks = 0;
+        while chunks < 16 {
+            if let EndpointMessage::Data(e_data) = endpoint_receiver.recv().await.unwrap() {
+                if e_data.len() > CHUNK_SIZE {
+                    chunks += 1;
+                }
+                frames.push(e_data);
+            }
+        }
+        drop(session_sender);
+        let session = task.await.unwrap();
+
+        let mut high = None;
+        let mut first_chunk = None;
+        for (i, e_data) in frames.into_iter().enumerate() {
+            let bytes = session.session_key.decrypt(e_data).unwrap();
+            match CoreData::from_bytes(bytes).unwrap() {
+                CoreData::Data(0, data) if data == vec![1] => high = Some(i),
+                CoreData::Chunk(..) if first_chunk.is_none() => first_chunk = Some(i),
+                _ => {}
+            }
+        }
+        assert!(high.unwrap() < first_chunk.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_early_data_confirmed() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, _endpoint_receiver) = test_session_with(global, true);
+        session.session_key.set_early(vec![7]);
+        assert!(out_receiver.try_recv().is_err());
+
+        // first frame from remote, the key is confirmed.
+        let e_data = session
+            .session_key
+            .encrypt(CoreData::Ping.to_bytes().unwrap());
+        let msg = EndpointMessage::Data(e_data);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            out_receiver.try_recv(),
+            Ok(ReceiveMessage::Data(_, data, true)) if data == vec![7]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lanes_bounded() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(LANE_BUFFER * 2);
+        global.out_sender = out_sender;
+        let (mut session, _) = test_session_with(global, true);
+
+        // full lane, stop reading outside, and the more is failed.
+        for i in 1..=LANE_BUFFER as u64 {
+            let pushed = session
+                .lanes
+                .push(Priority::Normal, CoreData::Data(i, vec![1]));
+            assert!(pushed.is_ok());
+        }
+        assert!(!session.lanes.has_room());
+        let pushed = session
+            .lanes
+            .push(Priority::Normal, CoreData::Data(0, vec![1]));
+        assert!(pushed.is_err());
+
+        // closed, every queued tid is failed.
+        assert!(session.close(true).await.is_err());
+        let mut failed = 0;
+        while let Ok(msg) = out_receiver.try_recv() {
+            if let ReceiveMessage::Delivery(DeliveryType::Data, _, false, _) = msg {
+                failed += 1;
+            }
+        }
+        assert_eq!(failed, LANE_BUFFER);
+    }
+
+    #[tokio::test]
+    async fn test_large_data_slow_outside() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, _) = test_session_with(global, true);
+
+        // outside not read the chunks, the session is not blocked, and drop it.
+        session.handle_chunk(0, 0, false, vec![1]).await.unwrap();
+        let mut chunks = match out_receiver.try_recv() {
+            Ok(ReceiveMessage::LargeData(_, 0, chunks)) => chunks,
+            _ => panic!("large data missing"),
+        };
+        for i in 1..=CHUNK_BUFFER as u64 {
+            session.handle_chunk(0, i, false, vec![1]).await.unwrap();
+        }
+        assert!(session.large_datas.is_empty());
+        let mut received = 0;
+        while chunks.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, CHUNK_BUFFER);
+
+        // the receiving large datas is limited.
+        for id in 1..=LARGE_DATAS_LIMIT as u64 + 1 {
+            session.handle_chunk(id, 0, false, vec![1]).await.unwrap();
+        }
+        assert_eq!(session.large_datas.len(), LARGE_DATAS_LIMIT);
+        assert!(!session
+            .large_datas
+            .contains_key(&(LARGE_DATAS_LIMIT as u64 + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_relay_verify_forward() {
+        let (mut global, _) = crate::global::tests::test_global();
+        global.relay_verify = true;
+        let (next_sender, mut next_receiver) = new_session_channel();
+        let (next_stream, _) = new_endpoint_channel();
+        let next = Peer::peer(PeerId([9u8; 20]));
+        let kv = KadValue(next_sender, next_stream, next);
+        assert!(global.peer_list.write().await.add_dht(kv).await);
+        let (mut session, _) = test_session_with(global, false);
+        let remote = session.remote_peer.id;
+        let to = PeerId([3u8; 20]);
+
+        // middle node drop the forged unsigned frame.
+        let forged = PeerId([7u8; 20]);
+        let msg = EndpointMessage::RelayData(forged, to, vec![1]);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(next_receiver.try_recv().is_err());
+
+        // the unsigned frame from the session remote itself is forwarded.
+        let msg = EndpointMessage::RelayData(remote, to, vec![2]);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            next_receiver.try_recv(),
+            Ok(SessionMessage::RelayData(from, _, _)) if from == remote
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_flows_limit() {
+        let (mut global, _) = crate::global::tests::test_global();
+        global.relay_flows = RelayFlows::new(1);
+        let (next_sender, mut next_receiver) = new_session_channel();
+        let (next_stream, _) = new_endpoint_channel();
+        let next = Peer::peer(PeerId([9u8; 20]));
+        let kv = KadValue(next_sender, next_stream, next);
+        assert!(global.peer_list.write().await.add_dht(kv).await);
+        let (mut session, mut endpoint_receiver) = test_session_with(global, false);
+
+        let (a, b, c) = (PeerId([2u8; 20]), PeerId([3u8; 20]), PeerId([4u8; 20]));
+
+        // first flow is relayed.
+        let msg = EndpointMessage::RelayData(a, b, vec![1]);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            next_receiver.try_recv(),
+            Ok(SessionMessage::RelayData(..))
+        ));
+
+        // full, new relay request is declined, and tell the source.
+        let from_peer = RemotePublic::new(Peer::peer(c), vec![]);
+        let msg = EndpointMessage::RelayHandshake(from_peer, b);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(next_receiver.try_recv().is_err());
+        assert!(matches!(
+            endpoint_receiver.try_recv(),
+            Ok(EndpointMessage::RelayReject(from, to, RejectReason::Full)) if from == c && to == b
+        ));
+
+        // the claimed source is not authenticated, its high tier is not exempt.
+        session.global.set_peer_tier(c, PeerTier::High);
+        let from_peer = RemotePublic::new(Peer::peer(c), vec![]);
+        let msg = EndpointMessage::RelayHandshake(from_peer, b);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(next_receiver.try_recv().is_err());
+        assert!(matches!(
+            endpoint_receiver.try_recv(),
+            Ok(EndpointMessage::RelayReject(_, _, RejectReason::Full))
+        ));
+
+        // the direct remote of high tier is exempt.
+        session
+            .global
+            .set_peer_tier(session.remote_peer.id, PeerTier::High);
+        let msg = EndpointMessage::RelayData(c, b, vec![3]);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            next_receiver.try_recv(),
+            Ok(SessionMessage::RelayData(..))
+        ));
+        session
+            .global
+            .set_peer_tier(session.remote_peer.id, PeerTier::Normal);
+
+        // existing flow continue, both directions.
+        let msg = EndpointMessage::RelayData(b, a, vec![2]);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            next_receiver.try_recv(),
+            Ok(SessionMessage::RelayData(..))
+        ));
+        assert_eq!(session.global.relay_flows.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_relay_next_closed() {
+        let (global, _) = crate::global::tests::test_global();
+        // the next hop session closed after lookup.
+        let (next_sender, next_receiver) = new_session_channel();
+        let (next_stream, _) = new_endpoint_channel();
+        let next = Peer::peer(PeerId([9u8; 20]));
+        let kv = KadValue(next_sender, next_stream, next);
+        assert!(global.peer_list.write().await.add_dht(kv).await);
+        drop(next_receiver);
+        let (mut session, mut endpoint_receiver) = test_session_with(global, false);
+
+        let (a, b) = (PeerId([2u8; 20]), PeerId([3u8; 20]));
+        let msg = EndpointMessage::RelayData(a, b, vec![1]);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(matches!(
+            endpoint_receiver.try_recv(),
+            Ok(EndpointMessage::RelayReject(from, to, RejectReason::Unreachable)) if from == a && to == b
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_reject_load() {
+        let (global, _) = crate::global::tests::test_global();
+        // two relay candidates, the remote (closest) and other.
+        let mut senders = vec![];
+        for id in [[1u8; 20], [9u8; 20]] {
+            let (sender, receiver) = new_session_channel();
+            let (stream, _) = new_endpoint_channel();
+            let mut peer = Peer::peer(PeerId(id));
+            peer.assist = PeerId(id);
+            let kv = KadValue(sender.clone(), stream, peer);
+            assert!(global.peer_list.write().await.add_dht(kv).await);
+            senders.push((sender, receiver));
+        }
+        let (mut session, _) = test_session_with(global, false);
+        let me = *session.global.peer_id();
+        let mut target = [1u8; 20];
+        target[19] = 2;
+        let target = PeerId(target);
+        let relay_is = |session: &Session, i: usize| {
+            let peers = session.global.peer_list.try_read().unwrap();
+            peers
+                .relay_get(&target)
+                .unwrap()
+                .same_channel(&senders[i].0)
+        };
+        assert!(relay_is(&session, 0));
+
+        // the next hop is gone, not the remote load.
+        let msg = EndpointMessage::RelayReject(me, target, RejectReason::Unreachable);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(relay_is(&session, 0));
+
+        // the remote is full, choose other.
+        let msg = EndpointMessage::RelayReject(me, target, RejectReason::Full);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(relay_is(&session, 1));
+    }
+
+    #[tokio::test]
+    async fn test_join_data_encrypted() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        let a = Key::generate(&mut ChaChaRng::from_seed([2u8; 32]));
+        let b = Key::generate(&mut ChaChaRng::from_seed([3u8; 32]));
+        let (mut a_key, a_bytes) = SessionKey::generate(&a);
+        let (mut b_key, b_bytes) = SessionKey::generate(&b);
+        assert!(a_key.complete(&b.peer_id(), b_bytes));
+        assert!(b_key.complete(&a.peer_id(), a_bytes));
+        session.session_key = a_key;
+
+        // the join data is sent after the session key is ok, never in the clear.
+        let token = b"membership-token".to_vec();
+        let data = CoreData::StableConnect(1, token.clone());
+        session.send_core_data(data).await.unwrap();
+        let e_data = match endpoint_receiver.try_recv() {
+            Ok(EndpointMessage::Data(e_data)) => e_data,
+            _ => panic!("stable connect frame missing"),
+        };
+        assert!(!e_data.windows(token.len()).any(|w| w == &token[..]));
+
+        // only the remote can read it.
+        let bytes = b_key.decrypt(e_data.clone()).unwrap();
+        assert!(matches!(
+            CoreData::from_bytes(bytes),
+            Ok(CoreData::StableConnect(1, data)) if data == token
+        ));
+        let c = Key::generate(&mut ChaChaRng::from_seed([4u8; 32]));
+        let (mut c_key, _) = SessionKey::generate(&c);
+        let (_, a_bytes) = SessionKey::generate(&a);
+        assert!(c_key.complete(&a.peer_id(), a_bytes));
+        assert!(c_key.decrypt(e_data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_network() {
+        let (mut global, mut trans_recv) = crate::global::tests::test_global();
+        global.static_network = true;
+        let (mut session, mut endpoint_receiver) = test_session_with(global, false);
+
+        // DHT gossip is ignored, no connect.
+        let peer = Peer::socket("1.2.3.4:7364".parse().unwrap());
+        let msg = EndpointMessage::DHT(DHT(vec![peer], 0, vec![]));
+        session.handle_endpoint(msg).await.unwrap();
+        let msg = EndpointMessage::FindNode(PeerId([3u8; 20]));
+        session.handle_endpoint(msg).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(trans_recv.try_recv().is_err());
+        assert!(endpoint_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signed_dht() {
+        let (mut global, mut trans_recv) = crate::global::tests::test_global();
+        global.signed_dht = true;
+        global.address_filter = crate::config::AddressFilter::new(|addrs| addrs).strip_local();
+        let (mut session, _endpoint_receiver) = test_session_with(global, false);
+        let remote = Key::generate(&mut ChaChaRng::from_seed([2u8; 32]));
+        session.remote_peer = Peer::peer(remote.peer_id());
+        let other = Key::generate(&mut ChaChaRng::from_seed([3u8; 32]));
+        let seq = session.global.next_record_seq();
+
+        // unsigned and forged entries are dropped, no connect.
+        let mut peer = Peer::socket("1.2.3.4:7364".parse().unwrap());
+        peer.id = other.peer_id();
+        let forged = crate::global::peer_record_sign(&remote, &peer, seq);
+        let msg = EndpointMessage::DHT(DHT(vec![peer, peer], 0, vec![(seq, forged)]));
+        session.handle_endpoint(msg).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(trans_recv.try_recv().is_err());
+
+        // the seq far ahead of the clock is dropped.
+        let future = seq + (session.global.max_clock_skew + 60) * 1000;
+        let sign = crate::global::peer_record_sign(&other, &peer, future);
+        let msg = EndpointMessage::DHT(DHT(vec![peer], 0, vec![(future, sign)]));
+        session.handle_endpoint(msg).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(trans_recv.try_recv().is_err());
+
+        // remote self record is kept, the signed entry is connected.
+        let mut me = Peer::socket("1.2.3.5:7364".parse().unwrap());
+        me.id = remote.peer_id();
+        let me_sign = crate::global::peer_record_sign(&remote, &me, seq);
+        let sign = crate::global::peer_record_sign(&other, &peer, seq);
+        let msg = EndpointMessage::DHT(DHT(
+            vec![me, peer],
+            0,
+            vec![(seq, me_sign.clone()), (seq, sign)],
+        ));
+        session.handle_endpoint(msg).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(trans_recv.try_recv().is_ok());
+
+        // only the newest record of remote is kept.
+        let mut moved = me;
+        moved.socket = "1.2.3.6:7364".parse().unwrap();
+        let old_sign = crate::global::peer_record_sign(&remote, &moved, seq - 1);
+        let msg = EndpointMessage::DHT(DHT(vec![moved], 0, vec![(seq - 1, old_sign)]));
+        session.handle_endpoint(msg).await.unwrap();
+        assert_eq!(
+            session.global.records.lock().unwrap().get(&me.id),
+            Some(&(me, seq, me_sign))
+        );
+        let new_sign = crate::global::peer_record_sign(&remote, &moved, seq + 1);
+        let msg = EndpointMessage::DHT(DHT(vec![moved], 0, vec![(seq + 1, new_sign.clone())]));
+        session.handle_endpoint(msg).await.unwrap();
+        assert_eq!(
+            session.global.records.lock().unwrap().get(&me.id),
+            Some(&(moved, seq + 1, new_sign))
+        );
+
+        // the record changed by the address filter is not gossiped.
+        let mut local = Peer::socket("192.168.1.2:7364".parse().unwrap());
+        local.add_addr("1.2.3.7:7364".parse().unwrap(), TransportType::QUIC);
+        local.id = other.peer_id();
+        let sign = crate::global::peer_record_sign(&other, &local, seq);
+        session.global.record_update(local, seq, sign);
+        let DHT(peers, _, _) = session.global.dht(vec![moved, local]);
+        assert!(peers == vec![moved]);
+    }
+
+    #[tokio::test]
+    async fn test_tmp_buffer_limit() {
+        let (mut global, _) = crate::global::tests::test_global();
+        global.buffer = Arc::new(tokio::sync::RwLock::new(crate::buffer::Buffer::init(1000)));
+        let me = *global.peer_id();
+        let global = Arc::new(global);
+
+        // many relay sessions stalled in handshake, their streams are not read.
+        let mut tmps = vec![];
+        for i in 0..8u8 {
+            let id = PeerId([100 + i; 20]);
+            let (ss, sr) = new_session_channel();
+            let (stream, stream_receiver) = new_endpoint_channel();
+            let kv = KadValue(ss, stream, Peer::default());
+            global.buffer.write().await.add_tmp(id, kv, false);
+            tmps.push((id, sr, stream_receiver));
+        }
+
+        let (session_key, _) = SessionKey::generate(global.key.as_ref());
+        let (session_sender, _) = new_session_channel();
+        let (_, stream_receiver) = new_endpoint_channel();
+        let (endpoint_sender, _endpoint_receiver) = new_endpoint_channel();
+        let mut session = Session::new(
+            Peer::peer(PeerId([1u8; 20])),
+            session_sender,
+            stream_receiver,
+            ConnectType::Direct(endpoint_sender),
+            session_key,
+            global.clone(),
+            false,
+            false,
+        );
+
+        for _ in 0..3 {
+            for (id, _, _) in &tmps {
+                let msg = EndpointMessage::RelayData(*id, me, vec![0u8; 100]);
+                session.handle_endpoint(msg).await.unwrap();
+                assert!(global.buffer.read().await.tmp_buffered() <= 1000);
+            }
+        }
+
+        // the oldest stalled sessions are closed first.
+        let (_, first_session, _) = &mut tmps[0];
+        assert!(matches!(
+            first_session.try_recv(),
+            Ok(SessionMessage::Close)
+        ));
+        assert!(!global.buffer.read().await.has_tmp(&tmps[0].0));
+        let (_, last_session, last_stream) = &mut tmps[7];
+        assert!(last_session.try_recv().is_err());
+        assert!(last_stream.try_recv().is_ok());
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // fixed keys and nonces, the handshake wire format (version 1) must not be changed.
+    const A_HANDSHAKE_HEX: &str = "010000009ef39fd6e51aad88f6f4ce6ab8827279cfffb92266000000000000000000000000000000000000000000000000000000000000ffff010203043412010103b988b4938c4a76867ecb76454d185865cfd8023011ff3cbfaf2e2aeff751d383562306a96587e4aa286b2411b91589815a00a399b6a42f77a78cd66e911e3da077fb877b5c6e830d101d93affe7d7343dfd2789a715326233d88f77dbf66b8cf1b";
+    const B_HANDSHAKE_HEX: &str = "010000009ece9ad6a3801a902250b1ec940a34a607907e1a62000000000000000000000000000000000000000000000000000000000000ffff0506070834120101033e666b626a3363edf560513990e82a4ba9d64095c318661605e6e73119bfef66327de98255400aa5de47c9acd5252ce22fdef397abf0ad9803c1613671cd185e65b57575f74bd01f97277e07bbae62c0dd0c7acbd218cf6ed5d4636eb907edd11c";
+    const DATA_HEX: &str = "05da781e0cbbccc7e1dfa732723f860aa3a4cf8162fdb28cc692db725319fa";
+
+    fn vector_keys() -> (Key, Key, Peer, Peer) {
+        let sk = chamomile_types::key::SecretKey::try_from(
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        )
+        .unwrap();
+        let a = Key::from_sec_key(sk);
+        let b = Key::generate(&mut ChaChaRng::from_seed([2u8; 32]));
+        let a_addr = "1.2.3.4:4660".parse().unwrap();
+        let b_addr = "5.6.7.8:4660".parse().unwrap();
+        let a_peer = Peer::new(a.peer_id(), a_addr, TransportType::TCP, true);
+        let b_peer = Peer::new(b.peer_id(), b_addr, TransportType::TCP, true);
+        (a, b, a_peer, b_peer)
+    }
+
+    fn handshake(frame: Vec<u8>) -> RemotePublic {
+        match EndpointMessage::from_bytes(frame) {
+            Ok(EndpointMessage::Handshake(remote)) => remote,
+            _ => panic!("handshake decode failure"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_vectors() {
+        let (a, b, a_peer, b_peer) = vector_keys();
+
+        // 1. a dial and send the handshake.
+        let mut rng = ChaChaRng::from_seed([3u8; 32]);
+        let (mut a_session, a_dh) = SessionKey::generate_with(&a, &mut rng);
+        let frame =
+            EndpointMessage::Handshake(RemotePublic(a_peer, a_dh, PROTOCOL_DEFAULT_VERSION))
+                .to_bytes();
+        assert_eq!(hex(&frame), A_HANDSHAKE_HEX);
+
+        // 2. b receive it, complete and answer.
+        let RemotePublic(remote, remote_dh, _) = handshake(frame);
+        assert_eq!(remote.id, a.peer_id());
+        let mut rng = ChaChaRng::from_seed([4u8; 32]);
+        let (mut b_session, b_dh) = SessionKey::generate_with(&b, &mut rng);
+        assert!(b_session.complete(&remote.id, remote_dh));
+        let frame =
+            EndpointMessage::Handshake(RemotePublic(b_peer, b_dh, PROTOCOL_DEFAULT_VERSION))
+                .to_bytes();
+        assert_eq!(hex(&frame), B_HANDSHAKE_HEX);
+
+        // 3. a complete, and send the first data.
+        let RemotePublic(remote, remote_dh, _) = handshake(frame);
+        assert!(a_session.complete(&remote.id, remote_dh));
+        let data = CoreData::Data(1, b"hello".to_vec()).to_bytes().unwrap();
+        let frame = EndpointMessage::Data(a_session.encrypt(data)).to_bytes();
+        assert_eq!(hex(&frame), DATA_HEX);
+
+        match EndpointMessage::from_bytes(frame) {
+            Ok(EndpointMessage::Data(e_data)) => {
+                let bytes = b_session.decrypt(e_data).unwrap();
+                assert!(matches!(
+                    CoreData::from_bytes(bytes),
+                    Ok(CoreData::Data(1, data)) if data == b"hello"
+                ));
+            }
+            _ => panic!("data decode failure"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_vectors_mismatch() {
+        let (a, b, _, _) = vector_keys();
+
+        // b expect other peer, the same handshake bytes is rejected.
+        let mut rng = ChaChaRng::from_seed([3u8; 32]);
+        let (_, a_dh) = SessionKey::generate_with(&a, &mut rng);
+        assert!(A_HANDSHAKE_HEX.ends_with(&hex(&a_dh)));
+        let mut rng = ChaChaRng::from_seed([4u8; 32]);
+        let (mut b_session, _) = SessionKey::generate_with(&b, &mut rng);
+        assert!(!b_session.complete(&b.peer_id(), a_dh.clone()));
+        assert!(!b_session.is_ok());
+
+        // tampered public key is rejected.
+        let mut tampered = a_dh;
+        tampered[1] ^= 1;
+        let (mut b_session, _) = SessionKey::generate_with(&b, &mut rng);
+        assert!(!b_session.complete(&a.peer_id(), tampered));
+        assert!(!b_session.is_ok());
+
+        // and the rejection frame.
+        assert_eq!(hex(&EndpointMessage::Close(None).to_bytes()), "00");
+    }
+
+    #[tokio::test]
+    async fn test_sequence_gap() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, _) = test_session_with(global, true);
+        let remote = session.remote_peer.id;
+
+        // 2 is skipped, and arrives late.
+        for seq in [1u64, 3, 2, 4] {
+            let data = CoreData::Sequenced(seq, Box::new(CoreData::Data(0, vec![seq as u8])));
+            let bytes = data.to_bytes().unwrap();
+            assert!(matches!(
+                CoreData::from_bytes(bytes.clone()),
+                Ok(CoreData::Sequenced(s, _)) if s == seq
+            ));
+            let e_data = session.session_key.encrypt(bytes);
+            session
+                .handle_endpoint(EndpointMessage::Data(e_data))
+                .await
+                .unwrap();
+        }
+
+        let mut events = vec![];
+        while let Ok(msg) = out_receiver.try_recv() {
+            match msg {
+                ReceiveMessage::Data(_, data, _) => events.push(format!("data {}", data[0])),
+                ReceiveMessage::SequenceGap(p, n) if p == remote => {
+                    events.push(format!("gap {}", n))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(events, ["data 1", "gap 1", "data 3", "data 2", "data 4"]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_received() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (session_key, _) = SessionKey::generate(global.key.as_ref());
+        let (session_sender, session_receiver) = new_session_channel();
+        let (stream_sender, stream_receiver) = new_endpoint_channel();
+        let (endpoint_sender, _endpoint_receiver) = new_endpoint_channel();
+        let mut session = Session::new(
+            Peer::peer(PeerId([1u8; 20])),
+            session_sender.clone(),
+            stream_receiver,
+            ConnectType::Direct(endpoint_sender),
+            session_key,
+            Arc::new(global),
+            true,
+            false,
+        );
+        let remote = session.remote_peer.id;
+
+        // the data is received, but the disconnect is handled first.
+        for i in 0..2u8 {
+            let e_data = session
+                .session_key
+                .encrypt(CoreData::Data(0, vec![i]).to_bytes().unwrap());
+            stream_sender
+                .send(EndpointMessage::Data(e_data))
+                .await
+                .unwrap();
+        }
+        session_sender
+            .send(SessionMessage::Disconnect(CloseReason::Normal))
+            .await
+            .unwrap();
+        let _ = session.listen(session_receiver).await;
+
+        // delivered by the delivery task, until closed.
+        let mut events = vec![];
+        let wait = Duration::from_secs(1);
+        while let Ok(Some(msg)) = tokio::time::timeout(wait, out_receiver.recv()).await {
+            match msg {
+                ReceiveMessage::Data(_, data, _) => events.push(format!("data {}", data[0])),
+                ReceiveMessage::PeerLeave(p, _) if p == remote => events.push("leave".to_owned()),
+                _ => {}
+            }
+        }
+        assert_eq!(events, ["data 0", "data 1", "leave"]);
+    }
+
+    #[tokio::test]
+    async fn test_peer_leave_once() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (session_key, _) = SessionKey::generate(global.key.as_ref());
+        let (session_sender, session_receiver) = new_session_channel();
+        let (stream_sender, stream_receiver) = new_endpoint_channel();
+        let (endpoint_sender, _endpoint_receiver) = new_endpoint_channel();
+        let mut session = Session::new(
+            Peer::peer(PeerId([1u8; 20])),
+            session_sender.clone(),
+            stream_receiver,
+            ConnectType::Direct(endpoint_sender),
+            session_key,
+            Arc::new(global),
+            true,
+            false,
+        );
+
+        // the remote close is received, but the stop is handled first.
+        let close = EndpointMessage::Close(Some(CloseReason::Normal));
+        stream_sender.send(close).await.unwrap();
+        let (confirm, _wait) = oneshot::channel();
+        session_sender
+            .send(SessionMessage::Stop(
+                CloseReason::Normal,
+                Confirm::new(confirm),
+            ))
+            .await
+            .unwrap();
+        let _ = session.listen(session_receiver).await;
+
+        let mut leaves = 0;
+        let wait = Duration::from_secs(1);
+        while let Ok(Some(msg)) = tokio::time::timeout(wait, out_receiver.recv()).await {
+            if matches!(msg, ReceiveMessage::PeerLeave(..)) {
+                leaves += 1;
+            }
+        }
+        assert_eq!(leaves, 1);
+    }
+
+    #[tokio::test]
+    async fn test_frame_padding() {
+        let (mut global, _) = crate::global::tests::test_global();
+        global.frame_padding = FramePadding::Buckets(vec![1024, 64, 256]);
+        let (mut session, mut endpoint_receiver) = test_session_with(global, true);
+        session.remote_version = PADDING_VERSION;
+
+        for (len, frame) in [(1, 64), (100, 256), (500, 1024), (2000, 2000 + 30)] {
+            let data = CoreData::Data(0, vec![7u8; len]);
+            session.send_core_data(data).await.unwrap();
+            let e_data = match endpoint_receiver.try_recv() {
+                Ok(EndpointMessage::Data(e_data)) => e_data,
+                _ => panic!("frame missing"),
+            };
+            assert_eq!(e_data.len(), frame);
+            let bytes = session.session_key.decrypt(e_data).unwrap();
+            match CoreData::from_bytes(bytes) {
+                Ok(CoreData::Data(0, data)) => assert_eq!(data, vec![7u8; len]),
+                _ => panic!("padding not stripped"),
+            }
+        }
+
+        let sizes = session.stats.frame_sizes();
+        assert_eq!(sizes, vec![(64, 1), (256, 1), (1024, 1), (2048, 1)]);
+        assert_eq!(FramePadding::PowerOfTwo.target(100), 128);
+
+        // the older remote cannot strip the padding.
+        session.remote_version = PROTOCOL_DEFAULT_VERSION;
+        let data = CoreData::Data(0, vec![7u8; 1]);
+        session.send_core_data(data).await.unwrap();
+        match endpoint_receiver.try_recv() {
+            Ok(EndpointMessage::Data(e_data)) => assert!(e_data.len() < 64),
+            _ => panic!("frame missing"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_core_data() {
+        let (mut session, mut endpoint_receiver) = test_session();
+
+        // newer peer send an unknown tag, skip it.
+        let e_data = session.session_key.encrypt(vec![200u8, 1, 2, 3]);
+        session
+            .handle_endpoint(EndpointMessage::Data(e_data))
+            .await
+            .unwrap();
+        assert!(endpoint_receiver.try_recv().is_err());
+        assert_eq!(session.decrypt_failures, 0);
+
+        // session still works.
+        let e_data = session
+            .session_key
+            .encrypt(CoreData::Ping.to_bytes().unwrap());
+        session
+            .handle_endpoint(EndpointMessage::Data(e_data))
+            .await
+            .unwrap();
+        let e_data = match endpoint_receiver.try_recv() {
+            Ok(EndpointMessage::Data(e_data)) => e_data,
+            _ => panic!("pong missing"),
+        };
+        let bytes = session.session_key.decrypt(e_data).unwrap();
+        assert!(matches!(CoreData::from_bytes(bytes), Ok(CoreData::Pong(_))));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_idle() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        for _ in 0..4 {
+            session.handle_heartbeat().await.unwrap();
+        }
+        let mut pings = 0;
+        while let Ok(EndpointMessage::Data(e_data)) = endpoint_receiver.try_recv() {
+            let bytes = session.session_key.decrypt(e_data).unwrap();
+            assert!(matches!(CoreData::from_bytes(bytes), Ok(CoreData::Ping)));
+            pings += 1;
+        }
+        assert_eq!(pings, 4);
+
+        // lost too many pings, timeout.
+        assert!(session.handle_heartbeat().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sequence_version() {
+        let (mut global, _) = crate::global::tests::test_global();
+        global.sequence_frames = true;
+        let (mut session, mut endpoint_receiver) = test_session_with(global, false);
+        let mut sent = vec![];
+        for version in [PROTOCOL_DEFAULT_VERSION, SEQUENCE_VERSION] {
+            session.remote_version = version;
+            let queued = Queued {
+                data: CoreData::Data(0, vec![1]),
+                confirm: None,
+                deadline: None,
+            };
+            session.send_lane(queued).await.unwrap();
+            let e_data = match endpoint_receiver.try_recv() {
+                Ok(EndpointMessage::Data(e_data)) => e_data,
+                _ => panic!("data missing"),
+            };
+            let bytes = session.session_key.decrypt(e_data).unwrap();
+            sent.push(CoreData::from_bytes(bytes).unwrap());
+        }
+        // the older remote cannot read the sequenced frame.
+        assert!(matches!(sent[0], CoreData::Data(0, _)));
+        assert!(matches!(sent[1], CoreData::Sequenced(1, _)));
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_failure_policy() {
+        let (mut session, mut endpoint_receiver) = test_session();
+        let policy = session.global.decrypt_policy;
+        for i in 1..policy.close_after {
+            let frame = EndpointMessage::Data(vec![0u8; 32]);
+            session.handle_endpoint(frame).await.unwrap();
+            // rekey when reach the rekey policy.
+            let rekey = matches!(
+                endpoint_receiver.try_recv(),
+                Ok(EndpointMessage::Handshake(_))
+            );
+            assert_eq!(rekey, i == policy.rekey_after);
+        }
+        assert!(session.rekey.is_some());
+
+        // still failure, close it.
+        let frame = EndpointMessage::Data(vec![0u8; 32]);
+        assert!(session.handle_endpoint(frame).await.is_err());
+        assert!(matches!(
+            endpoint_receiver.try_recv(),
+            Ok(EndpointMessage::Close(Some(CloseReason::Crypto)))
+        ));
+    }
+
+    fn rekey_session(seed: u8, remote: PeerId) -> (Session, Receiver<EndpointMessage>) {
+        let (mut global, _) = crate::global::tests::test_global();
+        let key = Key::generate(&mut ChaChaRng::from_seed([seed; 32]));
+        global.peer.id = key.peer_id();
+        global.key = Arc::new(key);
+        let (mut session, endpoint_receiver) = test_session_with(global, false);
+        session.remote_peer.id = remote;
+        (session, endpoint_receiver)
+    }
+
+    fn take_handshake(receiver: &mut Receiver<EndpointMessage>) -> RemotePublic {
+        match receiver.try_recv() {
+            Ok(EndpointMessage::Handshake(remote)) => remote,
+            _ => panic!("missing handshake"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rekey_simultaneous() {
+        let a_id = Key::generate(&mut ChaChaRng::from_seed([5u8; 32])).peer_id();
+        let b_id = Key::generate(&mut ChaChaRng::from_seed([6u8; 32])).peer_id();
+        let (mut a, mut a_endpoint) = rekey_session(5, b_id);
+        let (mut b, mut b_endpoint) = rekey_session(6, a_id);
+
+        // both start rekey at the same time, and cross the handshakes.
+        a.start_rekey().await.unwrap();
+        b.start_rekey().await.unwrap();
+        let a_hs = take_handshake(&mut a_endpoint);
+        let b_hs = take_handshake(&mut b_endpoint);
+        a.handle_rekey(b_hs).await.unwrap();
+        b.handle_rekey(a_hs).await.unwrap();
+
+        // no more answer, and both completed with the same key.
+        assert!(a_endpoint.try_recv().is_err());
+        assert!(b_endpoint.try_recv().is_err());
+        assert!(a.rekey.is_none() && b.rekey.is_none());
+        assert!(a.session_key.is_ok() && b.session_key.is_ok());
+        let e_data = a.session_key.encrypt(b"from a".to_vec());
+        assert_eq!(b.session_key.decrypt(e_data).unwrap(), b"from a".to_vec());
+        let e_data = b.session_key.encrypt(b"from b".to_vec());
+        assert_eq!(a.session_key.decrypt(e_data).unwrap(), b"from b".to_vec());
+
+        // one side rekey, the other answers it.
+        a.start_rekey().await.unwrap();
+        let a_hs = take_handshake(&mut a_endpoint);
+        b.handle_rekey(a_hs).await.unwrap();
+        let b_hs = take_handshake(&mut b_endpoint);
+        a.handle_rekey(b_hs).await.unwrap();
+        let e_data = a.session_key.encrypt(b"again".to_vec());
+        assert_eq!(b.session_key.decrypt(e_data).unwrap(), b"again".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_key_resend() {
+        let a_id = Key::generate(&mut ChaChaRng::from_seed([5u8; 32])).peer_id();
+        let b_id = Key::generate(&mut ChaChaRng::from_seed([6u8; 32])).peer_id();
+        let (mut a, mut a_endpoint) = rekey_session(5, b_id);
+        let (mut b, mut b_endpoint) = rekey_session(6, a_id);
+        let policy = a.global.decrypt_policy;
+
+        // the handshake to b is lost once, resent after the heartbeats.
+        a.start_rekey().await.unwrap();
+        let _ = take_handshake(&mut a_endpoint);
+        for _ in 1..policy.resend_key_after {
+            a.check_key().await.unwrap();
+            assert!(a_endpoint.try_recv().is_err());
+        }
+        a.check_key().await.unwrap();
+        b.handle_rekey(take_handshake(&mut a_endpoint))
+            .await
+            .unwrap();
+        a.handle_rekey(take_handshake(&mut b_endpoint))
+            .await
+            .unwrap();
+        assert!(a.rekey.is_none() && a.session_key.is_ok());
+        let e_data = a.session_key.encrypt(b"resent".to_vec());
+        assert_eq!(b.session_key.decrypt(e_data).unwrap(), b"resent".to_vec());
+        a.check_key().await.unwrap();
+        assert_eq!(a.key_wait, (0, 0));
+
+        // always lost, closed after the resends.
+        a.start_rekey().await.unwrap();
+        let mut ticks = 0;
+        while a.check_key().await.is_ok() {
+            ticks += 1;
+            assert!(ticks < 100);
+        }
+        let mut handshakes = 0;
+        while let Ok(msg) = a_endpoint.try_recv() {
+            match msg {
+                EndpointMessage::Handshake(_) => handshakes += 1,
+                msg => assert!(matches!(
+                    msg,
+                    EndpointMessage::Close(Some(CloseReason::Crypto))
+                )),
+            }
+        }
+        assert_eq!(handshakes, 1 + policy.resend_key_times);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_signals() {
+        // not fatal before handshake.
+        let (sender, mut receiver) = new_endpoint_channel();
+        let addr: std::net::SocketAddr = "1.2.3.4:7364".parse().unwrap();
+        let (session, _) = test_session();
+        let (_, dh) = SessionKey::generate(session.global.key.as_ref());
+        sender.send(EndpointMessage::Migrated(addr)).await.unwrap();
+        sender.send(EndpointMessage::Reflexive(addr)).await.unwrap();
+        let mut connect = ConnectTimer::start();
+        connect.trace.transport = Duration::from_millis(3);
+        sender
+            .send(EndpointMessage::Connected(connect))
+            .await
+            .unwrap();
+        let remote = RemotePublic::new(session.global.peer, dh);
+        sender
+            .send(EndpointMessage::Handshake(remote))
+            .await
+            .unwrap();
+        let mut timer = None;
+        assert!(wait_handshake(&mut receiver, &mut timer).await.is_some());
+        assert_eq!(timer.unwrap().trace.transport, Duration::from_millis(3));
+        sender.send(EndpointMessage::Close(None)).await.unwrap();
+        assert!(wait_handshake(&mut receiver, &mut timer).await.is_none());
+
+        // not fatal to running session.
+        let (mut session, mut endpoint_receiver) = test_session();
+        let (session_sender, session_receiver) = new_session_channel();
+        let (stream_sender, stream_receiver) = new_endpoint_channel();
+        session.stream_receiver = stream_receiver;
+        let task = tokio::spawn(async move { session.forever(session_receiver).await });
+        stream_sender
+            .send(EndpointMessage::Migrated(addr))
+            .await
+            .unwrap();
+        stream_sender
+            .send(EndpointMessage::Reflexive(addr))
+            .await
+            .unwrap();
+        let (tx, rx) = oneshot::channel();
+        session_sender
+            .send(SessionMessage::ConfirmData(0, vec![1], Confirm::new(tx)))
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().is_ok());
+        assert!(matches!(
+            endpoint_receiver.recv().await,
+            Some(EndpointMessage::Data(_))
+        ));
+        assert!(!task.is_finished());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_version_mismatch() {
+        let (global, mut trans_recv) = crate::global::tests::test_global();
+        let global = Arc::new(global);
+        let (_, dh) = SessionKey::generate(global.key.as_ref());
+        let to = Peer::socket("1.2.3.4:7364".parse().unwrap());
+        let task = tokio::spawn(direct_stable(0, vec![], to, global.clone(), true, false));
+
+        let (stream_sender, mut endpoint_receiver) = match trans_recv.recv().await {
+            Some(TransportSendMessage::StableConnect(s, r, _, _)) => (s, r),
+            _ => panic!("no stable connect"),
+        };
+        let remote = RemotePublic(Peer::peer(PeerId([3u8; 20])), dh, 0);
+        stream_sender
+            .send(EndpointMessage::Handshake(remote))
+            .await
+            .unwrap();
+        assert!(matches!(
+            endpoint_receiver.recv().await,
+            Some(EndpointMessage::Close(Some(CloseReason::Version)))
+        ));
+        assert!(task.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_key_mismatch() {
+        let (mut global, mut trans_recv) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let global = Arc::new(global);
+        // signed by self key, not the claimed remote.
+        let (_, dh) = SessionKey::generate(global.key.as_ref());
+        let to = Peer::socket("1.2.3.4:7364".parse().unwrap());
+        let task = tokio::spawn(direct_stable(7, vec![1], to, global.clone(), true, false));
+
+        let (stream_sender, mut endpoint_receiver) = match trans_recv.recv().await {
+            Some(TransportSendMessage::StableConnect(s, r, _, _)) => (s, r),
+            _ => panic!("no stable connect"),
+        };
+        let remote = RemotePublic::new(Peer::peer(PeerId([3u8; 20])), dh);
+        stream_sender
+            .send(EndpointMessage::Handshake(remote))
+            .await
+            .unwrap();
+        assert!(task.await.unwrap().is_err());
+
+        // the endpoint closed once with the reason.
+        assert!(matches!(
+            endpoint_receiver.recv().await,
+            Some(EndpointMessage::Close(Some(CloseReason::Crypto)))
+        ));
+        assert!(endpoint_receiver.recv().await.is_none());
+
+        // the outside is told failure once, no data.
+        assert!(matches!(
+            out_receiver.recv().await,
+            Some(ReceiveMessage::Delivery(
+                DeliveryType::StableConnect,
+                7,
+                false,
+                _
+            ))
+        ));
+        drop(global);
+        assert!(out_receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expiring_data() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, _) = test_session_with(global, true);
+        // stalled endpoint, only one frame in flight.
+        let (endpoint_sender, mut endpoint_receiver) = mpsc::channel(1);
+        session.endpoint = ConnectType::Direct(endpoint_sender);
+        let (session_sender, session_receiver) = new_session_channel();
+        let (_stream_sender, stream_receiver) = new_endpoint_channel();
+        session.stream_receiver = stream_receiver;
+        let task = tokio::spawn(async move {
+            let _ = session.forever(session_receiver).await;
+            session
+        });
+
+        let now = Instant::now();
+        let messages = vec![
+            SessionMessage::Data(0, vec![1], Priority::Normal),
+            SessionMessage::Data(0, vec![2], Priority::Normal),
+            SessionMessage::ExpiringData(7, vec![3], now + Duration::from_millis(50)),
+            SessionMessage::ExpiringData(8, vec![4], now + Duration::from_secs(10)),
+        ];
+        for message in messages {
+            session_sender.send(message).await.unwrap();
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        let mut frames = vec![];
+        while frames.len() < 3 {
+            if let Some(EndpointMessage::Data(e_data)) = endpoint_receiver.recv().await {
+                frames.push(e_data);
+            }
+        }
+        match out_receiver.recv().await {
+            Some(ReceiveMessage::Delivery(DeliveryType::Data, 7, false, _)) => {}
+            _ => panic!("expired data not failure delivered"),
+        }
+        // close frame also to the stalled endpoint.
+        tokio::spawn(async move { while endpoint_receiver.recv().await.is_some() {} });
+        drop(session_sender);
+        let session = task.await.unwrap();
+
+        // the short TTL one dropped, others sent.
+        let sent: Vec<_> = frames
+            .into_iter()
+            .map(|e_data| {
+                let bytes = session.session_key.decrypt(e_data).unwrap();
+                match CoreData::from_bytes(bytes).unwrap() {
+                    CoreData::Data(_, data) => data,
+                    _ => panic!("not data"),
+                }
+            })
+            .collect();
+        assert_eq!(sent, vec![vec![1], vec![2], vec![4]]);
+    }
+
+    #[tokio::test]
+    async fn test_expiring_data_handshake() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, mut endpoint_receiver) = test_session_with(global, true);
+        let (session_sender, session_receiver) = new_session_channel();
+        let (_stream_sender, stream_receiver) = new_endpoint_channel();
+        session.stream_receiver = stream_receiver;
+
+        // queued while the session is still in handshake.
+        let now = Instant::now();
+        session_sender
+            .send(SessionMessage::ExpiringData(
+                7,
+                vec![1],
+                now + Duration::from_millis(50),
+            ))
+            .await
+            .unwrap();
+        session_sender
+            .send(SessionMessage::ExpiringData(
+                8,
+                vec![2],
+                now + Duration::from_secs(10),
+            ))
+            .await
+            .unwrap();
+        // the handshake stalled past the short TTL.
+        sleep(Duration::from_millis(100)).await;
+        let task = tokio::spawn(async move {
+            let _ = session.forever(session_receiver).await;
+            session
+        });
+
+        match out_receiver.recv().await {
+            Some(ReceiveMessage::Delivery(DeliveryType::Data, 7, false, _)) => {}
+            _ => panic!("expired data not failure delivered"),
+        }
+        let e_data = match endpoint_receiver.recv().await {
+            Some(EndpointMessage::Data(e_data)) => e_data,
+            _ => panic!("no data frame"),
+        };
+        drop(session_sender);
+        let session = task.await.unwrap();
+        let bytes = session.session_key.decrypt(e_data).unwrap();
+        assert!(matches!(
+            CoreData::from_bytes(bytes),
+            Ok(CoreData::Data(8, data)) if data == vec![2]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_data_direct_tag() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, _) = test_session_with(global, true);
+        let remote = session.remote_peer.id;
+
+        session.recv_data(0, None, vec![1]).await.unwrap();
+        match out_receiver.try_recv() {
+            Ok(ReceiveMessage::Data(from, data, true)) => {
+                assert_eq!((from, data), (remote, vec![1]))
+            }
+            _ => panic!("direct data missing"),
+        }
+
+        // same remote, but via relay.
+        let (relay_sender, _relay_receiver) = new_session_channel();
+        session.endpoint = ConnectType::Relay(relay_sender);
+        session.recv_data(0, None, vec![2]).await.unwrap();
+        match out_receiver.try_recv() {
+            Ok(ReceiveMessage::Data(from, data, false)) => {
+                assert_eq!((from, data), (remote, vec![2]))
+            }
+            _ => panic!("relayed data missing"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sealed_replay() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let (mut session, mut endpoint_receiver) = test_session_with(global, true);
+        session.remote_version = SEALED_VERSION;
+
+        let content_key = [5u8; 32];
+        let frame = CoreData::Data(0, vec![1])
+            .to_padded_bytes(&session.global.frame_padding)
+            .unwrap();
+        let sealed = SessionKey::seal(&content_key, &frame);
+        session.send_sealed(content_key, &sealed).await.unwrap();
+        let (wrapped, bytes) = match endpoint_receiver.try_recv() {
+            Ok(EndpointMessage::Sealed(wrapped, bytes)) => (wrapped, bytes),
+            _ => panic!("sealed missing"),
+        };
+
+        // the replayed sealed frame is dropped.
+        for _ in 0..2 {
+            let msg = EndpointMessage::Sealed(wrapped.clone(), bytes.clone());
+            session.handle_endpoint(msg).await.unwrap();
+        }
+        assert!(matches!(
+            out_receiver.try_recv(),
+            Ok(ReceiveMessage::Data(_, data, true)) if data == vec![1]
+        ));
+        assert!(out_receiver.try_recv().is_err());
+
+        // the newer one is delivered.
+        let wrapped = session.session_key.wrap(&content_key, 2);
+        let msg = EndpointMessage::Sealed(wrapped, bytes);
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(out_receiver.try_recv().is_ok());
+
+        // the old version cannot read it, send as normal data in the lane.
+        session.remote_version = PROTOCOL_DEFAULT_VERSION;
+        session.send_sealed(content_key, &sealed).await.unwrap();
+        session.flush().await.unwrap();
+        assert!(matches!(
+            endpoint_receiver.try_recv(),
+            Ok(EndpointMessage::Data(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_verify() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        global.relay_verify = true;
+        let me = *global.peer_id();
+        let (mut session, _) = test_session_with(global, true);
+
+        let origin = Key::generate(&mut ChaChaRng::from_seed([2u8; 32]));
+        let origin_id = origin.peer_id();
+        let data = vec![1u8, 2, 3];
+        let sign = crate::global::relay_sign(&origin, &origin_id, &me, &data);
+
+        // relay rewrite the from.
+        let forged = PeerId([7u8; 20]);
+        let msg = EndpointMessage::RelaySigned(forged, me, sign.clone(), data.clone());
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(out_receiver.try_recv().is_err());
+
+        // no signature, cannot verify.
+        let msg = EndpointMessage::RelayData(forged, me, data.clone());
+        session.handle_endpoint(msg).await.unwrap();
+        assert!(out_receiver.try_recv().is_err());
+
+        // origin signed.
+        let msg = EndpointMessage::RelaySigned(origin_id, me, sign, data.clone());
+        session.handle_endpoint(msg).await.unwrap();
+        match out_receiver.try_recv() {
+            Ok(ReceiveMessage::Data(from, d, is_direct)) => {
+                assert_eq!((from, d, is_direct), (origin_id, data, false))
+            }
+            _ => panic!("relay signed data missing"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_origin_proof() {
+        let (mut global, _) = crate::global::tests::test_global();
+        let (out_sender, mut out_receiver) = mpsc::channel(8);
+        global.out_sender = out_sender;
+        let me = *global.peer_id();
+        let (mut session, _) = test_session_with(global, true);
+
+        let origin = Key::generate(&mut ChaChaRng::from_seed([2u8; 32]));
+        let origin_id = origin.peer_id();
+        let gateway = PeerId([9u8; 20]);
+        let frame = |proof: Vec<u8>, from: PeerId| {
+            let data = [&proof[SIGNATURE_LENGTH..], &[1u8, 2, 3][..]].concat();
+            EndpointMessage::RelaySigned(from, me, proof[..SIGNATURE_LENGTH].to_vec(), data)
+        };
+        let proof = crate::global::origin_proof(&origin, &gateway, &me, &[1, 2, 3]);
+
+        // gateway rewrite the origin.
+        let forged = PeerId([7u8; 20]);
+        session
+            .handle_endpoint(frame(proof.clone(), forged))
+            .await
+            .unwrap();
+        assert!(out_receiver.try_recv().is_err());
+
+        // expired proof.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let old = now - crate::global::ORIGIN_PROOF_WINDOW - 2 * session.global.max_clock_skew;
+        let expired = crate::global::origin_proof_at(&origin, &gateway, &me, &[1, 2, 3], old);
+        session
+            .handle_endpoint(frame(expired, origin_id))
+            .await
+            .unwrap();
+        assert!(out_receiver.try_recv().is_err());
+
+        session
+            .handle_endpoint(frame(proof.clone(), origin_id))
+            .await
+            .unwrap();
+        match out_receiver.try_recv() {
+            Ok(ReceiveMessage::Data(from, d, is_direct)) => {
+                assert_eq!((from, d, is_direct), (origin_id, vec![1, 2, 3], false))
+            }
+            _ => panic!("origin data missing"),
+        }
+
+        // the origin clock is ahead, in the skew.
+        let ahead = now + session.global.max_clock_skew / 2;
+        let ahead = crate::global::origin_proof_at(&origin, &gateway, &me, &[1, 2, 3], ahead);
+        session
+            .handle_endpoint(frame(ahead, origin_id))
+            .await
+            .unwrap();
+        assert!(out_receiver.try_recv().is_ok());
+        let future = crate::global::origin_proof_at(&origin, &gateway, &me, &[1, 2, 3], now + 60);
+        session
+            .handle_endpoint(frame(future, origin_id))
+            .await
+            .unwrap();
+        assert!(out_receiver.try_recv().is_err());
+
+        // replayed proof.
+        session
+            .handle_endpoint(frame(proof, origin_id))
+            .await
+            .unwrap();
+        assert!(out_receiver.try_recv().is_err());
+    }
+}
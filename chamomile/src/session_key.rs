@@ -2,12 +2,49 @@ use aes_gcm::aead::{generic_array::GenericArray, Aead};
 use aes_gcm::{Aes256Gcm, KeyInit};
 use chamomile_types::{
     key::secp256k1::{PublicKey, Secp256k1, SecretKey},
-    key::{Key, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH},
+    key::{Signature, Signer, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH},
     types::{new_io_error, PeerId},
 };
-use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaChaRng,
+};
+use sha3::{Digest, Keccak256};
 use std::io::Result;
 
+/// session resumption ticket secret.
+pub type Ticket = [u8; 32];
+
+/// full handshake bytes: pk_bytes (33) + sign_bytes (65).
+const FULL_LENGTH: usize = PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH;
+/// resumption bytes: nonce (32) + tag (32).
+pub(crate) const RESUME_LENGTH: usize = 64;
+
+const TICKET_DOMAIN: &[u8] = b"chamomile-ticket";
+const OFFER_DOMAIN: &[u8] = b"chamomile-resume-offer";
+const ANSWER_DOMAIN: &[u8] = b"chamomile-resume-answer";
+const CIPHER_DOMAIN: &[u8] = b"chamomile-resume-cipher";
+const EARLY_DOMAIN: &[u8] = b"chamomile-resume-early";
+
+/// the next ticket after a resumption with the ticket and two nonces (offer, answer).
+fn rotate(ticket: &Ticket, offer: &[u8], answer: &[u8]) -> Ticket {
+    keccak(&[TICKET_DOMAIN, ticket, offer, answer])
+}
+
+fn keccak(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+thread_local! {
+    /// count of the asymmetric operations (recover + DH) in this thread.
+    static ASYMMETRIC_OPS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 //#[derive(Zeroize)]
 pub struct SessionKey {
     /// Random secret key for this session
@@ -16,6 +53,13 @@ pub struct SessionKey {
     is_ok: bool,
     /// 256-bit key (random key from DH key)
     cipher: Aes256Gcm,
+    /// The ticket for next session resumption.
+    ticket: Option<Ticket>,
+    /// The offered resumption (ticket, nonce), waiting remote answer.
+    resume: Option<(Ticket, [u8; 32])>,
+    /// The early data. when dial, it is the data need resend after connected
+    /// (remote not accept it), when answer, it is the data received from remote.
+    early: Option<Vec<u8>>,
 }
 
 /// Simple DH on 25519 to get AES-256 session key.
@@ -24,14 +68,38 @@ pub struct SessionKey {
 /// 2. receive remote tmp public_key and signature, verify it.
 /// 3. use remote public_key and self tmp private key to compute.
 /// 4. get session key, and encrypt / decrypt message.
+///
+/// Session resumption (when both have the ticket from last DH):
+/// 1. send the full bytes and append a nonce and tag (proof of the ticket).
+/// 2. remote check the tag, answer its nonce and tag, skip the DH.
+/// 3. both derive the session key from ticket and two nonces.
+/// 4. if remote has no ticket or tag is invalid, it answer full bytes.
+/// 5. both rotate the ticket with the two nonces, a ticket is only used once.
+///
+/// Early data (0-RTT), when offer resumption, can append the first payload
+/// encrypted by the key from ticket and nonce, remote can read it when
+/// receive the offer, not wait the round trip. It is weaker than the session:
+/// no forward secrecy (leaked ticket can read it). Remote uses a ticket only once,
+/// and delivers the data after the first session frame (key confirmed), so a
+/// replayed offer is not delivered. If remote not accept the offer,
+/// the dialer will resend it in the session after connected.
 impl SessionKey {
     pub fn is_ok(&self) -> bool {
         self.is_ok
     }
 
-    pub fn generate(key: &Key) -> (SessionKey, Vec<u8>) {
-        let mut rng = ChaChaRng::from_entropy();
-        let sk = SecretKey::new(&mut rng);
+    /// the ticket for resume next session, only has when key is ok.
+    pub fn ticket(&self) -> Option<Ticket> {
+        self.ticket
+    }
+
+    pub fn generate(key: &dyn Signer) -> (SessionKey, Vec<u8>) {
+        Self::generate_with(key, &mut ChaChaRng::from_entropy())
+    }
+
+    /// generate with the rng, it is deterministic when the rng is seeded (test vectors).
+    pub(crate) fn generate_with(key: &dyn Signer, rng: &mut ChaChaRng) -> (SessionKey, Vec<u8>) {
+        let sk = SecretKey::new(rng);
         let pk = sk.public_key(&Secp256k1::new());
         let mut pk_bytes = pk.serialize().to_vec();
         let sign = key.sign(&pk_bytes);
@@ -42,17 +110,72 @@ impl SessionKey {
                 sk,
                 is_ok: false,
                 cipher: Aes256Gcm::new(GenericArray::from_slice(&[0u8; 32])),
+                ticket: None,
+                resume: None,
+                early: None,
             },
             pk_bytes,
         )
     }
 
+    /// generate full bytes and offer a session resumption with the ticket.
+    pub fn generate_resume(
+        key: &dyn Signer,
+        ticket: Ticket,
+        rng: &mut ChaChaRng,
+    ) -> (SessionKey, Vec<u8>) {
+        let (mut session, mut bytes) = Self::generate_with(key, rng);
+        let mut nonce = [0u8; 32];
+        rng.fill_bytes(&mut nonce);
+        bytes.extend(nonce);
+        bytes.extend(keccak(&[OFFER_DOMAIN, &ticket, &nonce]));
+        session.resume = Some((ticket, nonce));
+        (session, bytes)
+    }
+
+    /// offer a session resumption, and append the early data.
+    pub fn generate_resume_early(
+        key: &dyn Signer,
+        ticket: Ticket,
+        data: Vec<u8>,
+        rng: &mut ChaChaRng,
+    ) -> (SessionKey, Vec<u8>) {
+        let (mut session, mut bytes) = Self::generate_resume(key, ticket, rng);
+        let nonce = &bytes[FULL_LENGTH..FULL_LENGTH + 32];
+        let early_key = keccak(&[EARLY_DOMAIN, &ticket, nonce]);
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&early_key));
+        let e_data = cipher
+            .encrypt(GenericArray::from_slice(&[0u8; 12]), data.as_ref())
+            .unwrap_or(vec![]);
+        bytes.extend(e_data);
+        session.early = Some(data);
+        (session, bytes)
+    }
+
+    /// the early data, dialer need resend it, or answer received it.
+    pub fn take_early(&mut self) -> Option<Vec<u8>> {
+        self.early.take()
+    }
+
+    /// keep the data to send after session connected.
+    pub fn set_early(&mut self, data: Vec<u8>) {
+        self.early = Some(data);
+    }
+
     pub fn generate_complete(
-        key: &Key,
+        key: &dyn Signer,
         id: &PeerId,
         dh_bytes: Vec<u8>,
+        ticket: Option<Ticket>,
+        rng: &mut ChaChaRng,
     ) -> Option<(SessionKey, Vec<u8>)> {
-        let (mut session, bytes) = Self::generate(key);
+        if let Some(ticket) = ticket {
+            if let Some(resumed) = Self::answer_resume(ticket, &dh_bytes, rng) {
+                return Some(resumed);
+            }
+        }
+
+        let (mut session, bytes) = Self::generate_with(key, rng);
         if session.complete(id, dh_bytes) {
             Some((session, bytes))
         } else {
@@ -60,45 +183,321 @@ impl SessionKey {
         }
     }
 
+    /// check the remote offered resumption, if ok, answer it without DH.
+    fn answer_resume(
+        ticket: Ticket,
+        remote_dh: &[u8],
+        rng: &mut ChaChaRng,
+    ) -> Option<(SessionKey, Vec<u8>)> {
+        if remote_dh.len() < FULL_LENGTH + RESUME_LENGTH {
+            return None;
+        }
+        let (remote_nonce, remote_tag) =
+            remote_dh[FULL_LENGTH..FULL_LENGTH + RESUME_LENGTH].split_at(32);
+        if keccak(&[OFFER_DOMAIN, &ticket, remote_nonce]) != remote_tag {
+            return None;
+        }
+
+        let e_data = &remote_dh[FULL_LENGTH + RESUME_LENGTH..];
+        let early = if e_data.is_empty() {
+            None
+        } else {
+            let early_key = keccak(&[EARLY_DOMAIN, &ticket, remote_nonce]);
+            let cipher = Aes256Gcm::new(GenericArray::from_slice(&early_key));
+            Some(
+                cipher
+                    .decrypt(GenericArray::from_slice(&[0u8; 12]), e_data)
+                    .ok()?,
+            )
+        };
+
+        let mut nonce = [0u8; 32];
+        rng.fill_bytes(&mut nonce);
+        let cipher_key = keccak(&[CIPHER_DOMAIN, &ticket, remote_nonce, &nonce]);
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend(keccak(&[ANSWER_DOMAIN, &ticket, remote_nonce, &nonce]));
+        let session = SessionKey {
+            sk: SecretKey::new(rng),
+            is_ok: true,
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&cipher_key)),
+            ticket: Some(rotate(&ticket, remote_nonce, &nonce)),
+            resume: None,
+            early,
+        };
+        Some((session, bytes))
+    }
+
+    /// complete the offered resumption with remote answer.
+    fn complete_resume(&mut self, remote_dh: &[u8]) -> bool {
+        if let Some((ticket, nonce)) = self.resume.take() {
+            let (remote_nonce, remote_tag) = remote_dh.split_at(32);
+            if keccak(&[ANSWER_DOMAIN, &ticket, &nonce, remote_nonce]) != remote_tag {
+                return false;
+            }
+            let cipher_key = keccak(&[CIPHER_DOMAIN, &ticket, &nonce, remote_nonce]);
+            self.cipher = Aes256Gcm::new(GenericArray::from_slice(&cipher_key));
+            self.ticket = Some(rotate(&ticket, &nonce, remote_nonce));
+            self.is_ok = true;
+            // remote accepted the early data.
+            self.early = None;
+            return true;
+        }
+
+        false
+    }
+
     pub fn complete(&mut self, id: &PeerId, remote_dh: Vec<u8>) -> bool {
-        // pk_bytes (33) + sign_bytes (68)
-        if remote_dh.len() != PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH {
+        // resumption answer: nonce (32) + tag (32)
+        if remote_dh.len() == RESUME_LENGTH {
+            return self.complete_resume(&remote_dh);
+        }
+
+        // pk_bytes (33) + sign_bytes (65), maybe with a resumption offer and early data.
+        if remote_dh.len() != FULL_LENGTH && remote_dh.len() < FULL_LENGTH + RESUME_LENGTH {
             return false;
         }
+        self.resume = None;
+
+        #[cfg(test)]
+        ASYMMETRIC_OPS.with(|ops| ops.set(ops.get() + 1));
 
-        let (tmp_pk, tmp_sign) = remote_dh.split_at(PUBLIC_KEY_LENGTH);
-        match (
+        let (tmp_pk, tmp_sign) = remote_dh[..FULL_LENGTH].split_at(PUBLIC_KEY_LENGTH);
+        if let (Ok(pk), Ok(sign)) = (
             PublicKey::from_slice(tmp_pk),
             Signature::from_bytes(tmp_sign),
         ) {
-            (Ok(pk), Ok(sign)) => {
-                if let Ok(new_id) = sign.peer_id(tmp_pk) {
-                    if new_id != *id {
-                        return false;
-                    }
-                    if let Ok(dh) = pk.mul_tweak(&Secp256k1::new(), &self.sk.into()) {
-                        self.cipher =
-                            Aes256Gcm::new(GenericArray::from_slice(&dh.serialize()[0..32]));
-                        self.is_ok = true;
-                        return true;
-                    }
+            if let Ok(new_id) = sign.peer_id(tmp_pk) {
+                if new_id != *id {
+                    return false;
+                }
+                if let Ok(dh) = pk.mul_tweak(&Secp256k1::new(), &self.sk.into()) {
+                    let dh_bytes = dh.serialize();
+                    self.cipher = Aes256Gcm::new(GenericArray::from_slice(&dh_bytes[0..32]));
+                    self.ticket = Some(keccak(&[TICKET_DOMAIN, &dh_bytes]));
+                    self.is_ok = true;
+                    return true;
                 }
             }
-            _ => {}
         }
 
         false
     }
 
+    /// encrypt the data once under the content key, shared by all recipients of it,
+    /// every recipient get the content key by `wrap`.
+    pub fn seal(content_key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(content_key));
+        // the content key is new for every data, the fixed nonce is safe.
+        cipher
+            .encrypt(GenericArray::from_slice(&[0u8; 12]), data)
+            .unwrap_or(vec![])
+    }
+
+    /// decrypt the sealed data with the content key.
+    pub fn unseal(content_key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(content_key));
+        cipher
+            .decrypt(GenericArray::from_slice(&[0u8; 12]), sealed)
+            .map_err(|_e| new_io_error("unseal failure."))
+    }
+
+    /// encrypt the content key of sealed data and the sequence of the frame for the
+    /// remote of this session.
+    pub fn wrap(&self, content_key: &[u8; 32], seq: u64) -> Vec<u8> {
+        let mut bytes = content_key.to_vec();
+        bytes.extend(&seq.to_be_bytes()[..]);
+        self.encrypt(bytes)
+    }
+
+    /// decrypt the wrapped content key and sequence, and the sealed data with it.
+    pub fn open(&self, wrapped: Vec<u8>, sealed: &[u8]) -> Result<(u64, Vec<u8>)> {
+        let bytes = self.decrypt(wrapped)?;
+        if bytes.len() != 40 {
+            return Err(new_io_error("content key failure."));
+        }
+        let mut content_key = [0u8; 32];
+        content_key.copy_from_slice(&bytes[..32]);
+        let mut seq_bytes = [0u8; 8];
+        seq_bytes.copy_from_slice(&bytes[32..]);
+        let data = Self::unseal(&content_key, sealed)?;
+        Ok((u64::from_be_bytes(seq_bytes), data))
+    }
+
     pub fn encrypt(&self, msg: Vec<u8>) -> Vec<u8> {
         let nonce = GenericArray::from_slice(&[0u8; 12]);
-        self.cipher.encrypt(&nonce, msg.as_ref()).unwrap_or(vec![])
+        self.cipher.encrypt(nonce, msg.as_ref()).unwrap_or(vec![])
     }
 
     pub fn decrypt(&self, msg: Vec<u8>) -> Result<Vec<u8>> {
         let nonce = GenericArray::from_slice(&[0u8; 12]);
         self.cipher
-            .decrypt(&nonce, msg.as_ref())
+            .decrypt(nonce, msg.as_ref())
             .map_err(|_e| new_io_error("decrypt failure."))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chamomile_types::key::Key;
+
+    fn asymmetric_ops() -> usize {
+        ASYMMETRIC_OPS.with(|ops| ops.get())
+    }
+
+    #[test]
+    fn test_resume_handshake() {
+        let mut rng = ChaChaRng::from_seed([7u8; 32]);
+        let a = Key::generate(&mut rng);
+        let b = Key::generate(&mut rng);
+
+        // cold handshake.
+        let start = asymmetric_ops();
+        let (mut a_session, a_bytes) = SessionKey::generate(&a);
+        let (b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, None, &mut rng).unwrap();
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+        let cold_ops = asymmetric_ops() - start;
+        let a_ticket = a_session.ticket().unwrap();
+        assert_eq!(Some(a_ticket), b_session.ticket());
+
+        // resumed handshake.
+        let start = asymmetric_ops();
+        let (mut a_session, a_bytes) = SessionKey::generate_resume(&a, a_ticket, &mut rng);
+        let (b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, Some(a_ticket), &mut rng)
+                .unwrap();
+        assert_eq!(b_bytes.len(), RESUME_LENGTH);
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+        let resume_ops = asymmetric_ops() - start;
+        assert!(resume_ops < cold_ops);
+
+        let msg = b"resumed".to_vec();
+        let e_msg = a_session.encrypt(msg.clone());
+        assert_eq!(b_session.decrypt(e_msg).unwrap(), msg);
+
+        // both rotate the ticket, the used one cannot resume again.
+        let new_ticket = a_session.ticket().unwrap();
+        assert_ne!(new_ticket, a_ticket);
+        assert_eq!(Some(new_ticket), b_session.ticket());
+        let (_, a_bytes) = SessionKey::generate_resume(&a, a_ticket, &mut rng);
+        let (_, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, Some(new_ticket), &mut rng)
+                .unwrap();
+        assert_eq!(b_bytes.len(), FULL_LENGTH);
+    }
+
+    #[test]
+    fn test_sealed() {
+        let mut rng = ChaChaRng::from_seed([11u8; 32]);
+        let (a, b) = (Key::generate(&mut rng), Key::generate(&mut rng));
+        let (mut a_session, a_bytes) = SessionKey::generate(&a);
+        let (b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, None, &mut rng).unwrap();
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+
+        let content_key = [3u8; 32];
+        let sealed = SessionKey::seal(&content_key, b"group");
+        let wrapped = a_session.wrap(&content_key, 7);
+        assert_eq!(
+            b_session.open(wrapped, &sealed).unwrap(),
+            (7, b"group".to_vec())
+        );
+        assert!(b_session
+            .open(a_session.wrap(&[4u8; 32], 8), &sealed)
+            .is_err());
+    }
+
+    #[test]
+    fn test_resume_fallback() {
+        let mut rng = ChaChaRng::from_seed([8u8; 32]);
+        let a = Key::generate(&mut rng);
+        let b = Key::generate(&mut rng);
+
+        // remote has another ticket, fallback to full handshake.
+        let (mut a_session, a_bytes) = SessionKey::generate_resume(&a, [1u8; 32], &mut rng);
+        let (b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, Some([2u8; 32]), &mut rng)
+                .unwrap();
+        assert_eq!(b_bytes.len(), FULL_LENGTH);
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+        assert_eq!(a_session.ticket(), b_session.ticket());
+    }
+
+    #[test]
+    fn test_resume_early_data() {
+        let mut rng = ChaChaRng::from_seed([10u8; 32]);
+        let a = Key::generate(&mut rng);
+        let b = Key::generate(&mut rng);
+        let ticket = [3u8; 32];
+        let data = b"first payload".to_vec();
+
+        // remote read the early data from the offer, before the answer back.
+        let (mut a_session, a_bytes) =
+            SessionKey::generate_resume_early(&a, ticket, data.clone(), &mut rng);
+        let (mut b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, Some(ticket), &mut rng)
+                .unwrap();
+        assert_eq!(b_session.take_early(), Some(data.clone()));
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+        assert_eq!(a_session.take_early(), None);
+
+        // remote has no ticket, dialer keep the data to resend.
+        let (mut a_session, a_bytes) =
+            SessionKey::generate_resume_early(&a, ticket, data.clone(), &mut rng);
+        let (mut b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, None, &mut rng).unwrap();
+        assert_eq!(b_session.take_early(), None);
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+        assert_eq!(a_session.take_early(), Some(data));
+    }
+
+    /// signer which only talks to the key through a channel, like a remote signer.
+    struct RemoteSigner {
+        public: chamomile_types::key::PublicKey,
+        sender: std::sync::mpsc::Sender<(Vec<u8>, std::sync::mpsc::Sender<Signature>)>,
+    }
+
+    impl RemoteSigner {
+        fn new(key: Key) -> Self {
+            let public = key.public();
+            let (sender, receiver) =
+                std::sync::mpsc::channel::<(Vec<u8>, std::sync::mpsc::Sender<Signature>)>();
+            std::thread::spawn(move || {
+                while let Ok((msg, tx)) = receiver.recv() {
+                    let _ = tx.send(key.sign(&msg));
+                }
+            });
+            RemoteSigner { public, sender }
+        }
+    }
+
+    impl Signer for RemoteSigner {
+        fn sign(&self, msg: &[u8]) -> Signature {
+            let (tx, rx) = std::sync::mpsc::channel();
+            self.sender.send((msg.to_vec(), tx)).unwrap();
+            rx.recv().unwrap()
+        }
+
+        fn public_key(&self) -> chamomile_types::key::PublicKey {
+            self.public.clone()
+        }
+    }
+
+    #[test]
+    fn test_remote_signer_handshake() {
+        let mut rng = ChaChaRng::from_seed([9u8; 32]);
+        let a = RemoteSigner::new(Key::generate(&mut rng));
+        let b = Key::generate(&mut rng);
+
+        let (mut a_session, a_bytes) = SessionKey::generate(&a);
+        let (b_session, b_bytes) =
+            SessionKey::generate_complete(&b, &a.peer_id(), a_bytes, None, &mut rng).unwrap();
+        assert!(a_session.complete(&b.peer_id(), b_bytes));
+
+        let msg = b"signed elsewhere".to_vec();
+        let e_msg = b_session.encrypt(msg.clone());
+        assert_eq!(a_session.decrypt(e_msg).unwrap(), msg);
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::{fs, io::Result};
+
+/// the boxed future returned by the storage.
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Persistence backend of the key and peers (e.g. sled, redis or custom store).
+/// The keys are the `primitives::STORAGE_*` names.
+pub trait Storage: Send + Sync {
+    /// get the value, if not exists, return None.
+    fn get<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<Vec<u8>>>;
+
+    /// put (insert or replace) the value.
+    fn put<'a>(&'a self, key: &'a str, value: Vec<u8>) -> StorageFuture<'a, ()>;
+
+    /// delete the value, it is ok if not exists.
+    fn delete<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()>;
+}
+
+impl Debug for dyn Storage {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Storage")
+    }
+}
+
+/// Default storage, every key is a file in the directory.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Storage for FileStorage {
+    fn get<'a>(&'a self, key: &'a str) -> StorageFuture<'a, Option<Vec<u8>>> {
+        Box::pin(async move {
+            match fs::read(self.dir.join(key)).await {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, value: Vec<u8>) -> StorageFuture<'a, ()> {
+        Box::pin(async move { fs::write(self.dir.join(key), value).await })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            match fs::remove_file(self.dir.join(key)).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// use the config storage, or the files in the db dir.
+pub(crate) fn storage_or_file(storage: Option<Arc<dyn Storage>>, dir: PathBuf) -> Arc<dyn Storage> {
+    storage.unwrap_or_else(|| Arc::new(FileStorage::new(dir)))
+}
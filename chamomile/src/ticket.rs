@@ -0,0 +1,121 @@
+use aes_gcm::aead::{generic_array::GenericArray, Aead};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaChaRng,
+};
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chamomile_types::{
+    key::Signer,
+    types::{PeerId, PEER_ID_LENGTH},
+};
+
+use crate::primitives::STORAGE_TICKETS_KEY;
+use crate::session_key::Ticket;
+use crate::storage::Storage;
+
+const TICKET_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TICKETS_DOMAIN: &[u8] = b"chamomile-tickets";
+
+/// Session resumption tickets.
+/// saved value is: owner peer id (20) + nonce (12) + encrypted [peer id (20) + ticket (32)].
+/// If the owner (self identity) changed, all tickets are invalid.
+pub(crate) struct Tickets {
+    is_open: bool,
+    storage: Arc<dyn Storage>,
+    owner: PeerId,
+    cipher: Aes256Gcm,
+    tickets: HashMap<PeerId, Ticket>,
+}
+
+impl Tickets {
+    /// `saved` is the tickets bytes read from the storage.
+    pub fn load(key: &dyn Signer, storage: Arc<dyn Storage>, saved: &[u8], is_open: bool) -> Self {
+        // secp256k1 signature is deterministic (RFC6979), so it is a stable secret,
+        // and no need the raw secret key.
+        let mut hasher = Keccak256::new();
+        hasher.update(TICKETS_DOMAIN);
+        hasher.update(key.sign(TICKETS_DOMAIN).to_bytes());
+        let file_key = hasher.finalize();
+
+        let mut tickets = Tickets {
+            is_open,
+            storage,
+            owner: key.peer_id(),
+            cipher: Aes256Gcm::new(GenericArray::from_slice(&file_key)),
+            tickets: HashMap::new(),
+        };
+
+        if is_open && !saved.is_empty() {
+            tickets.decode(saved);
+        }
+        tickets
+    }
+
+    fn decode(&mut self, bytes: &[u8]) {
+        if bytes.len() < PEER_ID_LENGTH + NONCE_LENGTH
+            || bytes[..PEER_ID_LENGTH] != self.owner.0[..]
+        {
+            debug!("Session tickets owner changed, drop them.");
+            return;
+        }
+
+        let (nonce, e_data) = bytes[PEER_ID_LENGTH..].split_at(NONCE_LENGTH);
+        if let Ok(data) = self.cipher.decrypt(GenericArray::from_slice(nonce), e_data) {
+            for item in data.chunks_exact(PEER_ID_LENGTH + TICKET_LENGTH) {
+                if let Ok(peer_id) = PeerId::from_bytes(&item[..PEER_ID_LENGTH]) {
+                    let mut ticket = [0u8; TICKET_LENGTH];
+                    ticket.copy_from_slice(&item[PEER_ID_LENGTH..]);
+                    self.tickets.insert(peer_id, ticket);
+                }
+            }
+        }
+    }
+
+    async fn save(&self) {
+        let mut data = vec![];
+        for (peer_id, ticket) in self.tickets.iter() {
+            data.extend(peer_id.as_bytes());
+            data.extend(ticket);
+        }
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        ChaChaRng::from_entropy().fill_bytes(&mut nonce);
+        if let Ok(e_data) = self
+            .cipher
+            .encrypt(GenericArray::from_slice(&nonce), data.as_ref())
+        {
+            let mut bytes = self.owner.to_bytes();
+            bytes.extend(nonce);
+            bytes.extend(e_data);
+            let _ = self.storage.put(STORAGE_TICKETS_KEY, bytes).await;
+        }
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<Ticket> {
+        if !self.is_open || peer_id == &self.owner {
+            return None;
+        }
+        self.tickets.get(peer_id).copied()
+    }
+
+    pub async fn remove(&mut self, peer_id: &PeerId) {
+        if self.tickets.remove(peer_id).is_some() {
+            self.save().await;
+        }
+    }
+
+    pub async fn add(&mut self, peer_id: PeerId, ticket: Ticket) {
+        // own devices has same peer id, cannot use tickets.
+        if !self.is_open || peer_id == self.owner {
+            return;
+        }
+        if self.tickets.insert(peer_id, ticket) != Some(ticket) {
+            self.save().await;
+        }
+    }
+}
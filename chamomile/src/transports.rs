@@ -1,10 +1,18 @@
+use socket2::{Domain, Protocol, Socket, Type};
 use std::io::Result;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::{interval_at, Interval, MissedTickBehavior};
 
 use chamomile_types::{
-    peer::{Peer, PEER_LENGTH},
-    types::{new_io_error, PeerId, TransportType, PEER_ID_LENGTH},
+    key::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH},
+    message::ConnectTrace,
+    peer::{socket_addr_from_bytes, socket_addr_to_bytes, Peer},
+    types::{
+        new_io_error, CloseReason, ConnectError, ConnectResult, PeerId, TransportType,
+        PEER_ID_LENGTH,
+    },
 };
 
 mod rtp;
@@ -13,12 +21,39 @@ mod tcp;
 mod quic;
 mod udt;
 
+use crate::config::SocketOptions;
 use crate::hole_punching::{Hole, DHT};
-use crate::session_key::SessionKey;
+use crate::session_key::{SessionKey, RESUME_LENGTH};
+
+/// the full handshake bytes of session key, the resumption offer is after it.
+const HANDSHAKE_LENGTH: usize = PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH;
 
 /// waiting for connect time
 pub const CONNECTING_WAITING: u64 = 60; // 60s
 
+/// waiting for remote handshake time
+pub const HANDSHAKE_WAITING: u64 = 10; // 10s
+
+/// handshake protocol version, bump it when the wire format changes.
+/// the version 1 is not on the wire (same bytes as before the version), the newer
+/// one is appended after the session key bytes, the nodes before the version cannot
+/// read it, so bump it is a hard fork to them.
+/// 2: the sequenced and padded frames.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// the version of the remote not sent it.
+pub(crate) const PROTOCOL_DEFAULT_VERSION: u16 = 1;
+
+/// the oldest remote protocol version still compatible.
+pub const PROTOCOL_MIN_VERSION: u16 = 1;
+
+/// check the remote version is in the compatible range `[min, ..]`.
+/// newer remote is accepted here, the newer side knows if it can talk with us,
+/// and it will close with `CloseReason::Version` when not.
+pub(crate) fn compatible_version(version: u16, min: u16) -> bool {
+    version >= min
+}
+
 /// new a channel for send TransportSendMessage.
 pub fn new_transport_send_channel() -> (Sender<TransportSendMessage>, Receiver<TransportSendMessage>)
 {
@@ -37,10 +72,17 @@ pub fn new_endpoint_channel() -> (Sender<EndpointMessage>, Receiver<EndpointMess
 }
 
 /// Endpoint can receied this message channel.
+#[allow(clippy::large_enum_variant)]
 pub enum TransportSendMessage {
     /// connect to a socket address.
-    /// params is `socket_addr`, `remote_pk bytes`.
-    Connect(SocketAddr, RemotePublic, SessionKey),
+    /// params is `socket_addr`, `remote_pk bytes`, session key,
+    /// and the waiter of connect result if need.
+    Connect(
+        SocketAddr,
+        RemotePublic,
+        SessionKey,
+        Option<Sender<ConnectResult>>,
+    ),
     /// params is `delivery_id`, `socket_addr`, `remote_pk bytes`.
     StableConnect(
         Sender<EndpointMessage>,
@@ -61,13 +103,43 @@ pub struct TransportRecvMessage(
     pub Sender<EndpointMessage>,   // session's endpoint sender.
     pub Receiver<EndpointMessage>, // session's endpoint receiver.
     pub Sender<EndpointMessage>,   // transport's receiver.
+    pub ConnectTimer,              // connection establishment timer.
 );
 
+/// Connection establishment timer, record the durations from connect start.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectTimer {
+    pub start: Instant,
+    pub trace: ConnectTrace,
+}
+
+impl ConnectTimer {
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            trace: ConnectTrace::default(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// The reason of relay reject.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RejectReason {
+    /// the relay is full, it is load.
+    Full, // 0u8
+    /// the next hop is gone, not load.
+    Unreachable, // 1u8
+}
+
 /// Session Endpoint Message.
 /// bytes[0] is type, bytes[1..] is data.
 pub enum EndpointMessage {
-    /// type is 0u8.
-    Close,
+    /// type is 0u8. with the reason when force closed.
+    Close(Option<CloseReason>),
     /// type is 1u8.
     Handshake(RemotePublic),
     /// type is 2u8.
@@ -82,12 +154,33 @@ pub enum EndpointMessage {
     RelayHandshake(RemotePublic, PeerId),
     /// type is 7u8. encrypted's CoreData.
     RelayData(PeerId, PeerId, Vec<u8>),
+    /// type is 8u8. find the peer, remote will answer with DHT.
+    FindNode(PeerId),
+    /// type is 9u8. relay data with origin signature (from, to, signature, data).
+    RelaySigned(PeerId, PeerId, Vec<u8>, Vec<u8>),
+    /// type is 10u8. relay declined by a full relay, or the next hop is gone (from, to, reason),
+    /// send back to the source. the reason byte is appended when not full.
+    RelayReject(PeerId, PeerId, RejectReason),
+    /// type is 11u8. the address of remote as seen by us (reflexive address),
+    /// the inbound side tell it, so remote know its external address.
+    Reflexive(SocketAddr),
+    /// type is 12u8. sealed data for several recipients (wrapped content key, sealed
+    /// data), the content key and the sequence of sealed frames are encrypted by
+    /// session key, the padded `CoreData::Data` by content key. only to version 2.
+    Sealed(Vec<u8>, Vec<u8>),
+    /// local only, never on the wire. the transport found the remote address changed
+    /// (connection migration) on an authenticated frame, the new address.
+    Migrated(SocketAddr),
+    /// local only, never on the wire. the stable connect timer, sent before the
+    /// remote handshake.
+    Connected(ConnectTimer),
 }
 
 /// main function. start the endpoint listening.
 pub async fn start(
     peer: &Peer,
     out_send: Option<Sender<TransportRecvMessage>>,
+    options: &SocketOptions,
 ) -> Result<(
     SocketAddr,
     Sender<TransportSendMessage>,
@@ -105,18 +198,113 @@ pub async fn start(
 
     let local_addr = match peer.transport {
         //&TransportType::UDP => udp::UdpEndpoint::start(addr, recv_send, send_recv).await?,
-        TransportType::TCP => tcp::start(peer.socket, recv_send, send_recv, both).await?,
-        TransportType::QUIC => quic::start(peer.socket, recv_send, send_recv, both).await?,
+        TransportType::TCP => tcp::start(peer.socket, recv_send, send_recv, both, options).await?,
+        TransportType::QUIC => {
+            quic::start(peer.socket, recv_send, send_recv, both, options).await?
+        }
+        TransportType::UDT => udt::start(peer.socket, recv_send, send_recv, both, options).await?,
         _ => panic!("Not suppert, waiting"),
     };
 
     Ok((local_addr, send_send, recv_recv, main_out))
 }
 
-/// Rtemote Public Info, include local transport and public key bytes, session_key out_bytes.
-pub struct RemotePublic(pub Peer, pub Vec<u8>);
+/// new a socket with the options, and bind to the addr.
+fn bind_socket(
+    addr: SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+    options: &SocketOptions,
+) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    socket.set_reuse_address(options.reuse_address)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(options.reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// bind a TCP listener with the socket options.
+pub(crate) fn bind_tcp(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> Result<tokio::net::TcpListener> {
+    let socket = bind_socket(addr, Type::STREAM, Protocol::TCP, options)?;
+    socket.listen(options.backlog.min(i32::MAX as u32) as i32)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// bind a UDP socket with the socket options.
+pub(crate) fn bind_udp(addr: SocketAddr, options: &SocketOptions) -> Result<UdpSocket> {
+    let socket = bind_socket(addr, Type::DGRAM, Protocol::UDP, options)?;
+    Ok(socket.into())
+}
+
+/// the transport keepalive timer, none when disabled.
+pub(crate) fn new_keepalive(millis: u64) -> Option<Interval> {
+    if millis == 0 {
+        return None;
+    }
+    let period = Duration::from_millis(millis);
+    let mut ticker = interval_at(tokio::time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(ticker)
+}
+
+pub(crate) async fn keepalive_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// the handshake is transient failure (timeout or connection lost), can retry it.
+pub(crate) fn handshake_transient(
+    res: &std::result::Result<Result<RemotePublic>, tokio::time::error::Elapsed>,
+) -> bool {
+    match res {
+        Err(_) => true,
+        Ok(Err(e)) => matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::TimedOut
+        ),
+        Ok(Ok(_)) => false,
+    }
+}
+
+/// the connect error when read the remote handshake failure.
+pub(crate) fn handshake_error(
+    res: std::result::Result<Result<RemotePublic>, tokio::time::error::Elapsed>,
+) -> ConnectError {
+    match res {
+        Err(_) => ConnectError::Timeout,
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => ConnectError::Rejected,
+        _ => ConnectError::Handshake,
+    }
+}
+
+/// Rtemote Public Info, include local transport and public key bytes, session_key out_bytes,
+/// and the remote protocol version.
+pub struct RemotePublic(pub Peer, pub Vec<u8>, pub u16);
 
 impl RemotePublic {
+    /// new remote public info with current protocol version.
+    pub fn new(peer: Peer, dh_key: Vec<u8>) -> Self {
+        Self(peer, dh_key, PROTOCOL_VERSION)
+    }
+
+    /// if the remote protocol version is compatible with us.
+    pub fn is_compatible(&self) -> bool {
+        compatible_version(self.2, PROTOCOL_MIN_VERSION)
+    }
+
     pub fn id(&self) -> &PeerId {
         &self.0.id
     }
@@ -126,33 +314,58 @@ impl RemotePublic {
     }
 
     pub fn from_bytes(mut bytes: Vec<u8>) -> Result<Self> {
-        if bytes.len() < PEER_LENGTH + 2 {
+        let (peer, peer_len) = Peer::decode(&bytes)?;
+        if bytes.len() < peer_len + 2 {
             return Err(new_io_error("Remote bytes failure."));
         }
-        let peer = Peer::from_bytes(bytes.drain(0..PEER_LENGTH).as_slice())?;
-        Ok(Self(peer, bytes))
+        let mut dh_key = bytes.split_off(peer_len);
+        // the version is after the full or resumption bytes, absent is default.
+        let version = if dh_key.len() == HANDSHAKE_LENGTH + 2 || dh_key.len() == RESUME_LENGTH + 2 {
+            let v = dh_key.split_off(dh_key.len() - 2);
+            u16::from_be_bytes([v[0], v[1]])
+        } else {
+            PROTOCOL_DEFAULT_VERSION
+        };
+        Ok(Self(peer, dh_key, version))
     }
 
     pub fn to_bytes(mut self) -> Vec<u8> {
-        let mut bytes = vec![];
-        bytes.append(&mut self.0.to_bytes());
+        let mut bytes = self.0.to_bytes();
         bytes.append(&mut self.1);
+        if self.2 != PROTOCOL_DEFAULT_VERSION {
+            bytes.extend(&self.2.to_be_bytes());
+        }
         bytes
     }
+
+    /// split the remote bytes and the session resumption offer after the full handshake
+    /// bytes, the offer is sent after the remote, so the older nodes ignore it.
+    fn split_offer(mut self) -> (Vec<u8>, Vec<u8>) {
+        let offer = if self.1.len() > HANDSHAKE_LENGTH {
+            self.1.split_off(HANDSHAKE_LENGTH)
+        } else {
+            vec![]
+        };
+        (self.to_bytes(), offer)
+    }
 }
 
 impl EndpointMessage {
     pub fn to_bytes(self) -> Vec<u8> {
         let mut bytes = vec![0u8];
         match self {
-            EndpointMessage::Close => {
+            EndpointMessage::Close(reason) => {
                 bytes[0] = 0u8;
+                if let Some(reason) = reason {
+                    bytes.push(reason.to_byte());
+                }
             }
             EndpointMessage::Handshake(peer) => {
                 bytes[0] = 1u8;
-                let mut peer_bytes = peer.to_bytes();
+                let (mut peer_bytes, mut offer) = peer.split_offer();
                 bytes.extend(&(peer_bytes.len() as u32).to_be_bytes()[..]);
                 bytes.append(&mut peer_bytes);
+                bytes.append(&mut offer);
             }
             EndpointMessage::DHT(dht) => {
                 bytes[0] = 2u8;
@@ -171,10 +384,11 @@ impl EndpointMessage {
             }
             EndpointMessage::RelayHandshake(p1_peer, p2_id) => {
                 bytes[0] = 6u8;
-                let mut peer_bytes = p1_peer.to_bytes();
+                let (mut peer_bytes, mut offer) = p1_peer.split_offer();
                 bytes.extend(&(peer_bytes.len() as u32).to_be_bytes()[..]);
                 bytes.append(&mut peer_bytes);
                 bytes.append(&mut p2_id.to_bytes());
+                bytes.append(&mut offer);
             }
             EndpointMessage::RelayData(p1_id, p2_id, mut data) => {
                 bytes[0] = 7u8;
@@ -182,19 +396,54 @@ impl EndpointMessage {
                 bytes.append(&mut p2_id.to_bytes());
                 bytes.append(&mut data);
             }
+            EndpointMessage::FindNode(peer_id) => {
+                bytes[0] = 8u8;
+                bytes.append(&mut peer_id.to_bytes());
+            }
+            EndpointMessage::RelaySigned(p1_id, p2_id, mut sign, mut data) => {
+                bytes[0] = 9u8;
+                bytes.append(&mut p1_id.to_bytes());
+                bytes.append(&mut p2_id.to_bytes());
+                bytes.append(&mut sign);
+                bytes.append(&mut data);
+            }
+            EndpointMessage::RelayReject(p1_id, p2_id, reason) => {
+                bytes[0] = 10u8;
+                bytes.append(&mut p1_id.to_bytes());
+                bytes.append(&mut p2_id.to_bytes());
+                // full is same as before, so old nodes can read it.
+                if reason == RejectReason::Unreachable {
+                    bytes.push(1u8);
+                }
+            }
+            EndpointMessage::Reflexive(addr) => {
+                bytes[0] = 11u8;
+                bytes.append(&mut socket_addr_to_bytes(&addr));
+            }
+            EndpointMessage::Sealed(mut wrapped, mut sealed) => {
+                bytes[0] = 12u8;
+                bytes.extend(&(wrapped.len() as u32).to_be_bytes()[..]);
+                bytes.append(&mut wrapped);
+                bytes.append(&mut sealed);
+            }
+            EndpointMessage::Migrated(_) | EndpointMessage::Connected(_) => return vec![],
         }
 
         bytes
     }
 
-    fn from_bytes(mut bytes: Vec<u8>) -> Result<Self> {
-        if bytes.len() < 1 {
+    pub fn from_bytes(mut bytes: Vec<u8>) -> Result<Self> {
+        if bytes.is_empty() {
             return Err(new_io_error("EndpointMessage bytes failure."));
         }
 
         let t: Vec<u8> = bytes.drain(0..1).collect();
         match t[0] {
-            0u8 => Ok(EndpointMessage::Close),
+            0u8 => {
+                // reason is optional, only when force closed.
+                let reason = bytes.first().and_then(|b| CloseReason::from_byte(*b).ok());
+                Ok(EndpointMessage::Close(reason))
+            }
             1u8 => {
                 if bytes.len() < 4 {
                     return Err(new_io_error("EndpointMessage bytes failure."));
@@ -205,8 +454,10 @@ impl EndpointMessage {
                 if bytes.len() < peer_len {
                     return Err(new_io_error("EndpointMessage bytes failure."));
                 }
-                let peer = RemotePublic::from_bytes(bytes.drain(0..peer_len).collect())
+                let mut peer = RemotePublic::from_bytes(bytes.drain(0..peer_len).collect())
                     .map_err(|_| new_io_error("EndpointMessage bytes failure."))?;
+                // the rest is the resumption offer.
+                peer.1.append(&mut bytes);
                 Ok(EndpointMessage::Handshake(peer))
             }
             2u8 => {
@@ -232,20 +483,359 @@ impl EndpointMessage {
                 if bytes.len() < peer_len + PEER_ID_LENGTH {
                     return Err(new_io_error("EndpointMessage bytes failure."));
                 }
-                let peer = RemotePublic::from_bytes(bytes.drain(0..peer_len).collect())
+                let mut peer = RemotePublic::from_bytes(bytes.drain(0..peer_len).collect())
                     .map_err(|_| new_io_error("EndpointMessage bytes failure."))?;
-                let p2 = PeerId::from_bytes(&bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                let p2 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                // the rest is the resumption offer.
+                peer.1.append(&mut bytes);
                 Ok(EndpointMessage::RelayHandshake(peer, p2))
             }
             7u8 => {
                 if bytes.len() < PEER_ID_LENGTH * 2 {
                     return Err(new_io_error("EndpointMessage bytes failure."));
                 }
-                let p1 = PeerId::from_bytes(&bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
-                let p2 = PeerId::from_bytes(&bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                let p1 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                let p2 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
                 Ok(EndpointMessage::RelayData(p1, p2, bytes))
             }
-            _ => Err(new_io_error("EndpointMessage bytes failure.")),
+            8u8 => {
+                if bytes.len() != PEER_ID_LENGTH {
+                    return Err(new_io_error("EndpointMessage bytes failure."));
+                }
+                let peer_id = PeerId::from_bytes(&bytes)?;
+                Ok(EndpointMessage::FindNode(peer_id))
+            }
+            9u8 => {
+                if bytes.len() < PEER_ID_LENGTH * 2 + SIGNATURE_LENGTH {
+                    return Err(new_io_error("EndpointMessage bytes failure."));
+                }
+                let p1 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                let p2 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                let sign = bytes.drain(0..SIGNATURE_LENGTH).collect();
+                Ok(EndpointMessage::RelaySigned(p1, p2, sign, bytes))
+            }
+            10u8 => {
+                let reason = match bytes.get(PEER_ID_LENGTH * 2..) {
+                    Some([]) => RejectReason::Full,
+                    Some([1u8]) => RejectReason::Unreachable,
+                    _ => return Err(new_io_error("EndpointMessage bytes failure.")),
+                };
+                let p1 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                let p2 = PeerId::from_bytes(bytes.drain(0..PEER_ID_LENGTH).as_slice())?;
+                Ok(EndpointMessage::RelayReject(p1, p2, reason))
+            }
+            11u8 => {
+                let addr = socket_addr_from_bytes(&bytes)
+                    .map_err(|_| new_io_error("EndpointMessage bytes failure."))?;
+                Ok(EndpointMessage::Reflexive(addr))
+            }
+            12u8 => {
+                if bytes.len() < 4 {
+                    return Err(new_io_error("EndpointMessage bytes failure."));
+                }
+                let mut wrapped_len_bytes = [0u8; 4];
+                wrapped_len_bytes.copy_from_slice(bytes.drain(0..4).as_slice());
+                let wrapped_len = u32::from_be_bytes(wrapped_len_bytes) as usize;
+                if bytes.len() < wrapped_len {
+                    return Err(new_io_error("EndpointMessage bytes failure."));
+                }
+                let wrapped = bytes.drain(0..wrapped_len).collect();
+                Ok(EndpointMessage::Sealed(wrapped, bytes))
+            }
+            // from newer version, transports skip it.
+            _ => Err(new_io_error("EndpointMessage unknown tag.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_bind_reuse_port() {
+        let options = SocketOptions {
+            reuse_address: true,
+            reuse_port: true,
+            backlog: 16,
+            ..SocketOptions::default()
+        };
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let tcp1 = bind_tcp(addr, &options).unwrap();
+        let tcp_addr = tcp1.local_addr().unwrap();
+        let tcp2 = bind_tcp(tcp_addr, &options).unwrap();
+        assert_eq!(tcp2.local_addr().unwrap(), tcp_addr);
+
+        let udp1 = bind_udp(addr, &options).unwrap();
+        let udp_addr = udp1.local_addr().unwrap();
+        let udp2 = bind_udp(udp_addr, &options).unwrap();
+        assert_eq!(udp2.local_addr().unwrap(), udp_addr);
+
+        // without reuse, the port is in use.
+        let options = SocketOptions {
+            reuse_address: false,
+            reuse_port: false,
+            backlog: 16,
+            ..SocketOptions::default()
+        };
+        assert!(bind_tcp(tcp_addr, &options).is_err());
+        assert!(bind_udp(udp_addr, &options).is_err());
+    }
+
+    fn golden_peer() -> (Peer, Vec<u8>) {
+        let mut peer = Peer::new(
+            PeerId([1u8; 20]),
+            "1.2.3.4:4660".parse().unwrap(),
+            TransportType::TCP,
+            true,
+        );
+        peer.assist = PeerId([2u8; 20]);
+        let bytes = [
+            &[1u8; 20][..],
+            &[2u8; 20][..],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4],
+            &[0x34, 0x12, 1, 1],
+        ]
+        .concat();
+        (peer, bytes)
+    }
+
+    /// golden bytes, all integers are fixed endianness, not depend on target.
+    #[test]
+    fn test_dht_golden_bytes() {
+        let (peer, peer_bytes) = golden_peer();
+        let dht = DHT(vec![peer, peer], 0x01020304, vec![]);
+
+        let expected = [
+            &[2, 0, 0, 0][..], // count (le)
+            &peer_bytes,
+            &peer_bytes,
+            &[4, 3, 2, 1], // relay load (le)
+        ]
+        .concat();
+        assert_eq!(dht.to_bytes(), expected);
+
+        let DHT(peers, load, _) = DHT::from_bytes(&expected).unwrap();
+        assert!(peers == vec![peer, peer]);
+        assert_eq!(load, 0x01020304);
+
+        // old version without relay load.
+        let DHT(peers, load, _) = DHT::from_bytes(&expected[..expected.len() - 4]).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(load, 0);
+    }
+
+    #[test]
+    fn test_dht_roundtrip() {
+        let (peer, _) = golden_peer();
+        for count in [0usize, 1, 100] {
+            let peers = vec![peer; count];
+            let bytes = DHT(peers.clone(), 9, vec![]).to_bytes();
+            // count + peers + relay load.
+            assert_eq!(
+                bytes.len(),
+                4 + count * chamomile_types::peer::PEER_LENGTH + 4
+            );
+            let DHT(new_peers, load, _) = DHT::from_bytes(&bytes).unwrap();
+            assert!(new_peers == peers);
+            assert_eq!(load, 9);
         }
     }
+
+    #[test]
+    fn test_dht_multi_addrs() {
+        let (peer, _) = golden_peer();
+        let mut multi = peer;
+        multi.add_addr("[::1]:4660".parse().unwrap(), TransportType::QUIC);
+
+        let bytes = DHT(vec![multi, peer, multi], 7, vec![]).to_bytes();
+        let DHT(peers, load, _) = DHT::from_bytes(&bytes).unwrap();
+        assert!(peers == vec![multi, peer, multi]);
+        assert_eq!(load, 7);
+
+        // truncated in the middle of peers.
+        assert!(DHT::from_bytes(&bytes[..bytes.len() - 10]).is_err());
+
+        // with the signatures.
+        let signs = vec![
+            (1, vec![1u8; SIGNATURE_LENGTH]),
+            (2, vec![2u8; SIGNATURE_LENGTH]),
+        ];
+        let bytes = DHT(vec![multi, peer], 7, signs.clone()).to_bytes();
+        let DHT(peers, load, new_signs) = DHT::from_bytes(&bytes).unwrap();
+        assert!(peers == vec![multi, peer]);
+        assert_eq!((load, new_signs), (7, signs));
+        assert!(DHT::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let (peer, peer_bytes) = golden_peer();
+        let full = vec![5u8; HANDSHAKE_LENGTH];
+        // matching.
+        let remote = RemotePublic::new(peer, full.clone());
+        assert!(remote.is_compatible());
+        let remote = RemotePublic::from_bytes(remote.to_bytes()).unwrap();
+        assert_eq!(remote.2, PROTOCOL_VERSION);
+        assert!(remote.is_compatible());
+
+        // the default version is not on the wire, same as the nodes before it.
+        let legacy = [&peer_bytes[..], &full].concat();
+        let remote = RemotePublic(peer, full.clone(), PROTOCOL_DEFAULT_VERSION);
+        assert_eq!(remote.to_bytes(), legacy);
+        let remote = RemotePublic::from_bytes(legacy).unwrap();
+        assert_eq!(
+            (remote.1, remote.2),
+            (full.clone(), PROTOCOL_DEFAULT_VERSION)
+        );
+
+        // newer is appended after the full or resumption bytes.
+        for dh in [full.clone(), vec![6u8; RESUME_LENGTH]] {
+            let bytes = RemotePublic(peer, dh.clone(), 7).to_bytes();
+            assert_eq!(&bytes[bytes.len() - 2..], &[0u8, 7]);
+            let remote = RemotePublic::from_bytes(bytes).unwrap();
+            assert_eq!((remote.1, remote.2), (dh, 7));
+        }
+
+        // older but compatible, and newer.
+        assert!(compatible_version(3, 2));
+        assert!(compatible_version(5, 2));
+
+        // incompatible.
+        assert!(!compatible_version(1, 2));
+        let remote = RemotePublic(peer, full, PROTOCOL_MIN_VERSION - 1);
+        let remote = RemotePublic::from_bytes(remote.to_bytes()).unwrap();
+        assert!(!remote.is_compatible());
+    }
+
+    #[test]
+    fn test_resume_offer_bytes() {
+        let (peer, _) = golden_peer();
+        let full = vec![5u8; HANDSHAKE_LENGTH];
+        let offer = [full.clone(), vec![6u8; 64], vec![7u8; 3]].concat();
+
+        // the remote only has the full bytes, the offer is after it.
+        let remote_bytes = RemotePublic::new(peer, full).to_bytes();
+        let bytes = EndpointMessage::Handshake(RemotePublic::new(peer, offer.clone())).to_bytes();
+        let len = u32::from_be_bytes(bytes[1..5].try_into().unwrap()) as usize;
+        assert_eq!(&bytes[5..5 + len], &remote_bytes[..]);
+        assert_eq!(&bytes[5 + len..], &offer[HANDSHAKE_LENGTH..]);
+        match EndpointMessage::from_bytes(bytes).unwrap() {
+            EndpointMessage::Handshake(r) => assert_eq!(r.1, offer),
+            _ => panic!("endpoint handshake decode failure"),
+        }
+
+        let msg = EndpointMessage::RelayHandshake(RemotePublic::new(peer, offer.clone()), peer.id);
+        match EndpointMessage::from_bytes(msg.to_bytes()).unwrap() {
+            EndpointMessage::RelayHandshake(r, to) => assert_eq!((r.1, to), (offer, peer.id)),
+            _ => panic!("endpoint relay handshake decode failure"),
+        }
+    }
+
+    #[test]
+    fn test_endpoint_golden_bytes() {
+        let (peer, peer_bytes) = golden_peer();
+        let remote = RemotePublic(peer, vec![9u8, 8, 7], PROTOCOL_DEFAULT_VERSION);
+        let remote_bytes = [&peer_bytes[..], &[9, 8, 7]].concat();
+        assert_eq!(
+            RemotePublic(peer, vec![9u8, 8, 7], PROTOCOL_DEFAULT_VERSION).to_bytes(),
+            remote_bytes
+        );
+
+        // handshake: type + remote length (be) + remote (peer + dh).
+        let expected = [&[1u8, 0, 0, 0, 63][..], &remote_bytes].concat();
+        assert_eq!(EndpointMessage::Handshake(remote).to_bytes(), expected);
+        match EndpointMessage::from_bytes(expected.clone()).unwrap() {
+            EndpointMessage::Handshake(r) => assert_eq!(r.to_bytes(), remote_bytes),
+            _ => panic!("endpoint handshake decode failure"),
+        }
+
+        // relay handshake: type + remote length (be) + remote + to.
+        let remote = RemotePublic(peer, vec![9u8, 8, 7], PROTOCOL_DEFAULT_VERSION);
+        let expected = [&[6u8, 0, 0, 0, 63][..], &remote_bytes, &[3u8; 20]].concat();
+        let msg = EndpointMessage::RelayHandshake(remote, PeerId([3u8; 20]));
+        assert_eq!(msg.to_bytes(), expected);
+
+        // relay data: type + from + to + data.
+        let expected = [&[7u8][..], &[1u8; 20], &[3u8; 20], &[5, 5]].concat();
+        let msg = EndpointMessage::RelayData(PeerId([1u8; 20]), PeerId([3u8; 20]), vec![5, 5]);
+        assert_eq!(msg.to_bytes(), expected);
+
+        // relay signed: type + from + to + signature + data.
+        let expected = [&[9u8][..], &[1u8; 20], &[3u8; 20], &[7u8; 65], &[5, 5]].concat();
+        let msg = EndpointMessage::RelaySigned(
+            PeerId([1u8; 20]),
+            PeerId([3u8; 20]),
+            vec![7u8; 65],
+            vec![5, 5],
+        );
+        assert_eq!(msg.to_bytes(), expected);
+        assert!(matches!(
+            EndpointMessage::from_bytes(expected),
+            Ok(EndpointMessage::RelaySigned(_, _, sign, data)) if sign == vec![7u8; 65] && data == vec![5, 5]
+        ));
+
+        // relay reject: type + from + to.
+        let expected = [&[10u8][..], &[1u8; 20], &[3u8; 20]].concat();
+        let msg =
+            EndpointMessage::RelayReject(PeerId([1u8; 20]), PeerId([3u8; 20]), RejectReason::Full);
+        assert_eq!(msg.to_bytes(), expected);
+        assert!(matches!(
+            EndpointMessage::from_bytes(expected),
+            Ok(EndpointMessage::RelayReject(from, to, RejectReason::Full)) if from == PeerId([1u8; 20]) && to == PeerId([3u8; 20])
+        ));
+
+        // relay unreachable: type + from + to + reason.
+        let expected = [&[10u8][..], &[1u8; 20], &[3u8; 20], &[1u8]].concat();
+        let reason = RejectReason::Unreachable;
+        let msg = EndpointMessage::RelayReject(PeerId([1u8; 20]), PeerId([3u8; 20]), reason);
+        assert_eq!(msg.to_bytes(), expected);
+        assert!(matches!(
+            EndpointMessage::from_bytes(expected),
+            Ok(EndpointMessage::RelayReject(
+                _,
+                _,
+                RejectReason::Unreachable
+            ))
+        ));
+
+        // find node: type + target.
+        let expected = [&[8u8][..], &[3u8; 20]].concat();
+        assert_eq!(
+            EndpointMessage::FindNode(PeerId([3u8; 20])).to_bytes(),
+            expected
+        );
+        match EndpointMessage::from_bytes(expected).unwrap() {
+            EndpointMessage::FindNode(id) => assert_eq!(id, PeerId([3u8; 20])),
+            _ => panic!("endpoint find node decode failure"),
+        }
+
+        // reflexive: type + socket (ipv6 mapped + port le).
+        let addr = "1.2.3.4:4660".parse().unwrap();
+        let expected = [
+            &[11u8][..],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4],
+            &[0x34, 0x12],
+        ]
+        .concat();
+        assert_eq!(EndpointMessage::Reflexive(addr).to_bytes(), expected);
+        assert!(matches!(
+            EndpointMessage::from_bytes(expected),
+            Ok(EndpointMessage::Reflexive(a)) if a == addr
+        ));
+
+        assert_eq!(EndpointMessage::Close(None).to_bytes(), vec![0u8]);
+        let msg = EndpointMessage::Close(Some(CloseReason::Kick));
+        assert_eq!(msg.to_bytes(), vec![0u8, 1]);
+        assert!(matches!(
+            EndpointMessage::from_bytes(vec![0u8, 1]),
+            Ok(EndpointMessage::Close(Some(CloseReason::Kick)))
+        ));
+        assert_eq!(
+            EndpointMessage::Data(vec![5, 5]).to_bytes(),
+            vec![5u8, 5, 5]
+        );
+    }
 }
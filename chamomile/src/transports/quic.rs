@@ -1,20 +1,23 @@
 use serde::{Deserialize, Serialize};
-use socket2::Socket;
 use std::{
     collections::HashMap,
-    net::{SocketAddr, UdpSocket},
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 use structopt::StructOpt;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::{io::Result, join, select, sync::RwLock, task::JoinHandle};
+use tokio::{io::Result, join, sync::RwLock, task::JoinHandle, time::sleep};
 
+use chamomile_types::types::{ConnectError, ConnectResult};
+
+use crate::config::SocketOptions;
 use crate::session_key::SessionKey;
 
 use super::{
-    new_endpoint_channel, EndpointMessage, RemotePublic, TransportRecvMessage,
-    TransportSendMessage, CONNECTING_WAITING,
+    bind_udp, handshake_error, handshake_transient, new_endpoint_channel, ConnectTimer,
+    EndpointMessage, RemotePublic, TransportRecvMessage, TransportSendMessage, CONNECTING_WAITING,
+    HANDSHAKE_WAITING,
 };
 
 const DOMAIN: &str = "chamomile.quic";
@@ -28,13 +31,17 @@ pub async fn start(
     send: Sender<TransportRecvMessage>,
     recv: Receiver<TransportSendMessage>,
     both: bool,
+    options: &SocketOptions,
 ) -> tokio::io::Result<SocketAddr> {
-    let config = InternalConfig::try_from_config(Default::default()).unwrap();
+    let keep_alive_interval =
+        (options.keepalive > 0).then(|| Duration::from_millis(options.keepalive));
+    let config = InternalConfig::try_from_config(Config {
+        keep_alive_interval,
+        ..Default::default()
+    })
+    .unwrap();
 
-    let udp_socket = UdpSocket::bind(&bind_addr)?;
-    let socket = Socket::from(udp_socket);
-    socket.set_reuse_address(true)?;
-    let new_udp_socket: UdpSocket = socket.into();
+    let new_udp_socket = bind_udp(bind_addr, options)?;
 
     let endpoint = quinn::Endpoint::new(
         Default::default(),
@@ -50,64 +57,129 @@ pub async fn start(
     let out_send = send.clone();
     let incoming = endpoint.clone();
     let task = tokio::spawn(async move {
-        loop {
-            match incoming.accept().await {
-                Some(quinn_conn) => match quinn_conn.await {
-                    Ok(conn) => {
-                        if both {
-                            let (self_sender, self_receiver) = new_endpoint_channel();
-                            let (out_sender, out_receiver) = new_endpoint_channel();
-
-                            tokio::spawn(process_stream(
-                                conn,
-                                out_sender,
-                                self_receiver,
-                                OutType::DHT(out_send.clone(), self_sender, out_receiver),
-                                None,
-                                None,
-                            ));
-                        }
-                    }
-                    Err(err) => {
-                        error!("An incoming failed because of an error: {:?}", err);
+        while let Some(quinn_conn) = incoming.accept().await {
+            let mut timer = ConnectTimer::start();
+            match quinn_conn.await {
+                Ok(conn) => {
+                    timer.trace.transport = timer.elapsed();
+                    if both {
+                        let (self_sender, self_receiver) = new_endpoint_channel();
+                        let (out_sender, out_receiver) = new_endpoint_channel();
+
+                        tokio::spawn(process_stream(
+                            conn,
+                            out_sender,
+                            self_receiver,
+                            OutType::DHT(out_send.clone(), self_sender, out_receiver),
+                            None,
+                            None,
+                            timer,
+                        ));
                     }
-                },
-                None => {
-                    break;
+                }
+                Err(err) => {
+                    error!("An incoming failed because of an error: {:?}", err);
                 }
             }
         }
     });
 
     // QUIC listen from outside.
-    tokio::spawn(run_self_recv(endpoint, config.client, recv, send, task));
+    tokio::spawn(run_self_recv(
+        endpoint,
+        config.client,
+        recv,
+        send,
+        task,
+        *options,
+    ));
 
     Ok(addr)
 }
 
-async fn connect_to(
-    connect: std::result::Result<quinn::Connecting, quinn::ConnectError>,
-    remote_pk: RemotePublic,
-) -> Result<quinn::Connection> {
-    let conn = connect
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "connecting failure."))?
-        .await?;
-    let mut stream = conn.open_uni().await?;
-    stream
-        .write_all(&EndpointMessage::Handshake(remote_pk).to_bytes())
-        .await?;
-    stream.finish().await?;
-    Ok(conn)
+/// connect to the address and exchange the handshake,
+/// retry when the handshake failure is transient.
+async fn dial(
+    endpoint: &quinn::Endpoint,
+    client_cfg: &quinn::ClientConfig,
+    addr: SocketAddr,
+    bytes: &[u8],
+    options: &SocketOptions,
+    timer: &mut ConnectTimer,
+) -> std::result::Result<(quinn::Connection, RemotePublic), ConnectError> {
+    let mut attempt = 0;
+    loop {
+        let conn = endpoint
+            .connect_with(client_cfg.clone(), addr, DOMAIN)
+            .map_err(|_| ConnectError::Unreachable)?
+            .await
+            .map_err(|_| ConnectError::Unreachable)?;
+        timer.trace.transport = timer.elapsed();
+
+        // the read future is dropped when timeout, nothing left polling the connection.
+        let handshake = tokio::time::timeout(Duration::from_secs(HANDSHAKE_WAITING), async {
+            let mut stream = conn.open_uni().await.map_err(connection_error)?;
+            stream.write_all(bytes).await?;
+            stream.finish().await?;
+            read_handshake(&conn).await
+        })
+        .await;
+        match handshake {
+            Ok(Ok(remote_pk)) => {
+                timer.trace.handshake = timer.elapsed();
+                return Ok((conn, remote_pk));
+            }
+            res if attempt < options.handshake_retries && handshake_transient(&res) => {
+                debug!("Transport: handshake to {} failure, retry it.", addr);
+                conn.close(0u32.into(), b"handshake");
+                sleep(options.backoff.delay(attempt as u32)).await;
+                attempt += 1;
+            }
+            res => {
+                debug!("Transport: connect read publics failure, close it.");
+                conn.close(0u32.into(), b"handshake");
+                return Err(handshake_error(res));
+            }
+        }
+    }
+}
+
+/// the connection lost is transient, closed by remote is not.
+fn connection_error(err: quinn::ConnectionError) -> std::io::Error {
+    match err {
+        quinn::ConnectionError::ApplicationClosed(_) => std::io::Error::other("handshake stream"),
+        _ => std::io::ErrorKind::ConnectionReset.into(),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn dht_connect_to(
-    connect: std::result::Result<quinn::Connecting, quinn::ConnectError>,
+    endpoint: quinn::Endpoint,
+    client_cfg: quinn::ClientConfig,
+    addr: SocketAddr,
     out_send: Sender<TransportRecvMessage>,
     remote_pk: RemotePublic,
     session_key: SessionKey,
     connectiongs: Arc<RwLock<HashMap<SocketAddr, Instant>>>,
+    wait: Option<Sender<ConnectResult>>,
+    options: SocketOptions,
 ) -> Result<()> {
-    let conn = connect_to(connect, remote_pk).await?;
+    let mut timer = ConnectTimer::start();
+    let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+    let (conn, remote_pk) =
+        match dial(&endpoint, &client_cfg, addr, &bytes, &options, &mut timer).await {
+            Ok(res) => res,
+            Err(e) => {
+                if e != ConnectError::Unreachable {
+                    connectiongs.write().await.remove(&addr);
+                }
+                if let Some(wait) = wait {
+                    let _ = wait.try_send(Err(e));
+                }
+                return Ok(());
+            }
+        };
+    connectiongs.write().await.remove(&addr);
     let (self_sender, self_receiver) = new_endpoint_channel();
     let (out_sender, out_receiver) = new_endpoint_channel();
 
@@ -117,32 +189,44 @@ async fn dht_connect_to(
         self_receiver,
         OutType::DHT(out_send, self_sender, out_receiver),
         Some(session_key),
-        Some(connectiongs),
+        Some(remote_pk),
+        timer,
     )
     .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn stable_connect_to(
-    connect: std::result::Result<quinn::Connecting, quinn::ConnectError>,
+    endpoint: quinn::Endpoint,
+    client_cfg: quinn::ClientConfig,
+    addr: SocketAddr,
     out_sender: Sender<EndpointMessage>,
     self_receiver: Receiver<EndpointMessage>,
     remote_pk: RemotePublic,
     connectiongs: Arc<RwLock<HashMap<SocketAddr, Instant>>>,
+    options: SocketOptions,
 ) -> Result<()> {
-    match connect_to(connect, remote_pk).await {
-        Ok(conn) => {
+    let mut timer = ConnectTimer::start();
+    let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+    match dial(&endpoint, &client_cfg, addr, &bytes, &options, &mut timer).await {
+        Ok((conn, remote_pk)) => {
+            connectiongs.write().await.remove(&addr);
             process_stream(
                 conn,
                 out_sender,
                 self_receiver,
                 OutType::Stable,
                 None,
-                Some(connectiongs),
+                Some(remote_pk),
+                timer,
             )
             .await
         }
-        Err(_) => {
-            let _ = out_sender.send(EndpointMessage::Close).await;
+        Err(e) => {
+            if e != ConnectError::Unreachable {
+                connectiongs.write().await.remove(&addr);
+            }
+            let _ = out_sender.send(EndpointMessage::Close(None)).await;
             Ok(())
         }
     }
@@ -154,13 +238,14 @@ async fn run_self_recv(
     mut recv: Receiver<TransportSendMessage>,
     out_send: Sender<TransportRecvMessage>,
     task: JoinHandle<()>,
+    options: SocketOptions,
 ) -> Result<()> {
     let connecting: Arc<RwLock<HashMap<SocketAddr, Instant>>> =
         Arc::new(RwLock::new(HashMap::new()));
 
     while let Some(m) = recv.recv().await {
         match m {
-            TransportSendMessage::Connect(addr, remote_pk, session_key) => {
+            TransportSendMessage::Connect(addr, remote_pk, session_key, wait) => {
                 let read_lock = connecting.read().await;
                 if let Some(time) = read_lock.get(&addr) {
                     if time.elapsed().as_secs() < CONNECTING_WAITING {
@@ -173,14 +258,17 @@ async fn run_self_recv(
                 lock.insert(addr, Instant::now());
                 drop(lock);
 
-                let connect = endpoint.connect_with(client_cfg.clone(), addr, DOMAIN);
                 info!("QUIC dht connect to: {:?}", addr);
                 tokio::spawn(dht_connect_to(
-                    connect,
+                    endpoint.clone(),
+                    client_cfg.clone(),
+                    addr,
                     out_send.clone(),
                     remote_pk,
                     session_key,
                     connecting.clone(),
+                    wait,
+                    options,
                 ));
             }
             TransportSendMessage::StableConnect(out_sender, self_receiver, addr, remote_pk) => {
@@ -196,14 +284,16 @@ async fn run_self_recv(
                 lock.insert(addr, Instant::now());
                 drop(lock);
 
-                let connect = endpoint.connect_with(client_cfg.clone(), addr, DOMAIN);
                 info!("QUIC stable connect to: {:?}", addr);
                 tokio::spawn(stable_connect_to(
-                    connect,
+                    endpoint.clone(),
+                    client_cfg.clone(),
+                    addr,
                     out_sender,
                     self_receiver,
                     remote_pk,
                     connecting.clone(),
+                    options,
                 ));
             }
             TransportSendMessage::Stop => {
@@ -217,6 +307,7 @@ async fn run_self_recv(
     Ok(())
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum OutType {
     DHT(
         Sender<TransportRecvMessage>,
@@ -226,67 +317,72 @@ enum OutType {
     Stable,
 }
 
+/// read the remote handshake, the first uni-stream of the connection.
+async fn read_handshake(conn: &quinn::Connection) -> tokio::io::Result<RemotePublic> {
+    let mut recv = conn.accept_uni().await.map_err(|err| {
+        debug!(
+            "Failed to read incoming handshake for peer {:?} with error: {:?}",
+            conn.remote_address(),
+            err
+        );
+        connection_error(err)
+    })?;
+    let bytes = recv
+        .read_to_end(SIZE_LIMIT)
+        .await
+        .map_err(|err| match err {
+            quinn::ReadToEndError::Read(quinn::ReadError::ConnectionLost(e)) => connection_error(e),
+            _ => std::io::Error::other("handshake read"),
+        })?;
+    match EndpointMessage::from_bytes(bytes)? {
+        EndpointMessage::Handshake(remote_pk) => Ok(remote_pk),
+        EndpointMessage::Close(_) => Err(std::io::ErrorKind::ConnectionRefused.into()),
+        _ => Err(std::io::Error::other("not handshake")),
+    }
+}
+
 async fn process_stream(
     conn: quinn::Connection,
     out_sender: Sender<EndpointMessage>,
     mut self_receiver: Receiver<EndpointMessage>,
     out_type: OutType,
     has_session: Option<SessionKey>,
-    connectiongs: Option<Arc<RwLock<HashMap<SocketAddr, Instant>>>>,
+    handshake: Option<RemotePublic>,
+    mut timer: ConnectTimer,
 ) -> tokio::io::Result<()> {
     let addr = conn.remote_address();
 
-    let handshake: std::result::Result<RemotePublic, ()> = select! {
-        v = async {
-            match conn.accept_uni().await {
-                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                    debug!("Connection terminated by peer {:?}.", addr);
-                    Err(())
-                }
-                Err(err) => {
-                    debug!(
-                        "Failed to read incoming message on uni-stream for peer {:?} with error: {:?}",
-                        addr, err
-                    );
-                    Err(())
-                }
-                Ok(mut recv) => {
-                    if let Ok(bytes) = recv.read_to_end(SIZE_LIMIT).await {
-                        if let Ok(EndpointMessage::Handshake(remote_pk)) =
-                            EndpointMessage::from_bytes(bytes)
-                        {
-                            return Ok(remote_pk);
-                        } else {
-                            Err(())
-                        }
-                    } else {
-                        Err(())
-                    }
-                }
+    // outbound had exchanged the handshake when dial.
+    let remote_pk = if let Some(remote_pk) = handshake {
+        remote_pk
+    } else {
+        // the read future is dropped when timeout, nothing left polling the connection.
+        let handshake = tokio::time::timeout(
+            Duration::from_secs(HANDSHAKE_WAITING),
+            read_handshake(&conn),
+        )
+        .await;
+
+        match handshake {
+            Ok(Ok(remote_pk)) => {
+                timer.trace.handshake = timer.elapsed();
+                remote_pk
             }
-        } => v,
-        v = async {
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-            Err(())
-        } => v
+            _ => {
+                debug!("Transport: connect read publics failure, close it.");
+                conn.close(0u32.into(), b"handshake");
+                return Ok(());
+            }
+        }
     };
 
-    if handshake.is_err() {
-        // close it. if is_by_self, Better send outside not connect.
-        debug!("Transport: connect read publics timeout, close it.");
-        return Ok(());
-    }
-
-    let remote_pk = handshake.unwrap(); // safe. checked.
-
     match out_type {
         OutType::Stable => {
+            let _ = out_sender.send(EndpointMessage::Connected(timer)).await;
             out_sender
                 .send(EndpointMessage::Handshake(remote_pk))
                 .await
-                .map_err(|_e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "endpoint channel missing")
-                })?;
+                .map_err(|_e| std::io::Error::other("endpoint channel missing"))?;
         }
         OutType::DHT(sender, self_sender, out_receiver) => {
             sender
@@ -297,40 +393,24 @@ async fn process_stream(
                     out_sender.clone(),
                     out_receiver,
                     self_sender,
+                    timer,
                 ))
                 .await
-                .map_err(|_e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "server channel missing")
-                })?;
+                .map_err(|_e| std::io::Error::other("server channel missing"))?;
         }
     }
 
-    if let Some(connectiongs) = connectiongs {
-        let mut lock = connectiongs.write().await;
-        lock.remove(&addr);
-        drop(lock);
-        drop(connectiongs);
-    }
-
     let conn_send = conn.clone();
     let a = async move {
-        loop {
-            match self_receiver.recv().await {
-                Some(msg) => {
-                    let mut writer = conn_send.open_uni().await.map_err(|_e| ())?;
-                    let is_close = match msg {
-                        EndpointMessage::Close => true,
-                        _ => false,
-                    };
-
-                    let _ = writer.write_all(&msg.to_bytes()).await;
-                    let _ = writer.finish().await;
-
-                    if is_close {
-                        break;
-                    }
-                }
-                None => break,
+        while let Some(msg) = self_receiver.recv().await {
+            let mut writer = conn_send.open_uni().await.map_err(|_e| ())?;
+            let is_close = matches!(msg, EndpointMessage::Close(..));
+
+            let _ = writer.write_all(&msg.to_bytes()).await;
+            let _ = writer.finish().await;
+
+            if is_close {
+                break;
             }
         }
 
@@ -338,6 +418,7 @@ async fn process_stream(
     };
 
     let b = async {
+        let mut remote = addr;
         loop {
             match conn.accept_uni().await {
                 Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
@@ -353,6 +434,13 @@ async fn process_stream(
                 }
                 Ok(mut recv) => {
                     if let Ok(bytes) = recv.read_to_end(SIZE_LIMIT).await {
+                        // the frame is authenticated by QUIC, follow the new address.
+                        let now = conn.remote_address();
+                        if now != remote {
+                            debug!("Connection migrated from {:?} to {:?}.", remote, now);
+                            remote = now;
+                            let _ = out_sender.send(EndpointMessage::Migrated(now)).await;
+                        }
                         if let Ok(msg) = EndpointMessage::from_bytes(bytes) {
                             let _ = out_sender.send(msg).await;
                         }
@@ -404,6 +492,13 @@ pub struct Config {
     #[serde(default)]
     #[structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS")]
     pub idle_timeout: Option<Duration>,
+
+    /// Send QUIC ping frames in this interval when idle, keep the UDP mapping alive.
+    ///
+    /// If unspecified, no keep-alive will be sent.
+    #[serde(default)]
+    #[structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS")]
+    pub keep_alive_interval: Option<Duration>,
 }
 
 fn parse_millis(millis: &str) -> std::result::Result<Duration, std::num::ParseIntError> {
@@ -426,12 +521,11 @@ impl InternalConfig {
     pub(crate) fn try_from_config(config: Config) -> Result<Self> {
         let idle_timeout =
             quinn::IdleTimeout::try_from(config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT))
-                .map_err(|_e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "rcgen generate failure.")
-                })?;
+                .map_err(|_e| std::io::Error::other("rcgen generate failure."))?;
 
         let mut tconfig = quinn::TransportConfig::default();
         let _ = tconfig.max_idle_timeout(Some(idle_timeout));
+        let _ = tconfig.keep_alive_interval(config.keep_alive_interval);
         let transport = Arc::new(tconfig);
 
         let client = Self::new_client_config(transport.clone());
@@ -468,22 +562,19 @@ impl InternalConfig {
             .with_safe_defaults()
             .with_no_client_auth()
             .with_single_cert(vec![cert], key)
-            .map_err(|_e| {
-                std::io::Error::new(std::io::ErrorKind::Other, "server config failure.")
-            })?;
+            .map_err(|_e| std::io::Error::other("server config failure."))?;
         let mut config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
         config.transport = transport;
         Ok(config)
     }
 
     fn generate_cert() -> Result<(rustls::Certificate, rustls::PrivateKey)> {
-        let cert = rcgen::generate_simple_self_signed(vec![DOMAIN.to_string()]).map_err(|_e| {
-            std::io::Error::new(std::io::ErrorKind::Other, "rcgen generate failure.")
-        })?;
+        let cert = rcgen::generate_simple_self_signed(vec![DOMAIN.to_string()])
+            .map_err(|_e| std::io::Error::other("rcgen generate failure."))?;
 
-        let cert_der = cert.serialize_der().map_err(|_e| {
-            std::io::Error::new(std::io::ErrorKind::Other, "cert serialize failure.")
-        })?;
+        let cert_der = cert
+            .serialize_der()
+            .map_err(|_e| std::io::Error::other("cert serialize failure."))?;
         let key_der = cert.serialize_private_key_der();
 
         Ok((rustls::Certificate(cert_der), rustls::PrivateKey(key_der)))
@@ -505,3 +596,59 @@ impl rustls::client::ServerCertVerifier for SkipCertificateVerification {
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transports::{new_transport_recv_channel, new_transport_send_channel};
+    use chamomile_types::Peer;
+
+    async fn send_frame(conn: &quinn::Connection, msg: EndpointMessage) {
+        let mut writer = conn.open_uni().await.unwrap();
+        writer.write_all(&msg.to_bytes()).await.unwrap();
+        writer.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_migration() {
+        let (recv_send, mut recv_recv) = new_transport_recv_channel();
+        let (_send_send, send_recv) = new_transport_send_channel();
+        let addr = start(
+            "127.0.0.1:0".parse().unwrap(),
+            recv_send,
+            send_recv,
+            true,
+            &SocketOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let config = InternalConfig::try_from_config(Default::default()).unwrap();
+        let client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = client
+            .connect_with(config.client, addr, DOMAIN)
+            .unwrap()
+            .await
+            .unwrap();
+        let remote_pk = RemotePublic::new(Peer::socket(client.local_addr().unwrap()), vec![0u8; 2]);
+        send_frame(&conn, EndpointMessage::Handshake(remote_pk)).await;
+
+        let TransportRecvMessage(old_addr, _, _, _sender, mut receiver, _self_sender, _) =
+            recv_recv.recv().await.unwrap();
+        send_frame(&conn, EndpointMessage::Data(vec![1])).await;
+        assert!(matches!(receiver.recv().await, Some(EndpointMessage::Data(d)) if d == vec![1]));
+
+        // the client changed network, the same connection continue.
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.rebind(socket).unwrap();
+        let new_addr = client.local_addr().unwrap();
+        assert_ne!(old_addr, new_addr);
+
+        send_frame(&conn, EndpointMessage::Data(vec![2])).await;
+        assert!(matches!(
+            receiver.recv().await,
+            Some(EndpointMessage::Migrated(a)) if a == new_addr
+        ));
+        assert!(matches!(receiver.recv().await, Some(EndpointMessage::Data(d)) if d == vec![2]));
+    }
+}
@@ -1,6 +1,11 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Result},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ErrorKind, Result},
     join,
     net::{TcpListener, TcpStream},
     select,
@@ -9,13 +14,18 @@ use tokio::{
         RwLock,
     },
     task::JoinHandle,
+    time::sleep,
 };
 
+use chamomile_types::types::ConnectError;
+
+use crate::config::SocketOptions;
 use crate::session_key::SessionKey;
 
 use super::{
-    new_endpoint_channel, EndpointMessage, RemotePublic, TransportRecvMessage,
-    TransportSendMessage, CONNECTING_WAITING,
+    bind_tcp, handshake_error, handshake_transient, keepalive_tick, new_endpoint_channel,
+    new_keepalive, ConnectTimer, EndpointMessage, RemotePublic, TransportRecvMessage,
+    TransportSendMessage, CONNECTING_WAITING, HANDSHAKE_WAITING,
 };
 
 /// Init and run a TcpEndpoint object.
@@ -26,41 +36,51 @@ pub async fn start(
     send: Sender<TransportRecvMessage>,
     recv: Receiver<TransportSendMessage>,
     both: bool,
+    options: &SocketOptions,
 ) -> Result<SocketAddr> {
     let (addr, task) = if both {
-        let listener = TcpListener::bind(bind_addr).await.map_err(|e| {
+        let listener = bind_tcp(bind_addr, options).map_err(|e| {
             error!("TCP listen {:?}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, "TCP Listen")
+            std::io::Error::other("TCP Listen")
         })?;
         let addr = listener.local_addr()?;
         info!("TCP listening at: {:?}", addr);
 
         // TCP listen incoming.
-        let task = tokio::spawn(run_listen(listener, send.clone()));
+        let task = tokio::spawn(run_listen(listener, send.clone(), options.keepalive));
         (addr, Some(task))
     } else {
         (bind_addr, None)
     };
 
     // TCP listen from outside.
-    tokio::spawn(run_self_recv(recv, send, task));
+    tokio::spawn(run_self_recv(recv, send, task, *options));
 
     Ok(addr)
 }
 
-async fn run_listen(listener: TcpListener, out_send: Sender<TransportRecvMessage>) -> Result<()> {
+async fn run_listen(
+    listener: TcpListener,
+    out_send: Sender<TransportRecvMessage>,
+    keepalive: u64,
+) -> Result<()> {
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, addr) = listener.accept().await?;
+        // accepted, the transport is measured to the first bytes of remote.
+        let timer = ConnectTimer::start();
         let (self_sender, self_receiver) = new_endpoint_channel();
         let (out_sender, out_receiver) = new_endpoint_channel();
 
         tokio::spawn(process_stream(
             stream,
+            addr,
             out_sender,
             self_receiver,
             OutType::DHT(out_send.clone(), self_sender, out_receiver),
             None,
             None,
+            timer,
+            keepalive,
         ));
     }
 }
@@ -69,13 +89,14 @@ async fn run_self_recv(
     mut recv: Receiver<TransportSendMessage>,
     out_send: Sender<TransportRecvMessage>,
     task: Option<JoinHandle<Result<()>>>,
+    options: SocketOptions,
 ) -> Result<()> {
     let connecting: Arc<RwLock<HashMap<SocketAddr, Instant>>> =
         Arc::new(RwLock::new(HashMap::new()));
 
     while let Some(m) = recv.recv().await {
         match m {
-            TransportSendMessage::Connect(addr, remote_pk, session_key) => {
+            TransportSendMessage::Connect(addr, remote_pk, session_key, wait) => {
                 let read_lock = connecting.read().await;
                 if let Some(time) = read_lock.get(&addr) {
                     if time.elapsed().as_secs() < CONNECTING_WAITING {
@@ -91,26 +112,36 @@ async fn run_self_recv(
 
                 let server_send = out_send.clone();
                 tokio::spawn(async move {
-                    if let Ok(mut stream) = TcpStream::connect(addr).await {
-                        info!("TCP connect to {:?}", addr);
-                        let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
-                        let _ = stream.write(&(bytes.len() as u32).to_be_bytes()).await;
-                        let _ = stream.write_all(&bytes[..]).await;
-
-                        let (self_sender, self_receiver) = new_endpoint_channel();
-                        let (out_sender, out_receiver) = new_endpoint_channel();
-
-                        let _ = process_stream(
-                            stream,
-                            out_sender,
-                            self_receiver,
-                            OutType::DHT(server_send, self_sender, out_receiver),
-                            Some(session_key),
-                            Some(new_connecting),
-                        )
-                        .await;
-                    } else {
-                        info!("TCP cannot connect to {:?}", addr);
+                    let mut timer = ConnectTimer::start();
+                    let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+                    match dial(addr, &bytes, &options, &mut timer).await {
+                        Ok((stream, remote_pk)) => {
+                            new_connecting.write().await.remove(&addr);
+                            let (self_sender, self_receiver) = new_endpoint_channel();
+                            let (out_sender, out_receiver) = new_endpoint_channel();
+
+                            let _ = process_stream(
+                                stream,
+                                addr,
+                                out_sender,
+                                self_receiver,
+                                OutType::DHT(server_send, self_sender, out_receiver),
+                                Some(session_key),
+                                Some(remote_pk),
+                                timer,
+                                options.keepalive,
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            info!("TCP cannot connect to {:?}", addr);
+                            if e != ConnectError::Unreachable {
+                                new_connecting.write().await.remove(&addr);
+                            }
+                            if let Some(wait) = wait {
+                                let _ = wait.try_send(Err(e));
+                            }
+                        }
                     }
                 });
             }
@@ -129,24 +160,32 @@ async fn run_self_recv(
                 let new_connecting = connecting.clone();
 
                 tokio::spawn(async move {
-                    if let Ok(mut stream) = TcpStream::connect(addr).await {
-                        info!("TCP stable connect to {:?}", addr);
-                        let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
-                        let _ = stream.write(&(bytes.len() as u32).to_be_bytes()).await;
-                        let _ = stream.write_all(&bytes[..]).await;
-
-                        let _ = process_stream(
-                            stream,
-                            out_sender,
-                            self_receiver,
-                            OutType::Stable,
-                            None,
-                            Some(new_connecting),
-                        )
-                        .await;
-                    } else {
-                        info!("TCP cannot stable connect to {:?}", addr);
-                        let _ = out_sender.send(EndpointMessage::Close).await;
+                    let mut timer = ConnectTimer::start();
+                    let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+                    match dial(addr, &bytes, &options, &mut timer).await {
+                        Ok((stream, remote_pk)) => {
+                            info!("TCP stable connect to {:?}", addr);
+                            new_connecting.write().await.remove(&addr);
+                            let _ = process_stream(
+                                stream,
+                                addr,
+                                out_sender,
+                                self_receiver,
+                                OutType::Stable,
+                                None,
+                                Some(remote_pk),
+                                timer,
+                                options.keepalive,
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            info!("TCP cannot stable connect to {:?}", addr);
+                            if e != ConnectError::Unreachable {
+                                new_connecting.write().await.remove(&addr);
+                            }
+                            let _ = out_sender.send(EndpointMessage::Close(None)).await;
+                        }
                     }
                 });
             }
@@ -162,6 +201,105 @@ async fn run_self_recv(
     Ok(())
 }
 
+/// connect to the address and exchange the handshake,
+/// retry when the handshake failure is transient.
+async fn dial(
+    addr: SocketAddr,
+    bytes: &[u8],
+    options: &SocketOptions,
+    timer: &mut ConnectTimer,
+) -> std::result::Result<(TcpStream, RemotePublic), ConnectError> {
+    let mut attempt = 0;
+    loop {
+        let connect = match options.proxy {
+            Some(proxy) => socks5_connect(proxy, addr).await,
+            None => TcpStream::connect(addr).await,
+        };
+        let mut stream = connect.map_err(|_| ConnectError::Unreachable)?;
+        info!("TCP connect to {:?}", addr);
+        timer.trace.transport = timer.elapsed();
+        let _ = stream.write(&(bytes.len() as u32).to_be_bytes()).await;
+        let _ = stream.write_all(bytes).await;
+
+        // the read future is dropped when timeout, nothing left polling the stream.
+        let handshake = tokio::time::timeout(
+            Duration::from_secs(HANDSHAKE_WAITING),
+            read_handshake(&mut stream),
+        )
+        .await;
+        match handshake {
+            Ok(Ok(remote_pk)) => {
+                timer.trace.handshake = timer.elapsed();
+                return Ok((stream, remote_pk));
+            }
+            res if attempt < options.handshake_retries && handshake_transient(&res) => {
+                debug!("Transport: handshake to {} failure, retry it.", addr);
+                let _ = stream.shutdown().await;
+                sleep(options.backoff.delay(attempt as u32)).await;
+                attempt += 1;
+            }
+            res => {
+                debug!("Transport: connect read publics failure, close it.");
+                let _ = stream.shutdown().await;
+                return Err(handshake_error(res));
+            }
+        }
+    }
+}
+
+/// connect to the address through the SOCKS5 proxy, only no auth method.
+async fn socks5_connect(proxy: SocketAddr, addr: SocketAddr) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    // greeting: version, 1 method, no auth.
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, 0] {
+        return Err(std::io::Error::other("socks5 auth not supported"));
+    }
+
+    // connect: version, command, reserved, address type, address, port.
+    let mut request = vec![5u8, 1, 0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+    }
+    request.extend(addr.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 5 || head[1] != 0 {
+        return Err(ErrorKind::ConnectionRefused.into());
+    }
+    // skip the bound address and port.
+    let len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(std::io::Error::other("socks5 invalid address type")),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+/// max frame length, same as UDT and QUIC, check it before allocate.
+const MAX_FRAME: usize = 67108864; // 64 * 1024 * 1024 = 64 MB
+
+#[allow(clippy::upper_case_acronyms)]
 enum OutType {
     DHT(
         Sender<TransportRecvMessage>,
@@ -171,82 +309,107 @@ enum OutType {
     Stable,
 }
 
+/// read the remote handshake, the first frame of the stream.
+async fn read_handshake<R: AsyncRead + Unpin>(reader: &mut R) -> Result<RemotePublic> {
+    let mut read_len = [0u8; 4];
+    reader.read_exact(&mut read_len).await?;
+    let len = u32::from_be_bytes(read_len) as usize;
+    if len > MAX_FRAME {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "handshake too large",
+        ));
+    }
+    let mut read_bytes = vec![0u8; len];
+    reader.read_exact(&mut read_bytes).await?;
+    match EndpointMessage::from_bytes(read_bytes)? {
+        EndpointMessage::Handshake(remote_pk) => Ok(remote_pk),
+        EndpointMessage::Close(_) => Err(ErrorKind::ConnectionRefused.into()),
+        _ => Err(std::io::Error::other("not handshake")),
+    }
+}
+
+/// read the next frame, skip the empty (keepalive) ones. `None` when remote closed at
+/// the frame boundary, `UnexpectedEof` error when closed in the middle of the frame,
+/// the truncated frame is never returned.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    loop {
+        let mut read_len = [0u8; 4];
+        let mut filled = 0;
+        while filled < read_len.len() {
+            let size = reader.read(&mut read_len[filled..]).await?;
+            if size == 0 {
+                if filled == 0 {
+                    return Ok(None);
+                }
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            filled += size;
+        }
+
+        let len = u32::from_be_bytes(read_len) as usize;
+        if len == 0 {
+            // transport keepalive.
+            continue;
+        }
+        if len > MAX_FRAME {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "frame too large",
+            ));
+        }
+        let mut read_bytes = vec![0u8; len];
+        reader.read_exact(&mut read_bytes).await?;
+        return Ok(Some(read_bytes));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_stream(
     mut stream: TcpStream,
+    addr: SocketAddr,
     out_sender: Sender<EndpointMessage>,
     mut self_receiver: Receiver<EndpointMessage>,
     out_type: OutType,
     has_session: Option<SessionKey>,
-    connectiongs: Option<Arc<RwLock<HashMap<SocketAddr, Instant>>>>,
+    handshake: Option<RemotePublic>,
+    mut timer: ConnectTimer,
+    keepalive: u64,
 ) -> Result<()> {
-    let addr = stream.peer_addr()?;
     let (mut reader, mut writer) = stream.split();
 
-    let mut read_len = [0u8; 4];
-    let handshake: std::result::Result<RemotePublic, ()> = select! {
-        v = async {
-            match reader.read(&mut read_len).await {
-                Ok(size) => {
-                    if size != 4 {
-                        return Err(());
-                    }
-
-                    let len: usize = u32::from_be_bytes(read_len) as usize;
-                    let mut read_bytes = vec![0u8; len];
-                    let mut received: usize = 0;
-
-                    while let Ok(bytes_size) = reader.read(&mut read_bytes[received..]).await {
-                        received += bytes_size;
-                        if received < len {
-                            continue;
-                        }
-
-                        if let Ok(EndpointMessage::Handshake(remote_pk)) =
-                            EndpointMessage::from_bytes(read_bytes)
-                        {
-                            return Ok(remote_pk);
-                        } else {
-                            return Err(());
-                        }
-                    }
-
-                    Err(())
-                }
-                Err(e) => {
-                    error!("TCP READ ERROR: {:?}", e);
-                    Err(())
-                }
+    // outbound had exchanged the handshake when dial.
+    let remote_pk = if let Some(remote_pk) = handshake {
+        remote_pk
+    } else {
+        // the read future is dropped when timeout, nothing left polling the stream.
+        let handshake = tokio::time::timeout(Duration::from_secs(HANDSHAKE_WAITING), async {
+            reader.peek(&mut [0u8; 1]).await?;
+            timer.trace.transport = timer.elapsed();
+            read_handshake(&mut reader).await
+        })
+        .await;
+
+        match handshake {
+            Ok(Ok(remote_pk)) => {
+                timer.trace.handshake = timer.elapsed();
+                remote_pk
             }
-        } => v,
-        v = async {
-            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-            Err(())
-        } => v
+            _ => {
+                debug!("Transport: connect read publics failure, close it.");
+                let _ = writer.shutdown().await;
+                return Ok(());
+            }
+        }
     };
 
-    if handshake.is_err() {
-        // close it. if is_by_self, Better send outside not connect.
-        debug!("Transport: connect read publics timeout, close it.");
-        return Ok(());
-    }
-
-    let remote_pk = handshake.unwrap(); // safe. checked.
-
-    if let Some(connectiongs) = connectiongs {
-        let mut lock = connectiongs.write().await;
-        lock.remove(&addr);
-        drop(lock);
-        drop(connectiongs);
-    }
-
     match out_type {
         OutType::Stable => {
+            let _ = out_sender.send(EndpointMessage::Connected(timer)).await;
             out_sender
                 .send(EndpointMessage::Handshake(remote_pk))
                 .await
-                .map_err(|_e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "endpoint channel missing")
-                })?;
+                .map_err(|_e| std::io::Error::other("endpoint channel missing"))?;
         }
         OutType::DHT(sender, self_sender, out_receiver) => {
             sender
@@ -257,37 +420,45 @@ async fn process_stream(
                     out_sender.clone(),
                     out_receiver,
                     self_sender,
+                    timer,
                 ))
                 .await
-                .map_err(|_e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, "server channel missing")
-                })?;
+                .map_err(|_e| std::io::Error::other("server channel missing"))?;
         }
     }
 
     let a = async move {
+        let mut ticker = new_keepalive(keepalive);
         loop {
-            match self_receiver.recv().await {
-                Some(msg) => {
-                    let is_close = match msg {
-                        EndpointMessage::Close => true,
-                        _ => false,
-                    };
-
-                    let bytes = msg.to_bytes();
-                    if writer
-                        .write(&(bytes.len() as u32).to_be_bytes())
-                        .await
-                        .is_ok()
-                    {
-                        let _ = writer.write_all(&bytes[..]).await;
-                    }
-
-                    if is_close {
+            let msg = select! {
+                v = self_receiver.recv() => match v {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = keepalive_tick(&mut ticker) => {
+                    // empty frame, skipped by remote transport.
+                    if writer.write_all(&0u32.to_be_bytes()).await.is_err() {
                         break;
                     }
+                    continue;
                 }
-                None => break,
+            };
+            let is_close = matches!(msg, EndpointMessage::Close(..));
+
+            let bytes = msg.to_bytes();
+            if writer
+                .write(&(bytes.len() as u32).to_be_bytes())
+                .await
+                .is_ok()
+            {
+                let _ = writer.write_all(&bytes[..]).await;
+            }
+            if let Some(ticker) = ticker.as_mut() {
+                ticker.reset();
+            }
+
+            if is_close {
+                break;
             }
         }
 
@@ -295,41 +466,21 @@ async fn process_stream(
     };
 
     let b = async move {
-        let mut read_len = [0u8; 4];
-        let mut received: usize = 0;
-
         loop {
-            match reader.read(&mut read_len).await {
-                Ok(size) => {
-                    if size == 0 {
-                        // when close or better when many Ok(0)
-                        let _ = out_sender.send(EndpointMessage::Close).await;
-                        break;
+            match read_frame(&mut reader).await {
+                Ok(Some(bytes)) => {
+                    if let Ok(msg) = EndpointMessage::from_bytes(bytes) {
+                        let _ = out_sender.send(msg).await;
                     }
-
-                    let len: usize = u32::from_be_bytes(read_len) as usize;
-                    let mut read_bytes = vec![0u8; len];
-                    while let Ok(bytes_size) = reader.read(&mut read_bytes[received..]).await {
-                        received += bytes_size;
-                        if received > len {
-                            break;
-                        }
-
-                        if received != len {
-                            continue;
-                        }
-
-                        if let Ok(msg) = EndpointMessage::from_bytes(read_bytes) {
-                            let _ = out_sender.send(msg).await;
-                        }
-
-                        break;
-                    }
-                    read_len = [0u8; 4];
-                    received = 0;
                 }
-                Err(_e) => {
-                    let _ = out_sender.send(EndpointMessage::Close).await;
+                Ok(None) => {
+                    debug!("Transport: {} closed.", addr);
+                    let _ = out_sender.send(EndpointMessage::Close(None)).await;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Transport: {} read failure: {}", addr, e);
+                    let _ = out_sender.send(EndpointMessage::Close(None)).await;
                     break;
                 }
             }
@@ -344,3 +495,317 @@ async fn process_stream(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transports::{new_transport_recv_channel, new_transport_send_channel};
+    use tokio::runtime::Handle;
+
+    async fn wait_tasks(baseline: usize) -> bool {
+        for _ in 0..100 {
+            if Handle::current().metrics().num_alive_tasks() <= baseline {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_eof() {
+        // keepalive skipped, then clean close at the frame boundary.
+        let mut reader: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 2, 7, 8];
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(vec![7, 8]));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+
+        // closed in the length prefix.
+        let mut reader: &[u8] = &[0, 0, 0, 2, 7, 8, 0, 0];
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(vec![7, 8]));
+        let err = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        // closed in the body, the truncated body is not returned.
+        let mut reader: &[u8] = &[0, 0, 0, 5, 1, 2];
+        let err = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_too_large() {
+        // one over the max is rejected before allocate.
+        let len = (MAX_FRAME as u32 + 1).to_be_bytes();
+        let mut reader: &[u8] = &[len[0], len[1], len[2], len[3], 1, 2];
+        let err = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut reader: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        let err = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_too_large() {
+        // rejected before allocate and read the body.
+        let mut reader: &[u8] = &[0xff, 0xff, 0xff, 0xff, 1, 2];
+        match read_handshake(&mut reader).await {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            Ok(_) => panic!("oversize handshake accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failure_no_leak() {
+        let (recv_send, mut recv_recv) = new_transport_recv_channel();
+        let (_send_send, send_recv) = new_transport_send_channel();
+        let addr = start(
+            "127.0.0.1:0".parse().unwrap(),
+            recv_send,
+            send_recv,
+            true,
+            &SocketOptions::default(),
+        )
+        .await
+        .unwrap();
+        let baseline = Handle::current().metrics().num_alive_tasks();
+
+        // 1. closed before the length.
+        let stream = TcpStream::connect(addr).await.unwrap();
+        drop(stream);
+        assert!(wait_tasks(baseline).await);
+
+        // 2. closed in the middle of the frame.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&100u32.to_be_bytes()).await.unwrap();
+        stream.write_all(&[1u8; 10]).await.unwrap();
+        drop(stream);
+        assert!(wait_tasks(baseline).await);
+
+        // 3. the first frame is not handshake, closed by us.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let bytes = EndpointMessage::Close(None).to_bytes();
+        stream
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        assert!(wait_tasks(baseline).await);
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        assert!(recv_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_retry() {
+        let (recv_send, mut recv_recv) = new_transport_recv_channel();
+        let (send_send, send_recv) = new_transport_send_channel();
+        start(
+            "127.0.0.1:0".parse().unwrap(),
+            recv_send,
+            send_recv,
+            false,
+            &SocketOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // remote drop the first connection, answer the second.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_handshake(&mut stream).await.unwrap();
+            let remote_pk = RemotePublic::new(chamomile_types::Peer::socket(addr), vec![0u8; 2]);
+            let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+            stream
+                .write_all(&(bytes.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&bytes).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+
+        let key = chamomile_types::key::Key::default();
+        let (session_key, bytes) = SessionKey::generate(&key);
+        let remote_pk = RemotePublic::new(chamomile_types::Peer::socket(addr), bytes);
+        send_send
+            .send(TransportSendMessage::Connect(
+                addr,
+                remote_pk,
+                session_key,
+                None,
+            ))
+            .await
+            .unwrap();
+
+        let msg = tokio::time::timeout(Duration::from_secs(5), recv_recv.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.0, addr);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_ticker() {
+        assert!(new_keepalive(0).is_none());
+
+        // first tick is after a full period, not at once.
+        let mut ticker = new_keepalive(50);
+        let now = Duration::ZERO;
+        tokio::time::advance(Duration::from_millis(49)).await;
+        let tick = tokio::time::timeout(now, keepalive_tick(&mut ticker)).await;
+        assert!(tick.is_err());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let tick = tokio::time::timeout(now, keepalive_tick(&mut ticker)).await;
+        assert!(tick.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive() {
+        let (recv_send, mut recv_recv) = new_transport_recv_channel();
+        let (_send_send, send_recv) = new_transport_send_channel();
+        let options = SocketOptions {
+            keepalive: 50,
+            ..SocketOptions::default()
+        };
+        let addr = start(
+            "127.0.0.1:0".parse().unwrap(),
+            recv_send,
+            send_recv,
+            true,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let remote_pk = RemotePublic::new(chamomile_types::Peer::socket(addr), vec![0u8; 2]);
+        let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+        stream
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        let TransportRecvMessage(_, _, _, _, mut out_receiver, self_sender, _) =
+            recv_recv.recv().await.unwrap();
+
+        // idle stream, only empty frames in the sub-interval.
+        // the time is paused, it only advances to the next keepalive when idle.
+        let mut last = None;
+        for _ in 0..4 {
+            let mut len = [0u8; 4];
+            tokio::time::timeout(Duration::from_secs(1), stream.read_exact(&mut len))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(u32::from_be_bytes(len), 0);
+            let now = tokio::time::Instant::now();
+            if let Some(last) = last {
+                let gap = now - last;
+                assert!(gap >= Duration::from_millis(50) && gap < Duration::from_millis(100));
+            }
+            last = Some(now);
+        }
+
+        // remote keepalive is never delivered to session.
+        stream.write_all(&0u32.to_be_bytes()).await.unwrap();
+        let bytes = EndpointMessage::Close(None).to_bytes();
+        stream
+            .write_all(&(bytes.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(1), out_receiver.recv())
+            .await
+            .unwrap();
+        assert!(matches!(msg, Some(EndpointMessage::Close(None))));
+        drop(self_sender);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_proxy() {
+        // remote answer the handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_handshake(&mut stream).await.unwrap();
+            let remote_pk = RemotePublic::new(chamomile_types::Peer::socket(addr), vec![0u8; 2]);
+            let bytes = EndpointMessage::Handshake(remote_pk).to_bytes();
+            stream
+                .write_all(&(bytes.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&bytes).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+
+        // mock SOCKS5 proxy, record the target and forward it.
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let (target_send, mut target_recv) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 1]);
+            let ip: [u8; 4] = request[4..8].try_into().unwrap();
+            let port = u16::from_be_bytes([request[8], request[9]]);
+            let target = SocketAddr::from((ip, port));
+            target_send.send(target).await.unwrap();
+
+            let mut remote = TcpStream::connect(target).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut remote).await;
+        });
+
+        let (recv_send, mut recv_recv) = new_transport_recv_channel();
+        let (send_send, send_recv) = new_transport_send_channel();
+        let options = SocketOptions {
+            proxy: Some(proxy_addr),
+            ..SocketOptions::default()
+        };
+        start(
+            "127.0.0.1:0".parse().unwrap(),
+            recv_send,
+            send_recv,
+            false,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let key = chamomile_types::key::Key::default();
+        let (session_key, bytes) = SessionKey::generate(&key);
+        let remote_pk = RemotePublic::new(chamomile_types::Peer::socket(addr), bytes);
+        send_send
+            .send(TransportSendMessage::Connect(
+                addr,
+                remote_pk,
+                session_key,
+                None,
+            ))
+            .await
+            .unwrap();
+
+        let msg = tokio::time::timeout(Duration::from_secs(5), recv_recv.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(target_recv.recv().await, Some(addr));
+        // the remote is the dialed address, not the proxy.
+        assert_eq!(msg.0, addr);
+    }
+}
@@ -1 +1,1098 @@
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaChaRng,
+};
+use sha3::{Digest, Keccak256};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{ErrorKind, Result},
+    net::UdpSocket,
+    select,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        RwLock,
+    },
+    task::JoinHandle,
+    time::{interval, timeout, Interval, MissedTickBehavior},
+};
 
+use chamomile_types::{
+    peer::socket_addr_to_bytes,
+    types::{ConnectError, ConnectResult},
+};
+
+use crate::config::SocketOptions;
+use crate::session_key::SessionKey;
+
+use super::{
+    bind_udp, handshake_error, keepalive_tick, new_endpoint_channel, new_keepalive, ConnectTimer,
+    EndpointMessage, RemotePublic, TransportRecvMessage, TransportSendMessage, CONNECTING_WAITING,
+    HANDSHAKE_WAITING,
+};
+
+/// max fragment bytes in a packet, the frame is splited to fragments,
+/// keep the packet small than common MTU.
+const FRAGMENT_SIZE: usize = 1200;
+
+/// packet head: type(1) + connection id(4) + sequence(4).
+const HEAD_SIZE: usize = 9;
+
+/// max packets sent but not acked.
+const SEND_WINDOW: usize = 256;
+
+/// max packets buffered ahead of the next expected one, more are dropped (remote will resend).
+const RECV_WINDOW: u32 = 1024;
+
+/// the first retransmit timeout (milliseconds), doubled for every retry.
+const RTO: u64 = 200;
+
+/// the max retransmit timeout (milliseconds).
+const RTO_MAX: u64 = 5000;
+
+/// the connection is lost when a packet is retransmitted so many times.
+const MAX_RETRIES: u32 = 10;
+
+/// check the retransmit timeout every (milliseconds).
+const TICK: u64 = 50;
+
+/// when close, waiting the sent frames acked time.
+const CLOSE_WAITING: u64 = 2; // 2s
+
+/// packet type: fragment of the frame, head + is_final(1) + fragment.
+const DATA: u8 = 0;
+
+/// packet type: selective ack, head (sequence is the next expected) + bitmap(4),
+/// the bit i is the `sequence + 1 + i` had received.
+const ACK: u8 = 1;
+
+/// packet type: the first fragment of the dialer, head + cookie + is_final(1) + fragment,
+/// the listener only opens the connection with a valid cookie.
+const OPEN: u8 = 2;
+
+/// packet type: ask the cookie, head + padding (same size as the cookie reply),
+/// so the reply is not larger than it.
+const HELLO: u8 = 3;
+
+/// packet type: the cookie reply of hello, head + cookie.
+const COOKIE: u8 = 4;
+
+/// the cookie bytes.
+const COOKIE_SIZE: usize = 16;
+
+/// the cookie is valid in the current and last period.
+const COOKIE_PERIOD: u64 = 30; // 30s
+
+/// max connections of the endpoint, more opens are dropped.
+const MAX_CONNS: usize = 1024;
+
+/// max packets waiting for the send window, more writes wait the acks,
+/// like the full TCP send buffer.
+const MAX_PENDING: usize = 1024;
+
+/// max completed frames not read, more data packets are dropped (remote will resend).
+const MAX_FRAMES: usize = 64;
+
+/// max bytes of a frame, same as QUIC.
+const MAX_FRAME: usize = 67108864; // 64 * 1024 * 1024 = 64 MB
+
+/// build the packet head.
+fn head(ty: u8, conn: u32, seq: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEAD_SIZE + COOKIE_SIZE + 1 + FRAGMENT_SIZE);
+    bytes.push(ty);
+    bytes.extend(conn.to_be_bytes());
+    bytes.extend(seq.to_be_bytes());
+    bytes
+}
+
+/// parse the packet head, type, connection id and sequence.
+fn parse_head(packet: &[u8]) -> Option<(u8, u32, u32)> {
+    if packet.len() < HEAD_SIZE {
+        return None;
+    }
+    let mut conn = [0u8; 4];
+    conn.copy_from_slice(&packet[1..5]);
+    let mut seq = [0u8; 4];
+    seq.copy_from_slice(&packet[5..9]);
+    Some((packet[0], u32::from_be_bytes(conn), u32::from_be_bytes(seq)))
+}
+
+/// The stateless cookies of new connections, a keyed hash of the remote address and
+/// connection id, so the spoofed address cannot open connections.
+struct Cookies([u8; 32]);
+
+impl Cookies {
+    fn new() -> Self {
+        let mut secret = [0u8; 32];
+        ChaChaRng::from_entropy().fill_bytes(&mut secret);
+        Self(secret)
+    }
+
+    fn period() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() / COOKIE_PERIOD)
+            .unwrap_or(0)
+    }
+
+    fn make(&self, addr: &SocketAddr, conn: u32, period: u64) -> [u8; COOKIE_SIZE] {
+        let mut hasher = Keccak256::new();
+        hasher.update(self.0);
+        hasher.update(socket_addr_to_bytes(addr));
+        hasher.update(conn.to_be_bytes());
+        hasher.update(period.to_be_bytes());
+        let mut cookie = [0u8; COOKIE_SIZE];
+        cookie.copy_from_slice(&hasher.finalize()[..COOKIE_SIZE]);
+        cookie
+    }
+
+    fn issue(&self, addr: &SocketAddr, conn: u32) -> [u8; COOKIE_SIZE] {
+        self.make(addr, conn, Self::period())
+    }
+
+    fn check(&self, addr: &SocketAddr, conn: u32, cookie: &[u8]) -> bool {
+        let period = Self::period();
+        [period, period.saturating_sub(1)]
+            .iter()
+            .any(|p| self.make(addr, conn, *p)[..] == *cookie)
+    }
+}
+
+/// Selective-ack ARQ of a connection: sequence numbers, acks and retransmit on timeout.
+/// the frames are delivered reliable and in order, the duplicated (resent or replayed)
+/// packets are dropped by the sequence.
+/// it is sans-io, the caller sends the returned packets.
+pub(crate) struct Arq {
+    conn: u32,
+    /// the cookie of listener, the dialer opens the connection with it.
+    cookie: Option<[u8; COOKIE_SIZE]>,
+    next_seq: u32,
+    /// sequence => (packet, last sent time, retries).
+    unacked: BTreeMap<u32, (Vec<u8>, Instant, u32)>,
+    /// the packets waiting for the send window.
+    pending: VecDeque<Vec<u8>>,
+    /// the next sequence to deliver.
+    expected: u32,
+    /// received ahead of the expected: sequence => (is_final, fragment).
+    received: BTreeMap<u32, (bool, Vec<u8>)>,
+    /// the fragments of the current frame.
+    frame: Vec<u8>,
+}
+
+impl Arq {
+    pub fn new(conn: u32) -> Self {
+        Self {
+            conn,
+            cookie: None,
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            pending: VecDeque::new(),
+            expected: 0,
+            received: BTreeMap::new(),
+            frame: vec![],
+        }
+    }
+
+    /// split the frame to fragments, return the packets can be sent now.
+    pub fn send(&mut self, frame: &[u8], now: Instant) -> Vec<Vec<u8>> {
+        // empty frame (keepalive) is also a packet.
+        let count = frame.len().div_ceil(FRAGMENT_SIZE).max(1);
+        for i in 0..count {
+            let end = frame.len().min((i + 1) * FRAGMENT_SIZE);
+            let fragment = &frame[(i * FRAGMENT_SIZE).min(end)..end];
+
+            let mut packet = match self.cookie {
+                Some(cookie) if self.next_seq == 0 => {
+                    let mut packet = head(OPEN, self.conn, 0);
+                    packet.extend(cookie);
+                    packet
+                }
+                _ => head(DATA, self.conn, self.next_seq),
+            };
+            packet.push((i + 1 == count) as u8);
+            packet.extend_from_slice(fragment);
+            self.pending.push_back(packet);
+            self.next_seq += 1;
+        }
+        self.flush(now)
+    }
+
+    /// handle the remote packet, return the completed frames and the packets need send.
+    /// error when the frame is too large.
+    #[allow(clippy::type_complexity)]
+    pub fn recv(&mut self, packet: &[u8], now: Instant) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>)> {
+        let body = match parse_head(packet) {
+            Some((DATA, _, seq)) if packet.len() > HEAD_SIZE => Some((seq, HEAD_SIZE)),
+            Some((OPEN, _, 0)) if packet.len() > HEAD_SIZE + COOKIE_SIZE => {
+                Some((0, HEAD_SIZE + COOKIE_SIZE))
+            }
+            _ => None,
+        };
+        if let Some((seq, offset)) = body {
+            // older is duplicated, only ack it again.
+            if seq >= self.expected && seq - self.expected < RECV_WINDOW {
+                self.received
+                    .entry(seq)
+                    .or_insert_with(|| (packet[offset] == 1, packet[offset + 1..].to_vec()));
+            }
+
+            let mut frames = vec![];
+            while let Some((is_final, fragment)) = self.received.remove(&self.expected) {
+                self.expected += 1;
+                if self.frame.len() + fragment.len() > MAX_FRAME {
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                self.frame.extend(fragment);
+                if is_final {
+                    frames.push(std::mem::take(&mut self.frame));
+                }
+            }
+            return Ok((frames, vec![self.ack()]));
+        }
+
+        match parse_head(packet) {
+            Some((ACK, _, next)) if packet.len() >= HEAD_SIZE + 4 => {
+                let mut bitmap = [0u8; 4];
+                bitmap.copy_from_slice(&packet[HEAD_SIZE..HEAD_SIZE + 4]);
+                let bitmap = u32::from_be_bytes(bitmap);
+                self.unacked.retain(|seq, _| {
+                    *seq >= next
+                        && !(*seq > next
+                            && *seq - next <= 32
+                            && bitmap & (1 << (*seq - next - 1)) != 0)
+                });
+                Ok((vec![], self.flush(now)))
+            }
+            _ => Ok((vec![], vec![])),
+        }
+    }
+
+    /// the packets need retransmit, error when the remote is lost.
+    pub fn tick(&mut self, now: Instant) -> Result<Vec<Vec<u8>>> {
+        let mut packets = vec![];
+        for (packet, sent, retries) in self.unacked.values_mut() {
+            let rto = Duration::from_millis((RTO << *retries).min(RTO_MAX));
+            if now.duration_since(*sent) >= rto {
+                if *retries >= MAX_RETRIES {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                *retries += 1;
+                *sent = now;
+                packets.push(packet.clone());
+            }
+        }
+        Ok(packets)
+    }
+
+    /// all sent packets are acked.
+    pub fn is_idle(&self) -> bool {
+        self.unacked.is_empty() && self.pending.is_empty()
+    }
+
+    /// the packets waiting for the send window.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn ack(&self) -> Vec<u8> {
+        let mut bitmap = 0u32;
+        for i in 0..32 {
+            if self.received.contains_key(&(self.expected + 1 + i)) {
+                bitmap |= 1 << i;
+            }
+        }
+        let mut packet = head(ACK, self.conn, self.expected);
+        packet.extend(bitmap.to_be_bytes());
+        packet
+    }
+
+    /// move the pending packets to the send window.
+    fn flush(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        while self.unacked.len() < SEND_WINDOW {
+            if let Some(packet) = self.pending.pop_front() {
+                let (_, _, seq) = parse_head(&packet).unwrap_or_default(); // safe, built by self.
+                self.unacked.insert(seq, (packet.clone(), now, 0));
+                packets.push(packet);
+            } else {
+                break;
+            }
+        }
+        packets
+    }
+}
+
+/// the connections: (remote address, connection id) => packets sender.
+type Conns = Arc<Mutex<HashMap<(SocketAddr, u32), Sender<Vec<u8>>>>>;
+
+/// A UDT connection, ARQ over the shared UDP socket.
+struct Conn {
+    socket: Arc<UdpSocket>,
+    conns: Conns,
+    addr: SocketAddr,
+    id: u32,
+    arq: Arq,
+    packets: Receiver<Vec<u8>>,
+    frames: VecDeque<Vec<u8>>,
+    ticker: Interval,
+}
+
+impl Conn {
+    fn new(
+        socket: Arc<UdpSocket>,
+        conns: Conns,
+        addr: SocketAddr,
+        id: u32,
+    ) -> (Self, Sender<Vec<u8>>) {
+        let (sender, packets) = mpsc::channel(1024);
+        conns.lock().unwrap().insert((addr, id), sender.clone());
+        let mut ticker = interval(Duration::from_millis(TICK));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let conn = Self {
+            socket,
+            conns,
+            addr,
+            id,
+            arq: Arq::new(id),
+            packets,
+            frames: VecDeque::new(),
+            ticker,
+        };
+        (conn, sender)
+    }
+
+    async fn output(&self, packets: Vec<Vec<u8>>) {
+        for packet in packets {
+            let _ = self.socket.send_to(&packet, self.addr).await;
+        }
+    }
+
+    /// get the cookie of the listener before open, hello is resent as the ARQ.
+    async fn hello(&mut self) -> Result<()> {
+        let mut hello = head(HELLO, self.id, 0);
+        hello.resize(HEAD_SIZE + COOKIE_SIZE, 0);
+        for retries in 0..MAX_RETRIES {
+            let _ = self.socket.send_to(&hello, self.addr).await;
+            let rto = Duration::from_millis((RTO << retries).min(RTO_MAX));
+            let reply = timeout(rto, async {
+                while let Some(packet) = self.packets.recv().await {
+                    if matches!(parse_head(&packet), Some((COOKIE, _, _)))
+                        && packet.len() == HEAD_SIZE + COOKIE_SIZE
+                    {
+                        let mut cookie = [0u8; COOKIE_SIZE];
+                        cookie.copy_from_slice(&packet[HEAD_SIZE..]);
+                        return Some(cookie);
+                    }
+                }
+                None
+            })
+            .await;
+            match reply {
+                Ok(Some(cookie)) => {
+                    self.arq.cookie = Some(cookie);
+                    return Ok(());
+                }
+                Ok(None) => return Err(ErrorKind::ConnectionAborted.into()),
+                Err(_) => continue,
+            }
+        }
+        Err(ErrorKind::TimedOut.into())
+    }
+
+    /// send the frame, wait when the send window and pending are full.
+    async fn write(&mut self, frame: &[u8]) -> Result<()> {
+        while self.arq.pending() >= MAX_PENDING {
+            self.step().await?;
+        }
+        let packets = self.arq.send(frame, Instant::now());
+        self.output(packets).await;
+        Ok(())
+    }
+
+    /// read the next frame, it is cancel safe, the state is in the connection.
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+            self.step().await?;
+        }
+    }
+
+    /// handle a remote packet or the retransmit timer.
+    async fn step(&mut self) -> Result<()> {
+        select! {
+            v = self.packets.recv() => {
+                let packet = v.ok_or(ErrorKind::ConnectionAborted)?;
+                // not read frames are full, not ack the data, remote will resend it.
+                let is_data = matches!(parse_head(&packet), Some((DATA | OPEN, _, _)));
+                if is_data && self.frames.len() >= MAX_FRAMES {
+                    return Ok(());
+                }
+                let (frames, packets) = self.arq.recv(&packet, Instant::now())?;
+                self.frames.extend(frames);
+                self.output(packets).await;
+            }
+            _ = self.ticker.tick() => {
+                let packets = self.arq.tick(Instant::now())?;
+                self.output(packets).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// waiting the sent frames acked, or timeout.
+    async fn flush(&mut self) {
+        let _ = timeout(Duration::from_secs(CLOSE_WAITING), async {
+            while !self.arq.is_idle() {
+                if self.read().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        if let Ok(mut conns) = self.conns.lock() {
+            conns.remove(&(self.addr, self.id));
+        }
+    }
+}
+
+/// Init and run a UdtEndpoint object.
+/// It is UDP with a selective-ack ARQ, so the frames are reliable and in order as TCP.
+pub async fn start(
+    bind_addr: SocketAddr,
+    send: Sender<TransportRecvMessage>,
+    recv: Receiver<TransportSendMessage>,
+    both: bool,
+    options: &SocketOptions,
+) -> Result<SocketAddr> {
+    let socket = Arc::new(UdpSocket::from_std(bind_udp(bind_addr, options)?)?);
+    let addr = socket.local_addr()?;
+    info!("UDT listening at: {:?}", addr);
+    let conns: Conns = Arc::new(Mutex::new(HashMap::new()));
+
+    // UDT listen incoming, and dispatch the packets to connections.
+    let incoming = if both { Some(send.clone()) } else { None };
+    let task = tokio::spawn(run_listen(
+        socket.clone(),
+        conns.clone(),
+        incoming,
+        options.keepalive,
+    ));
+
+    // UDT listen from outside.
+    tokio::spawn(run_self_recv(socket, conns, recv, send, task, *options));
+
+    Ok(addr)
+}
+
+async fn run_listen(
+    socket: Arc<UdpSocket>,
+    conns: Conns,
+    out_send: Option<Sender<TransportRecvMessage>>,
+    keepalive: u64,
+) -> Result<()> {
+    let cookies = Cookies::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (size, addr) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            // remote port unreachable on some platforms.
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(e),
+        };
+        let packet = buf[..size].to_vec();
+        let (ty, id, seq) = match parse_head(&packet) {
+            Some(head) => head,
+            None => continue,
+        };
+
+        let sender = conns.lock().unwrap().get(&(addr, id)).cloned();
+        let out_send = match (sender, &out_send) {
+            (Some(sender), _) => {
+                // connection is busy, drop it, remote will resend.
+                let _ = sender.try_send(packet);
+                continue;
+            }
+            (None, Some(out_send)) => out_send,
+            (None, None) => continue,
+        };
+        match ty {
+            HELLO if size >= HEAD_SIZE + COOKIE_SIZE => {
+                // stateless, the connection is opened when the cookie is back.
+                let mut reply = head(COOKIE, id, 0);
+                reply.extend(cookies.issue(&addr, id));
+                let _ = socket.send_to(&reply, addr).await;
+            }
+            OPEN if seq == 0
+                && size > HEAD_SIZE + COOKIE_SIZE
+                && cookies.check(&addr, id, &packet[HEAD_SIZE..HEAD_SIZE + COOKIE_SIZE]) =>
+            {
+                if conns.lock().unwrap().len() >= MAX_CONNS {
+                    debug!("UDT connections are full, drop the open.");
+                    continue;
+                }
+                // new connection, the first packet is the remote handshake, so no
+                // transport time before it.
+                let timer = ConnectTimer::start();
+                let (conn, sender) = Conn::new(socket.clone(), conns.clone(), addr, id);
+                let _ = sender.try_send(packet);
+                let (self_sender, self_receiver) = new_endpoint_channel();
+                let (out_sender, out_receiver) = new_endpoint_channel();
+
+                tokio::spawn(process_conn(
+                    conn,
+                    out_sender,
+                    self_receiver,
+                    OutType::DHT(out_send.clone(), self_sender, out_receiver),
+                    None,
+                    None,
+                    timer,
+                    keepalive,
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn run_self_recv(
+    socket: Arc<UdpSocket>,
+    conns: Conns,
+    mut recv: Receiver<TransportSendMessage>,
+    out_send: Sender<TransportRecvMessage>,
+    task: JoinHandle<Result<()>>,
+    options: SocketOptions,
+) -> Result<()> {
+    let connecting: Arc<RwLock<HashMap<SocketAddr, Instant>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+
+    while let Some(m) = recv.recv().await {
+        match m {
+            TransportSendMessage::Connect(addr, remote_pk, session_key, wait) => {
+                let read_lock = connecting.read().await;
+                if let Some(time) = read_lock.get(&addr) {
+                    if time.elapsed().as_secs() < CONNECTING_WAITING {
+                        drop(read_lock);
+                        continue;
+                    }
+                }
+                drop(read_lock);
+                let mut lock = connecting.write().await;
+                lock.insert(addr, Instant::now());
+                drop(lock);
+
+                info!("UDT dht connect to: {:?}", addr);
+                tokio::spawn(dht_connect_to(
+                    Conn::new(socket.clone(), conns.clone(), addr, new_conn_id()).0,
+                    out_send.clone(),
+                    remote_pk,
+                    session_key,
+                    connecting.clone(),
+                    wait,
+                    options.keepalive,
+                ));
+            }
+            TransportSendMessage::StableConnect(out_sender, self_receiver, addr, remote_pk) => {
+                let read_lock = connecting.read().await;
+                if let Some(time) = read_lock.get(&addr) {
+                    if time.elapsed().as_secs() < CONNECTING_WAITING {
+                        drop(read_lock);
+                        continue;
+                    }
+                }
+                drop(read_lock);
+                let mut lock = connecting.write().await;
+                lock.insert(addr, Instant::now());
+                drop(lock);
+
+                info!("UDT stable connect to: {:?}", addr);
+                tokio::spawn(stable_connect_to(
+                    Conn::new(socket.clone(), conns.clone(), addr, new_conn_id()).0,
+                    out_sender,
+                    self_receiver,
+                    remote_pk,
+                    connecting.clone(),
+                    options.keepalive,
+                ));
+            }
+            TransportSendMessage::Stop => {
+                task.abort();
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn new_conn_id() -> u32 {
+    ChaChaRng::from_entropy().next_u32()
+}
+
+/// get the cookie, send the handshake and wait the remote's, no retry, the ARQ had resent it.
+async fn dial(
+    conn: &mut Conn,
+    remote_pk: RemotePublic,
+    timer: &mut ConnectTimer,
+) -> std::result::Result<RemotePublic, ConnectError> {
+    let handshake = timeout(Duration::from_secs(HANDSHAKE_WAITING), async {
+        conn.hello().await?;
+        timer.trace.transport = timer.elapsed();
+        conn.write(&EndpointMessage::Handshake(remote_pk).to_bytes())
+            .await?;
+        read_handshake(conn).await
+    })
+    .await;
+    match handshake {
+        Ok(Ok(remote_pk)) => {
+            timer.trace.handshake = timer.elapsed();
+            Ok(remote_pk)
+        }
+        res => {
+            debug!("Transport: connect read publics failure, close it.");
+            Err(handshake_error(res))
+        }
+    }
+}
+
+async fn dht_connect_to(
+    mut conn: Conn,
+    out_send: Sender<TransportRecvMessage>,
+    remote_pk: RemotePublic,
+    session_key: SessionKey,
+    connecting: Arc<RwLock<HashMap<SocketAddr, Instant>>>,
+    wait: Option<Sender<ConnectResult>>,
+    keepalive: u64,
+) -> Result<()> {
+    let addr = conn.addr;
+    let mut timer = ConnectTimer::start();
+    let result = dial(&mut conn, remote_pk, &mut timer).await;
+    connecting.write().await.remove(&addr);
+    let remote_pk = match result {
+        Ok(remote_pk) => remote_pk,
+        Err(e) => {
+            info!("UDT cannot connect to {:?}", addr);
+            if let Some(wait) = wait {
+                let _ = wait.try_send(Err(e));
+            }
+            return Ok(());
+        }
+    };
+    let (self_sender, self_receiver) = new_endpoint_channel();
+    let (out_sender, out_receiver) = new_endpoint_channel();
+
+    process_conn(
+        conn,
+        out_sender,
+        self_receiver,
+        OutType::DHT(out_send, self_sender, out_receiver),
+        Some(session_key),
+        Some(remote_pk),
+        timer,
+        keepalive,
+    )
+    .await
+}
+
+async fn stable_connect_to(
+    mut conn: Conn,
+    out_sender: Sender<EndpointMessage>,
+    self_receiver: Receiver<EndpointMessage>,
+    remote_pk: RemotePublic,
+    connecting: Arc<RwLock<HashMap<SocketAddr, Instant>>>,
+    keepalive: u64,
+) -> Result<()> {
+    let addr = conn.addr;
+    let mut timer = ConnectTimer::start();
+    let result = dial(&mut conn, remote_pk, &mut timer).await;
+    connecting.write().await.remove(&addr);
+    match result {
+        Ok(remote_pk) => {
+            info!("UDT stable connect to {:?}", addr);
+            process_conn(
+                conn,
+                out_sender,
+                self_receiver,
+                OutType::Stable,
+                None,
+                Some(remote_pk),
+                timer,
+                keepalive,
+            )
+            .await
+        }
+        Err(_) => {
+            info!("UDT cannot stable connect to {:?}", addr);
+            let _ = out_sender.send(EndpointMessage::Close(None)).await;
+            Ok(())
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+enum OutType {
+    DHT(
+        Sender<TransportRecvMessage>,
+        Sender<EndpointMessage>,
+        Receiver<EndpointMessage>,
+    ),
+    Stable,
+}
+
+/// read the remote handshake, the first frame of the connection.
+async fn read_handshake(conn: &mut Conn) -> Result<RemotePublic> {
+    match EndpointMessage::from_bytes(conn.read().await?)? {
+        EndpointMessage::Handshake(remote_pk) => Ok(remote_pk),
+        EndpointMessage::Close(_) => Err(ErrorKind::ConnectionRefused.into()),
+        _ => Err(std::io::Error::other("not handshake")),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_conn(
+    mut conn: Conn,
+    out_sender: Sender<EndpointMessage>,
+    mut self_receiver: Receiver<EndpointMessage>,
+    out_type: OutType,
+    has_session: Option<SessionKey>,
+    handshake: Option<RemotePublic>,
+    mut timer: ConnectTimer,
+    keepalive: u64,
+) -> Result<()> {
+    // outbound had exchanged the handshake when dial.
+    let remote_pk = if let Some(remote_pk) = handshake {
+        remote_pk
+    } else {
+        let handshake = timeout(
+            Duration::from_secs(HANDSHAKE_WAITING),
+            read_handshake(&mut conn),
+        )
+        .await;
+
+        match handshake {
+            Ok(Ok(remote_pk)) => {
+                timer.trace.handshake = timer.elapsed();
+                remote_pk
+            }
+            _ => {
+                debug!("Transport: connect read publics failure, close it.");
+                return Ok(());
+            }
+        }
+    };
+
+    match out_type {
+        OutType::Stable => {
+            let _ = out_sender.send(EndpointMessage::Connected(timer)).await;
+            out_sender
+                .send(EndpointMessage::Handshake(remote_pk))
+                .await
+                .map_err(|_e| std::io::Error::other("endpoint channel missing"))?;
+        }
+        OutType::DHT(sender, self_sender, out_receiver) => {
+            sender
+                .send(TransportRecvMessage(
+                    conn.addr,
+                    remote_pk,
+                    has_session,
+                    out_sender.clone(),
+                    out_receiver,
+                    self_sender,
+                    timer,
+                ))
+                .await
+                .map_err(|_e| std::io::Error::other("server channel missing"))?;
+        }
+    }
+
+    let mut ticker = new_keepalive(keepalive);
+    loop {
+        select! {
+            v = self_receiver.recv() => match v {
+                Some(msg) => {
+                    let is_close = matches!(msg, EndpointMessage::Close(..));
+                    if conn.write(&msg.to_bytes()).await.is_err() {
+                        // remote lost.
+                        let _ = out_sender.send(EndpointMessage::Close(None)).await;
+                        break;
+                    }
+                    if let Some(ticker) = ticker.as_mut() {
+                        ticker.reset();
+                    }
+                    if is_close {
+                        conn.flush().await;
+                        break;
+                    }
+                }
+                None => break,
+            },
+            v = conn.read() => match v {
+                // empty frame is transport keepalive.
+                Ok(bytes) if bytes.is_empty() => {}
+                Ok(bytes) => {
+                    if let Ok(msg) = EndpointMessage::from_bytes(bytes) {
+                        let _ = out_sender.send(msg).await;
+                    }
+                }
+                Err(_) => {
+                    // remote lost.
+                    let _ = out_sender.send(EndpointMessage::Close(None)).await;
+                    break;
+                }
+            },
+            _ = keepalive_tick(&mut ticker) => {
+                if conn.write(&[]).await.is_err() {
+                    let _ = out_sender.send(EndpointMessage::Close(None)).await;
+                    break;
+                }
+            }
+        }
+    }
+
+    debug!("close stream: {}", conn.addr);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transports::{new_transport_recv_channel, new_transport_send_channel};
+
+    /// in-memory lossy link: drop every 3rd packet, and reorder by reverse the batch.
+    fn lossy(packets: Vec<Vec<u8>>, count: &mut usize) -> Vec<Vec<u8>> {
+        let mut kept = vec![];
+        for packet in packets {
+            *count += 1;
+            if !(*count).is_multiple_of(3) {
+                kept.push(packet);
+            }
+        }
+        kept.reverse();
+        kept
+    }
+
+    #[test]
+    fn test_arq_lossy() {
+        let mut a = Arq::new(1);
+        let mut b = Arq::new(1);
+        let mut now = Instant::now();
+        let mut count = 0;
+
+        // small and large (fragmented) frames.
+        let frames: Vec<Vec<u8>> = (0..50u32)
+            .map(|i| vec![i as u8; if i % 10 == 0 { 5000 } else { i as usize }])
+            .collect();
+        let mut to_b = vec![];
+        for frame in frames.iter() {
+            to_b.extend(a.send(frame, now));
+        }
+
+        let mut received = vec![];
+        for _ in 0..1000 {
+            let mut to_a = vec![];
+            for packet in lossy(std::mem::take(&mut to_b), &mut count) {
+                let (frames, packets) = b.recv(&packet, now).unwrap();
+                received.extend(frames);
+                to_a.extend(packets);
+            }
+            for packet in lossy(to_a, &mut count) {
+                let (frames, packets) = a.recv(&packet, now).unwrap();
+                assert!(frames.is_empty());
+                to_b.extend(packets);
+            }
+            if a.is_idle() {
+                break;
+            }
+            now += Duration::from_millis(RTO_MAX);
+            to_b.extend(a.tick(now).unwrap());
+        }
+
+        assert!(a.is_idle());
+        assert_eq!(received, frames);
+    }
+
+    #[test]
+    fn test_arq_lost() {
+        let mut a = Arq::new(1);
+        let mut now = Instant::now();
+        assert_eq!(a.send(&[1, 2, 3], now).len(), 1);
+        for _ in 0..MAX_RETRIES {
+            now += Duration::from_millis(RTO_MAX);
+            assert_eq!(a.tick(now).unwrap().len(), 1);
+        }
+        now += Duration::from_millis(RTO_MAX);
+        assert!(a.tick(now).is_err());
+    }
+
+    #[test]
+    fn test_arq_frame_limit() {
+        let mut b = Arq::new(1);
+        let now = Instant::now();
+        let fragment = vec![1u8; FRAGMENT_SIZE];
+        let count = MAX_FRAME / FRAGMENT_SIZE + 1;
+        for seq in 0..count as u32 {
+            let mut packet = head(DATA, 1, seq);
+            packet.push(0);
+            packet.extend(&fragment);
+            let res = b.recv(&packet, now);
+            if seq + 1 < count as u32 {
+                assert!(res.is_ok());
+            } else {
+                assert!(res.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_cookie() {
+        let cookies = Cookies::new();
+        let addr: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let other: SocketAddr = "1.2.3.5:5678".parse().unwrap();
+        let cookie = cookies.issue(&addr, 7);
+        assert!(cookies.check(&addr, 7, &cookie));
+        assert!(!cookies.check(&addr, 8, &cookie));
+        assert!(!cookies.check(&other, 7, &cookie));
+        assert!(!Cookies::new().check(&addr, 7, &cookie));
+    }
+
+    #[tokio::test]
+    async fn test_udt_spoofed() {
+        let (a_recv_send, mut a_recv) = new_transport_recv_channel();
+        let (_a_send, a_send_recv) = new_transport_send_channel();
+        let a_addr = start(
+            "127.0.0.1:0".parse().unwrap(),
+            a_recv_send,
+            a_send_recv,
+            true,
+            &SocketOptions::default(),
+        )
+        .await
+        .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; 2048];
+        let peer = chamomile_types::Peer::socket(a_addr);
+        let handshake =
+            EndpointMessage::Handshake(RemotePublic::new(peer, vec![1u8; 2])).to_bytes();
+        let mut arq = Arq::new(9);
+        let packet = arq.send(&handshake, Instant::now()).remove(0);
+
+        // the data without cookie not opens the connection.
+        socket.send_to(&packet, a_addr).await.unwrap();
+        // the short hello has no reply, not amplified.
+        socket.send_to(&head(HELLO, 9, 0), a_addr).await.unwrap();
+        let wait = Duration::from_millis(200);
+        assert!(timeout(wait, socket.recv_from(&mut buf)).await.is_err());
+
+        // the wrong cookie not opens the connection.
+        let mut arq = Arq::new(9);
+        arq.cookie = Some([0u8; COOKIE_SIZE]);
+        let packet = arq.send(&handshake, Instant::now()).remove(0);
+        socket.send_to(&packet, a_addr).await.unwrap();
+        assert!(timeout(wait, socket.recv_from(&mut buf)).await.is_err());
+        assert!(a_recv.try_recv().is_err());
+
+        // hello with the padding, and open with the cookie.
+        let mut hello = head(HELLO, 9, 0);
+        hello.resize(HEAD_SIZE + COOKIE_SIZE, 0);
+        socket.send_to(&hello, a_addr).await.unwrap();
+        let (size, _) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, hello.len());
+        assert!(matches!(parse_head(&buf[..size]), Some((COOKIE, 9, 0))));
+        let mut arq = Arq::new(9);
+        arq.cookie = Some(buf[HEAD_SIZE..size].try_into().unwrap());
+        let packet = arq.send(&handshake, Instant::now()).remove(0);
+        socket.send_to(&packet, a_addr).await.unwrap();
+        assert!(a_recv.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_udt_connect() {
+        let (a_recv_send, mut a_recv) = new_transport_recv_channel();
+        let (_a_send, a_send_recv) = new_transport_send_channel();
+        let options = SocketOptions::default();
+        let a_addr = start(
+            "127.0.0.1:0".parse().unwrap(),
+            a_recv_send,
+            a_send_recv,
+            true,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let (b_recv_send, _b_recv) = new_transport_recv_channel();
+        let (b_send, b_send_recv) = new_transport_send_channel();
+        start(
+            "127.0.0.1:0".parse().unwrap(),
+            b_recv_send,
+            b_send_recv,
+            true,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        // b stable connect to a.
+        let (b_out_sender, mut b_out) = new_endpoint_channel();
+        let (b_self, b_self_receiver) = new_endpoint_channel();
+        let peer = chamomile_types::Peer::socket(a_addr);
+        b_send
+            .send(TransportSendMessage::StableConnect(
+                b_out_sender,
+                b_self_receiver,
+                a_addr,
+                RemotePublic::new(peer, vec![1u8; 2]),
+            ))
+            .await
+            .unwrap();
+
+        // a accept it, and answer the handshake.
+        let TransportRecvMessage(_, _, _, _, mut a_out, a_self, _) = a_recv.recv().await.unwrap();
+        a_self
+            .send(EndpointMessage::Handshake(RemotePublic::new(
+                peer,
+                vec![2u8; 2],
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            b_out.recv().await,
+            Some(EndpointMessage::Connected(_))
+        ));
+        assert!(matches!(
+            b_out.recv().await,
+            Some(EndpointMessage::Handshake(_))
+        ));
+
+        // large frame is fragmented, and in order.
+        let large = vec![7u8; 100_000];
+        b_self
+            .send(EndpointMessage::Data(large.clone()))
+            .await
+            .unwrap();
+        b_self.send(EndpointMessage::Data(vec![8u8])).await.unwrap();
+        match a_out.recv().await {
+            Some(EndpointMessage::Data(data)) => assert_eq!(data, large),
+            _ => panic!("udt data failure"),
+        }
+        assert!(matches!(a_out.recv().await, Some(EndpointMessage::Data(d)) if d == vec![8u8]));
+
+        // close is delivered.
+        b_self.send(EndpointMessage::Close(None)).await.unwrap();
+        assert!(matches!(
+            a_out.recv().await,
+            Some(EndpointMessage::Close(None))
+        ));
+    }
+}
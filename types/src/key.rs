@@ -1,8 +1,12 @@
 use rand_core::{CryptoRng, RngCore};
 use secp256k1::{
     constants::ONE,
+    ecdh::SharedSecret,
     ecdsa::{RecoverableSignature, RecoveryId},
-    Message as SecpMessage, PublicKey as SecpPublicKey, Secp256k1, SecretKey as SecpSecretKey,
+    hashes::{hmac, sha512, Hash, HashEngine},
+    schnorr::Signature as SecpSchnorrSignature,
+    Keypair, Message as SecpMessage, PublicKey as SecpPublicKey, Scalar, Secp256k1,
+    SecretKey as SecpSecretKey, XOnlyPublicKey as SecpXOnlyPublicKey,
 };
 use sha3::{Digest, Keccak256};
 
@@ -13,6 +17,20 @@ use crate::types::{new_io_error, PeerId, PEER_ID_LENGTH};
 pub const SECRET_KEY_LENGTH: usize = 32;
 pub const PUBLIC_KEY_LENGTH: usize = 33;
 pub const SIGNATURE_LENGTH: usize = 65;
+pub const XONLY_PUBLIC_KEY_LENGTH: usize = 32;
+pub const SCHNORR_SIGNATURE_LENGTH: usize = 64;
+
+// compressed public key is the x-only key with the parity byte,
+// peer id is the tail of the keccak256 (32 bytes) of the public key.
+const _: () = assert!(PUBLIC_KEY_LENGTH == XONLY_PUBLIC_KEY_LENGTH + 1);
+const _: () = assert!(PEER_ID_LENGTH <= 32);
+pub const CHAIN_CODE_LENGTH: usize = 32;
+/// the header of key db bytes, magic and version.
+pub const KEY_DB_HEADER: [u8; 3] = [b'c', b'k', 1];
+/// the checksum length of key db bytes, keccak256(secret) prefix.
+pub const KEY_DB_CHECKSUM_LENGTH: usize = 4;
+/// the first hardened child index of HD derivation.
+pub const HARDENED_INDEX: u32 = 0x8000_0000;
 
 /// Public Key
 #[derive(Clone)]
@@ -23,6 +41,20 @@ pub struct SecretKey(SecpSecretKey);
 
 pub struct Signature(RecoverableSignature);
 
+/// X-only Public Key (BIP340), used to verify the schnorr signature.
+#[derive(Clone, Eq, PartialEq)]
+pub struct XOnlyPublicKey(SecpXOnlyPublicKey);
+
+/// BIP32 extended private key, the key with chain code, it can derive child keys.
+pub struct ExtendedKey {
+    pub key: Key,
+    pub chain_code: [u8; CHAIN_CODE_LENGTH],
+}
+
+/// BIP340 schnorr signature, 64 bytes, no recovery id.
+/// Default peer signature is still the recoverable ECDSA `Signature`.
+pub struct SchnorrSignature(SecpSchnorrSignature);
+
 /// The keypair, include pk, sk, address
 pub struct Key {
     pub pub_key: PublicKey,
@@ -37,6 +69,7 @@ impl Key {
         Self { pub_key, sec_key }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Self {
         let sec_key = SecretKey(SecpSecretKey::from_slice(&ONE).unwrap());
         Self::from_sec_key(sec_key)
@@ -79,24 +112,201 @@ impl Key {
         self.sign(&eth_message)
     }
 
+    /// BIP340 schnorr sign, the msg is signed as is (no keccak256),
+    /// for Bitcoin/Nostr it is usually a 32 bytes hash.
+    pub fn sign_schnorr(&self, msg: &[u8]) -> SchnorrSignature {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &self.sec_key.0);
+        SchnorrSignature(secp.sign_schnorr(msg, &keypair))
+    }
+
+    /// BIP340 schnorr sign with the given auxiliary random data (deterministic).
+    pub fn sign_schnorr_with_aux(&self, msg: &[u8], aux: &[u8; 32]) -> SchnorrSignature {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &self.sec_key.0);
+        SchnorrSignature(secp.sign_schnorr_with_aux_rand(msg, &keypair, aux))
+    }
+
+    /// ECDH shared secret with the remote public key, sha256 of the shared point,
+    /// both sides derive the same. For app encryption (e.g. data at rest to a peer),
+    /// it is deterministic of the two static keys, so it is NOT forward-secret.
+    pub fn ecdh(&self, remote: &PublicKey) -> [u8; 32] {
+        SharedSecret::new(&remote.0, &self.sec_key.0).secret_bytes()
+    }
+
+    /// derive the child key by BIP32 path (e.g. `m/0'/1`), this key's secret is
+    /// used as the seed of the master, so it only need to save this key.
+    pub fn derive(&self, path: &str) -> std::io::Result<Key> {
+        let master = ExtendedKey::from_seed(&self.sec_key.0.secret_bytes())?;
+        Ok(master.derive_path(path)?.key)
+    }
+
+    /// db bytes: header(3) + secret(32) + checksum(4).
     pub fn to_db_bytes(&self) -> Vec<u8> {
+        let secret = self.sec_key.0.secret_bytes();
         let mut bytes = vec![];
-        bytes.extend(&self.sec_key.0.secret_bytes());
+        bytes.extend(&KEY_DB_HEADER);
+        bytes.extend(&secret);
+        bytes.extend(&key_checksum(&secret));
         bytes
     }
 
+    /// only accept the checked bytes, corrupted bytes will return error.
     pub fn from_db_bytes(bytes: &[u8]) -> std::io::Result<Self> {
-        if bytes.len() < SECRET_KEY_LENGTH {
+        Self::from_db_bytes_with(bytes, false)
+    }
+
+    /// when `legacy` is true, also accept the old raw secret bytes (no header).
+    pub fn from_db_bytes_with(bytes: &[u8], legacy: bool) -> std::io::Result<Self> {
+        let secret = if bytes.starts_with(&KEY_DB_HEADER) {
+            let body = &bytes[KEY_DB_HEADER.len()..];
+            if body.len() != SECRET_KEY_LENGTH + KEY_DB_CHECKSUM_LENGTH {
+                return Err(new_io_error("key db bytes length invalid, file corrupted."));
+            }
+            let (secret, checksum) = body.split_at(SECRET_KEY_LENGTH);
+            if key_checksum(secret) != checksum {
+                return Err(new_io_error(
+                    "key db bytes checksum invalid, file corrupted.",
+                ));
+            }
+            secret
+        } else if legacy && bytes.len() >= SECRET_KEY_LENGTH {
+            &bytes[..SECRET_KEY_LENGTH]
+        } else {
             return Err(new_io_error("keypair from db bytes failure."));
-        }
+        };
+
         let sec_key = SecretKey(
-            SecpSecretKey::from_slice(&bytes[..SECRET_KEY_LENGTH])
+            SecpSecretKey::from_slice(secret)
                 .map_err(|_| new_io_error("secret key from db bytes failure."))?,
         );
         Ok(Self::from_sec_key(sec_key))
     }
 }
 
+fn key_checksum(secret: &[u8]) -> [u8; KEY_DB_CHECKSUM_LENGTH] {
+    let hash = Keccak256::digest(secret);
+    let mut checksum = [0u8; KEY_DB_CHECKSUM_LENGTH];
+    checksum.copy_from_slice(&hash[..KEY_DB_CHECKSUM_LENGTH]);
+    checksum
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(key);
+    engine.input(data);
+    hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array()
+}
+
+impl ExtendedKey {
+    /// BIP32 master key from the seed (16 - 64 bytes).
+    pub fn from_seed(seed: &[u8]) -> std::io::Result<Self> {
+        let i = hmac_sha512(b"Bitcoin seed", seed);
+        let sec_key =
+            SecpSecretKey::from_slice(&i[..32]).map_err(|_| new_io_error("Invalid master key"))?;
+        let mut chain_code = [0u8; CHAIN_CODE_LENGTH];
+        chain_code.copy_from_slice(&i[32..]);
+        Ok(Self {
+            key: Key::from_sec_key(SecretKey(sec_key)),
+            chain_code,
+        })
+    }
+
+    /// derive the child key, `index >= HARDENED_INDEX` is hardened derivation.
+    pub fn derive_child(&self, index: u32) -> std::io::Result<Self> {
+        let mut data = Vec::with_capacity(37);
+        if index >= HARDENED_INDEX {
+            data.push(0u8);
+            data.extend(self.key.sec_key.0.secret_bytes());
+        } else {
+            data.extend(self.key.pub_key.0.serialize());
+        }
+        data.extend(index.to_be_bytes());
+
+        let i = hmac_sha512(&self.chain_code, &data);
+        let mut tweak = [0u8; 32];
+        tweak.copy_from_slice(&i[..32]);
+        let tweak =
+            Scalar::from_be_bytes(tweak).map_err(|_| new_io_error("Invalid child key index"))?;
+        let sec_key = self
+            .key
+            .sec_key
+            .0
+            .add_tweak(&tweak)
+            .map_err(|_| new_io_error("Invalid child key index"))?;
+        let mut chain_code = [0u8; CHAIN_CODE_LENGTH];
+        chain_code.copy_from_slice(&i[32..]);
+        Ok(Self {
+            key: Key::from_sec_key(SecretKey(sec_key)),
+            chain_code,
+        })
+    }
+
+    /// derive by path, e.g. `m/44'/0'/0/1`, hardened index use `'` or `h`.
+    pub fn derive_path(&self, path: &str) -> std::io::Result<Self> {
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(new_io_error("Invalid derivation path"));
+        }
+
+        let mut key = Self {
+            key: Key::from_sec_key(SecretKey(self.key.sec_key.0)),
+            chain_code: self.chain_code,
+        };
+        for part in parts {
+            let (num, hardened) = match part.strip_suffix(['\'', 'h', 'H']) {
+                Some(num) => (num, true),
+                None => (part, false),
+            };
+            let index: u32 = num
+                .parse()
+                .map_err(|_| new_io_error("Invalid derivation path"))?;
+            if index >= HARDENED_INDEX {
+                return Err(new_io_error("Invalid derivation path"));
+            }
+            key = key.derive_child(if hardened {
+                index + HARDENED_INDEX
+            } else {
+                index
+            })?;
+        }
+        Ok(key)
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.key.peer_id()
+    }
+}
+
+/// Signer of the peer identity. The built-in is `Key`, and it can be implemented
+/// by the remote signer or HSM which cannot expose the secret key.
+/// The signature is recoverable secp256k1 signature of keccak256(msg).
+pub trait Signer: Send + Sync {
+    /// sign the message.
+    fn sign(&self, msg: &[u8]) -> Signature;
+
+    /// the public key of this signer.
+    fn public_key(&self) -> PublicKey;
+
+    /// the peer id of this signer.
+    fn peer_id(&self) -> PeerId {
+        self.public_key().peer_id()
+    }
+}
+
+impl Signer for Key {
+    fn sign(&self, msg: &[u8]) -> Signature {
+        Key::sign(self, msg)
+    }
+
+    fn public_key(&self) -> PublicKey {
+        self.public()
+    }
+
+    fn peer_id(&self) -> PeerId {
+        Key::peer_id(self)
+    }
+}
+
 impl PublicKey {
     pub fn new(pk: SecpPublicKey) -> Self {
         Self(pk)
@@ -112,9 +322,55 @@ impl PublicKey {
         hasher.update(&public_key[1..]);
         let result = hasher.finalize();
         let mut bytes = [0u8; PEER_ID_LENGTH];
-        bytes.copy_from_slice(&result[12..]);
+        bytes.copy_from_slice(&result[32 - PEER_ID_LENGTH..]);
         PeerId(bytes)
     }
+
+    /// the x-only public key for schnorr verify.
+    pub fn x_only(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey(self.0.x_only_public_key().0)
+    }
+}
+
+impl XOnlyPublicKey {
+    pub fn raw(&self) -> &SecpXOnlyPublicKey {
+        &self.0
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.serialize().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        if bytes.len() != XONLY_PUBLIC_KEY_LENGTH {
+            return Err(new_io_error("Invalid x-only public key length"));
+        }
+        SecpXOnlyPublicKey::from_slice(bytes)
+            .map(XOnlyPublicKey)
+            .map_err(|_| new_io_error("Invalid x-only public key value"))
+    }
+}
+
+impl SchnorrSignature {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_byte_array().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        if bytes.len() != SCHNORR_SIGNATURE_LENGTH {
+            return Err(new_io_error("Invalid schnorr signature length"));
+        }
+        SecpSchnorrSignature::from_slice(bytes)
+            .map(SchnorrSignature)
+            .map_err(|_| new_io_error("Invalid schnorr signature value"))
+    }
+
+    /// verify the signature of msg against the x-only public key.
+    pub fn verify(&self, msg: &[u8], pk: &XOnlyPublicKey) -> std::io::Result<()> {
+        let secp = Secp256k1::verification_only();
+        secp.verify_schnorr(&self.0, msg, &pk.0)
+            .map_err(|_| new_io_error("Invalid schnorr signature"))
+    }
 }
 
 impl SecretKey {
@@ -156,7 +412,8 @@ impl Signature {
             v @ 35.. => (v - 1) % 2,
         };
 
-        let recv = RecoveryId::try_from(id as i32).map_err(|_| new_io_error("Invalid signature value"))?;
+        let recv =
+            RecoveryId::try_from(id as i32).map_err(|_| new_io_error("Invalid signature value"))?;
         RecoverableSignature::from_compact(&bytes[..64], recv)
             .map(Signature)
             .map_err(|_| new_io_error("Invalid signature value"))
@@ -194,7 +451,8 @@ impl TryFrom<&str> for PublicKey {
     type Error = std::io::Error;
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|_| new_io_error("Invalid public key hex"))?;
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|_| new_io_error("Invalid public key hex"))?;
         if bytes.len() != PUBLIC_KEY_LENGTH {
             return Err(new_io_error("Invalid public key length"));
         }
@@ -205,9 +463,9 @@ impl TryFrom<&str> for PublicKey {
     }
 }
 
-impl ToString for PublicKey {
-    fn to_string(&self) -> String {
-        format!("0x{}", hex::encode(self.0.serialize()))
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.0.serialize()))
     }
 }
 
@@ -215,7 +473,8 @@ impl TryFrom<&str> for SecretKey {
     type Error = std::io::Error;
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|_| new_io_error("Invalid secret key hex"))?;
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|_| new_io_error("Invalid secret key hex"))?;
         if bytes.len() != SECRET_KEY_LENGTH {
             return Err(new_io_error("Invalid secret key length"));
         }
@@ -226,9 +485,9 @@ impl TryFrom<&str> for SecretKey {
     }
 }
 
-impl ToString for SecretKey {
-    fn to_string(&self) -> String {
-        format!("0x{}", hex::encode(self.0.secret_bytes()))
+impl std::fmt::Display for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.0.secret_bytes()))
     }
 }
 
@@ -236,7 +495,8 @@ impl TryFrom<&str> for Signature {
     type Error = std::io::Error;
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|_| new_io_error("Invalid secret key hex"))?;
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|_| new_io_error("Invalid secret key hex"))?;
         if bytes.len() != SIGNATURE_LENGTH {
             return Err(new_io_error("Invalid secret key length"));
         }
@@ -244,9 +504,41 @@ impl TryFrom<&str> for Signature {
     }
 }
 
-impl ToString for Signature {
-    fn to_string(&self) -> String {
-        format!("0x{}", hex::encode(self.to_bytes()))
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl TryFrom<&str> for XOnlyPublicKey {
+    type Error = std::io::Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|_| new_io_error("Invalid x-only public key hex"))?;
+        XOnlyPublicKey::from_bytes(&bytes)
+    }
+}
+
+impl std::fmt::Display for XOnlyPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl TryFrom<&str> for SchnorrSignature {
+    type Error = std::io::Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|_| new_io_error("Invalid schnorr signature hex"))?;
+        SchnorrSignature::from_bytes(&bytes)
+    }
+}
+
+impl std::fmt::Display for SchnorrSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", hex::encode(self.to_bytes()))
     }
 }
 
@@ -269,6 +561,37 @@ mod tests {
         assert_eq!(peer_id1, peer_id);
     }
 
+    #[test]
+    fn test_ecdh() {
+        let a = Key::from_sec_key(SecretKey::try_from(SK_HEX).unwrap());
+        let b = a.derive("m/0").unwrap();
+        let c = a.derive("m/1").unwrap();
+        let secret = a.ecdh(&b.public());
+        assert_eq!(secret, b.ecdh(&a.public()));
+        assert_ne!(secret, a.ecdh(&c.public()));
+    }
+
+    #[test]
+    fn test_key_db_bytes() {
+        let key = Key::from_sec_key(SecretKey::try_from(SK_HEX).unwrap());
+        let bytes = key.to_db_bytes();
+        let key2 = Key::from_db_bytes(&bytes).unwrap();
+        assert_eq!(key.peer_id(), key2.peer_id());
+
+        for i in 0..bytes.len() {
+            let mut flipped = bytes.clone();
+            flipped[i] ^= 1;
+            assert!(Key::from_db_bytes(&flipped).is_err());
+        }
+        assert!(Key::from_db_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // legacy raw secret only read with the flag.
+        let raw = key.sec_key.0.secret_bytes();
+        assert!(Key::from_db_bytes(&raw).is_err());
+        let key3 = Key::from_db_bytes_with(&raw, true).unwrap();
+        assert_eq!(key.peer_id(), key3.peer_id());
+    }
+
     #[test]
     fn test_signature() {
         let peer_id = PeerId::from_hex(PEER_ID_HEX).unwrap();
@@ -284,4 +607,165 @@ mod tests {
         let peer_id3 = sign3.peer_id_eth(MESSAGE.as_bytes()).unwrap();
         assert_eq!(peer_id, peer_id3);
     }
+
+    // BIP340 test vectors: (secret_key, public_key, aux_rand, message, signature).
+    const BIP340_SIGN: [(&str, &str, &str, &str, &str); 4] = [
+        (
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA821525F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+        ),
+        (
+            "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+        ),
+        (
+            "C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+            "DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+            "C87AA53824B4D7AE2EB035A2B5BBBCCC080E76CDC6D1692C4B0B62D798E6D906",
+            "7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+            "5831AAEED7B44BB74E5EAB94BA9D4294C49BCF2A60728D8B4C200F50DD313C1BAB745879A5AD954A72C45A91C3A51D3C7ADEA98D82F8481E0E1E03674A6F3FB7",
+        ),
+        (
+            "0B432B2677937381AEF05BB02A66ECD012773062CF3FA2549E44F58ED2401710",
+            "25D1DFF95105F5253C4022F628A996AD3A0D95FBF21D468A1B33F8C160D8F517",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+            "7EB0509757E246F19449885651611CB965ECC1A187DD51B64FDA1EDC9637D5EC97582B9CB13DB3933705B32BA982AF5AF25FD78881EBB32771FC5922EFC66EA3",
+        ),
+    ];
+
+    // BIP340 verify only test vectors: (public_key, message, signature, is_valid).
+    const BIP340_VERIFY: [(&str, &str, &str, bool); 3] = [
+        (
+            "D69C3509BB99E412E68B0FE8544E72837DFA30746D8BE2AA65975F29D22DC7B9",
+            "4DF3C3F68FCC83B27E9D42C90431A72499F17875C81A599B566C9889B9696703",
+            "00000000000000000000003B78CE563F89A0ED9414F5AA28AD0D96D6795F9C6376AFB1548AF603B3EB45C9F8207DEE1060CB71C04E80F593060B07D28308D7F4",
+            true,
+        ),
+        (
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "FFF97BD5755EEEA420453A14355235D382F6472F8568A18B2F057A14602975563CC27944640AC607CD107AE10923D9EF7A73C643E166BE5EBEAFA34B1AC553E2",
+            false,
+        ),
+        (
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "1FA62E331EDBC21C394792D2AB1100A7B432B013DF3F6FF4F99FCB33E0E1515F28890B3EDB6E7189B630448B515CE4F8622A954CFE545735AAEA5134FCCDB2BD",
+            false,
+        ),
+    ];
+
+    #[test]
+    fn test_schnorr_signature() {
+        for (sk, pk, aux, msg, sig) in BIP340_SIGN {
+            let key = Key::from_sec_key(SecretKey::try_from(sk).unwrap());
+            let pk = XOnlyPublicKey::try_from(pk).unwrap();
+            assert!(key.public().x_only() == pk);
+
+            let mut aux_rand = [0u8; 32];
+            aux_rand.copy_from_slice(&hex::decode(aux).unwrap());
+            let msg = hex::decode(msg).unwrap();
+            let sign = key.sign_schnorr_with_aux(&msg, &aux_rand);
+            assert_eq!(sign.to_bytes(), hex::decode(sig).unwrap());
+
+            let sign2 = SchnorrSignature::from_bytes(&sign.to_bytes()).unwrap();
+            assert!(sign2.verify(&msg, &pk).is_ok());
+            assert!(key.sign_schnorr(&msg).verify(&msg, &pk).is_ok());
+        }
+
+        for (pk, msg, sig, is_valid) in BIP340_VERIFY {
+            let pk = XOnlyPublicKey::try_from(pk).unwrap();
+            let msg = hex::decode(msg).unwrap();
+            let sign = SchnorrSignature::try_from(sig).unwrap();
+            assert_eq!(sign.verify(&msg, &pk).is_ok(), is_valid);
+        }
+
+        // public key not on the curve.
+        assert!(XOnlyPublicKey::try_from(
+            "EEFDEA4CDB677750A420FEE807EACF21EB9898AE79B9768766E4FAA04A2D4A34"
+        )
+        .is_err());
+        // recoverable signature length is not schnorr.
+        assert!(SchnorrSignature::try_from(SIGN_HEX).is_err());
+    }
+
+    // BIP32 test vectors: (path, secret_key, chain_code).
+    const BIP32_SEED1: &str = "000102030405060708090a0b0c0d0e0f";
+    const BIP32_VECTOR1: [(&str, &str, &str); 6] = [
+        (
+            "m",
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35",
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508",
+        ),
+        (
+            "m/0'",
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea",
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141",
+        ),
+        (
+            "m/0'/1",
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368",
+            "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19",
+        ),
+        (
+            "m/0'/1/2'",
+            "cbce0d719ecf7431d88e6a89fa1483e02e35092af60c042b1df2ff59fa424dca",
+            "04466b9cc8e161e966409ca52986c584f07e9dc81f735db683c3ff6ec7b1503f",
+        ),
+        (
+            "m/0'/1/2'/2",
+            "0f479245fb19a38a1954c5c7c0ebab2f9bdfd96a17563ef28a6a4b1a2a764ef4",
+            "cfb71883f01676f587d023cc53a35bc7f88f724b1f8c2892ac1275ac822a3edd",
+        ),
+        (
+            "m/0h/1/2h/2/1000000000",
+            "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8",
+            "c783e67b921d2beb8f6b389cc646d7263b4145701dadd2161548a8b078e65e9e",
+        ),
+    ];
+
+    #[test]
+    fn test_hd_derive() {
+        let master = ExtendedKey::from_seed(&hex::decode(BIP32_SEED1).unwrap()).unwrap();
+        for (path, sk, chain_code) in BIP32_VECTOR1 {
+            let child = master.derive_path(path).unwrap();
+            assert_eq!(child.key.sec_key.to_string(), format!("0x{}", sk));
+            assert_eq!(hex::encode(child.chain_code), chain_code);
+        }
+
+        // vector 3, the leading zeros of master key are retained.
+        let seed = hex::decode("4b381541583be4423346c643850da4b320e46a87ae3d2a4e6da11eba819cd4acba45d239319ac14f863b8d5ab5a0d0c64d2e8a1e7d1457df2e5a3c51c73235be").unwrap();
+        let child = ExtendedKey::from_seed(&seed)
+            .unwrap()
+            .derive_path("m/0'")
+            .unwrap();
+        assert_eq!(
+            child.key.sec_key.to_string(),
+            "0x491f7a2eebc7b57028e0d3faa0acda02e75c33b03c48fb288c41e2ea44e1daef"
+        );
+
+        // hardened and normal are different.
+        let normal = master.derive_child(0).unwrap();
+        let hardened = master.derive_child(HARDENED_INDEX).unwrap();
+        assert_ne!(normal.peer_id(), hardened.peer_id());
+
+        // derived peer id is stable from the master key.
+        let key = Key::from_sec_key(SecretKey::try_from(SK_HEX).unwrap());
+        let id1 = key.derive("m/0'/7").unwrap().peer_id();
+        let id2 = key.derive("m/0h/7").unwrap().peer_id();
+        assert_eq!(id1, id2);
+        assert_ne!(id1, key.peer_id());
+        assert_ne!(id1, key.derive("m/0'/8").unwrap().peer_id());
+
+        assert!(key.derive("0/1").is_err());
+        assert!(key.derive("m/x").is_err());
+        assert!(key.derive("m/2147483648").is_err());
+    }
 }
@@ -1,7 +1,20 @@
-use tokio::sync::mpsc::Sender;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot,
+};
 
 use crate::peer::Peer;
-use crate::types::{Broadcast, PeerId, TransportStream};
+use crate::types::{
+    Broadcast, CloseReason, ConnectResult, Metadata, PeerId, PeerTier, Priority, SessionState,
+    TransportStream, TransportType,
+};
 
 /// Custom apply for build a stream between nodes.
 #[derive(Debug)]
@@ -41,13 +54,30 @@ pub enum ReceiveMessage {
     StableLeave(Peer),
     /// when received a data from a trusted peer,
     /// send to outside.
-    /// params is `peer_id` and `data_bytes`.
-    Data(PeerId, Vec<u8>),
+    /// params is `peer_id`, `data_bytes` and `is_direct`, it is true when received
+    /// from the peer directly (the hop is the origin), false when via relay, the
+    /// origin is told by relays, apply different trust if need.
+    Data(PeerId, Vec<u8>, bool),
+    /// when received a data with metadata from a trusted peer,
+    /// send to outside.
+    /// params is `peer_id`, `metadata` and `data_bytes`.
+    Metadata(PeerId, Metadata, Vec<u8>),
+    /// when received a sub-protocol data, and the receiver of the protocol is not
+    /// registered or is full, send to outside, so it is never dropped.
+    /// params is `peer_id`, `protocol`, `data_bytes` and `is_direct`.
+    Protocol(PeerId, u16, Vec<u8>, bool),
+    /// when received a large data from a trusted peer, it will be received as chunks,
+    /// and when outside is slow to consume the chunks, the peer will slow to send.
+    /// params is `peer_id`, `data_id` and chunks receiver.
+    LargeData(PeerId, u64, Receiver<DataChunk>),
     /// (Only stable connected) Apply for build a stream between nodes.
     /// params is `u32` stream symbol, and `StreamType`.
     Stream(u32, StreamType, Vec<u8>),
     /// (Only stable connected) Delivery feedback. include StableConnect, StableResult, Data. `id(u32) != 0`.
     Delivery(DeliveryType, u64, bool, Vec<u8>),
+    /// when a peer force disconnected by self or remote, will tell outside.
+    /// params is `peer_id` and the close reason.
+    PeerLeave(PeerId, CloseReason),
     /// when network lost all DHT network and direct stables. will tell outside.
     NetworkLost,
     /// when same PeerId peer is connected.
@@ -59,6 +89,34 @@ pub enum ReceiveMessage {
     /// when receive same PeerId message.
     /// params is `assist_id` and `data_bytes`.
     OwnEvent(PeerId, Vec<u8>),
+    /// (leader election) when the leader of self and stable connected peers changed.
+    /// params is the leader's `peer_id`, maybe self.
+    Leader(PeerId),
+    /// when the external address of self (reported by connected peers) changed.
+    /// params is the new `socket_addr`, re-advertise it if need.
+    ExternalAddrChanged(SocketAddr),
+    /// (identity pinning) when the dialed address presents a different peer id
+    /// from the pinned one, maybe MITM or address reused.
+    /// params is the `socket_addr`, the pinned `peer_id` and the presented `peer_id`.
+    PinMismatch(SocketAddr, PeerId, PeerId),
+    /// (sequence frames) some frames from the peer are missing (e.g. dropped by relay),
+    /// only for diagnostics, the session keeps going.
+    /// params is `peer_id` and the missing frames count of this gap.
+    SequenceGap(PeerId, u64),
+    /// (maintenance stats) the stats snapshot, emitted every maintenance interval.
+    Stats(NetworkStats),
+}
+
+/// the chunk of large data.
+#[derive(Debug, Clone)]
+pub struct DataChunk {
+    /// the large data id.
+    pub id: u64,
+    /// the offset of this chunk in the large data.
+    pub offset: u64,
+    /// if it is the last chunk.
+    pub is_final: bool,
+    pub data: Vec<u8>,
 }
 
 /// main send message for outside channel, send from outside to chamomile.
@@ -87,6 +145,38 @@ pub enum SendMessage {
     /// if connected, chamomile will add to kad and bootstrap.
     /// params is `Peer`.
     Connect(Peer),
+    /// (DHT connected) when outside only know the peer id, will find it in DHT,
+    /// and connect to its advertised addresses.
+    /// params is `peer_id`, and return channel's sender, result is the connected
+    /// peer, or error if cannot find it in 10s.
+    ConnectPeerId(PeerId, Sender<Result<Peer>>),
+    /// (DHT connected) same as `Connect`, and wait the result.
+    /// params is `Peer`, and return channel's sender, result is the remote peer id,
+    /// or the reason when failure or not connected in 10s.
+    ConnectWait(Peer, Sender<ConnectResult>),
+    /// (not connected) dial the peer, send one data and close, no session is kept,
+    /// for telemetry or fire-once notifications.
+    /// params is `Peer`, `data_bytes`, and return channel's sender, result is the remote
+    /// peer id when the data is sent, or the reason when failure.
+    Oneshot(Peer, Vec<u8>, Sender<ConnectResult>),
+    /// (DHT connected) same as `Connect`, and send the first data to the peer.
+    /// if had the session ticket of the peer, the data is sent in the handshake,
+    /// remote can read it without waiting the round trip, and deliver it when the
+    /// session key confirmed. NOTICE: this early data has no forward secrecy.
+    /// without ticket, it will be sent after connected.
+    /// params is `Peer` and data bytes.
+    ConnectData(Peer, Vec<u8>),
+    /// when outside want to kick a peer (DHT or stable), force close the connection
+    /// and tell remote the reason, both will receive `PeerLeave`.
+    /// if the peer is not connected, do nothing.
+    /// params is `peer_id` and the close reason.
+    PeerDisconnect(PeerId, CloseReason),
+    /// ban the peer, new connections from/to it will be rejected, and close it if connected.
+    /// params is `peer_id` and the ban duration, `None` is permanent.
+    Ban(PeerId, Option<Duration>),
+    /// remove the peer from the ban list, it can connect again.
+    /// params is `peer_id`.
+    Unban(PeerId),
     /// (DHT connected) when outside donnot want to connect peer. use it to force close.
     /// it will remove from kad and bootstrap list.
     /// params is `Peer`.
@@ -95,7 +185,66 @@ pub enum SendMessage {
     /// the chamomile will help you send data to there.
     /// params is `delivery_feedback_id`, `peer_id` and `data_bytes`.
     /// if `delivery_feedback_id = 0` will not feedback.
+    /// the data to the same peer in a direct session is received in send order (FIFO),
+    /// see the crate doc "Ordering".
     Data(u64, PeerId, Vec<u8>),
+    /// (gateway, need `relay_on_behalf`) send a data on behalf of other peer id,
+    /// e.g. the gateway of offline devices. the data carry the origin proof signed by
+    /// the source for this gateway (`origin_proof`), receiver verify it and receive as
+    /// relay data (`ReceiveMessage::Data(from, data, false)`), invalid, expired or
+    /// replayed proof is dropped.
+    /// params is `from_peer_id`, `to_peer_id`, `origin_proof` and `data_bytes`.
+    DataFrom(PeerId, PeerId, Vec<u8>, Vec<u8>),
+    /// same as `Data`, but with the send priority, `Data` is `Normal`.
+    /// the order is kept in the same priority, the higher may overtake the lower.
+    /// params is `delivery_feedback_id`, `peer_id`, `data_bytes` and `priority`.
+    PriorityData(u64, PeerId, Vec<u8>, Priority),
+    /// same as `Data`, and with the metadata of the data, remote will receive
+    /// `ReceiveMessage::Metadata`. only to connected peers (not relay).
+    /// params is `delivery_feedback_id`, `peer_id`, `metadata` and `data_bytes`.
+    Metadata(u64, PeerId, Metadata, Vec<u8>),
+    /// same as `Data`, and the data is of a sub-protocol, the id is out of the data.
+    /// remote send it to the receiver registered by `Protocol`, or to outside as
+    /// `ReceiveMessage::Protocol`. only to connected peers (not relay).
+    /// params is `delivery_feedback_id`, `peer_id`, `protocol` and `data_bytes`.
+    ProtocolData(u64, PeerId, u16, Vec<u8>),
+    /// when need send a large data (e.g. file) to a connected peer,
+    /// it will be split to chunks (with `Low` priority), and remote will receive
+    /// it as `LargeData`.
+    /// params is `peer_id` and `data_bytes`.
+    LargeData(PeerId, Vec<u8>),
+    /// same as `Data`, but only to connected peers (not relay), and the `confirm` will be
+    /// resolved `Ok` when the data is encrypted and handed to the transport,
+    /// or `Err` when the peer is not connected or the session closed before send.
+    /// params is `delivery_feedback_id`, `peer_id`, `data_bytes` and `confirm`.
+    ConfirmData(u64, PeerId, Vec<u8>, oneshot::Sender<Result<()>>),
+    /// same as `Data`, but only to connected or in handshake peers (not relay), and with
+    /// the TTL, if it is still queued (handshake or backpressured) when TTL passed, it is
+    /// dropped, not sent late.
+    /// when dropped and `delivery_feedback_id != 0`, will feedback the delivery failure.
+    /// params is `delivery_feedback_id`, `peer_id`, `data_bytes` and `ttl`.
+    ExpiringData(u64, PeerId, Vec<u8>, Duration),
+    /// wait all queued data to a connected peer (not relay) handed to the transport,
+    /// e.g. before disconnect it. `PeerDisconnect` and `NetworkStop` also flush it.
+    /// params is `peer_id` and `done` sender, `Err` when the peer is not connected
+    /// or the session closed before flushed.
+    Flush(PeerId, oneshot::Sender<Result<()>>),
+    /// stop the session of a connected peer (not relay) only, the node keeps running.
+    /// it runs the normal close path (flush, deliver the received, tell remote the
+    /// reason and outside `PeerLeave`). params is `peer_id`, `reason` and `done` sender,
+    /// it is resolved when the session is fully closed, `Err` when not connected.
+    /// the stop is a message to the session channel, not a cancellation of its task,
+    /// so the session handles it after the messages already sent to it.
+    StopSession(PeerId, CloseReason, oneshot::Sender<Result<()>>),
+    /// send the same data to several connected peers, encrypted once under a random
+    /// content key, and only the content key is encrypted per peer by its session key.
+    /// remote receive it as `ReceiveMessage::Data`. not connected peers are skipped,
+    /// and no delivery confirm (tid is 0). params is `peer_ids` and `data_bytes`.
+    SealedData(Vec<PeerId>, Vec<u8>),
+    /// when need send a data to all connected peers (DHT and stable) matched the filter,
+    /// e.g. only public peers.
+    /// params is `peer_filter` and `data_bytes`.
+    DataWhere(PeerFilter, Vec<u8>),
     /// when need broadcast a data to all network,
     /// chamomile support some common algorithm, use it, donnot worry.
     /// params is `broadcast_type` and `data_bytes`
@@ -106,12 +255,41 @@ pub enum SendMessage {
     /// Request for return the network current state info.
     /// params is request type, and return channel's sender (async).
     NetworkState(StateRequest, Sender<StateResponse>),
+    /// merge the known peers (not sessions) of the snapshot to bootstraps (seed list),
+    /// they are dialed in next `NetworkReboot` or restart.
+    ImportTopology(TopologySnapshot),
+    /// attach (or clear with `None`) the user state to a connected peer, it is ignored
+    /// when the peer is not connected, and auto cleared when the peer leaves.
+    /// get it by `StateRequest::PeerState`.
+    PeerState(PeerId, Option<PeerState>),
+    /// (app backpressure) pause delivering the remote data to outside, nothing is
+    /// dropped, the sessions stop reading when their delivery queues are full, so the
+    /// peers are slowed down by transports, the sessions still send and keep alive.
+    PauseDelivery,
+    /// resume delivering the remote data, continue where it paused, in order.
+    ResumeDelivery,
     /// When receive `ReceiveMessage::NetworkLost`, want to reboot network, it can use.
     NetworkReboot,
     /// When want to close p2p network.
     NetworkStop,
+    /// Gracefully close p2p network for maintenance: not accept new inbound
+    /// sessions and relay flows, keep the existing sessions in the grace time,
+    /// then stop it (same as `NetworkStop`).
+    NetworkDrain(Duration),
     /// when want to broadcast message with same PeerId.
     OwnEvent(Vec<u8>),
+    /// set (or remove with `None`) a read-only observer channel, it will receive a copy
+    /// of the messages sent to outside (except `LargeData` and `Stream`).
+    /// if the observer is full, the copy is dropped, so it never stalls the node.
+    Observe(Option<Sender<ReceiveMessage>>),
+    /// register (or remove with `None`) the receiver of a sub-protocol id, the
+    /// `ProtocolData` of the id is sent to it as `ReceiveMessage::Data`. the receiver is
+    /// not waited, when it is full, the data goes to outside as `ReceiveMessage::Protocol`.
+    Protocol(u16, Option<Sender<ReceiveMessage>>),
+    /// set the tier of the peer (default is `Normal`). the high ones are exempt from
+    /// `max_sessions` and the relay flows limit of their direct sessions, never evicted
+    /// from the handshake buffer (the low ones first), and redialed when lost.
+    PeerTier(PeerId, PeerTier),
 }
 
 /// Network state info response.
@@ -120,6 +298,170 @@ pub enum StateRequest {
     Stable,
     DHT,
     Seed,
+    Stats,
+    Health,
+    Banned,
+    LocalAddrs,
+    PeerReady(PeerId),
+    Topology,
+    PeerState(PeerId),
+    /// check the remote timestamp (unix seconds) is in the window (seconds),
+    /// with the node's `max_clock_skew`.
+    Timestamp(u64, u64),
+    /// the session state transitions of the latest connection to the peer. the
+    /// inbound one starts at `KeyExchange`, when the remote is authenticated.
+    SessionState(PeerId),
+}
+
+/// Network statistics info.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    /// local relay load, relayed KiB for other peers in last period.
+    pub relay_load: u32,
+    /// local relay flows (pair of origin and target) served for other peers.
+    pub relay_flows: usize,
+    /// the connection establishment trace of the connected DHT peers.
+    pub connects: Vec<(PeerId, ConnectTrace)>,
+    /// live session tasks, DHT and incoming relay ones are capped by `max_sessions` config.
+    pub sessions: usize,
+    /// known peers (DHT and stable), recomputed by the maintenance metrics.
+    pub peers: usize,
+    /// the sent encrypted frame sizes histogram of peers, the size is rounded up
+    /// to power of two, and the frames count.
+    pub frame_sizes: Vec<(PeerId, Vec<(usize, u64)>)>,
+    /// the live sessions throughput in the last `rate_window` seconds.
+    pub throughput: Throughput,
+    /// the throughput of peers in the last `rate_window` seconds.
+    pub peer_throughput: Vec<(PeerId, Throughput)>,
+}
+
+/// The rates (per second) of the session frames, average in a sliding window.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    pub send_bytes: f64,
+    pub send_msgs: f64,
+    pub recv_bytes: f64,
+    pub recv_msgs: f64,
+}
+
+/// The known peers view of the node, portable (serde) for diagnostics and seeding
+/// other nodes (`SendMessage::ImportTopology`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TopologySnapshot {
+    pub peers: Vec<TopologyPeer>,
+}
+
+/// The known peer in the topology snapshot.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TopologyPeer {
+    pub id: PeerId,
+    pub socket: SocketAddr,
+    pub transport: TransportType,
+    pub is_pub: bool,
+    /// is connected when export, or only known address (bootstrap).
+    pub is_connected: bool,
+    /// last seen time (UNIX seconds), none if not seen by this node.
+    pub last_seen: Option<u64>,
+}
+
+impl TopologyPeer {
+    pub fn peer(&self) -> Peer {
+        Peer::new(self.id, self.socket, self.transport, self.is_pub)
+    }
+}
+
+/// The connected peer info, use it to select peers.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerInfo {
+    /// the peer, socket is the address seen by us, `is_pub` is it can be connected directly.
+    pub peer: Peer,
+    /// is stable connected.
+    pub is_stable: bool,
+    /// is connected directly, not relay.
+    pub is_direct: bool,
+    /// last received frame time (UNIX seconds), none if not received.
+    pub last_seen: Option<u64>,
+    /// last keepalive round trip time, none if not measured.
+    pub rtt: Option<Duration>,
+}
+
+/// User-supplied filter of connected peers, return true to select it.
+#[derive(Clone)]
+pub struct PeerFilter(Arc<dyn Fn(&PeerInfo) -> bool + Send + Sync>);
+
+impl PeerFilter {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&PeerInfo) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn matches(&self, info: &PeerInfo) -> bool {
+        (self.0)(info)
+    }
+}
+
+impl Debug for PeerFilter {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "PeerFilter")
+    }
+}
+
+/// User-provided state attached to a connected peer (subscriptions, auth level...).
+#[derive(Clone)]
+pub struct PeerState(Arc<dyn Any + Send + Sync>);
+
+impl PeerState {
+    pub fn new<T: Any + Send + Sync>(state: T) -> Self {
+        Self(Arc::new(state))
+    }
+
+    /// get the state if it is the type `T`.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref::<T>()
+    }
+}
+
+impl Debug for PeerState {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "PeerState")
+    }
+}
+
+/// Node liveness info, it is read from local state, no network I/O.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthStatus {
+    /// the main transport listener is bound.
+    pub bound: bool,
+    /// connected peers (DHT and stable).
+    pub peers: usize,
+    /// had connected to the network (any peer) since start.
+    pub bootstrapped: bool,
+    /// last time received message from remotes.
+    pub last_activity: Option<SystemTime>,
+}
+
+impl HealthStatus {
+    /// bound and has connected peers.
+    pub fn is_healthy(&self) -> bool {
+        self.bound && self.peers > 0
+    }
+}
+
+/// Connection establishment timing, all durations are from the connect start
+/// (dial, or the first transport event when accepted), so can tell the slowness
+/// is transport, crypto or NAT. DHT and stable connects are both recorded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectTrace {
+    /// transport connected.
+    pub transport: Duration,
+    /// remote public info (handshake) received.
+    pub handshake: Duration,
+    /// session key is ok.
+    pub session_key: Duration,
+    /// first data received.
+    pub first_data: Option<Duration>,
 }
 
 /// Network state info response.
@@ -131,4 +473,25 @@ pub enum StateResponse {
     DHT(Vec<PeerId>),
     /// response is socket list.
     Seed(Vec<Peer>),
+    /// response is network statistics.
+    Stats(NetworkStats),
+    /// response is node liveness info.
+    Health(HealthStatus),
+    /// response is banned peers and the expire time, `None` is permanent.
+    Banned(Vec<(PeerId, Option<Instant>)>),
+    /// response is the bound listening addresses, the port is assigned by OS
+    /// when bind to port 0.
+    LocalAddrs(Vec<SocketAddr>),
+    /// response is the peer's session had completed key exchange,
+    /// data to it can be sent immediately, not buffered.
+    PeerReady(bool),
+    /// response is the known peers snapshot.
+    Topology(TopologySnapshot),
+    /// response is the user state attached to the peer.
+    PeerState(Option<PeerState>),
+    /// response is the remote timestamp is valid.
+    Timestamp(bool),
+    /// response is the session state transitions of the latest connection, in order,
+    /// the last is the current state. empty if unknown (never connected, or pruned).
+    SessionState(Vec<SessionState>),
 }
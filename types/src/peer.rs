@@ -5,10 +5,13 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
-use crate::types::{new_io_error, PeerId, TransportType};
+use crate::types::{new_io_error, PeerId, TransportType, PEER_ID_LENGTH};
 
-// [u8; 18]
-fn socket_addr_to_bytes(socket: &SocketAddr) -> Vec<u8> {
+/// ipv6 (or ipv4-mapped) 16 bytes + port 2 bytes.
+pub const SOCKET_ADDR_LENGTH: usize = 18;
+
+// [u8; SOCKET_ADDR_LENGTH]
+pub fn socket_addr_to_bytes(socket: &SocketAddr) -> Vec<u8> {
     let ip_bytes: [u8; 16] = match socket {
         SocketAddr::V4(ipv4) => ipv4.ip().to_ipv6_mapped().octets(),
         SocketAddr::V6(ipv6) => ipv6.ip().octets(),
@@ -21,8 +24,8 @@ fn socket_addr_to_bytes(socket: &SocketAddr) -> Vec<u8> {
     bytes
 }
 
-fn socket_addr_from_bytes(bytes: &[u8]) -> Result<SocketAddr> {
-    if bytes.len() != 18 {
+pub fn socket_addr_from_bytes(bytes: &[u8]) -> Result<SocketAddr> {
+    if bytes.len() != SOCKET_ADDR_LENGTH {
         return Err(new_io_error("peer bytes failure."));
     }
     let mut port_bytes = [0u8; 2];
@@ -32,7 +35,7 @@ fn socket_addr_from_bytes(bytes: &[u8]) -> Result<SocketAddr> {
     let mut ip_bytes = [0u8; 16];
     ip_bytes.copy_from_slice(&bytes[0..16]);
     let ipv6 = Ipv6Addr::from(ip_bytes);
-    if let Some(ipv4) = ipv6.to_ipv4() {
+    if let Some(ipv4) = ipv6.to_ipv4_mapped() {
         Ok(SocketAddr::new(IpAddr::V4(ipv4), port))
     } else {
         Ok(SocketAddr::new(IpAddr::V6(ipv6), port))
@@ -46,10 +49,34 @@ pub struct Peer {
     pub socket: SocketAddr,
     pub transport: TransportType,
     pub is_pub: bool,
+    /// other candidate addresses (e.g. IPv6, VPN), try them in order after `socket`.
+    pub others: [Option<(SocketAddr, TransportType)>; PEER_MAX_OTHERS],
 }
 
 // PEER_ID_LENGTH + ASSIST + SOCKET_ADDR_LENGTH + 2 = 20 + 20 + 18 + 2 = 60
-pub const PEER_LENGTH: usize = 60;
+// if has other addresses, flags has PEER_FLAG_OTHERS, and append:
+// count (1) + count * (SOCKET_ADDR_LENGTH + 1)
+pub const PEER_LENGTH: usize = PEER_ID_LENGTH * 2 + SOCKET_ADDR_LENGTH + 2;
+
+/// max other candidate addresses in a peer.
+pub const PEER_MAX_OTHERS: usize = 3;
+
+// SOCKET_ADDR_LENGTH + TRANSPORT = 18 + 1 = 19
+const PEER_OTHER_LENGTH: usize = SOCKET_ADDR_LENGTH + 1;
+
+// the wire layout is fixed, changing the lengths breaks the old nodes.
+const _: () = assert!(PEER_ID_LENGTH == 20);
+const _: () = assert!(PEER_LENGTH == 60);
+const _: () = assert!(PEER_OTHER_LENGTH == 19);
+
+// fields offset in the peer bytes.
+const ASSIST_OFFSET: usize = PEER_ID_LENGTH;
+const SOCKET_OFFSET: usize = ASSIST_OFFSET + PEER_ID_LENGTH;
+const TRANSPORT_OFFSET: usize = SOCKET_OFFSET + SOCKET_ADDR_LENGTH;
+const FLAGS_OFFSET: usize = TRANSPORT_OFFSET + 1;
+
+const PEER_FLAG_PUB: u8 = 0b01;
+const PEER_FLAG_OTHERS: u8 = 0b10;
 
 impl Peer {
     /// generate assist peer id for DHT.
@@ -67,6 +94,7 @@ impl Peer {
             transport,
             is_pub,
             assist: PeerId::default(),
+            others: Default::default(),
         }
     }
 
@@ -78,10 +106,12 @@ impl Peer {
             transport: TransportType::QUIC,
             is_pub: true,
             assist: PeerId::default(),
+            others: Default::default(),
         }
     }
 
     /// create peer by only peer id.
+    #[allow(clippy::self_named_constructors)]
     pub fn peer(id: PeerId) -> Self {
         Self {
             id,
@@ -89,6 +119,7 @@ impl Peer {
             transport: TransportType::QUIC,
             is_pub: true,
             assist: PeerId::default(),
+            others: Default::default(),
         }
     }
 
@@ -111,31 +142,104 @@ impl Peer {
         self.socket.set_port(0)
     }
 
+    /// add other candidate address, if full or had it, return false.
+    pub fn add_addr(&mut self, socket: SocketAddr, transport: TransportType) -> bool {
+        if self.addrs().contains(&(socket, transport)) {
+            return false;
+        }
+        for other in self.others.iter_mut() {
+            if other.is_none() {
+                *other = Some((socket, transport));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// all candidate addresses, the main `socket` is first.
+    pub fn addrs(&self) -> Vec<(SocketAddr, TransportType)> {
+        let mut addrs = vec![(self.socket, self.transport)];
+        addrs.extend(self.others.iter().flatten());
+        addrs
+    }
+
+    /// load peer from the exact bytes.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        if bytes.len() != PEER_LENGTH {
+        let (peer, len) = Self::decode(bytes)?;
+        if len != bytes.len() {
             return Err(new_io_error("peer bytes failure."));
         }
+        Ok(peer)
+    }
 
-        let id = PeerId::from_bytes(&bytes[0..20])?;
-        let assist = PeerId::from_bytes(&bytes[20..40])?;
-        let socket = socket_addr_from_bytes(&bytes[40..58])?;
-        let transport = TransportType::from_byte(bytes[58])?;
-        let is_pub = bytes[59] == 1u8;
-        Ok(Self {
-            id,
-            assist,
-            socket,
-            transport,
-            is_pub,
-        })
+    /// load peer from the bytes prefix, return the peer and used length.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize)> {
+        if bytes.len() < PEER_LENGTH {
+            return Err(new_io_error("peer bytes failure."));
+        }
+
+        let id = PeerId::from_bytes(&bytes[0..ASSIST_OFFSET])?;
+        let assist = PeerId::from_bytes(&bytes[ASSIST_OFFSET..SOCKET_OFFSET])?;
+        let socket = socket_addr_from_bytes(&bytes[SOCKET_OFFSET..TRANSPORT_OFFSET])?;
+        let transport = TransportType::from_byte(bytes[TRANSPORT_OFFSET])?;
+        let flags = bytes[FLAGS_OFFSET];
+        let is_pub = flags & PEER_FLAG_PUB == PEER_FLAG_PUB;
+
+        let mut len = PEER_LENGTH;
+        let mut others: [Option<(SocketAddr, TransportType)>; PEER_MAX_OTHERS] = Default::default();
+        if flags & PEER_FLAG_OTHERS == PEER_FLAG_OTHERS {
+            if bytes.len() < len + 1 {
+                return Err(new_io_error("peer bytes failure."));
+            }
+            let count = bytes[len] as usize;
+            len += 1;
+            if count == 0
+                || count > PEER_MAX_OTHERS
+                || bytes.len() < len + count * PEER_OTHER_LENGTH
+            {
+                return Err(new_io_error("peer bytes failure."));
+            }
+            for other in others.iter_mut().take(count) {
+                let socket = socket_addr_from_bytes(&bytes[len..len + SOCKET_ADDR_LENGTH])?;
+                let transport = TransportType::from_byte(bytes[len + SOCKET_ADDR_LENGTH])?;
+                *other = Some((socket, transport));
+                len += PEER_OTHER_LENGTH;
+            }
+        }
+
+        Ok((
+            Self {
+                id,
+                assist,
+                socket,
+                transport,
+                is_pub,
+                others,
+            },
+            len,
+        ))
     }
+
     pub fn to_bytes(&self) -> Vec<u8> {
+        let others: Vec<&(SocketAddr, TransportType)> = self.others.iter().flatten().collect();
+        let mut flags = if self.is_pub { PEER_FLAG_PUB } else { 0u8 };
+        if !others.is_empty() {
+            flags |= PEER_FLAG_OTHERS;
+        }
+
         let mut bytes = vec![];
         bytes.append(&mut self.id.to_bytes()); // 20-bytes
         bytes.append(&mut self.assist.to_bytes()); // 20-bytes
         bytes.append(&mut socket_addr_to_bytes(&self.socket)); // 18-bytes
         bytes.push(self.transport.to_byte()); // 1-bytes
-        bytes.push(if self.is_pub { 1u8 } else { 0u8 }); // 1-bytes
+        bytes.push(flags); // 1-bytes
+        if !others.is_empty() {
+            bytes.push(others.len() as u8); // 1-bytes
+            for (socket, transport) in others {
+                bytes.append(&mut socket_addr_to_bytes(socket)); // 18-bytes
+                bytes.push(transport.to_byte()); // 1-bytes
+            }
+        }
         bytes
     }
 
@@ -147,7 +251,8 @@ impl Peer {
     /// 5 is open or not,
     /// 6 is peer id hex encode.
     /// example: "/ip4/127.0.0.1/tcp/1234/false/xxxxxx"
-    pub fn to_string<'a>(&self) -> String {
+    #[allow(clippy::inherent_to_string)]
+    pub fn to_string(&self) -> String {
         let v = if self.socket.is_ipv4() { "4" } else { "6" };
 
         format!(
@@ -190,6 +295,7 @@ impl Peer {
             socket,
             transport,
             assist: PeerId::default(),
+            others: Default::default(),
         })
     }
 
@@ -218,6 +324,7 @@ impl Peer {
             id: Default::default(),
             is_pub: true,
             assist: PeerId::default(),
+            others: Default::default(),
         })
     }
 
@@ -248,6 +355,7 @@ impl Default for Peer {
             transport: TransportType::TCP,
             is_pub: true,
             assist: PeerId::default(),
+            others: Default::default(),
         }
     }
 }
@@ -257,3 +365,98 @@ impl Debug for Peer {
         write!(f, "Peer: {:?} {}", self.id, self.to_multiaddr_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// golden bytes, all integers are fixed endianness, not depend on target.
+    #[test]
+    fn test_peer_golden_bytes() {
+        let mut peer = Peer::new(
+            PeerId([1u8; 20]),
+            "1.2.3.4:4660".parse().unwrap(),
+            TransportType::TCP,
+            true,
+        );
+        peer.assist = PeerId([2u8; 20]);
+
+        let expected = [
+            &[1u8; 20][..],                                          // id
+            &[2u8; 20][..],                                          // assist
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4], // ipv4-mapped
+            &[0x34, 0x12],                                           // port (le)
+            &[1u8],                                                  // transport (TCP)
+            &[1u8],                                                  // is_pub
+        ]
+        .concat();
+        assert_eq!(peer.to_bytes(), expected);
+        assert_eq!(expected.len(), PEER_LENGTH);
+        assert!(Peer::from_bytes(&expected).unwrap() == peer);
+
+        let peer6 = Peer::new(
+            PeerId([1u8; 20]),
+            "[::1]:7364".parse().unwrap(),
+            TransportType::QUIC,
+            false,
+        );
+        let bytes = peer6.to_bytes();
+        assert_eq!(&bytes[40..56], &Ipv6Addr::LOCALHOST.octets()[..]);
+        assert_eq!(&bytes[56..60], &[0xc4, 0x1c, 0, 0]);
+    }
+
+    #[test]
+    fn test_peer_length() {
+        let sockets: [SocketAddr; 3] = [
+            "1.2.3.4:4660".parse().unwrap(),
+            "0.0.0.0:0".parse().unwrap(),
+            "[2001:db8::1]:65535".parse().unwrap(),
+        ];
+        for socket in sockets {
+            assert_eq!(socket_addr_to_bytes(&socket).len(), SOCKET_ADDR_LENGTH);
+            for transport in [TransportType::QUIC, TransportType::TCP, TransportType::UDT] {
+                for is_pub in [true, false] {
+                    let mut peer = Peer::new(PeerId([0xffu8; 20]), socket, transport, is_pub);
+                    peer.assist = PeerId([0xeeu8; 20]);
+                    let bytes = peer.to_bytes();
+                    assert_eq!(bytes.len(), PEER_LENGTH);
+                    assert_eq!(Peer::decode(&bytes).unwrap().1, PEER_LENGTH);
+                }
+            }
+        }
+        assert_eq!(PeerId::default().to_bytes().len(), PEER_ID_LENGTH);
+    }
+
+    #[test]
+    fn test_peer_multi_addrs() {
+        let mut peer = Peer::socket("1.2.3.4:4660".parse().unwrap());
+        peer.id = PeerId([1u8; 20]);
+        assert!(peer.add_addr("[::1]:4660".parse().unwrap(), TransportType::QUIC));
+        assert!(peer.add_addr("10.0.0.1:7364".parse().unwrap(), TransportType::TCP));
+        assert!(!peer.add_addr("10.0.0.1:7364".parse().unwrap(), TransportType::TCP));
+
+        let bytes = peer.to_bytes();
+        assert_eq!(bytes.len(), PEER_LENGTH + 1 + 2 * PEER_OTHER_LENGTH);
+        assert_eq!(bytes[59], PEER_FLAG_PUB | PEER_FLAG_OTHERS);
+        assert_eq!(bytes[60], 2);
+
+        let new_peer = Peer::from_bytes(&bytes).unwrap();
+        assert!(new_peer == peer);
+        assert_eq!(new_peer.addrs(), peer.addrs());
+        assert_eq!(new_peer.addrs()[2].1, TransportType::TCP);
+
+        // prefix decode, with other bytes after it.
+        let mut more = bytes.clone();
+        more.extend([9u8; 5]);
+        let (new_peer, len) = Peer::decode(&more).unwrap();
+        assert!(new_peer == peer);
+        assert_eq!(len, bytes.len());
+        assert!(Peer::from_bytes(&more).is_err());
+
+        // truncated.
+        for i in 0..bytes.len() {
+            assert!(Peer::from_bytes(&bytes[..i]).is_err());
+            assert!(Peer::decode(&bytes[..i]).is_err());
+        }
+    }
+}
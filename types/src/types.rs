@@ -1,17 +1,18 @@
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Keccak256};
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::io::Result;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 #[inline]
 pub fn new_io_error(s: &str) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, s)
+    std::io::Error::other(s)
 }
 
 /// peer's network id.
 #[derive(Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
-pub struct PeerId(pub [u8; 20]);
+pub struct PeerId(pub [u8; PEER_ID_LENGTH]);
 
 pub const PEER_ID_LENGTH: usize = 20;
 
@@ -42,15 +43,25 @@ impl PeerId {
         self.0.to_vec()
     }
 
+    /// parse from hex string, if it is mixed-case, must be the valid checksum (EIP-55),
+    /// all-lowercase or all-uppercase is accepted without checksum.
     pub fn from_hex(s: &str) -> Result<PeerId> {
-        let raw = if s.starts_with("0x") { &s[2..] } else { s };
+        let raw = s.strip_prefix("0x").unwrap_or(s);
         let bytes = hex::decode(raw).map_err(|_| new_io_error("Invalid hex string"))?;
         if bytes.len() != PEER_ID_LENGTH {
             return Err(new_io_error("Invalid address length"));
         }
         let mut fixed_bytes = [0u8; PEER_ID_LENGTH];
         fixed_bytes.copy_from_slice(&bytes);
-        Ok(PeerId(fixed_bytes))
+        let peer_id = PeerId(fixed_bytes);
+
+        let has_lower = raw.chars().any(|c| c.is_ascii_lowercase());
+        let has_upper = raw.chars().any(|c| c.is_ascii_uppercase());
+        if has_lower && has_upper && peer_id.to_hex()[2..] != *raw {
+            return Err(new_io_error("Invalid address checksum"));
+        }
+
+        Ok(peer_id)
     }
 
     pub fn to_hex(&self) -> String {
@@ -60,7 +71,7 @@ impl PeerId {
         let mut hasher = Keccak256::new();
         hasher.update(hex.as_bytes());
         let hash = hasher.finalize();
-        let check_hash = hex::encode(&hash);
+        let check_hash = hex::encode(hash);
 
         let mut res = String::from("0x");
         for (index, byte) in hex[..PEER_ID_LENGTH * 2].chars().enumerate() {
@@ -74,12 +85,24 @@ impl PeerId {
     }
 }
 
+/// the deterministic leader of the peers (the lowest peer id), every peer
+/// compute it with same peers set (include self) will get same leader.
+pub fn elect_leader(peers: &[PeerId]) -> Option<PeerId> {
+    peers.iter().min().copied()
+}
+
 impl Debug for PeerId {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(f, "{}", self.to_hex())
     }
 }
 
+impl Display for PeerId {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
 /// support some common broadcast algorithm.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Broadcast {
@@ -87,6 +110,112 @@ pub enum Broadcast {
     StableAll,
 }
 
+/// The small metadata of a data (e.g. content-type, timestamp, sequence),
+/// sent alongside the data bytes.
+pub type Metadata = BTreeMap<String, Vec<u8>>;
+
+/// The send priority of data in a session, high will be sent first,
+/// e.g. small control messages with high, and bulk file with low.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// The tier of a peer set by outside, not the send priority of the data.
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PeerTier {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// The state of the session to a peer, in the order of a connection.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum SessionState {
+    /// dialing the peer.
+    Connecting,
+    /// our handshake is sent, waiting the remote's.
+    Handshaking,
+    /// the remote handshake is received, completing the session key.
+    KeyExchange,
+    /// the session is running, the data is sent immediately.
+    Ready,
+    /// the session is closing.
+    Closing,
+    /// the session is closed, or the connection failed.
+    Closed,
+}
+
+/// The reason when force close a connection.
+#[derive(Debug, Copy, Clone, Hash, Deserialize, Serialize, Eq, PartialEq)]
+pub enum CloseReason {
+    Normal,   // 0u8
+    Kick,     // 1u8
+    Resource, // 2u8
+    Crypto,   // 3u8
+    Version,  // 4u8
+    Lifetime, // 5u8
+}
+
+impl CloseReason {
+    pub fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0u8 => Ok(CloseReason::Normal),
+            1u8 => Ok(CloseReason::Kick),
+            2u8 => Ok(CloseReason::Resource),
+            3u8 => Ok(CloseReason::Crypto),
+            4u8 => Ok(CloseReason::Version),
+            5u8 => Ok(CloseReason::Lifetime),
+            _ => Err(new_io_error("close reason bytes failure.")),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            CloseReason::Normal => 0u8,
+            CloseReason::Kick => 1u8,
+            CloseReason::Resource => 2u8,
+            CloseReason::Crypto => 3u8,
+            CloseReason::Version => 4u8,
+            CloseReason::Lifetime => 5u8,
+        }
+    }
+}
+
+/// The reason when connect to a peer failure.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum ConnectError {
+    /// no result in time.
+    Timeout,
+    /// cannot connect to the address.
+    Unreachable,
+    /// handshake failure, invalid remote info or session key.
+    Handshake,
+    /// rejected by remote or self, e.g. blocked, too many sessions, connected.
+    Rejected,
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let s = match self {
+            ConnectError::Timeout => "connect timeout",
+            ConnectError::Unreachable => "connect unreachable",
+            ConnectError::Handshake => "connect handshake failure",
+            ConnectError::Rejected => "connect rejected",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// The connect result, it is the remote peer id when connected.
+pub type ConnectResult = std::result::Result<PeerId, ConnectError>;
+
 /// Transports types support by Endpoint.
 #[derive(Debug, Copy, Clone, Hash, Deserialize, Serialize, Eq, PartialEq)]
 pub enum TransportType {
@@ -98,6 +227,7 @@ pub enum TransportType {
 
 impl TransportType {
     /// transports from parse from str.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         match s {
             "quic" => TransportType::QUIC,
@@ -169,3 +299,47 @@ impl TransportStream {
         (self.sender, self.receiver)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elect_leader() {
+        let peers: Vec<PeerId> = [9u8, 3, 200, 42, 7]
+            .iter()
+            .map(|i| PeerId([*i; 20]))
+            .collect();
+        // every peer has its own view order, all elect the same.
+        for i in 0..peers.len() {
+            let mut view = peers.clone();
+            view.rotate_left(i);
+            assert_eq!(elect_leader(&view), Some(PeerId([3u8; 20])));
+        }
+        assert_eq!(elect_leader(&[]), None);
+    }
+
+    #[test]
+    fn test_peer_id_checksum() {
+        // EIP-55 vectors.
+        let vectors = [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ];
+        for v in vectors {
+            let peer_id = PeerId::from_hex(v).unwrap();
+            assert_eq!(peer_id.to_hex(), v);
+            assert_eq!(peer_id.to_string(), v);
+
+            // lenient for all-lowercase / all-uppercase.
+            assert_eq!(PeerId::from_hex(&v.to_lowercase()).unwrap(), peer_id);
+            assert_eq!(PeerId::from_hex(&v[2..].to_uppercase()).unwrap(), peer_id);
+        }
+
+        // wrong casing.
+        assert!(PeerId::from_hex("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD").is_err());
+        assert!(PeerId::from_hex("0x5AAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").is_err());
+    }
+}